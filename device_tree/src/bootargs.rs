@@ -0,0 +1,130 @@
+//! Kernel command line arguments, as passed in `bootargs` of `/chosen`.
+//!
+//! The command line is a list of arguments separated by whitespace, each
+//! either a `key=value` pair or a bare `key`. `BootArgs` keeps them in
+//! order, so that the platform can merge its compiled-in defaults with the
+//! ones from the bootloader and hand the result on as a string again.
+
+use core::fmt;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+use crate::DeviceTree;
+
+/// The arguments of a kernel command line.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BootArgs {
+    /// A list of `(key, value)`, where a bare `key` has no value.
+    args: Vec<(String, Option<String>)>,
+}
+
+impl BootArgs {
+    /// Create an empty command line.
+    pub fn new() -> Self {
+        Self { args: Vec::new() }
+    }
+
+    /// Parse a command line, keeping the arguments in order.
+    ///
+    /// Quoting isn't supported, so a value can't contain whitespace.
+    pub fn parse(cmdline: &str) -> Self {
+        let args = cmdline.split_whitespace()
+            .map(|arg| match arg.split_once('=') {
+                Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+                None => (arg.to_owned(), None),
+            })
+            .collect();
+        Self { args }
+    }
+
+    /// Parse `bootargs` of `/chosen` (or `/chosen@0`). The command line is
+    /// empty if there's no such node or property.
+    pub fn from_tree(dt: &DeviceTree) -> Self {
+        dt.find("/chosen").or_else(|| dt.find("/chosen@0"))
+            .and_then(|chosen| chosen.prop_str("bootargs").ok())
+            .map_or_else(Self::new, Self::parse)
+    }
+
+    /// Get the number of arguments.
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    /// Whether there are no arguments at all.
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// Whether `key` is given, with or without a value.
+    pub fn contains(&self, key: &str) -> bool {
+        self.args.iter().any(|(k, _)| k == key)
+    }
+
+    /// Get the value of `key`, which is the last one if it's given more
+    /// than once. A bare `key` has an empty value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.args.iter().rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_deref().unwrap_or(""))
+    }
+
+    /// Set `key` to `value`, or to a bare `key` if `value` is `None`.
+    ///
+    /// An existing `key` is overridden in place, and any other occurrences
+    /// of it are dropped; otherwise it's appended at the end.
+    pub fn set(&mut self, key: &str, value: Option<&str>) {
+        let value = value.map(|v| v.to_owned());
+        match self.args.iter().position(|(k, _)| k == key) {
+            Some(index) => {
+                self.args[index].1 = value;
+                let mut i = index + 1;
+                while i < self.args.len() {
+                    if self.args[i].0 == key {
+                        self.args.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+            },
+            None => self.args.push((key.to_owned(), value)),
+        }
+    }
+
+    /// Merge the arguments of `cmdline`, which override the ones with the
+    /// same keys already here.
+    pub fn append(&mut self, cmdline: &str) {
+        for (key, value) in BootArgs::parse(cmdline).args {
+            self.set(&key, value.as_deref());
+        }
+    }
+
+    /// Remove every occurrence of `key`, returning whether there was any.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let len = self.args.len();
+        self.args.retain(|(k, _)| k != key);
+        self.args.len() != len
+    }
+
+    /// Iterate over the arguments as `(key, value)`, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.args.iter().map(|(k, v)| (k.as_str(), v.as_deref()))
+    }
+}
+
+/// Serialize back into a command line, with the arguments separated by
+/// a single space.
+impl fmt::Display for BootArgs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(key)?;
+            if let Some(value) = value {
+                write!(f, "={}", value)?;
+            }
+        }
+        Ok(())
+    }
+}
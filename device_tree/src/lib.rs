@@ -14,7 +14,8 @@
 //! to try out are [the Raspberry Pi ones]
 //! (https://github.com/raspberrypi/firmware/tree/master/boot).
 //!
-//! The library does not use `std`, just `core`.
+//! The library does not use `std`, just `core` and `alloc`. Host-side tools
+//! can enable the `std` feature, which adds `DeviceTree::load_file`.
 //!
 //! # Examples
 //!
@@ -30,24 +31,42 @@
 //! }
 //! ```
 
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 extern crate core;
 extern crate alloc;
 
+pub mod bootargs;
+pub mod noderef;
 pub mod util;
 
+use core::fmt;
+use core::ops::ControlFlow;
 use core::str;
+use alloc::collections::BTreeMap;
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::borrow::ToOwned;
 use util::{align, SliceRead, SliceReadError};
+use noderef::DeviceTreeRef;
 
 const MAGIC_NUMBER     : u32 = 0xd00dfeed;
 const SUPPORTED_VERSION: u32 = 17;
+const MIN_VERSION      : u32 = 16;
 const OF_DT_BEGIN_NODE : u32 = 0x00000001;
 const OF_DT_END_NODE   : u32 = 0x00000002;
 const OF_DT_PROP       : u32 = 0x00000003;
+const OF_DT_NOP        : u32 = 0x00000004;
+const OF_DT_END        : u32 = 0x00000009;
+const HEADER_SIZE      : usize = 40;
+const HEADER_SIZE_V16  : usize = 36;
+
+/// The deepest level of nodes accepted by `load`, where the root is at 0.
+///
+/// `Node::load` recurses for each level, so a bound keeps a malformed blob
+/// from overflowing a small stack, such as the kernel's during boot.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
 
 /// An error describe parsing problems when creating device trees.
 #[derive(Debug)]
@@ -72,6 +91,10 @@ pub enum DeviceTreeError {
 
     /// The device tree version is not supported by this library.
     VersionNotSupported,
+
+    /// Failed to read the blob from a file.
+    #[cfg(feature = "std")]
+    IoError(std::io::Error),
 }
 
 /// Device tree structure.
@@ -92,7 +115,7 @@ pub struct DeviceTree {
 }
 
 /// A single node in the device tree.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Node {
     /// The name of the node, as it appears in the node path.
     pub name: String,
@@ -104,12 +127,140 @@ pub struct Node {
     pub children: Vec<Node>,
 }
 
+/// A property value decoded by `Node::get_property`.
+#[derive(Debug, PartialEq)]
+pub enum PropValue {
+    /// A property without value, e.g. `ranges;`.
+    Empty,
+    /// A single cell.
+    U32(u32),
+    /// An array of cells.
+    U32Array(Vec<u32>),
+    /// A NUL-terminated string.
+    Str(String),
+    /// A list of NUL-terminated strings.
+    StrList(Vec<String>),
+    /// Anything else.
+    Bytes(Vec<u8>),
+}
+
 #[derive(Debug)]
 pub enum PropError {
     NotFound,
     Utf8Error,
     Missing0,
     SliceReadError(SliceReadError),
+
+    /// Reading `needed` bytes at the byte offset `pos` runs out of the
+    /// property, whose value is `len` bytes long.
+    OutOfBounds { len: usize, pos: usize, needed: usize },
+}
+
+/// An entry of a "phandle + arguments" list such as `clocks`, `resets`
+/// or `*-gpios`, decoded by `DeviceTree::parse_phandle_with_args`.
+#[derive(Debug, PartialEq)]
+pub struct PhandleArgs<'a> {
+    /// The node referenced by the phandle, e.g. a GPIO controller.
+    pub node: &'a Node,
+    /// The cells following the phandle, as many as the node says.
+    pub args: Vec<u32>,
+}
+
+/// A GPIO specifier decoded by `DeviceTree::gpio_spec`.
+pub type GpioSpec<'a> = PhandleArgs<'a>;
+
+/// An iterator over the descendants of a node in depth-first pre-order,
+/// made by `Node::descendants`.
+#[derive(Debug, Clone)]
+pub struct Descendants<'a> {
+    // the children yet to visit at each level down from the node
+    stack: Vec<core::slice::Iter<'a, Node>>,
+}
+
+/// A borrowing iterator over a property as an array of big-endian values
+/// of `width` cells each, which reads the property in place.
+///
+/// Layouts mixing widths, such as `reg` with 2-cell addresses and 1-cell
+/// sizes, can step through the values with `read`. A trailing part too
+/// short for a whole value is left unread.
+#[derive(Debug, Clone)]
+pub struct Cells<'a> {
+    raw: &'a [u8],
+    width: usize,
+}
+
+// Where the blocks of a blob are, as found by `DeviceTree::read_header`.
+pub(crate) struct Header {
+    pub version: u32,
+    pub boot_cpuid_phys: u32,
+    pub off_mem_rsvmap: usize,
+    pub off_dt_struct: usize,
+    pub struct_end: usize,
+    pub off_dt_strings: usize,
+    pub strings_end: usize,
+}
+
+impl Header {
+    // The blob up to the declared end of the structure block, which is
+    // where walking the structure has to stop.
+    pub fn structs<'a>(&self, buffer: &'a [u8]) -> &'a [u8] {
+        &buffer[..self.struct_end]
+    }
+
+    // Where the reserved memory block has to end: at the structure block
+    // which follows it, or else at the end of the blob.
+    pub fn rsvmap_end(&self, buffer: &[u8]) -> usize {
+        if self.off_mem_rsvmap < self.off_dt_struct {
+            self.off_dt_struct
+        } else {
+            buffer.len()
+        }
+    }
+
+    // Exactly the strings block.
+    pub fn strings<'a>(&self, buffer: &'a [u8]) -> &'a [u8] {
+        &buffer[self.off_dt_strings..self.strings_end]
+    }
+}
+
+/// An error describing why a node path is malformed.
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    /// The path doesn't start with `/`.
+    NotAbsolute,
+    /// The path has an empty component, e.g. `//foo` or `/foo//bar`.
+    EmptyComponent,
+    /// The path other than `/` ends with `/`.
+    TrailingSlash,
+}
+
+/// An error describing why `DeviceTree::apply_overlay` failed.
+///
+/// Fragments are named by their node names in the overlay.
+#[derive(Debug, PartialEq)]
+pub enum OverlayError {
+    /// The fragment has neither `target-path` nor `target`.
+    NoTarget(String),
+    /// The target of the fragment isn't in the base tree.
+    TargetNotFound(String),
+}
+
+/// A difference between two trees reported by `DeviceTree::diff`.
+///
+/// Nodes are named by their full path, properties by the path of their
+/// node and their own name.
+#[derive(Debug, PartialEq)]
+pub enum TreeChange {
+    /// The node only exists in the other tree.
+    NodeAdded(String),
+    /// The node only exists in this tree.
+    NodeRemoved(String),
+    /// The property only exists in the other tree.
+    PropAdded(String, String),
+    /// The property only exists in this tree.
+    PropRemoved(String, String),
+    /// The property exists in both trees with different values.
+    PropChanged(String, String),
 }
 
 impl From<SliceReadError> for DeviceTreeError {
@@ -124,9 +275,76 @@ impl From<str::Utf8Error> for DeviceTreeError {
     }
 }
 
+#[cfg(feature = "std")]
+impl From<std::io::Error> for DeviceTreeError {
+    fn from(e: std::io::Error) -> DeviceTreeError {
+        DeviceTreeError::IoError(e)
+    }
+}
+
 impl DeviceTree {
     //! Load a device tree from a memory buffer.
+    //!
+    //! The blob is read byte by byte, but the format itself lays out every
+    //! header field, token and cell on a 4-byte boundary, and other users of
+    //! the same memory may rely on that. So the start of `buffer` is expected
+    //! to be 4-aligned; use `load_unaligned` if that can't be guaranteed.
+    //!
+    //! Trees of version 16 and 17 are accepted, as well as later ones still
+    //! compatible with 17 according to `last_comp_version`.
     pub fn load(buffer: &[u8]) -> Result<DeviceTree, DeviceTreeError> {
+        DeviceTree::load_with_max_depth(buffer, DEFAULT_MAX_DEPTH)
+    }
+
+    /// Like `load`, but nodes may be nested up to `max_depth` levels below
+    /// the root instead of `DEFAULT_MAX_DEPTH`. A deeper node is reported
+    /// as a `ParseError` at its position.
+    pub fn load_with_max_depth(buffer: &[u8], max_depth: usize)
+    -> Result<DeviceTree, DeviceTreeError> {
+        let header = Self::read_header(buffer)?;
+
+        // load reserved memory list, which must be terminated in its block
+        let mut reserved = Vec::new();
+        let mut pos = header.off_mem_rsvmap;
+        let rsvmap_end = header.rsvmap_end(buffer);
+
+        loop {
+            if pos + 16 > rsvmap_end {
+                return Err(DeviceTreeError::ParseError(pos));
+            }
+            let offset = buffer.read_be_u64(pos)?;
+            pos += 8;
+            let size = buffer.read_be_u64(pos)?;
+            pos += 8;
+
+            reserved.push((offset, size));
+
+            if size == 0 {
+                break;
+            }
+        }
+
+        let (_, root) = Node::load(header.structs(buffer), header.off_dt_struct,
+                                   header.strings(buffer), max_depth)?;
+
+        Ok(DeviceTree{
+            version: header.version,
+            boot_cpuid_phys: header.boot_cpuid_phys,
+            reserved: reserved,
+            root: root,
+        })
+    }
+
+    /// Like `load`, but borrow names and values from `buffer` rather than
+    /// copying them, so nothing is allocated at all. The blob is checked
+    /// as a whole here, just as `load` does.
+    pub fn load_ref<'a>(buffer: &'a [u8])
+    -> Result<DeviceTreeRef<'a>, DeviceTreeError> {
+        DeviceTreeRef::load(buffer, DEFAULT_MAX_DEPTH)
+    }
+
+    // Check the header of a blob and find out where its blocks are.
+    pub(crate) fn read_header(buffer: &[u8]) -> Result<Header, DeviceTreeError> {
         //  0  magic_number: u32,
 
         //  4  totalsize: u32,
@@ -154,42 +372,239 @@ impl DeviceTree {
             return Err(DeviceTreeError::SizeMismatch);
         }
 
-        // check version
+        let version = Self::check_version(buffer)?;
+
+        let off_dt_struct = buffer.read_be_u32(8)? as usize;
+        let off_dt_strings = buffer.read_be_u32(12)? as usize;
+        let off_mem_rsvmap = buffer.read_be_u32(16)? as usize;
+        let boot_cpuid_phys = buffer.read_be_u32(28)?;
+
+        // size_dt_strings (v3) and size_dt_struct (v17) bound the blocks.
+        // Without the latter, the structure block of a v16 tree runs up to
+        // the strings block following it, or to the end of the blob.
+        let size_dt_strings = buffer.read_be_u32(32)? as usize;
+        let struct_end = if version >= 17 {
+            off_dt_struct + buffer.read_be_u32(36)? as usize
+        } else if off_dt_strings > off_dt_struct {
+            off_dt_strings
+        } else {
+            buffer.len()
+        };
+        let strings_end = off_dt_strings + size_dt_strings;
+        if struct_end > buffer.len() || strings_end > buffer.len() {
+            return Err(DeviceTreeError::SizeMismatch);
+        }
+
+        Ok(Header {
+            version,
+            boot_cpuid_phys,
+            off_mem_rsvmap,
+            off_dt_struct,
+            struct_end,
+            off_dt_strings,
+            strings_end,
+        })
+    }
+
+    /// Like `load`, but accept a buffer at any address.
+    ///
+    /// If the start of `buffer` isn't 4-aligned, the blob is copied into
+    /// an aligned buffer first and the tree is loaded from the copy.
+    pub fn load_unaligned(buffer: &[u8]) -> Result<DeviceTree, DeviceTreeError> {
+        if (buffer.as_ptr() as usize).is_multiple_of(4) {
+            return DeviceTree::load(buffer);
+        }
+
+        // Vec<u8> makes no promise about its alignment, so back the copy
+        // with u32 words.
+        let mut words: Vec<u32> = vec![0; align(buffer.len(), 4) / 4];
+        let copy = unsafe {
+            core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8,
+                                            buffer.len())
+        };
+        copy.copy_from_slice(buffer);
+        DeviceTree::load(copy)
+    }
+
+    /// Load a device tree from a blob file on the host.
+    ///
+    /// The file is read as a whole, so its contents carry no alignment
+    /// promise and are loaded by `load_unaligned`.
+    #[cfg(feature = "std")]
+    pub fn load_file<P: AsRef<std::path::Path>>(path: P)
+    -> Result<DeviceTree, DeviceTreeError> {
+        let buffer = std::fs::read(path)?;
+        DeviceTree::load_unaligned(&buffer)
+    }
+
+    // Check the version of the blob and return it. Any version that is
+    // backwards compatible with v17 (by last_comp_version) is readable,
+    // except those before v16, whose node names are full paths.
+    fn check_version(buffer: &[u8]) -> Result<u32, DeviceTreeError> {
         let version = buffer.read_be_u32(20)?;
-        if version != SUPPORTED_VERSION {
+        let last_comp_version = buffer.read_be_u32(24)?;
+        if version < MIN_VERSION || last_comp_version > SUPPORTED_VERSION {
             return Err(DeviceTreeError::VersionNotSupported);
         }
+        Ok(version)
+    }
+
+    // The v16 header ends before size_dt_struct.
+    fn header_size(version: u32) -> usize {
+        if version >= 17 { HEADER_SIZE } else { HEADER_SIZE_V16 }
+    }
+
+    /// Serialize the tree into a flattened blob of version 17, which
+    /// `load` reads back into an equal tree.
+    ///
+    /// Property names share their entries in the strings block. Reserved
+    /// regions of size 0 are left out, since one would end the list early;
+    /// the terminator is added at the end instead. Anything too large for
+    /// the 32-bit fields of the format is a `SizeMismatch`.
+    pub fn store(&self) -> Result<Vec<u8>, DeviceTreeError> {
+        let mut structs = Vec::new();
+        let mut strings = Vec::new();
+        let mut offsets = BTreeMap::new();
+        self.root.store(&mut structs, &mut strings, &mut offsets)?;
+        structs.extend_from_slice(&OF_DT_END.to_be_bytes());
+
+        let reserved: Vec<_> = self.reserved.iter()
+            .filter(|r| r.1 != 0).collect();
+        let off_mem_rsvmap = HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + (reserved.len() + 1) * 16;
+        let off_dt_strings = off_dt_struct + structs.len();
+        let totalsize = off_dt_strings + strings.len();
+
+        let to_u32 = |v: usize| u32::try_from(v)
+            .map_err(|_| DeviceTreeError::SizeMismatch);
+        let mut blob = Vec::with_capacity(totalsize);
+        for field in [MAGIC_NUMBER, to_u32(totalsize)?, to_u32(off_dt_struct)?,
+                      to_u32(off_dt_strings)?, to_u32(off_mem_rsvmap)?,
+                      SUPPORTED_VERSION, MIN_VERSION, self.boot_cpuid_phys,
+                      to_u32(strings.len())?, to_u32(structs.len())?] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+
+        for &&(offset, size) in reserved.iter() {
+            blob.extend_from_slice(&offset.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+
+        blob.extend_from_slice(&structs);
+        blob.extend_from_slice(&strings);
+        Ok(blob)
+    }
+
+    /// Check the structure of a blob without building the tree.
+    ///
+    /// It verifies the header (magic, totalsize, version and the ordering
+    /// of blocks), the termination of the reserved memory block and that
+    /// nodes in the structure block are well nested. This is lighter than
+    /// `load` and meant to be a fast gate before trusting a blob.
+    pub fn verify(buffer: &[u8]) -> Result<(), DeviceTreeError> {
+        if buffer.read_be_u32(0)? != MAGIC_NUMBER {
+            return Err(DeviceTreeError::InvalidMagicNumber)
+        }
+
+        let totalsize = buffer.read_be_u32(4)? as usize;
+        if totalsize != buffer.len() || totalsize < HEADER_SIZE_V16 {
+            return Err(DeviceTreeError::SizeMismatch);
+        }
+
+        let header_size = Self::header_size(Self::check_version(buffer)?);
+        if totalsize < header_size {
+            return Err(DeviceTreeError::SizeMismatch);
+        }
 
         let off_dt_struct = buffer.read_be_u32(8)? as usize;
         let off_dt_strings = buffer.read_be_u32(12)? as usize;
         let off_mem_rsvmap = buffer.read_be_u32(16)? as usize;
-        let boot_cpuid_phys = buffer.read_be_u32(28)?;
 
-        // load reserved memory list
-        let mut reserved = Vec::new();
-        let mut pos = off_mem_rsvmap;
-
-        loop {
-            let offset = buffer.read_be_u64(pos)?;
-            pos += 8;
-            let size = buffer.read_be_u64(pos)?;
-            pos += 8;
+        if off_mem_rsvmap > totalsize || off_dt_struct > totalsize ||
+           off_dt_strings > totalsize {
+            return Err(DeviceTreeError::SizeMismatch);
+        }
 
-            reserved.push((offset, size));
+        // blocks are laid out as: header, rsvmap, struct, strings
+        if off_mem_rsvmap < header_size {
+            return Err(DeviceTreeError::ParseError(16));
+        }
+        if off_dt_struct < off_mem_rsvmap {
+            return Err(DeviceTreeError::ParseError(8));
+        }
+        if off_dt_strings < off_dt_struct {
+            return Err(DeviceTreeError::ParseError(12));
+        }
 
+        // the reserved memory block must be terminated inside itself
+        let mut pos = off_mem_rsvmap;
+        loop {
+            if pos + 16 > off_dt_struct {
+                return Err(DeviceTreeError::ParseError(pos));
+            }
+            let size = buffer.read_be_u64(pos+8)?;
+            pos += 16;
             if size == 0 {
                 break;
             }
         }
 
-        let (_, root) = Node::load(buffer, off_dt_struct, off_dt_strings)?;
+        Self::verify_struct(buffer, off_dt_struct, off_dt_strings)
+    }
 
-        Ok(DeviceTree{
-            version: version,
-            boot_cpuid_phys: boot_cpuid_phys,
-            reserved: reserved,
-            root: root,
-        })
+    fn verify_struct(buffer: &[u8], start: usize, end: usize)
+    -> Result<(), DeviceTreeError> {
+        // the structure block must not run into the strings block
+        let structs = &buffer[..end];
+        let mut pos = start;
+        let mut depth = 0;
+        let mut has_root = false;
+
+        loop {
+            let token = structs.read_be_u32(pos)?;
+            match token {
+                OF_DT_BEGIN_NODE => {
+                    // only one root node is allowed
+                    if depth == 0 && has_root {
+                        return Err(DeviceTreeError::ParseError(pos));
+                    }
+                    let name = structs.read_bstring0(pos+4)?;
+                    pos = align(pos + 4 + name.len() + 1, 4);
+                    depth += 1;
+                    has_root = true;
+                },
+                OF_DT_PROP => {
+                    if depth == 0 {
+                        return Err(DeviceTreeError::ParseError(pos));
+                    }
+                    let val_size = structs.read_be_u32(pos+4)? as usize;
+                    if pos + 12 + val_size > structs.len() {
+                        return Err(DeviceTreeError::ParseError(pos));
+                    }
+                    pos = align(pos + 12 + val_size, 4);
+                },
+                OF_DT_END_NODE => {
+                    if depth == 0 {
+                        return Err(DeviceTreeError::ParseError(pos));
+                    }
+                    depth -= 1;
+                    pos += 4;
+                },
+                OF_DT_NOP => {
+                    pos += 4;
+                },
+                OF_DT_END => {
+                    if depth != 0 || !has_root {
+                        return Err(DeviceTreeError::ParseError(pos));
+                    }
+                    return Ok(());
+                },
+                _ => {
+                    return Err(DeviceTreeError::ParseError(pos));
+                }
+            }
+        }
     }
 
     pub fn find<'a>(&'a self, path: &str) -> Option<&'a Node> {
@@ -200,36 +615,473 @@ impl DeviceTree {
 
         self.root.find(&path[1..])
     }
+
+    /// Like `find`, but a path segment without a unit address, such as
+    /// `uart` in `/soc/uart`, matches the first child with that name before
+    /// its `@`, e.g. `uart@10000000`. Segments with an `@` match exactly.
+    pub fn find_by_name<'a>(&'a self, path: &str) -> Option<&'a Node> {
+        if ! path.starts_with('/') {
+            return None
+        }
+
+        self.root.find_by_name(&path[1..])
+    }
+
+    /// Find the node an alias of `/aliases` refers to, e.g. `serial0` for
+    /// `serial0 = "/soc/uart@10000000"`. `None` if there's no such alias
+    /// or no node at the path it gives.
+    pub fn resolve_alias(&self, alias: &str) -> Option<&Node> {
+        let path = self.find("/aliases")?.prop_str(alias).ok()?;
+        self.find(path)
+    }
+
+    /// Like `find`, but get the node for changing it.
+    pub fn find_mut<'a>(&'a mut self, path: &str) -> Option<&'a mut Node> {
+        if ! path.starts_with('/') {
+            return None
+        }
+
+        self.root.find_mut(&path[1..])
+    }
+
+    /// Like `find`, but a malformed path is reported as an error
+    /// rather than just being a miss.
+    pub fn try_find<'a>(&'a self, path: &str)
+        -> Result<Option<&'a Node>, PathError> {
+        let rest = path.strip_prefix('/').ok_or(PathError::NotAbsolute)?;
+        if rest.is_empty() {
+            return Ok(Some(&self.root));
+        }
+        if rest.ends_with('/') {
+            return Err(PathError::TrailingSlash);
+        }
+        if rest.split('/').any(|s| s.is_empty()) {
+            return Err(PathError::EmptyComponent);
+        }
+
+        Ok(self.root.find(rest))
+    }
+
+    /// Get the frequency of the timebase (in Hz) shared by all cpus.
+    ///
+    /// It is normally found in `/cpus`, but some trees only put it in
+    /// the `cpu` nodes themselves, so fall back to those.
+    pub fn timebase_frequency(&self) -> Result<u64, PropError> {
+        let cpus = self.find("/cpus").ok_or(PropError::NotFound)?;
+        if let Ok(freq) = cpus.prop_cell("timebase-frequency") {
+            return Ok(freq);
+        }
+
+        for cpu in cpus.children.iter() {
+            match cpu.prop_str("device_type") {
+                Ok("cpu") => {},
+                _ => continue,
+            }
+            if let Ok(freq) = cpu.prop_cell("timebase-frequency") {
+                return Ok(freq);
+            }
+        }
+
+        Err(PropError::NotFound)
+    }
+
+    /// Get the number of all nodes in the tree, including the root.
+    pub fn node_count(&self) -> usize {
+        self.root.node_count()
+    }
+
+    /// Iterate over all nodes in depth-first pre-order, starting from
+    /// the root.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        core::iter::once(&self.root).chain(self.root.descendants())
+    }
+
+    /// Get the `(base, size)` regions of all memory nodes, i.e. children of
+    /// the root with `device_type = "memory"`, in the order of their `reg`.
+    ///
+    /// The cells come from `#address-cells` and `#size-cells` of the root,
+    /// which are 1 if absent. Regions of size 0 are left out. A `reg` not
+    /// made of whole entries is `OutOfBounds` at the partial entry.
+    pub fn memory_regions(&self) -> Result<Vec<(u64, u64)>, PropError> {
+        let addr_cells = self.root.prop_u32_or("#address-cells", 1) as usize;
+        let size_cells = self.root.prop_u32_or("#size-cells", 1) as usize;
+        let entry_size = (addr_cells + size_cells) * 4;
+
+        let mut regions = Vec::new();
+        for node in self.root.children.iter() {
+            if node.prop_str("device_type").map_or(true, |t| t != "memory") {
+                continue;
+            }
+            let reg = match node.prop_raw("reg") {
+                Some(reg) if entry_size > 0 => reg.as_slice(),
+                _ => continue,
+            };
+
+            for (i, entry) in reg.chunks(entry_size).enumerate() {
+                if entry.len() < entry_size {
+                    return Err(PropError::OutOfBounds {
+                        len: reg.len(), pos: i * entry_size, needed: entry_size,
+                    });
+                }
+                let (base, size) = entry.split_at(addr_cells * 4);
+                let (base, size) = (Self::read_number(base), Self::read_number(size));
+                if size != 0 {
+                    regions.push((base, size));
+                }
+            }
+        }
+        Ok(regions)
+    }
+
+    // Read a number of any cells, keeping the low 64 bits like Linux does.
+    fn read_number(cells: &[u8]) -> u64 {
+        cells.chunks(4).fold(0, |val, cell| {
+            let cell = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+            (val << 32) | cell as u64
+        })
+    }
+
+    /// Iterate over the nodes listing `compat` in `compatible`, in the same
+    /// order as `nodes`.
+    pub fn find_compatible<'a>(&'a self, compat: &'a str)
+        -> impl Iterator<Item = &'a Node> {
+        self.nodes().filter(move |n| n.is_compatible(compat))
+    }
+
+    /// Get the interrupt specifiers of `node`, each of which is made of
+    /// `#interrupt-cells` cells of its interrupt controller.
+    ///
+    /// The controller is the node referenced by `interrupt-parent`, which
+    /// is inherited from the ancestors, falling back to the parent of
+    /// `node` as the binding says.
+    pub fn interrupts_of(&self, node: &Node) -> Result<Vec<Vec<u32>>, PropError> {
+        let raw = node.prop_raw("interrupts").ok_or(PropError::NotFound)?;
+
+        let path = self.path_to(node).ok_or(PropError::NotFound)?;
+        let parent = path.iter().rev()
+            .find_map(|n| n.prop_u32("interrupt-parent").ok());
+        let controller = match parent {
+            Some(phandle) => self.find_phandle(phandle),
+            None if path.len() >= 2 => Some(path[path.len() - 2]),
+            None => None,
+        }.ok_or(PropError::NotFound)?;
+
+        let cells = controller.prop_u32("#interrupt-cells")? as usize;
+        if cells == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut specs = Vec::new();
+        let mut pos = 0;
+        while pos < raw.len() {
+            let mut spec = Vec::with_capacity(cells);
+            for _ in 0..cells {
+                spec.push(raw.as_slice().read_be_u32(pos)?);
+                pos += 4;
+            }
+            specs.push(spec);
+        }
+        Ok(specs)
+    }
+
+    /// Decode a list of phandles each followed by its arguments, e.g.
+    /// `clocks = <&osc 0 &pll 2>`.
+    ///
+    /// The number of arguments after a phandle is the value of `cells_name`
+    /// (such as `#clock-cells`) in the node it references. A phandle of 0
+    /// is an empty entry without arguments, which is skipped.
+    pub fn parse_phandle_with_args<'a>(&'a self, node: &Node, prop: &str,
+                                       cells_name: &str)
+        -> Result<Vec<PhandleArgs<'a>>, PropError> {
+        let raw = node.prop_raw(prop).ok_or(PropError::NotFound)?.as_slice();
+
+        let mut list = Vec::new();
+        let mut pos = 0;
+        while pos < raw.len() {
+            let phandle = raw.read_be_u32(pos)?;
+            pos += 4;
+            if phandle == 0 {
+                continue;
+            }
+
+            let target = self.find_phandle(phandle).ok_or(PropError::NotFound)?;
+            let cells = target.prop_u32(cells_name)? as usize;
+            let mut args = Vec::with_capacity(cells);
+            for _ in 0..cells {
+                args.push(raw.read_be_u32(pos)?);
+                pos += 4;
+            }
+            list.push(PhandleArgs { node: target, args });
+        }
+        Ok(list)
+    }
+
+    /// Get the GPIO specifiers of `prop` in `node`, such as `reset-gpios`,
+    /// whose arguments are sized by `#gpio-cells` of their controllers.
+    pub fn gpio_spec<'a>(&'a self, node: &Node, prop: &str)
+        -> Result<Vec<GpioSpec<'a>>, PropError> {
+        self.parse_phandle_with_args(node, prop, "#gpio-cells")
+    }
+
+    /// Get the first node whose `phandle` (or `linux,phandle`) matches,
+    /// in the order of `nodes`.
+    ///
+    /// This scans the tree on each call; build a `phandle_index` instead
+    /// to resolve many phandles.
+    pub fn find_phandle(&self, phandle: u32) -> Option<&Node> {
+        self.nodes().find(|n| n.phandle() == Some(phandle))
+    }
+
+    /// Apply a device tree overlay to this tree.
+    ///
+    /// Each child of the overlay root with an `__overlay__` node is a
+    /// fragment, whose target in this tree is given by `target-path`, or
+    /// by `target` as a phandle. The `__overlay__` node is merged into its
+    /// target by `Node::merge_overlay`. Other nodes such as `__fixups__`
+    /// are ignored, so phandles in the overlay must be resolved already.
+    ///
+    /// The tree is left unchanged if any fragment fails.
+    pub fn apply_overlay(&mut self, overlay: &DeviceTree)
+        -> Result<(), OverlayError> {
+        let mut root = self.root.clone();
+        for fragment in overlay.root.children.iter() {
+            if let Some(patch) = fragment.child("__overlay__") {
+                Self::overlay_target(&mut root, fragment)?
+                    .merge_overlay(patch);
+            }
+        }
+        self.root = root;
+        Ok(())
+    }
+
+    fn overlay_target<'a>(root: &'a mut Node, fragment: &Node)
+        -> Result<&'a mut Node, OverlayError> {
+        let target = if let Ok(path) = fragment.prop_str("target-path") {
+            path.strip_prefix('/').and_then(|p| root.find_mut(p))
+        } else if let Ok(phandle) = fragment.prop_u32("target") {
+            root.find_phandle_mut(phandle)
+        } else {
+            return Err(OverlayError::NoTarget(fragment.name.clone()));
+        };
+        target.ok_or_else(|| OverlayError::TargetNotFound(fragment.name.clone()))
+    }
+
+    /// Build an index from phandles to their nodes.
+    ///
+    /// It's for callers resolving many phandles, each of which would be a
+    /// walk of the whole tree with `find_phandle`. If a phandle is used by
+    /// more than one node, the first one in depth-first order wins as in
+    /// `find_phandle`.
+    pub fn phandle_index(&self) -> BTreeMap<u32, &Node> {
+        fn walk<'a>(node: &'a Node, index: &mut BTreeMap<u32, &'a Node>) {
+            if let Some(phandle) = node.phandle() {
+                index.entry(phandle).or_insert(node);
+            }
+            for child in node.children.iter() {
+                walk(child, index);
+            }
+        }
+        let mut index = BTreeMap::new();
+        walk(&self.root, &mut index);
+        index
+    }
+
+    /// Get the nodes from the root down to `node` itself, which has to
+    /// be a node of this tree (it's compared by address).
+    fn path_to<'a>(&'a self, node: &Node) -> Option<Vec<&'a Node>> {
+        fn walk<'a>(cur: &'a Node, node: &Node, path: &mut Vec<&'a Node>)
+            -> bool {
+            path.push(cur);
+            if core::ptr::eq(cur, node) ||
+                cur.children.iter().any(|n| walk(n, node, path)) {
+                return true;
+            }
+            path.pop();
+            false
+        }
+
+        let mut path = Vec::new();
+        if walk(&self.root, node, &mut path) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Enumerate the nodes and properties that differ from `other`,
+    /// from the point of view of `self`, e.g. a property only present
+    /// in `other` is reported as added.
+    ///
+    /// Header fields and reserved regions are not part of the diff.
+    pub fn diff(&self, other: &DeviceTree) -> Vec<TreeChange> {
+        let mut changes = Vec::new();
+        self.root.diff_into(&other.root, "/", &mut changes);
+        changes
+    }
+}
+
+/// Build a `DeviceTree` in memory without assembling a blob.
+///
+/// Nodes are opened by `begin_node` and closed by `end_node` in the same
+/// order as in the structure block, and properties go to the node opened
+/// last. The result has the same shape as what `load` yields for the
+/// equivalent blob, including the terminating entry of `reserved`.
+///
+/// ```ignore
+/// let dt = DeviceTreeBuilder::new()
+///     .begin_node("")
+///         .prop_u32("#address-cells", 2)
+///         .begin_node("chosen")
+///         .end_node()
+///     .end_node()
+///     .build();
+/// ```
+pub struct DeviceTreeBuilder {
+    boot_cpuid_phys: u32,
+    reserved: Vec<(u64, u64)>,
+    // nodes opened but not closed yet, the innermost one is the last
+    open: Vec<Node>,
+    root: Option<Node>,
+}
+
+impl Default for DeviceTreeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTreeBuilder {
+    pub fn new() -> Self {
+        DeviceTreeBuilder {
+            boot_cpuid_phys: 0,
+            reserved: Vec::new(),
+            open: Vec::new(),
+            root: None,
+        }
+    }
+
+    pub fn boot_cpuid_phys(&mut self, cpuid: u32) -> &mut Self {
+        self.boot_cpuid_phys = cpuid;
+        self
+    }
+
+    /// Add an entry to the reserved memory list.
+    pub fn reserve(&mut self, base: u64, size: u64) -> &mut Self {
+        self.reserved.push((base, size));
+        self
+    }
+
+    /// Open a new node as a child of the current one.
+    ///
+    /// Panics if the root node has been closed already.
+    pub fn begin_node(&mut self, name: &str) -> &mut Self {
+        assert!(self.root.is_none(), "only one root node is allowed");
+        self.open.push(Node {
+            name: name.to_owned(),
+            props: Vec::new(),
+            children: Vec::new(),
+        });
+        self
+    }
+
+    /// Add a property to the current node.
+    ///
+    /// Panics if there's no open node.
+    pub fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+        let node = self.open.last_mut().expect("no open node for property");
+        node.props.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Add a property of a single big-endian cell to the current node.
+    pub fn prop_u32(&mut self, name: &str, value: u32) -> &mut Self {
+        self.prop(name, &value.to_be_bytes())
+    }
+
+    /// Close the current node.
+    ///
+    /// Panics if there's no open node.
+    pub fn end_node(&mut self) -> &mut Self {
+        let node = self.open.pop().expect("no open node to end");
+        match self.open.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => self.root = Some(node),
+        }
+        self
+    }
+
+    /// Take the tree built so far, which leaves the builder empty.
+    ///
+    /// Panics if any node is still open or there's no root node.
+    pub fn build(&mut self) -> DeviceTree {
+        assert!(self.open.is_empty(), "nodes are still open");
+        let root = self.root.take().expect("no root node");
+
+        // load keeps the terminator of the reserved memory list
+        let mut reserved = core::mem::take(&mut self.reserved);
+        reserved.push((0, 0));
+
+        DeviceTree {
+            version: SUPPORTED_VERSION,
+            boot_cpuid_phys: self.boot_cpuid_phys,
+            reserved,
+            root,
+        }
+    }
+}
+
+impl PartialEq for DeviceTree {
+    fn eq(&self, other: &Self) -> bool {
+        self.version == other.version &&
+            self.boot_cpuid_phys == other.boot_cpuid_phys &&
+            self.reserved == other.reserved &&
+            self.root == other.root
+    }
 }
 
 
 impl Node {
-    fn load(buffer: &[u8], start: usize, off_dt_strings: usize)
+    // `structs` ends at the declared end of the structure block and
+    // `strings` is exactly the strings block, so walking past either one
+    // is an error instead of reading whatever follows. Children may be
+    // nested `depth_left` more levels below this node.
+    fn load(structs: &[u8], start: usize, strings: &[u8], depth_left: usize)
     -> Result<(usize, Node), DeviceTreeError> {
         // check for DT_BEGIN_NODE
-        if buffer.read_be_u32(start)? != OF_DT_BEGIN_NODE {
+        if Self::read_token(structs, start)? != OF_DT_BEGIN_NODE {
             return Err(DeviceTreeError::ParseError(start))
         }
 
-        let raw_name = buffer.read_bstring0(start+4)?;
+        // the name can't reach beyond the end of the structure block
+        let name_max = structs.len().saturating_sub(start+4);
+        let raw_name = structs.read_bstring0_max(start+4, name_max)
+            .map_err(|_| DeviceTreeError::ParseError(start))?;
 
         // read all the props
         let mut pos = align(start + 4 + raw_name.len() + 1, 4);
 
-        let mut props = Vec::new();
+        // the props are counted first, which is a cheap walk over their
+        // headers, so that big nodes don't reallocate the vec over and over
+        let mut props = Vec::with_capacity(Self::count_props(structs, pos));
 
-        while buffer.read_be_u32(pos)? == OF_DT_PROP {
-            let val_size = buffer.read_be_u32(pos+4)? as usize;
-            let name_offset = buffer.read_be_u32(pos+8)? as usize;
+        while Self::read_token(structs, pos)? == OF_DT_PROP {
+            if pos + 12 > structs.len() {
+                return Err(DeviceTreeError::ParseError(pos));
+            }
+            let val_size = structs.read_be_u32(pos+4)? as usize;
+            let name_offset = structs.read_be_u32(pos+8)? as usize;
 
             // get value slice
             let val_start = pos + 12;
             let val_end = val_start + val_size;
-            let val = buffer.subslice(val_start, val_end)?;
+            if val_end > structs.len() {
+                return Err(DeviceTreeError::ParseError(pos));
+            }
+            let val = &structs[val_start..val_end];
 
             // lookup name in strings table
-            let prop_name =
-                buffer.read_bstring0(off_dt_strings + name_offset)?;
+            let name_max = strings.len().saturating_sub(name_offset);
+            let prop_name = strings.read_bstring0_max(name_offset, name_max)?;
 
             props.push((
                 str::from_utf8(prop_name)?.to_owned(),
@@ -242,15 +1094,18 @@ impl Node {
         // finally, parse children
         let mut children = Vec::new();
 
-        while buffer.read_be_u32(pos)? == OF_DT_BEGIN_NODE {
-            let (new_pos, child_node) = Node::load(buffer, pos,
-                off_dt_strings)?;
+        while Self::read_token(structs, pos)? == OF_DT_BEGIN_NODE {
+            if depth_left == 0 {
+                return Err(DeviceTreeError::ParseError(pos));
+            }
+            let (new_pos, child_node) =
+                Node::load(structs, pos, strings, depth_left - 1)?;
             pos = new_pos;
 
             children.push(child_node);
         }
 
-        if buffer.read_be_u32(pos)? != OF_DT_END_NODE {
+        if Self::read_token(structs, pos)? != OF_DT_END_NODE {
             return Err(DeviceTreeError::ParseError(pos))
         }
 
@@ -263,6 +1118,77 @@ impl Node {
         }))
     }
 
+    // Count the props starting from `pos`. This only skips their headers
+    // and values; a malformed prop just ends the count, and is reported by
+    // `load` while reading it.
+    fn count_props(structs: &[u8], mut pos: usize) -> usize {
+        let mut count = 0;
+        while let Ok(OF_DT_PROP) = Self::read_token(structs, pos) {
+            let val_end = match structs.read_be_u32(pos+4) {
+                Ok(size) => pos + 12 + size as usize,
+                Err(_) => break,
+            };
+            if val_end > structs.len() {
+                break;
+            }
+            count += 1;
+            pos = align(val_end, 4);
+        }
+        count
+    }
+
+    // Append the node to the structure block in the layout `load` reads,
+    // with each token on a 4-byte boundary. New property names go to the
+    // strings block, whose entries are found by name in `offsets`.
+    fn store<'a>(&'a self, structs: &mut Vec<u8>, strings: &mut Vec<u8>,
+                 offsets: &mut BTreeMap<&'a str, u32>)
+    -> Result<(), DeviceTreeError> {
+        fn pad(buf: &mut Vec<u8>) {
+            buf.resize(align(buf.len(), 4), 0);
+        }
+
+        structs.extend_from_slice(&OF_DT_BEGIN_NODE.to_be_bytes());
+        structs.extend_from_slice(self.name.as_bytes());
+        structs.push(0);
+        pad(structs);
+
+        for (key, val) in self.props.iter() {
+            let name_offset = match offsets.get(key.as_str()) {
+                Some(&offset) => offset,
+                None => {
+                    let offset = u32::try_from(strings.len())
+                        .map_err(|_| DeviceTreeError::SizeMismatch)?;
+                    strings.extend_from_slice(key.as_bytes());
+                    strings.push(0);
+                    offsets.insert(key.as_str(), offset);
+                    offset
+                },
+            };
+            let val_size = u32::try_from(val.len())
+                .map_err(|_| DeviceTreeError::SizeMismatch)?;
+
+            structs.extend_from_slice(&OF_DT_PROP.to_be_bytes());
+            structs.extend_from_slice(&val_size.to_be_bytes());
+            structs.extend_from_slice(&name_offset.to_be_bytes());
+            structs.extend_from_slice(val);
+            pad(structs);
+        }
+
+        for child in self.children.iter() {
+            child.store(structs, strings, offsets)?;
+        }
+
+        structs.extend_from_slice(&OF_DT_END_NODE.to_be_bytes());
+        Ok(())
+    }
+
+    fn read_token(structs: &[u8], pos: usize) -> Result<u32, DeviceTreeError> {
+        if pos + 4 > structs.len() {
+            return Err(DeviceTreeError::ParseError(pos));
+        }
+        Ok(structs.read_be_u32(pos)?)
+    }
+
     pub fn find<'a>(&'a self, path: &str) -> Option<&'a Node> {
         if path == "" {
             return Some(self)
@@ -290,6 +1216,168 @@ impl Node {
         }
     }
 
+    /// Like `find`, but get the node for changing it.
+    pub fn find_mut<'a>(&'a mut self, path: &str) -> Option<&'a mut Node> {
+        if path.is_empty() {
+            return Some(self)
+        }
+
+        let (name, subpath) = match path.find('/') {
+            Some(idx) => (&path[..idx], &path[idx+1..]),
+            None => (path, ""),
+        };
+        self.children.iter_mut().find(|n| n.name == name)?.find_mut(subpath)
+    }
+
+    /// Like `find`, but matching segments without unit addresses as
+    /// `DeviceTree::find_by_name` does.
+    pub fn find_by_name<'a>(&'a self, path: &str) -> Option<&'a Node> {
+        if path.is_empty() {
+            return Some(self)
+        }
+
+        let (name, subpath) = match path.find('/') {
+            Some(idx) => (&path[..idx], &path[idx+1..]),
+            None => (path, ""),
+        };
+        let matches = |n: &&Node| if name.contains('@') {
+            n.name == name
+        } else {
+            n.name.split('@').next() == Some(name)
+        };
+        self.children.iter().find(matches)?.find_by_name(subpath)
+    }
+
+    fn find_phandle_mut(&mut self, phandle: u32) -> Option<&mut Node> {
+        if self.phandle() == Some(phandle) {
+            return Some(self);
+        }
+        self.children.iter_mut().find_map(|n| n.find_phandle_mut(phandle))
+    }
+
+    /// Set the value of a property, which is added if it's not there.
+    pub fn set_prop(&mut self, name: &str, value: &[u8]) {
+        match self.props.iter_mut().find(|(key, _)| key == name) {
+            Some((_, val)) => *val = value.to_owned(),
+            None => self.props.push((name.to_owned(), value.to_owned())),
+        }
+    }
+
+    /// Merge the properties and children of `overlay` into this node.
+    ///
+    /// Properties of the overlay override those of the same name here.
+    /// Its children are merged into the children of the same name, or
+    /// added as new ones. The name of `overlay` itself doesn't matter.
+    pub fn merge_overlay(&mut self, overlay: &Node) {
+        for (key, val) in overlay.props.iter() {
+            self.set_prop(key, val);
+        }
+        for child in overlay.children.iter() {
+            match self.children.iter_mut().find(|n| n.name == child.name) {
+                Some(n) => n.merge_overlay(child),
+                None => self.children.push(child.clone()),
+            }
+        }
+    }
+
+    /// Get the number of direct children.
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Get the number of properties.
+    pub fn prop_count(&self) -> usize {
+        self.props.len()
+    }
+
+    /// Whether the node has no children.
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Get the number of nodes in the subtree rooted at this node.
+    pub fn node_count(&self) -> usize {
+        1 + self.children.iter().map(|n| n.node_count()).sum::<usize>()
+    }
+
+    /// Visit the subtree rooted at this node in depth-first pre-order.
+    ///
+    /// The visitor gets each node with its depth, which is 0 for this node.
+    /// Returning `ControlFlow::Break` stops the walk at once, and the walk
+    /// returns `Break` as well in that case.
+    pub fn walk<F>(&self, visitor: &mut F) -> ControlFlow<()>
+    where F: FnMut(&Node, usize) -> ControlFlow<()> {
+        self.walk_at(0, visitor)
+    }
+
+    /// Iterate over the nodes below this one (but not itself) in
+    /// depth-first pre-order. Unlike `walk`, it doesn't recurse, so
+    /// a deep tree costs heap rather than stack.
+    pub fn descendants(&self) -> Descendants<'_> {
+        Descendants { stack: vec![self.children.iter()] }
+    }
+
+    fn walk_at<F>(&self, depth: usize, visitor: &mut F) -> ControlFlow<()>
+    where F: FnMut(&Node, usize) -> ControlFlow<()> {
+        visitor(self, depth)?;
+        for child in self.children.iter() {
+            child.walk_at(depth + 1, visitor)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|n| n.name == name)
+    }
+
+    fn child_path(path: &str, name: &str) -> String {
+        let mut child = String::from(path);
+        if !path.ends_with('/') {
+            child.push('/');
+        }
+        child.push_str(name);
+        child
+    }
+
+    fn diff_into(&self, other: &Node, path: &str,
+                 changes: &mut Vec<TreeChange>) {
+        for (key, val) in self.props.iter() {
+            match other.prop_raw(key) {
+                None => changes.push(
+                    TreeChange::PropRemoved(path.to_owned(), key.clone())),
+                Some(v) if v != val => changes.push(
+                    TreeChange::PropChanged(path.to_owned(), key.clone())),
+                _ => {},
+            }
+        }
+        for (key, _) in other.props.iter() {
+            if !self.has_prop(key) {
+                changes.push(
+                    TreeChange::PropAdded(path.to_owned(), key.clone()));
+            }
+        }
+
+        for child in self.children.iter() {
+            let child_path = Node::child_path(path, &child.name);
+            match other.child(&child.name) {
+                Some(n) => child.diff_into(n, &child_path, changes),
+                None => changes.push(TreeChange::NodeRemoved(child_path)),
+            }
+        }
+        for child in other.children.iter() {
+            if self.child(&child.name).is_none() {
+                let child_path = Node::child_path(path, &child.name);
+                changes.push(TreeChange::NodeAdded(child_path));
+            }
+        }
+    }
+
+    /// Iterate over the properties as `(name, value)` in the order of
+    /// the blob, without exposing how they are stored.
+    pub fn props_iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.props.iter().map(|(key, val)| (key.as_str(), val.as_slice()))
+    }
+
     pub fn has_prop(&self, name: &str) -> bool {
         if let Some(_) = self.prop_raw(name) {
             true
@@ -317,6 +1405,31 @@ impl Node {
         Ok(str::from_utf8(&raw[..(l-1)])?)
     }
 
+    /// Get a stringlist property such as `compatible`, which is a number
+    /// of strings each terminated by NUL.
+    ///
+    /// The whole value is checked to be UTF-8 here, so the iterator just
+    /// yields the strings in order.
+    pub fn prop_str_list<'a>(&'a self, name: &str)
+        -> Result<impl Iterator<Item = &'a str>, PropError> {
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+
+        let l = raw.len();
+        if l < 1 || raw[l-1] != 0 {
+            return Err(PropError::Missing0)
+        }
+
+        // NUL is ASCII, so the parts are UTF-8 iff the whole is
+        Ok(str::from_utf8(&raw[..(l-1)])?.split('\0'))
+    }
+
+    /// Whether `compat` is one of the strings in `compatible`. A node
+    /// without a well-formed `compatible` is compatible with nothing.
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop_str_list("compatible")
+            .is_ok_and(|mut list| list.any(|s| s == compat))
+    }
+
     pub fn prop_raw<'a>(&'a self, name: &str) -> Option<&'a Vec<u8>> {
         for &(ref key, ref val) in self.props.iter() {
             if key == name {
@@ -326,11 +1439,29 @@ impl Node {
         None
     }
 
-    pub fn prop_u64_at(&self, name: &str, pos: usize)
-        -> Result<u64, PropError> {
+    /// Iterate over property `name` as values of `width` (1 or 2) cells,
+    /// without allocating.
+    pub fn cells<'a>(&'a self, name: &str, width: usize)
+        -> Result<Cells<'a>, PropError> {
         let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+        Ok(Cells::new(raw, width))
+    }
 
-        Ok(raw.as_slice().read_be_u64(pos)?)
+    /// Like `cells`, but property `name` must be made of whole values of
+    /// `cell_count` cells, or it's an error rather than a short read.
+    pub fn prop_cells<'a>(&'a self, name: &str, cell_count: usize)
+        -> Result<Cells<'a>, PropError> {
+        let cells = self.cells(name, cell_count)?;
+        if !self.prop_len(name).is_multiple_of(cell_count * 4) {
+            return Err(PropError::SliceReadError(
+                SliceReadError::UnexpectedEndOfInput));
+        }
+        Ok(cells)
+    }
+
+    pub fn prop_u64_at(&self, name: &str, pos: usize)
+        -> Result<u64, PropError> {
+        Ok(self.prop_raw_at(name, pos, 8)?.read_be_u64(0)?)
     }
 
     pub fn prop_u64(&self, name: &str) -> Result<u64, PropError> {
@@ -339,14 +1470,263 @@ impl Node {
 
     pub fn prop_u32_at(&self, name: &str, pos: usize)
         -> Result<u32, PropError> {
+        Ok(self.prop_raw_at(name, pos, 4)?.read_be_u32(0)?)
+    }
+
+    /// Get `len` raw bytes of a property from the byte offset `pos`.
+    /// It's `OutOfBounds` if the value is too short to have them.
+    pub fn prop_raw_at<'a>(&'a self, name: &str, pos: usize, len: usize)
+        -> Result<&'a [u8], PropError> {
         let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+        match pos.checked_add(len) {
+            Some(end) if end <= raw.len() => Ok(&raw[pos..end]),
+            _ => Err(PropError::OutOfBounds { len: raw.len(), pos, needed: len }),
+        }
+    }
 
-        Ok(raw.as_slice().read_be_u32(pos)?)
+    /// Read a little-endian u32 at the byte offset `pos` of a property.
+    ///
+    /// This is non-standard: cells of the device tree are big-endian, so
+    /// it's only for vendor properties which embed little-endian data.
+    pub fn prop_le_u32_at(&self, name: &str, pos: usize)
+        -> Result<u32, PropError> {
+        Ok(self.prop_raw_at(name, pos, 4)?.read_le_u32(0)?)
+    }
+
+    /// Like `prop_le_u32_at`, but read a little-endian u64. Non-standard.
+    pub fn prop_le_u64_at(&self, name: &str, pos: usize)
+        -> Result<u64, PropError> {
+        Ok(self.prop_raw_at(name, pos, 8)?.read_le_u64(0)?)
     }
 
     pub fn prop_u32(&self, name: &str) -> Result<u32, PropError> {
         self.prop_u32_at(name, 0)
     }
+
+    /// Read a u32 property, or return `default` if it can't be read.
+    ///
+    /// Useful for optional properties when probing drivers, e.g. a UART:
+    ///
+    /// ```ignore
+    /// let uart = dt.find("/soc/serial@10000000").unwrap();
+    /// let base = uart.prop_u64_at("reg", 0)?;
+    /// let irq = uart.prop_u32("interrupts")?;
+    /// let clock = uart.prop_u32_or("clock-frequency", 1843200);
+    /// ```
+    pub fn prop_u32_or(&self, name: &str, default: u32) -> u32 {
+        self.prop_u32(name).unwrap_or(default)
+    }
+
+    /// Get a property with its value decoded by guessing its type.
+    ///
+    /// The binary format doesn't record types of values, so this is only
+    /// a heuristic (e.g. a cell may happen to look like a string) which
+    /// is meant for generic tree browsers. `prop_raw` and the typed
+    /// accessors remain authoritative when the binding is known.
+    pub fn get_property(&self, name: &str) -> Option<PropValue> {
+        let raw = self.prop_raw(name)?;
+
+        if raw.is_empty() {
+            return Some(PropValue::Empty);
+        }
+
+        if let Some(mut strs) = Self::guess_str_list(raw) {
+            if strs.len() == 1 {
+                return Some(PropValue::Str(strs.remove(0)));
+            }
+            return Some(PropValue::StrList(strs));
+        }
+
+        if raw.len() % 4 == 0 {
+            let mut cells = Vec::with_capacity(raw.len() / 4);
+            for pos in (0..raw.len()).step_by(4) {
+                cells.push(raw.as_slice().read_be_u32(pos).ok()?);
+            }
+            if cells.len() == 1 {
+                return Some(PropValue::U32(cells[0]));
+            }
+            return Some(PropValue::U32Array(cells));
+        }
+
+        Some(PropValue::Bytes(raw.clone()))
+    }
+
+    /// Treat the value as strings if it consists of non-empty printable
+    /// ASCII strings which are all terminated by NUL.
+    fn guess_str_list(raw: &[u8]) -> Option<Vec<String>> {
+        if !Self::looks_like_str_list(raw) {
+            return None;
+        }
+
+        let mut strs = Vec::new();
+        for s in raw[..raw.len()-1].split(|c| *c == 0) {
+            strs.push(str::from_utf8(s).ok()?.to_owned());
+        }
+        Some(strs)
+    }
+
+    fn looks_like_str_list(raw: &[u8]) -> bool {
+        raw.last() == Some(&0) &&
+            raw[..raw.len()-1].split(|c| *c == 0).all(|s| {
+                !s.is_empty() && s.iter().all(|c| (0x20..0x7f).contains(c))
+            })
+    }
+
+    // Write the subtree in .dts syntax, indented by `depth` levels. Values
+    // are formatted by the same guess as `get_property`, straight from the
+    // raw bytes.
+    fn fmt_at(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let indent = depth * 2;
+        let name = if depth == 0 && self.name.is_empty() { "/" } else { &self.name };
+        writeln!(f, "{:indent$}{} {{", "", name, indent = indent)?;
+
+        for (key, raw) in self.props.iter() {
+            write!(f, "{:indent$}{}", "", key, indent = indent + 2)?;
+            if raw.is_empty() {
+                writeln!(f, ";")?;
+                continue;
+            }
+
+            f.write_str(" = ")?;
+            if Self::looks_like_str_list(raw) {
+                for (i, s) in raw[..raw.len()-1].split(|c| *c == 0).enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_str("\"")?;
+                    for &c in s {
+                        if c == b'"' || c == b'\\' {
+                            f.write_str("\\")?;
+                        }
+                        write!(f, "{}", c as char)?;
+                    }
+                    f.write_str("\"")?;
+                }
+            } else if raw.len() % 4 == 0 {
+                f.write_str("<")?;
+                for (i, cell) in raw.chunks(4).enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{:#x}", cell.read_be_u32(0).map_err(|_| fmt::Error)?)?;
+                }
+                f.write_str(">")?;
+            } else {
+                f.write_str("[")?;
+                for (i, byte) in raw.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{:02x}", byte)?;
+                }
+                f.write_str("]")?;
+            }
+            writeln!(f, ";")?;
+        }
+
+        for child in self.children.iter() {
+            writeln!(f)?;
+            child.fmt_at(f, depth + 1)?;
+        }
+        writeln!(f, "{:indent$}}};", "", indent = indent)
+    }
+
+    /// Get the `(address, size)` of the register bank labelled `name`
+    /// in `reg-names`, which pairs with the entries of `reg` by position.
+    ///
+    /// `addr_cells` and `size_cells` come from `#address-cells` and
+    /// `#size-cells` of the parent. Returns `None` if the name isn't
+    /// present or `reg` has no entry at its index.
+    pub fn reg_named(&self, name: &str, addr_cells: usize, size_cells: usize)
+        -> Option<(u64, u64)> {
+        let names = self.prop_raw("reg-names")?;
+        if names.last() != Some(&0) {
+            return None;
+        }
+        let index = names[..names.len()-1].split(|c| *c == 0)
+            .position(|s| s == name.as_bytes())?;
+
+        let reg = self.prop_raw("reg")?.as_slice();
+        let entry_size = (addr_cells + size_cells) * 4;
+        if entry_size == 0 || reg.len() % entry_size != 0 ||
+            (index + 1) * entry_size > reg.len() {
+            return None;
+        }
+
+        let pos = index * entry_size;
+        let addr = Self::read_cells(reg, pos, addr_cells)?;
+        let size = Self::read_cells(reg, pos + addr_cells * 4, size_cells)?;
+        Some((addr, size))
+    }
+
+    /// Read a number of zero, one or two cells at `pos`.
+    fn read_cells(raw: &[u8], pos: usize, cells: usize) -> Option<u64> {
+        match cells {
+            0 => Some(0),
+            1 => raw.read_be_u32(pos).ok().map(|v| v as u64),
+            2 => raw.read_be_u64(pos).ok(),
+            _ => None,
+        }
+    }
+
+    /// Get the value of `phandle`, or `linux,phandle` of older trees.
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle")
+            .or_else(|_| self.prop_u32("linux,phandle")).ok()
+    }
+
+    /// Read a property which may be encoded as either one or two cells.
+    fn prop_cell(&self, name: &str) -> Result<u64, PropError> {
+        if self.prop_len(name) == 8 {
+            self.prop_u64(name)
+        } else {
+            Ok(self.prop_u32(name)? as u64)
+        }
+    }
+}
+
+/// Nodes are equal if they have the same name, the same properties
+/// regardless of their order, and equal children in the same order.
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        if self.name != other.name ||
+            self.props.len() != other.props.len() ||
+            self.children.len() != other.children.len() {
+            return false;
+        }
+
+        let mut props: Vec<_> = self.props.iter().collect();
+        let mut other_props: Vec<_> = other.props.iter().collect();
+        props.sort();
+        other_props.sort();
+        if props != other_props {
+            return false;
+        }
+
+        // names may repeat among siblings, so match the children by position
+        self.children.iter().zip(other.children.iter()).all(|(a, b)| a == b)
+    }
+}
+
+/// Print the tree in .dts syntax, with the reserved memory regions as
+/// `/memreserve/` entries.
+impl fmt::Display for DeviceTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "/dts-v1/;")?;
+        for &(offset, size) in self.reserved.iter().filter(|r| r.1 != 0) {
+            writeln!(f, "/memreserve/ {:#x} {:#x};", offset, size)?;
+        }
+        writeln!(f)?;
+        self.root.fmt_at(f, 0)
+    }
+}
+
+/// Print the subtree rooted at the node in .dts syntax, indenting each
+/// level by two spaces.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_at(f, 0)
+    }
 }
 
 impl From<str::Utf8Error> for PropError {
@@ -360,3 +1740,62 @@ impl From<SliceReadError> for PropError {
         PropError::SliceReadError(e)
     }
 }
+
+impl<'a> Iterator for Descendants<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        loop {
+            match self.stack.last_mut()?.next() {
+                Some(node) => {
+                    self.stack.push(node.children.iter());
+                    return Some(node);
+                },
+                None => {
+                    self.stack.pop();
+                },
+            }
+        }
+    }
+}
+
+impl<'a> Cells<'a> {
+    /// Read `raw` as values of `width` cells, which must be 1 or 2.
+    pub fn new(raw: &'a [u8], width: usize) -> Self {
+        assert!(width == 1 || width == 2, "bad cell width {}", width);
+        Self { raw, width }
+    }
+
+    /// Read the next value of `cells` (0, 1 or 2) cells, whatever the
+    /// width of the iterator is. Nothing is consumed on failure.
+    pub fn read(&mut self, cells: usize) -> Option<u64> {
+        let val = Node::read_cells(self.raw, 0, cells)?;
+        self.raw = &self.raw[cells * 4..];
+        Some(val)
+    }
+
+    /// Get the number of cells left.
+    pub fn remaining(&self) -> usize {
+        self.raw.len() / 4
+    }
+
+    /// Whether all the cells have been read.
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+impl<'a> Iterator for Cells<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.read(self.width)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.remaining() / self.width;
+        (n, Some(n))
+    }
+}
+
+impl<'a> ExactSizeIterator for Cells<'a> {}
@@ -0,0 +1,310 @@
+//! A borrowing view of a device tree, which reads names and values in
+//! place from the blob instead of copying them.
+//!
+//! `DeviceTree::load_ref` checks the whole structure block once, the same
+//! way `DeviceTree::load` does, but without allocating anything. Nodes and
+//! properties are then found by walking the blob again on each lookup,
+//! which trades some time for memory on targets where the heap is scarce.
+
+use core::str;
+
+use crate::util::{align, SliceRead, SliceReadError};
+use crate::{DeviceTreeError, Node, PropError};
+use crate::{OF_DT_BEGIN_NODE, OF_DT_END_NODE, OF_DT_PROP};
+
+/// A device tree borrowed from its blob.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceTreeRef<'a> {
+    /// Version, as indicated by version header
+    pub version: u32,
+
+    /// The number of the CPU the system boots from
+    pub boot_cpuid_phys: u32,
+
+    /// The root node.
+    pub root: NodeRef<'a>,
+
+    buffer: &'a [u8],
+    off_mem_rsvmap: usize,
+}
+
+/// A node borrowed from the blob.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeRef<'a> {
+    /// The name of the node, as it appears in the node path.
+    pub name: &'a str,
+
+    structs: &'a [u8],
+    strings: &'a [u8],
+    // where the props of the node start
+    props_pos: usize,
+}
+
+/// An iterator over the properties of a `NodeRef`, as `(key, value)`.
+#[derive(Debug, Clone)]
+pub struct PropsRef<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    pos: usize,
+}
+
+/// An iterator over the children of a `NodeRef`.
+#[derive(Debug, Clone)]
+pub struct ChildrenRef<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    pos: usize,
+}
+
+/// An iterator over the reserved memory regions, as `(offset, length)`.
+#[derive(Debug, Clone)]
+pub struct ReservedRef<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+// Get the nul-terminated string at `pos`, which has to end inside `raw`.
+// Unlike `SliceRead::read_bstring0`, it borrows from the blob itself.
+fn bstring0(raw: &[u8], pos: usize) -> Option<&[u8]> {
+    let tail = raw.get(pos..)?;
+    let len = tail.iter().position(|c| *c == 0)?;
+    Some(&tail[..len])
+}
+
+impl<'a> DeviceTreeRef<'a> {
+    pub(crate) fn load(buffer: &'a [u8], max_depth: usize)
+    -> Result<DeviceTreeRef<'a>, DeviceTreeError> {
+        let header = crate::DeviceTree::read_header(buffer)?;
+
+        // the reserved memory list must be terminated
+        let mut pos = header.off_mem_rsvmap;
+        let rsvmap_end = header.rsvmap_end(buffer);
+        loop {
+            if pos + 16 > rsvmap_end {
+                return Err(DeviceTreeError::ParseError(pos));
+            }
+            if buffer.read_be_u64(pos + 8)? == 0 {
+                break;
+            }
+            pos += 16;
+        }
+
+        let (_, root) = NodeRef::load(header.structs(buffer),
+                                      header.off_dt_struct,
+                                      header.strings(buffer), max_depth)?;
+
+        Ok(DeviceTreeRef {
+            version: header.version,
+            boot_cpuid_phys: header.boot_cpuid_phys,
+            root,
+            buffer,
+            off_mem_rsvmap: header.off_mem_rsvmap,
+        })
+    }
+
+    /// Iterate over the reserved memory regions. Unlike
+    /// `DeviceTree::reserved`, the terminating `(0, 0)` isn't included.
+    pub fn reserved(&self) -> ReservedRef<'a> {
+        ReservedRef { buffer: self.buffer, pos: self.off_mem_rsvmap }
+    }
+
+    /// Find a node by its absolute path, like `DeviceTree::find`.
+    pub fn find(&self, path: &str) -> Option<NodeRef<'a>> {
+        // we only find root nodes on the device tree
+        if ! path.starts_with('/') {
+            return None
+        }
+
+        self.root.find(&path[1..])
+    }
+}
+
+impl<'a> NodeRef<'a> {
+    // Check the node at `start` and everything below it just like
+    // `Node::load`, returning the node and the position after it.
+    fn load(structs: &'a [u8], start: usize, strings: &'a [u8],
+            depth_left: usize)
+    -> Result<(usize, NodeRef<'a>), DeviceTreeError> {
+        let node = NodeRef::at(structs, start, strings)?;
+
+        let mut props = node.props();
+        while Node::read_token(structs, props.pos)? == OF_DT_PROP {
+            props.read()?;
+        }
+
+        let mut pos = props.pos;
+        while Node::read_token(structs, pos)? == OF_DT_BEGIN_NODE {
+            if depth_left == 0 {
+                return Err(DeviceTreeError::ParseError(pos));
+            }
+            let (new_pos, _) =
+                NodeRef::load(structs, pos, strings, depth_left - 1)?;
+            pos = new_pos;
+        }
+
+        if Node::read_token(structs, pos)? != OF_DT_END_NODE {
+            return Err(DeviceTreeError::ParseError(pos))
+        }
+
+        Ok((pos + 4, node))
+    }
+
+    // Read the BEGIN_NODE token and the name of the node at `start`.
+    fn at(structs: &'a [u8], start: usize, strings: &'a [u8])
+    -> Result<NodeRef<'a>, DeviceTreeError> {
+        if Node::read_token(structs, start)? != OF_DT_BEGIN_NODE {
+            return Err(DeviceTreeError::ParseError(start))
+        }
+
+        // the name can't reach beyond the end of the structure block
+        let raw_name = bstring0(structs, start+4)
+            .ok_or(DeviceTreeError::ParseError(start))?;
+
+        Ok(NodeRef {
+            name: str::from_utf8(raw_name)?,
+            structs,
+            strings,
+            props_pos: align(start + 4 + raw_name.len() + 1, 4),
+        })
+    }
+
+    /// Iterate over the properties.
+    pub fn props(&self) -> PropsRef<'a> {
+        PropsRef {
+            structs: self.structs,
+            strings: self.strings,
+            pos: self.props_pos,
+        }
+    }
+
+    /// Iterate over the direct children.
+    pub fn children(&self) -> ChildrenRef<'a> {
+        let mut props = self.props();
+        while props.next().is_some() {}
+
+        ChildrenRef {
+            structs: self.structs,
+            strings: self.strings,
+            pos: props.pos,
+        }
+    }
+
+    /// Get the first direct child named `name`.
+    pub fn child(&self, name: &str) -> Option<NodeRef<'a>> {
+        self.children().find(|n| n.name == name)
+    }
+
+    /// Find a node by its path relative to this node, like `Node::find`.
+    pub fn find(&self, path: &str) -> Option<NodeRef<'a>> {
+        if path.is_empty() {
+            return Some(*self)
+        }
+
+        match path.find('/') {
+            Some(idx) => self.child(&path[..idx])?.find(&path[idx+1..]),
+            None => self.child(path),
+        }
+    }
+
+    pub fn has_prop(&self, name: &str) -> bool {
+        self.prop_raw(name).is_some()
+    }
+
+    pub fn prop_raw(&self, name: &str) -> Option<&'a [u8]> {
+        self.props().find(|(key, _)| *key == name).map(|(_, val)| val)
+    }
+
+    pub fn prop_str(&self, name: &str) -> Result<&'a str, PropError> {
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+
+        let l = raw.len();
+        if l < 1 || raw[l-1] != 0 {
+            return Err(PropError::Missing0)
+        }
+
+        Ok(str::from_utf8(&raw[..(l-1)])?)
+    }
+
+    pub fn prop_u32_at(&self, name: &str, pos: usize)
+        -> Result<u32, PropError> {
+        let raw = self.prop_raw(name).ok_or(PropError::NotFound)?;
+        match pos.checked_add(4) {
+            Some(end) if end <= raw.len() => Ok(raw.read_be_u32(pos)?),
+            _ => Err(PropError::OutOfBounds { len: raw.len(), pos, needed: 4 }),
+        }
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Result<u32, PropError> {
+        self.prop_u32_at(name, 0)
+    }
+}
+
+impl<'a> PropsRef<'a> {
+    // Read the property at `pos`, which must be a PROP token, and step
+    // over it.
+    fn read(&mut self) -> Result<(&'a str, &'a [u8]), DeviceTreeError> {
+        let (structs, strings, pos) = (self.structs, self.strings, self.pos);
+        if pos + 12 > structs.len() {
+            return Err(DeviceTreeError::ParseError(pos));
+        }
+        let val_size = structs.read_be_u32(pos+4)? as usize;
+        let name_offset = structs.read_be_u32(pos+8)? as usize;
+
+        let val_start = pos + 12;
+        let val_end = val_start + val_size;
+        if val_end > structs.len() {
+            return Err(DeviceTreeError::ParseError(pos));
+        }
+
+        let name = bstring0(strings, name_offset)
+            .ok_or(SliceReadError::UnexpectedEndOfInput)?;
+
+        self.pos = align(val_end, 4);
+        Ok((str::from_utf8(name)?, &structs[val_start..val_end]))
+    }
+}
+
+impl<'a> Iterator for PropsRef<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if Node::read_token(self.structs, self.pos).ok()? != OF_DT_PROP {
+            return None;
+        }
+        self.read().ok()
+    }
+}
+
+impl<'a> Iterator for ChildrenRef<'a> {
+    type Item = NodeRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if Node::read_token(self.structs, self.pos).ok()? != OF_DT_BEGIN_NODE {
+            return None;
+        }
+        let child = NodeRef::at(self.structs, self.pos, self.strings).ok()?;
+
+        // step over the whole subtree of the child to its sibling
+        let mut children = child.children();
+        while children.next().is_some() {}
+        if Node::read_token(self.structs, children.pos).ok()? != OF_DT_END_NODE {
+            return None;
+        }
+        self.pos = children.pos + 4;
+        Some(child)
+    }
+}
+
+impl<'a> Iterator for ReservedRef<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.buffer.read_be_u64(self.pos).ok()?;
+        let size = self.buffer.read_be_u64(self.pos + 8).ok()?;
+        if size == 0 {
+            return None;
+        }
+        self.pos += 16;
+        Some((offset, size))
+    }
+}
@@ -13,16 +13,35 @@ pub enum SliceReadError {
 pub type SliceReadResult<T> = Result<T, SliceReadError>;
 
 pub trait SliceRead {
+    fn read_be_u16(&self, pos: usize) -> SliceReadResult<u16>;
     fn read_be_u32(&self, pos: usize) -> SliceReadResult<u32>;
     fn read_be_u64(&self, pos: usize) -> SliceReadResult<u64>;
+    fn read_be_i32(&self, pos: usize) -> SliceReadResult<i32>;
+    fn read_be_i64(&self, pos: usize) -> SliceReadResult<i64>;
+    fn read_le_u32(&self, pos: usize) -> SliceReadResult<u32>;
+    fn read_le_u64(&self, pos: usize) -> SliceReadResult<u64>;
     fn read_bstring0(&self, pos: usize) -> SliceReadResult<&[u8]>;
+    fn read_bstring0_max(&self, pos: usize, max_len: usize)
+        -> SliceReadResult<&[u8]>;
     fn subslice(&self, start: usize, len: usize) -> SliceReadResult<&[u8]>;
 }
 
 impl<'a> SliceRead for &'a [u8] {
+    fn read_be_u16(&self, pos: usize) -> SliceReadResult<u16> {
+        // check size is valid
+        if pos+2 > self.len() {
+            return Err(SliceReadError::UnexpectedEndOfInput)
+        }
+
+        Ok(
+            (self[pos] as u16) << 8
+            | (self[pos+1] as u16)
+        )
+    }
+
     fn read_be_u32(&self, pos: usize) -> SliceReadResult<u32> {
         // check size is valid
-        if ! (pos+4 <= self.len()) {
+        if pos+4 > self.len() {
             return Err(SliceReadError::UnexpectedEndOfInput)
         }
 
@@ -36,7 +55,7 @@ impl<'a> SliceRead for &'a [u8] {
 
     fn read_be_u64(&self, pos: usize) -> SliceReadResult<u64> {
         // check size is valid
-        if ! (pos+8 <= self.len()) {
+        if pos+8 > self.len() {
             return Err(SliceReadError::UnexpectedEndOfInput)
         }
 
@@ -52,9 +71,60 @@ impl<'a> SliceRead for &'a [u8] {
         )
     }
 
+    // signed values are stored as two's complement in the same layout
+    fn read_be_i32(&self, pos: usize) -> SliceReadResult<i32> {
+        self.read_be_u32(pos).map(|v| v as i32)
+    }
+
+    fn read_be_i64(&self, pos: usize) -> SliceReadResult<i64> {
+        self.read_be_u64(pos).map(|v| v as i64)
+    }
+
+    // little-endian reads aren't for the device tree format itself, which
+    // is big-endian throughout, but for vendor data embedded in properties
+    fn read_le_u32(&self, pos: usize) -> SliceReadResult<u32> {
+        // check size is valid
+        if pos+4 > self.len() {
+            return Err(SliceReadError::UnexpectedEndOfInput)
+        }
+
+        Ok(
+            (self[pos] as u32)
+            | (self[pos+1] as u32) << 8
+            | (self[pos+2] as u32) << 16
+            | (self[pos+3] as u32) << 24
+        )
+    }
+
+    fn read_le_u64(&self, pos: usize) -> SliceReadResult<u64> {
+        // check size is valid
+        if pos+8 > self.len() {
+            return Err(SliceReadError::UnexpectedEndOfInput)
+        }
+
+        Ok(
+            (self[pos] as u64)
+            | (self[pos+1] as u64) << 8
+            | (self[pos+2] as u64) << 16
+            | (self[pos+3] as u64) << 24
+            | (self[pos+4] as u64) << 32
+            | (self[pos+5] as u64) << 40
+            | (self[pos+6] as u64) << 48
+            | (self[pos+7] as u64) << 56
+        )
+    }
+
     fn read_bstring0(&self, pos: usize) -> SliceReadResult<&[u8]> {
+        self.read_bstring0_max(pos, self.len().saturating_sub(pos))
+    }
+
+    // the NUL must be within `max_len` bytes from `pos`, so a string
+    // missing its NUL can't make the scan run on through the buffer
+    fn read_bstring0_max(&self, pos: usize, max_len: usize)
+        -> SliceReadResult<&[u8]> {
+        let end = core::cmp::min(pos.saturating_add(max_len), self.len());
         let mut cur = pos;
-        while cur < self.len() {
+        while cur < end {
             if self[cur] == 0 {
                 return Ok(&self[pos..cur])
             }
@@ -66,7 +136,7 @@ impl<'a> SliceRead for &'a [u8] {
     }
 
     fn subslice(&self, start: usize, end: usize) -> SliceReadResult<&[u8]> {
-        if ! (end < self.len()) {
+        if end >= self.len() {
             return Err(SliceReadError::UnexpectedEndOfInput)
         }
 
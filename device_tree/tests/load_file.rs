@@ -0,0 +1,94 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+#![cfg(feature = "std")]
+
+use std::fs;
+use std::path::PathBuf;
+use device_tree::{DeviceTree, DeviceTreeError};
+
+fn push_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_be_bytes());
+}
+
+/* "/" with a property "model" and an empty child "chosen" */
+fn sample_blob() -> Vec<u8> {
+    let mut structs = Vec::new();
+    push_u32(&mut structs, 0x1);            /* BEGIN_NODE "" */
+    push_u32(&mut structs, 0);
+    push_u32(&mut structs, 0x3);            /* PROP model */
+    push_u32(&mut structs, 4);
+    push_u32(&mut structs, 0);
+    structs.extend_from_slice(b"toy\0");
+    push_u32(&mut structs, 0x1);            /* BEGIN_NODE "chosen" */
+    structs.extend_from_slice(b"chosen\0\0");
+    push_u32(&mut structs, 0x2);            /* END_NODE */
+    push_u32(&mut structs, 0x2);            /* END_NODE */
+    push_u32(&mut structs, 0x9);            /* END */
+    let strings = b"model\0";
+
+    let off_mem_rsvmap = 40;
+    let off_dt_struct = off_mem_rsvmap + 16;
+    let off_dt_strings = off_dt_struct + structs.len();
+    let totalsize = off_dt_strings + strings.len();
+
+    let mut blob = Vec::new();
+    push_u32(&mut blob, 0xd00dfeed);
+    push_u32(&mut blob, totalsize as u32);
+    push_u32(&mut blob, off_dt_struct as u32);
+    push_u32(&mut blob, off_dt_strings as u32);
+    push_u32(&mut blob, off_mem_rsvmap as u32);
+    push_u32(&mut blob, 17);
+    push_u32(&mut blob, 16);
+    push_u32(&mut blob, 0);
+    push_u32(&mut blob, strings.len() as u32);
+    push_u32(&mut blob, structs.len() as u32);
+    blob.extend_from_slice(&[0u8; 16]);
+    blob.extend_from_slice(&structs);
+    blob.extend_from_slice(strings);
+    blob
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!("device_tree-{}-{}", std::process::id(), name));
+    path
+}
+
+#[test]
+fn test_load_file() {
+    let blob = sample_blob();
+    let path = temp_path("sample.dtb");
+    fs::write(&path, &blob).unwrap();
+
+    let dt = DeviceTree::load_file(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(dt == DeviceTree::load(&blob).unwrap());
+    assert_eq!(dt.root.prop_str("model").unwrap(), "toy");
+    assert!(dt.find("/chosen").is_some());
+}
+
+#[test]
+fn test_load_file_missing() {
+    let path = temp_path("missing.dtb");
+    assert!(matches!(DeviceTree::load_file(&path),
+                     Err(DeviceTreeError::IoError(_))));
+}
+
+#[test]
+fn test_load_file_bad_magic() {
+    let mut blob = sample_blob();
+    blob[0] = 0;
+    let path = temp_path("bad.dtb");
+    fs::write(&path, &blob).unwrap();
+
+    let result = DeviceTree::load_file(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(DeviceTreeError::InvalidMagicNumber)));
+}
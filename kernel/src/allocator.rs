@@ -27,6 +27,7 @@ use crate::types::*;
 use crate::klib::list::{List, Linked};
 use crate::page::vm_page_t;
 use crate::pmm::{pmm_alloc_pages, pmm_alloc_contiguous, paddr_to_vm_page, pmm_free};
+use crate::pmm::PMM_ALLOC_FLAG_ANY;
 
 extern crate alloc;
 
@@ -237,7 +238,7 @@ impl VirtualAlloc {
         }
         /* Allocate and map the bitmap pages into the start of the range
          * we were given. */
-        self.alloc_map_pages(base, bitmap_pages)?;
+        self.alloc_map_pages(base, bitmap_pages, PMM_ALLOC_FLAG_ANY)?;
 
         self.bitmap.storage_init(base, bitmap_pages * PAGE_SIZE);
 
@@ -254,7 +255,7 @@ impl VirtualAlloc {
         Ok(())
     }
 
-    fn alloc_map_pages(&self, va: vaddr_t, num_pages: usize)
+    fn alloc_map_pages(&self, va: vaddr_t, num_pages: usize, alloc_flags: u32)
         -> Result<(), ErrNO> {
 
         let mmu_flags = ARCH_MMU_FLAG_CACHED |
@@ -279,9 +280,34 @@ impl VirtualAlloc {
              * alignment, and so we must use the general
              * pmm_alloc_contiguous. */
             let mut pa: paddr_t = 0;
-            pmm_alloc_contiguous(align_pages, 0, self.align_log2,
-                                 &mut pa, &mut contiguous_pages)?;
-            panic!("mapped_count + align_pages <= num_pages");
+            if pmm_alloc_contiguous(align_pages, alloc_flags, self.align_log2,
+                                    &mut pa, &mut contiguous_pages).is_err() {
+                /* No such run is left, so fall back to
+                 * allocating the rest of the pages one by one. */
+                break;
+            }
+
+            for page in contiguous_pages.iter() {
+                unsafe { (*page).set_state(self.allocated_page_state); }
+            }
+
+            let ret = unsafe {
+                let aspace_list = ASPACE_LIST.lock();
+                let kernel_aspace = aspace_list.head();
+                (*kernel_aspace).map_contiguous(va + mapped_count * PAGE_SIZE,
+                                                pa, align_pages, mmu_flags)
+            };
+            match ret {
+                Ok(mapped) => ZX_ASSERT!(mapped == align_pages),
+                Err(e) => {
+                    pmm_free(&mut contiguous_pages);
+                    Self::unwind_map_pages(va, mapped_count, &mut alloc_pages);
+                    return Err(e);
+                }
+            }
+
+            alloc_pages.splice(&mut contiguous_pages);
+            mapped_count += align_pages;
         }
 
         if mapped_count == num_pages {
@@ -291,7 +317,11 @@ impl VirtualAlloc {
         /* Allocate any remaining pages. */
         let mut remaining_pages = List::<vm_page_t>::new();
         remaining_pages.init();
-        pmm_alloc_pages(num_pages - mapped_count, 0, &mut remaining_pages)?;
+        if let Err(e) = pmm_alloc_pages(num_pages - mapped_count, alloc_flags,
+                                        &mut remaining_pages) {
+            Self::unwind_map_pages(va, mapped_count, &mut alloc_pages);
+            return Err(e);
+        }
 
         /* Place them specifically at the end of any already allocated pages.
          * This ensures that if we should iterate too far we will hit
@@ -299,10 +329,9 @@ impl VirtualAlloc {
          * never attempt to map something twice.
          * Due to how list_node's work this does not affect the current_page
          * pointer we already retrieved. */
+        let mut page = remaining_pages.head();
         alloc_pages.splice(&mut remaining_pages);
 
-        let mut page = alloc_pages.head();
-
         while mapped_count < num_pages {
             let mut paddrs: [usize; BATCH_PAGES] = [0; BATCH_PAGES];
             let map_pages = min(BATCH_PAGES, num_pages - mapped_count);
@@ -319,15 +348,20 @@ impl VirtualAlloc {
                 }
             }
 
-            unsafe {
+            let ret = unsafe {
                 let aspace_list = ASPACE_LIST.lock();
                 println!("alloc_map_pages");
                 let kernel_aspace = aspace_list.head();
-                let mapped =
-                    (*kernel_aspace).map(va + mapped_count * PAGE_SIZE,
-                                         &paddrs[..], map_pages, mmu_flags,
-                                         ExistingEntryAction::Error)?;
-                ZX_ASSERT!(mapped == map_pages);
+                (*kernel_aspace).map(va + mapped_count * PAGE_SIZE,
+                                     &paddrs[..], map_pages, mmu_flags,
+                                     ExistingEntryAction::Error)
+            };
+            match ret {
+                Ok(mapped) => ZX_ASSERT!(mapped == map_pages),
+                Err(e) => {
+                    Self::unwind_map_pages(va, mapped_count, &mut alloc_pages);
+                    return Err(e);
+                }
             }
 
             mapped_count += map_pages;
@@ -336,7 +370,28 @@ impl VirtualAlloc {
         Ok(())
     }
 
+    /* Undo a failed alloc_map_pages: unmap the |mapped_count| pages
+     * mapped at |va| so far, and free all of the pages allocated. */
+    fn unwind_map_pages(va: vaddr_t, mapped_count: usize,
+                        alloc_pages: &mut List<vm_page_t>) {
+        if mapped_count > 0 {
+            let aspace_list = ASPACE_LIST.lock();
+            let kernel_aspace = aspace_list.head();
+            let ret = unsafe { (*kernel_aspace).unmap(va, mapped_count, false) };
+            ZX_ASSERT!(ret == Ok(mapped_count));
+        }
+        pmm_free(alloc_pages);
+    }
+
+    #[allow(dead_code)]
     pub fn alloc_pages(&mut self, pages: usize) -> Result<vaddr_t, ErrNO> {
+        self.alloc_pages_etc(pages, PMM_ALLOC_FLAG_ANY)
+    }
+
+    /* Same as alloc_pages, but the backing pages are allocated with
+     * alloc_flags, e.g. to prefer a numa node. */
+    pub fn alloc_pages_etc(&mut self, pages: usize, alloc_flags: u32)
+        -> Result<vaddr_t, ErrNO> {
         if self.alloc_base == 0 {
             return Err(ErrNO::BadState);
         }
@@ -352,7 +407,7 @@ impl VirtualAlloc {
         /* Turn the bitmap index into a virtual address and
          * allocate the pages there. */
         let vstart = self.alloc_base + start * PAGE_SIZE;
-        self.alloc_map_pages(vstart, pages)?;
+        self.alloc_map_pages(vstart, pages, alloc_flags)?;
 
         Ok(vstart)
     }
@@ -418,9 +473,8 @@ impl VirtualAlloc {
         }
         let unmapped = unsafe { (*kernel_aspace).unmap(va, pages, false)? };
         ZX_ASSERT!(unmapped == pages);
-        pmm_free(&free_list);
-
-        todo!("unmap_free_pages!");
+        pmm_free(&mut free_list);
+        Ok(())
     }
 
     fn bitmap_alloc(&mut self, num_pages: usize) -> Result<vaddr_t, ErrNO> {
@@ -451,15 +505,15 @@ impl VirtualAlloc {
          * The assumption here is that the block of pages we map in later
          * will not be large enough to benefit from any alignment,
          * so might as well avoid fragmentation and do a more efficient search. */
-        if num_pages >= align_pages && align_pages > 1 {
-            todo!("num_pages >= align_pages && align_pages > 1");
-        }
-
-        /* See if there's an unaligned range that will satisfy. */
-        let mut alloc_start = self.bitmap.find(false, start, end, find_pages)?;
+        let alloc_start = if num_pages >= align_pages && align_pages > 1 {
+            self.bitmap_find_aligned(num_pages, start, end, align_pages)?
+        } else {
+            /* See if there's an unaligned range that will satisfy. */
+            let alloc_start = self.bitmap.find(false, start, end, find_pages)?;
 
-        /* Increase our start to skip the padding we want to leave. */
-        alloc_start += self.alloc_guard;
+            /* Increase our start to skip the padding we want to leave. */
+            alloc_start + self.alloc_guard
+        };
         /* Record the end of this allocation as our next search start.
          * We set the end to not include the padding so that the padding
          * at the end of this allocation becomes the padding at the start
@@ -472,10 +526,85 @@ impl VirtualAlloc {
         Ok(alloc_start)
     }
 
+    /* Find the start of an aligned run of num_pages unset bits in
+     * [start, end), which has alloc_guard unset bits on either side of it.
+     * The padding itself doesn't need to be aligned. */
+    fn bitmap_find_aligned(&self, num_pages: usize, start: usize, end: usize,
+                           align_pages: usize) -> Result<usize, ErrNO> {
+        let mut alloc_start = ROUNDUP!(start + self.alloc_guard, align_pages);
+        loop {
+            let run_end = alloc_start + num_pages + self.alloc_guard;
+            if run_end > end {
+                return Err(ErrNO::NoResources);
+            }
+
+            let mut set: usize = 0;
+            if self.bitmap.scan(alloc_start - self.alloc_guard, run_end,
+                                false, &mut set) {
+                return Ok(alloc_start);
+            }
+            /* Skip over the allocated page to the next aligned start */
+            alloc_start = ROUNDUP!(set + 1 + self.alloc_guard, align_pages);
+        }
+    }
+
+    /* Get [start, end) of the run of allocated pages which |va| falls in.
+     * Neither the bitmap itself nor the guard pages are included. */
+    pub fn allocated_run(&self, va: vaddr_t) -> Option<(vaddr_t, vaddr_t)> {
+        if self.alloc_base == 0 || va < self.alloc_base {
+            return None;
+        }
+        let index = (va - self.alloc_base) / PAGE_SIZE;
+        let first = self.bitmap_pages();
+        if index < first || index >= self.bitmap.size() {
+            return None;
+        }
+
+        let mut unset: usize = 0;
+        if !self.bitmap.scan(index, index + 1, true, &mut unset) {
+            return None;
+        }
+        let start = if self.bitmap.reverse_scan(first, index, true, &mut unset) {
+            first
+        } else {
+            unset + 1
+        };
+        let end = if self.bitmap.scan(index, self.bitmap.size(), true,
+                                      &mut unset) {
+            self.bitmap.size()
+        } else {
+            unset
+        };
+        Some((self.alloc_base + start * PAGE_SIZE,
+              self.alloc_base + end * PAGE_SIZE))
+    }
+
     pub fn bitmap_pages(&self) -> usize {
         self.bitmap.storage_num() * BYTES_PER_USIZE / PAGE_SIZE
     }
 
+    /* Number of the pages handed out, without the bitmap's own pages. */
+    pub fn allocated_pages(&self) -> usize {
+        if self.alloc_base == 0 {
+            return 0;
+        }
+        self.bitmap.count_set(self.bitmap_pages(), self.bitmap.size())
+    }
+
+    /* Number of the pages left, including the ones padding
+     * the allocations, so not all of them can be handed out. */
+    pub fn free_pages_available(&self) -> usize {
+        if self.alloc_base == 0 {
+            return 0;
+        }
+        self.bitmap.size() - self.bitmap.count_set(0, self.bitmap.size())
+    }
+
+    /* Base and size of the whole region, the bitmap included. */
+    pub fn region_bounds(&self) -> (vaddr_t, usize) {
+        (self.alloc_base, self.bitmap.size() * PAGE_SIZE)
+    }
+
 }
 
 pub fn heap_init() -> Result<(), ErrNO> {
@@ -488,15 +617,19 @@ pub fn heap_init() -> Result<(), ErrNO> {
     virtual_alloc.init(vm_get_kernel_heap_base(), vm_get_kernel_heap_size(),
                        1, ARCH_HEAP_ALIGN_BITS)?;
 
+    let (base, size) = virtual_alloc.region_bounds();
     dprintf!(INFO, "Kernel heap [{:x}, {:x}) using {} pages ({} KiB) \
              for tracking bitmap\n",
-             vm_get_kernel_heap_base(),
-             vm_get_kernel_heap_base() + vm_get_kernel_heap_size(),
+             base, base + size,
              virtual_alloc.bitmap_pages(),
              virtual_alloc.bitmap_pages() * PAGE_SIZE / 1024);
 
     cmpct_init()?;
 
+    dprintf!(INFO, "Kernel heap has {} pages allocated, {} pages free\n",
+             virtual_alloc.allocated_pages(),
+             virtual_alloc.free_pages_available());
+
     ALLOCATOR.switch_stage(AllocatorStage::Boot);
 
     /* Todo: Memory occupied by BumpAllocator can be checked!
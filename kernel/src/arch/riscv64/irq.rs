@@ -33,4 +33,29 @@ pub fn arch_irqs_disabled_flags(flags: usize) -> bool {
 #[inline]
 pub fn arch_irqs_disabled() -> bool {
     arch_irqs_disabled_flags(arch_local_save_flags())
-}
\ No newline at end of file
+}
+
+/* disable interrupts and return the previous state of them */
+#[inline]
+pub fn arch_local_irq_save() -> usize {
+    let flags: usize;
+    unsafe {
+        asm!(
+            "csrrc {0}, sstatus, {1}",
+            out(reg) flags,
+            in(reg) SR_IE,
+        );
+    }
+    flags
+}
+
+/* restore the interrupt state returned by arch_local_irq_save */
+#[inline]
+pub fn arch_local_irq_restore(flags: usize) {
+    unsafe {
+        asm!(
+            "csrs sstatus, {0}",
+            in(reg) flags & SR_IE,
+        );
+    }
+}
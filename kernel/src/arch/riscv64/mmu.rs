@@ -10,15 +10,17 @@ use core::cmp::min;
 use core::ptr::null_mut;
 use core::arch::asm;
 use crate::BOOT_CONTEXT;
-use crate::println;
 use crate::types::*;
 use crate::defines::*;
 use crate::errors::ErrNO;
 use crate::debug::*;
 use crate::vm_page_state;
 use crate::page::vm_page_t;
-use crate::pmm::{pmm_alloc_page, PMM_ALLOC_FLAG_ANY};
+use crate::pmm::{pmm_alloc_page, pmm_free, paddr_to_vm_page, PMM_ALLOC_FLAG_ZERO};
+use crate::klib::list::List;
+use crate::arch::tlbflush::local_flush_tlb_all;
 use crate::{dprintf, print};
+use crate::ZX_ASSERT;
 
 const PAGE_TABLE_ENTRIES: usize = 1 << (PAGE_SHIFT - 3);
 
@@ -41,6 +43,7 @@ const _PAGE_DIRTY   : usize = 1 << 7;     /* Dirty (set by hardware)*/
 
 pub const PAGE_READ : usize = _PAGE_READ;
 pub const PAGE_WRITE: usize = _PAGE_WRITE;
+pub const PAGE_EXEC : usize = _PAGE_EXEC;
 
 /*
  * when all of R/W/X are zero, the PTE is a pointer to the next level
@@ -84,6 +87,20 @@ impl PageTable {
         self.item_present(index) && ((self.0[index] & _PAGE_LEAF) != 0)
     }
 
+    fn clear_item(&mut self, index: usize) {
+        self.0[index] = 0;
+    }
+
+    /* Replace the R/W/X bits of the leaf at |index| with those of |prot|,
+     * keeping the PFN and the rest of the bits as they are. */
+    fn set_item_prot(&mut self, index: usize, prot: prot_t) {
+        self.0[index] = (self.0[index] & !_PAGE_LEAF) | (prot & _PAGE_LEAF);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|&pte| (pte & _PAGE_PRESENT) == 0)
+    }
+
     fn item_descend(&self, index: usize) -> usize {
         (self.0[index] >> _PAGE_PFN_SHIFT) << PAGE_SHIFT
     }
@@ -93,6 +110,16 @@ impl PageTable {
     }
 }
 
+/* Whether the present |pte| maps a page (or block) rather than
+ * pointing to the next level of the page table. */
+pub const fn pte_is_leaf(pte: usize) -> bool {
+    (pte & _PAGE_LEAF) != 0
+}
+
+pub const fn pte_is_wx(pte: usize) -> bool {
+    (pte & (_PAGE_WRITE | _PAGE_EXEC)) == (_PAGE_WRITE | _PAGE_EXEC)
+}
+
 extern "C" {
     pub fn _start();
     pub static mut _swapper_pgd: PageTable;
@@ -302,11 +329,82 @@ pub fn zero_page(va: vaddr_t) {
 
 pub fn protect_pages(vaddr: vaddr_t, size: usize, prot: prot_t)
     -> Result<(), ErrNO> {
+    dprintf!(SPEW, "vaddr {:x}, size {:x}, prot {:x}\n", vaddr, size, prot);
 
-    /* Todo: NOT implement it yet! */
-    println!("Not implement protect pages in risc-v! \
-              [0x{:x}, 0x{:x}) prot 0x{:x}",
-             vaddr, vaddr + size, prot);
+    /* Without any of R/W/X, the leaf would turn into a table pointer */
+    if (prot & _PAGE_LEAF) == 0 {
+        return Err(ErrNO::InvalidArgs);
+    }
+
+    let ret = unsafe {
+        protect_page_table(vaddr, size, prot, 0, &mut _swapper_pgd)
+    };
+    unsafe { local_flush_tlb_all(); }
+    ret
+}
+
+/* Change the protection of [vaddr, vaddr + size) in the page table tree
+ * at |level| to |prot|. The holes are skipped over, and a block which
+ * is only partly covered is split up first. */
+pub fn protect_page_table(mut vaddr: vaddr_t, mut size: usize, prot: prot_t,
+                      level: usize, page_table: &mut PageTable)
+    -> Result<(), ErrNO> {
+
+    let block_size = LEVEL_SIZE!(level);
+
+    if ((vaddr | size) & !PAGE_MASK) != 0 {
+        return Err(ErrNO::InvalidArgs);
+    }
+
+    while size > 0 {
+        /* the part of the range covered by this entry */
+        let chunk_size = min(size, block_size - (vaddr & (block_size - 1)));
+        let index = vaddr_to_index(vaddr, level);
+
+        if page_table.item_leaf(index) && chunk_size != block_size {
+            split_block(page_table, index, level)?;
+        }
+
+        if page_table.item_leaf(index) {
+            page_table.set_item_prot(index, prot);
+        } else if page_table.item_present(index) {
+            let next_pt = paddr_to_physmap(page_table.item_descend(index))
+                as *mut PageTable;
+            unsafe {
+                protect_page_table(vaddr, chunk_size, prot, level + 1,
+                                   &mut (*next_pt))?;
+            }
+        }
+
+        vaddr += chunk_size;
+        size -= chunk_size;
+    }
+
+    Ok(())
+}
+
+/* Replace the block of the |index| entry at |level| with a table of the
+ * next level, which maps the same range with the same bits page by page
+ * (or by smaller blocks), so that a part of it can be changed alone. */
+fn split_block(page_table: &mut PageTable, index: usize, level: usize)
+    -> Result<(), ErrNO> {
+    ZX_ASSERT!(level + 1 < MMU_LEVELS);
+
+    let pte = page_table.item(index);
+    let pa = page_table.item_descend(index);
+    let prot = PTE_TO_PROT!(pte);
+    let child_size = LEVEL_SIZE!(level + 1);
+
+    let page_table_paddr = alloc_page_table()?;
+    let next_pt = paddr_to_physmap(page_table_paddr) as *mut PageTable;
+    for i in 0..PAGE_TABLE_ENTRIES {
+        unsafe {
+            (*next_pt).mk_item(i, PA_TO_PFN!(pa + i * child_size), prot);
+        }
+    }
+
+    page_table.mk_item(index, PA_TO_PFN!(page_table_paddr), PAGE_TABLE);
+    dprintf!(SPEW, "split block {:x} into table {:x}\n", pte, page_table_paddr);
     Ok(())
 }
 
@@ -353,13 +451,10 @@ pub fn map_page_table(mut vaddr: vaddr_t, mut paddr: paddr_t, mut size: usize,
                         as *mut PageTable;
                 }
             } else {
+                /* The page table comes zeroed from the pmm */
                 let page_table_paddr = alloc_page_table()?;
                 let pt_vaddr = paddr_to_physmap(page_table_paddr);
-
-                unsafe {
-                    arch_zero_page(pt_vaddr);
-                    /* Fence */
-                }
+                /* Fence */
 
                 page_table.mk_item(index, PA_TO_PFN!(page_table_paddr),
                                    PAGE_TABLE);
@@ -390,6 +485,160 @@ pub fn map_page_table(mut vaddr: vaddr_t, mut paddr: paddr_t, mut size: usize,
     Ok(mapped_size)
 }
 
+pub fn unmap_pages(vaddr: vaddr_t, size: usize) -> Result<usize, ErrNO> {
+    dprintf!(SPEW, "vaddr {:x}, size {:x}\n", vaddr, size);
+
+    let ret = unsafe {
+        unmap_page_table(vaddr, size, 0, &mut _swapper_pgd)
+    };
+    unsafe { local_flush_tlb_all(); }
+    ret
+}
+
+/* Unmap [vaddr, vaddr + size) from the page table tree at |level|, and
+ * return the size which was mapped there, as the holes are skipped over.
+ * The tables which are left empty are freed, except the boot ones.
+ * The whole range is checked first, so nothing is unmapped on error. */
+pub fn unmap_page_table(vaddr: vaddr_t, size: usize, level: usize,
+                        page_table: &mut PageTable) -> Result<usize, ErrNO> {
+    if ((vaddr | size) & !PAGE_MASK) != 0 {
+        return Err(ErrNO::InvalidArgs);
+    }
+
+    check_unmap_range(vaddr, size, level, page_table)?;
+    Ok(_unmap_page_table(vaddr, size, level, page_table))
+}
+
+/* Whether [vaddr, vaddr + size) can be unmapped from the page table tree
+ * at |level|. It can't if it covers only a part of a block.
+ * Todo: split up the block to unmap a part of it. */
+fn check_unmap_range(mut vaddr: vaddr_t, mut size: usize, level: usize,
+                     page_table: &PageTable) -> Result<(), ErrNO> {
+
+    let block_size = LEVEL_SIZE!(level);
+
+    while size > 0 {
+        let chunk_size = min(size, block_size - (vaddr & (block_size - 1)));
+        let index = vaddr_to_index(vaddr, level);
+
+        if page_table.item_leaf(index) {
+            if chunk_size != block_size {
+                dprintf!(WARN, "can't unmap part of a block, {:x}\n",
+                         page_table.item(index));
+                return Err(ErrNO::NotSupported);
+            }
+        } else if page_table.item_present(index) {
+            let next_pt = paddr_to_physmap(page_table.item_descend(index))
+                as *const PageTable;
+            unsafe {
+                check_unmap_range(vaddr, chunk_size, level + 1, &(*next_pt))?;
+            }
+        }
+
+        vaddr += chunk_size;
+        size -= chunk_size;
+    }
+
+    Ok(())
+}
+
+fn _unmap_page_table(mut vaddr: vaddr_t, mut size: usize, level: usize,
+                     page_table: &mut PageTable) -> usize {
+
+    let block_size = LEVEL_SIZE!(level);
+
+    let mut unmapped_size = 0;
+    while size > 0 {
+        /* the part of the range covered by this entry */
+        let chunk_size = min(size, block_size - (vaddr & (block_size - 1)));
+        let index = vaddr_to_index(vaddr, level);
+
+        if page_table.item_leaf(index) {
+            page_table.clear_item(index);
+            unmapped_size += chunk_size;
+            dprintf!(SPEW, "pte [{}] cleared (va {:x})\n", index, vaddr);
+        } else if page_table.item_present(index) {
+            let next_pt = paddr_to_physmap(page_table.item_descend(index))
+                as *mut PageTable;
+            unsafe {
+                unmapped_size +=
+                    _unmap_page_table(vaddr, chunk_size, level + 1, &mut (*next_pt));
+                if (*next_pt).is_empty() {
+                    free_page_table(page_table, index);
+                }
+            }
+        }
+
+        vaddr += chunk_size;
+        size -= chunk_size;
+    }
+
+    unmapped_size
+}
+
+/* Free the empty table which the |index| entry points to, if it's been
+ * allocated by alloc_page_table rather than set up at boot. */
+fn free_page_table(page_table: &mut PageTable, index: usize) {
+    let page = paddr_to_vm_page(page_table.item_descend(index));
+    if page.is_null() || unsafe { (*page).state() } != vm_page_state::MMU {
+        return;
+    }
+
+    page_table.clear_item(index);
+    /* Nothing may walk through the table once it's back in the pmm */
+    unsafe { local_flush_tlb_all(); }
+
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    list.add_tail(page);
+    pmm_free(&mut list);
+    dprintf!(SPEW, "freed page table {:x}\n", unsafe { (*page).paddr() });
+}
+
+/* Visit every present entry of the page table tree rooted at |root|,
+ * where |base_va| is the virtual address mapped by the first entry of
+ * |root|. Each entry is visited as |visit(va, level, pte)| before the
+ * table it points to (if it is not a leaf) is walked. */
+pub fn walk_page_table<F>(root: &PageTable, base_va: vaddr_t, mut visit: F)
+    where F: FnMut(vaddr_t, usize, usize) {
+    _walk_page_table(root, 0, base_va, &mut visit);
+}
+
+/* Visit the leaf entries of the page table tree rooted at |root| which map
+ * pages writable and executable at the same time, as |report(va, level,
+ * pte)|, where |va| is sign-extended into the canonical form. */
+pub fn find_wx_mappings<F>(root: &PageTable, mut report: F)
+    where F: FnMut(vaddr_t, usize, usize) {
+    let shift = usize::BITS as usize - (LEVEL_SHIFT!(0) + PAGE_SHIFT - 3);
+    walk_page_table(root, 0, |va, level, pte| {
+        if pte_is_leaf(pte) && pte_is_wx(pte) {
+            report((((va << shift) as isize) >> shift) as vaddr_t, level, pte);
+        }
+    });
+}
+
+fn _walk_page_table<F>(table: &PageTable, level: usize, base_va: vaddr_t,
+                       visit: &mut F)
+    where F: FnMut(vaddr_t, usize, usize) {
+
+    for index in 0..PAGE_TABLE_ENTRIES {
+        if !table.item_present(index) {
+            continue;
+        }
+
+        let va = base_va.wrapping_add(index << LEVEL_SHIFT!(level));
+        visit(va, level, table.item(index));
+
+        if !table.item_leaf(index) {
+            let next_pt = paddr_to_physmap(table.item_descend(index))
+                as *const PageTable;
+            unsafe {
+                _walk_page_table(&*next_pt, level + 1, va, visit);
+            }
+        }
+    }
+}
+
 fn alloc_page_table() -> Result<paddr_t, ErrNO> {
     let page = cache_alloc_page()?;
 
@@ -402,7 +651,7 @@ fn alloc_page_table() -> Result<paddr_t, ErrNO> {
 
 fn cache_alloc_page() -> Result<*mut vm_page_t, ErrNO> {
     /* Todo: Implement PageCache on the next step. */
-    let page = pmm_alloc_page(PMM_ALLOC_FLAG_ANY);
+    let page = pmm_alloc_page(PMM_ALLOC_FLAG_ZERO);
     if page == null_mut() {
         return Err(ErrNO::NoMem);
     }
@@ -4,4 +4,6 @@ pub mod tlbflush;
 pub mod topology;
 pub mod irq;
 pub mod csr;
-pub mod smp;
\ No newline at end of file
+pub mod smp;
+pub mod timer;
+pub mod thread;
\ No newline at end of file
@@ -0,0 +1,81 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use core::arch::{asm, global_asm};
+use core::mem;
+use crate::{IS_ALIGNED, ZX_ASSERT};
+
+global_asm!(include_str!("switch.S"));
+
+extern "C" {
+    fn riscv64_context_switch(old: *mut ArchThreadState,
+                              new: *const ArchThreadState);
+}
+
+/*
+ * The registers to be kept across a context switch: ra, sp, the
+ * callee-saved s0-s11 and tp which points to the Thread itself.
+ * The layout is shared with riscv64_context_switch in switch.S.
+ * NOTE: Kernel doesn't use the floating point, so fs0-fs11 aren't here.
+ */
+#[repr(C)]
+pub struct ArchThreadState {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+    tp: usize,
+}
+
+const _: () = assert!(mem::size_of::<ArchThreadState>() == 120);
+
+impl ArchThreadState {
+    pub const fn new() -> Self {
+        Self {
+            ra: 0,
+            sp: 0,
+            s: [0; 12],
+            tp: 0,
+        }
+    }
+
+    /*
+     * Setup the initial context of a thread which hasn't run yet.
+     * The first switch into it returns to |entry| on the stack.
+     * As |entry| is returned into rather than called, it must never
+     * return itself.
+     */
+    #[allow(dead_code)]
+    pub fn init(&mut self, entry: extern "C" fn() -> !, stack_top: usize,
+                thread: usize) {
+        ZX_ASSERT!(IS_ALIGNED!(stack_top, 16));
+
+        *self = Self::new();
+        self.ra = entry as usize;
+        self.sp = stack_top;
+        self.tp = thread;
+    }
+}
+
+/*
+ * Save the registers of the current thread into |old| and continue
+ * with the ones of |new|. It returns when another thread switches back
+ * into |old|. Interrupts should be disabled by the caller.
+ */
+pub fn arch_context_switch(old: *mut ArchThreadState,
+                           new: *const ArchThreadState) {
+    unsafe {
+        riscv64_context_switch(old, new);
+    }
+}
+
+/* Wait for an interrupt in a low-power state of the hart */
+pub fn arch_idle() {
+    unsafe {
+        asm!("wfi");
+    }
+}
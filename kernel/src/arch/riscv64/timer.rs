@@ -0,0 +1,53 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+#![allow(dead_code)]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::ZX_ASSERT;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/* Frequency (in Hz) of the 'time' csr, from 'timebase-frequency' of dtb */
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+pub fn riscv_timer_set_frequency(freq: u64) {
+    ZX_ASSERT!(freq != 0);
+    TIMEBASE_FREQUENCY.store(freq, Ordering::Relaxed);
+}
+
+pub fn riscv_timer_frequency() -> u64 {
+    let freq = TIMEBASE_FREQUENCY.load(Ordering::Relaxed);
+    ZX_ASSERT!(freq != 0);
+    freq
+}
+
+/* convert nanoseconds to ticks of the 'time' csr */
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    ((ns as u128) * (riscv_timer_frequency() as u128)
+     / (NSEC_PER_SEC as u128)) as u64
+}
+
+/* convert ticks of the 'time' csr to nanoseconds */
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    ((ticks as u128) * (NSEC_PER_SEC as u128)
+     / (riscv_timer_frequency() as u128)) as u64
+}
+
+/* nanoseconds since the 'time' csr was reset */
+pub fn current_time() -> u64 {
+    let ticks: u64;
+    unsafe {
+        asm!(
+            "rdtime {0}",
+            out(reg) ticks,
+        );
+    }
+    ticks_to_ns(ticks)
+}
@@ -6,8 +6,50 @@
  * at https://opensource.org/licenses/MIT
  */
 
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::{dprintf, print};
+use crate::debug::*;
+use crate::defines::SMP_MAX_CPUS;
 use crate::errors::ErrNO;
+use crate::percpu::PERCPU_ARRAY;
+use crate::sched::{SchedPerformanceScale, K_PERFORMANCE_SCALE_ONE};
+
+/* Relative capacity of each cpu, from 'capacity-dmips-mhz' of dtb.
+ * Zero means that it's unknown. */
+static CPU_CAPACITY: [AtomicU32; SMP_MAX_CPUS] =
+    [const { AtomicU32::new(0) }; SMP_MAX_CPUS];
+
+pub fn topology_set_cpu_capacity(cpu: usize, capacity: u32) {
+    if cpu < SMP_MAX_CPUS {
+        CPU_CAPACITY[cpu].store(capacity, Ordering::Relaxed);
+    }
+}
+
+/* The performance scale of the cpu relative to the most capable one,
+ * or None if the topology doesn't tell. */
+pub fn topology_performance_scale(cpu: usize)
+    -> Option<SchedPerformanceScale> {
+    let max = CPU_CAPACITY.iter()
+        .map(|c| c.load(Ordering::Relaxed)).max().unwrap_or(0);
+    if cpu >= SMP_MAX_CPUS || max == 0 {
+        return None;
+    }
+    let capacity = CPU_CAPACITY[cpu].load(Ordering::Relaxed);
+    if capacity == 0 {
+        return None;
+    }
+    Some(capacity as usize * K_PERFORMANCE_SCALE_ONE / max as usize)
+}
 
 pub fn topology_init() -> Result<(), ErrNO> {
+    let mut percpu_array = unsafe { PERCPU_ARRAY.lock() };
+    for percpu in percpu_array.iter_mut() {
+        let sched = percpu.scheduler();
+        if let Some(scale) = topology_performance_scale(sched.this_cpu) {
+            dprintf!(INFO, "cpu {} performance scale {}/{}\n",
+                     sched.this_cpu, scale, K_PERFORMANCE_SCALE_ONE);
+            sched.set_performance_scale(scale);
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
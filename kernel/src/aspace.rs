@@ -31,31 +31,29 @@ use crate::klib::list::List;
 use crate::klib::list::ListNode;
 use crate::locking::mutex::Mutex;
 use crate::types::*;
-use crate::vm::vm::ARCH_MMU_FLAG_PERM_EXECUTE;
 use crate::vm::vm::ARCH_MMU_FLAG_PERM_READ;
 use crate::vm::vm::ARCH_MMU_FLAG_PERM_WRITE;
 use crate::vm::vm::kernel_regions_base;
 use crate::vm::vm::mmu_prot_from_flags;
-use crate::vm::vmar::VmAddressRegion;
+use crate::vm::vmar::{AllocMode, VmAddressRegion};
 use crate::debug::*;
 use crate::{KERNEL_ASPACE_BASE, KERNEL_ASPACE_SIZE};
 use crate::{ErrNO, types::vaddr_t, ZX_ASSERT};
-use crate::pmm::pmm_alloc_page;
+use crate::pmm::{pmm_alloc_page, PMM_ALLOC_FLAG_ZERO};
 use crate::vm_page_state;
-use crate::arch::mmu::arch_zero_page;
-use crate::arch::mmu::map_pages;
+use crate::arch::mmu::{map_pages, unmap_pages};
 
 /* Allow VmMappings to be created inside the new region with the SPECIFIC
  * or OFFSET_IS_UPPER_LIMIT flag. */
-const VMAR_FLAG_CAN_MAP_SPECIFIC: usize = 1 << 3;
+pub const VMAR_FLAG_CAN_MAP_SPECIFIC: usize = 1 << 3;
 /* When on a VmAddressRegion, allow VmMappings to be created inside the region
  * with read permissions.  When on a VmMapping, controls whether or not the
  * mapping can gain this permission. */
-const VMAR_FLAG_CAN_MAP_READ: usize = 1 << 4;
+pub const VMAR_FLAG_CAN_MAP_READ: usize = 1 << 4;
 /* When on a VmAddressRegion, allow VmMappings to be created inside the region
  * with write permissions.  When on a VmMapping, controls whether or not the
  * mapping can gain this permission. */
-const VMAR_FLAG_CAN_MAP_WRITE: usize = 1 << 5;
+pub const VMAR_FLAG_CAN_MAP_WRITE: usize = 1 << 5;
 /* When on a VmAddressRegion, allow VmMappings to be created inside the region
  * with execute permissions.  When on a VmMapping, controls whether or not the
  * mapping can gain this permission. */
@@ -64,6 +62,9 @@ const VMAR_FLAG_CAN_MAP_EXECUTE: usize = 1 << 6;
 const VMAR_CAN_RWX_FLAGS: usize = VMAR_FLAG_CAN_MAP_READ |
     VMAR_FLAG_CAN_MAP_WRITE | VMAR_FLAG_CAN_MAP_EXECUTE;
 
+/* The kernel aspace is the first one created */
+pub const KERNEL_ASPACE_ID: usize = 0;
+
 #[allow(dead_code)]
 pub enum VmAspaceType {
     User,
@@ -127,6 +128,10 @@ impl VmAspace {
         /* InitializeAslr(); */
     }
 
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     pub fn root_vmar(&mut self) -> &mut VmAddressRegion {
         if let Some(vmar) = &mut self.root_vmar {
             return vmar;
@@ -192,9 +197,50 @@ impl VmAspace {
         Ok(count)
     }
 
-    pub fn unmap(&self, _va: vaddr_t, _count: usize, _enlarge: bool)
+    /* Map |count| physically contiguous pages starting at |paddr|. */
+    pub fn map_contiguous(&mut self, vaddr: vaddr_t, paddr: paddr_t,
+                          count: usize, mmu_flags: usize)
         -> Result<usize, ErrNO> {
-        todo!("unmap!");
+        if !IS_PAGE_ALIGNED!(paddr) {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        let mut mapped = 0;
+        while mapped < count {
+            let phys = [paddr + mapped * PAGE_SIZE];
+            mapped += self.map(vaddr + mapped * PAGE_SIZE, &phys, 1,
+                               mmu_flags, ExistingEntryAction::Error)?;
+        }
+        Ok(mapped)
+    }
+
+    /* Unmap |count| pages from |va|, and return how many of them were
+     * mapped. Unless |enlarge|, they all have to be mapped, or nothing
+     * is unmapped at all. */
+    pub fn unmap(&self, va: vaddr_t, count: usize, enlarge: bool)
+        -> Result<usize, ErrNO> {
+        if !IS_PAGE_ALIGNED!(va) {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        if count == 0 {
+            return Ok(0);
+        }
+
+        if !self.is_valid_vaddr(va) ||
+            !self.is_valid_vaddr(va + count * PAGE_SIZE - 1) {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        if !enlarge {
+            for i in 0..count {
+                self.query_locked(va + i * PAGE_SIZE)?;
+            }
+        }
+
+        let size = unmap_pages(va, count * PAGE_SIZE)?;
+        //MarkAspaceModified();
+        Ok(size / PAGE_SIZE)
     }
 
     pub fn protect(&self, vaddr: vaddr_t, count: usize, mmu_flags: usize)
@@ -211,10 +257,6 @@ impl VmAspace {
             return Err(ErrNO::InvalidArgs);
         }
 
-        if (mmu_flags & ARCH_MMU_FLAG_PERM_EXECUTE) != 0 {
-            todo!("ARCH_MMU_FLAG_PERM_EXECUTE");
-        }
-
         let prot = mmu_prot_from_flags(mmu_flags);
         let status = protect_pages(vaddr, count * PAGE_SIZE, prot);
         // MarkAspaceModified();
@@ -263,16 +305,13 @@ pub fn vm_init_preheap() -> Result<(), ErrNO> {
     vm_init_preheap_vmars();
 
     // grab a page and mark it as the zero page
-    let zero_page = pmm_alloc_page(0);
+    let zero_page = pmm_alloc_page(PMM_ALLOC_FLAG_ZERO);
     if zero_page == null_mut() {
         panic!("alloc zero page error!");
     }
     /* consider the zero page a wired page part of the kernel. */
     unsafe {
         (*zero_page).set_state(vm_page_state::WIRED);
-        let va = paddr_to_physmap((*zero_page).paddr());
-        ZX_ASSERT!(va != 0);
-        arch_zero_page(va);
     }
 
     /* AnonymousPageRequester::Init(); */
@@ -287,15 +326,13 @@ fn vm_init_preheap_vmars() {
      */
     let flags = VMAR_FLAG_CAN_MAP_SPECIFIC | VMAR_CAN_RWX_FLAGS;
 
-    let mut kernel_physmap_vmar= VmAddressRegion::new();
-    kernel_physmap_vmar.init(PHYSMAP_BASE, PHYSMAP_SIZE, flags);
-
     let aspace_list = ASPACE_LIST.lock();
     println!("vm_init_preheap_vmars");
     let kernel_aspace = aspace_list.head();
     let root_vmar = unsafe { (*kernel_aspace).root_vmar() };
 
-    root_vmar.insert_child(kernel_physmap_vmar);
+    root_vmar.reserve_region(PHYSMAP_BASE, PHYSMAP_SIZE, flags)
+        .unwrap_or_else(|e| panic!("VM: can't reserve physmap: {:?}", e));
 
     /*
      * |kernel_image_size| is the size in bytes of the region of memory occupied by
@@ -316,28 +353,25 @@ fn vm_init_preheap_vmars() {
      * regions. The thinking is that it's both simpler and safer to not use the address space that
      * exists between kernel program segments.
      */
-    let mut kernel_image_vmar= VmAddressRegion::new();
-    kernel_image_vmar.init(kernel_regions_base(), kernel_image_size, flags);
-    root_vmar.insert_child(kernel_image_vmar);
+    root_vmar.reserve_region(kernel_regions_base(), kernel_image_size, flags)
+        .unwrap_or_else(|e| panic!("VM: can't reserve kernel image: {:?}", e));
 
     /* Reserve the range for the heap. */
     let heap_bytes = ROUNDUP!(HEAP_MAX_SIZE_MB * MB, 1 << ARCH_HEAP_ALIGN_BITS);
     let kernel_heap_base =
         root_vmar.alloc_spot_locked(heap_bytes, ARCH_HEAP_ALIGN_BITS,
             ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE,
-            usize::MAX);
+            usize::MAX, false, AllocMode::Leftmost);
 
     /*
      * The heap has nothing to initialize later and we can create this
      * from the beginning with only read and write and no execute.
      */
-    let mut kernel_heap_vmar= VmAddressRegion::new();
-    kernel_heap_vmar.init(kernel_heap_base, heap_bytes,
-        VMAR_FLAG_CAN_MAP_SPECIFIC | VMAR_FLAG_CAN_MAP_READ | VMAR_FLAG_CAN_MAP_WRITE);
-
     dprintf!(INFO, "VM: kernel heap placed in range [{:x}, {:x})\n",
-             kernel_heap_vmar.base, kernel_heap_vmar.base + kernel_heap_vmar.size);
-    root_vmar.insert_child(kernel_heap_vmar);
+             kernel_heap_base, kernel_heap_base + heap_bytes);
+    root_vmar.reserve_region(kernel_heap_base, heap_bytes,
+        VMAR_FLAG_CAN_MAP_SPECIFIC | VMAR_FLAG_CAN_MAP_READ | VMAR_FLAG_CAN_MAP_WRITE)
+        .unwrap_or_else(|e| panic!("VM: can't reserve kernel heap: {:?}", e));
 
     unsafe {
         let ctx = &mut (*BOOT_CONTEXT.data.get());
@@ -355,7 +389,7 @@ fn kernel_aspace_init_preheap() -> Result<(), ErrNO> {
     use alloc::alloc::alloc;
     let kernel_aspace = unsafe { alloc(layout) as *mut VmAspace };
     unsafe {
-        (*kernel_aspace).init(0, VmAspaceType::Kernel,
+        (*kernel_aspace).init(KERNEL_ASPACE_ID, VmAspaceType::Kernel,
                               KERNEL_ASPACE_BASE, KERNEL_ASPACE_SIZE);
         (*kernel_aspace).root_vmar = Some(root_vmar);
     }
@@ -380,4 +414,6 @@ pub fn vm_get_kernel_heap_size() -> usize {
     }
 }
 
+/* It's taken after HEAP_LOCK, so nothing may allocate from or free to
+ * the kernel heap while holding it: see HEAP_LOCK in cmpctmalloc. */
 pub static ASPACE_LIST: Mutex<List<VmAspace>> = Mutex::new(List::<VmAspace>::new());
\ No newline at end of file
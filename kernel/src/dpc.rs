@@ -0,0 +1,169 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+#![allow(dead_code)]
+
+use crate::arch::irq::arch_local_irq_restore;
+use crate::cpu::cpu_num_to_mask;
+use crate::errors::ErrNO;
+use crate::klib::list::{Linked, List, ListNode};
+use crate::locking::spinlock::RawSpinLock;
+use crate::sched::{BlockReason, Scheduler};
+use crate::thread::{Thread, ThreadArg};
+use crate::wait_queue::WaitQueue;
+
+/* Deferred procedure call: |func(arg)| is run later by the dpc
+ * thread of the CPU, out of the context where it's queued. */
+pub type DpcFunc = fn(usize);
+
+/* The number of dpcs which can be queued on a CPU at once */
+pub const DPC_POOL_SIZE: usize = 16;
+
+/* A dpc is a slot of the pool of its queue, and is linked into the
+ * queue or the free slots by its node, so that queuing never allocates
+ * and the caller never owns it. */
+struct Dpc {
+    queue_node: ListNode,
+    func: DpcFunc,
+    arg: usize,
+}
+
+impl Linked<Dpc> for Dpc {
+    fn from_node(ptr: *mut ListNode) -> *mut Dpc {
+        unsafe {
+            crate::container_of!(ptr, Dpc, queue_node)
+        }
+    }
+
+    fn into_node(&mut self) -> *mut ListNode {
+        &mut (self.queue_node)
+    }
+}
+
+impl Dpc {
+    const fn new() -> Self {
+        Self {
+            queue_node: ListNode::new(),
+            func: dpc_nop,
+            arg: 0,
+        }
+    }
+}
+
+fn dpc_nop(_arg: usize) {
+}
+
+/* Each CPU has a queue of dpcs which is drained by its dpc thread. */
+pub struct DpcQueue {
+    /* Guards the list. It's taken with the local interrupts disabled,
+     * as dpcs may be queued from an interrupt handler. */
+    lock: RawSpinLock,
+    list: List<Dpc>,
+    /* The slots of the pool which aren't queued */
+    free: List<Dpc>,
+    pool: [Dpc; DPC_POOL_SIZE],
+    /* The dpc thread waits here while the list is empty. Only the
+     * owning CPU touches it, with the local interrupts disabled. */
+    waiters: WaitQueue,
+}
+
+impl DpcQueue {
+    pub const fn new() -> Self {
+        Self {
+            lock: RawSpinLock::new(),
+            list: List::new(),
+            free: List::new(),
+            pool: [const { Dpc::new() }; DPC_POOL_SIZE],
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /* The lists refer to themselves and to the pool, so they're
+     * set up in place. */
+    pub fn init(&mut self) {
+        self.list.init();
+        self.free.init();
+        for dpc in self.pool.iter_mut() {
+            self.free.add_tail(dpc);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        let flags = self.lock.lock_irqsave();
+        let count = self.list._len();
+        self.lock.unlock_irqrestore(flags);
+        count
+    }
+
+    /* Appends |func(arg)| in a free slot of the pool and wakes up the
+     * dpc thread if it's waiting. It's NoMem if all of the slots are
+     * queued already. */
+    pub fn queue(&mut self, func: DpcFunc, arg: usize) -> Result<(), ErrNO> {
+        let flags = self.lock.lock_irqsave();
+        let dpc = self.free.pop_head();
+        if dpc.is_null() {
+            self.lock.unlock_irqrestore(flags);
+            return Err(ErrNO::NoMem);
+        }
+        unsafe {
+            (*dpc).func = func;
+            (*dpc).arg = arg;
+        }
+        self.list.add_tail(dpc);
+        self.lock.unlock();
+
+        self.waiters.wake_one();
+        arch_local_irq_restore(flags);
+        Ok(())
+    }
+}
+
+/* Queues |func(arg)| to the dpc thread of the current CPU. */
+pub fn dpc_queue(func: DpcFunc, arg: usize) -> Result<(), ErrNO> {
+    Thread::current().percpu().dpc_queue().queue(func, arg)
+}
+
+/*
+ * The body of the dpc thread, which runs at DPC_PRIORITY on its own CPU.
+ * It runs the dpcs in FIFO order, and blocks when there's none left.
+ * A dpc is copied out and its slot freed before it's run, so that
+ * its func may queue again.
+ */
+pub fn dpc_thread(_arg: Option<ThreadArg>) -> Result<(), ErrNO> {
+    loop {
+        let queue = Thread::current().percpu().dpc_queue();
+        let flags = queue.lock.lock_irqsave();
+        let dpc = queue.list.pop_head();
+        if dpc.is_null() {
+            queue.lock.unlock();
+            /* The interrupts stay disabled until the thread has blocked,
+             * so a dpc queued by a handler in between can't be missed. */
+            Scheduler::block_current(BlockReason::Normal, &mut queue.waiters);
+            arch_local_irq_restore(flags);
+            continue;
+        }
+        let (func, arg) = unsafe { ((*dpc).func, (*dpc).arg) };
+        queue.free.add_tail(dpc);
+        queue.lock.unlock_irqrestore(flags);
+
+        func(arg);
+    }
+}
+
+/* Starts the dpc thread of the current CPU, which is ready to run
+ * and blocks until there is a dpc. Like the CPU, it never exits. */
+pub fn dpc_init_for_cpu() -> Result<(), ErrNO> {
+    let cpu = Thread::current().thread_info.cpu;
+    let thread = Thread::create("dpc", dpc_thread, None,
+                                Thread::DPC_PRIORITY)?;
+    unsafe {
+        (*thread).sched_state().set_hard_affinity(cpu_num_to_mask(cpu));
+        (*thread).resume();
+    }
+    Ok(())
+}
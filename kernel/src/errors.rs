@@ -24,6 +24,10 @@ pub enum ErrNO {
     NoDev,
     NoMem,
 
+    /* Memory is low, and the caller which is able to wait
+     * should wait and try again. */
+    ShouldWait,
+
     BadAlign,
     OutOfRange,
 
@@ -7,13 +7,19 @@
  */
 
 use core::{cmp, ptr::null_mut};
+use alloc::vec;
+use alloc::vec::Vec;
 
-use crate::{types::vaddr_t, errors::ErrNO, defines::{BYTE_BITS, BYTES_PER_USIZE}};
+use crate::{types::vaddr_t, errors::ErrNO, defines::BYTES_PER_USIZE};
 
 pub struct Bitmap {
     size: usize,
     storage_num: usize,    /* units of storage */
     storage_data: *mut usize,
+    /* Backing words allocated from heap by with_capacity, which are
+     * freed along with the bitmap. It is empty when the storage is
+     * provided by the caller through storage_init. */
+    owned_storage: Vec<usize>,
 }
 
 impl Bitmap {
@@ -22,10 +28,27 @@ impl Bitmap {
             size: 0,
             storage_num: 0,
             storage_data: null_mut(),
+            owned_storage: Vec::new(),
         }
     }
 
+    /* Create a bitmap of |bits| with its storage allocated from heap. */
+    pub fn with_capacity(bits: usize) -> Self {
+        let storage_num = ROUNDUP!(bits, BITMAP_UNIT_BITS) / BITMAP_UNIT_BITS;
+        let mut owned_storage = vec![0usize; storage_num];
+        Self {
+            size: bits,
+            storage_num,
+            storage_data: owned_storage.as_mut_ptr(),
+            owned_storage,
+        }
+    }
+
+    /* Use the raw memory [base, base + size) as storage. The caller
+     * keeps ownership of the memory (e.g. the bootstrapping allocator
+     * which hosts the bitmap in its own pages). */
     pub fn storage_init(&mut self, base: vaddr_t, size: usize) {
+        self.owned_storage = Vec::new();
         self.storage_num = size / BYTES_PER_USIZE;
         self.storage_data = base as *mut usize;
     }
@@ -117,7 +140,7 @@ impl Bitmap {
         if bitoff >= bitmax {
             return true;
         }
-        for i in first_idx(bitoff)..last_idx(bitmax) {
+        for i in first_idx(bitoff)..=last_idx(bitmax) {
             let data = self.storage_unit_ref(i);
             let masked = mask_bits(data, i, bitoff, bitmax, is_set);
             if masked != 0 {
@@ -128,6 +151,19 @@ impl Bitmap {
         return true;
     }
 
+    /* Number of the set bits in [bitoff, bitmax). */
+    pub fn count_set(&self, bitoff: usize, mut bitmax: usize) -> usize {
+        bitmax = cmp::min(bitmax, self.size);
+        if bitoff >= bitmax {
+            return 0;
+        }
+        (first_idx(bitoff)..=last_idx(bitmax)).map(|i| {
+            /* The bits which aren't clear, among those in the range */
+            mask_bits(self.storage_unit_ref(i), i, bitoff, bitmax, false)
+                .count_ones() as usize
+        }).sum()
+    }
+
     pub fn reverse_scan(&self, bitoff: usize, mut bitmax: usize, is_set: bool,
                         out: &mut usize) -> bool {
         bitmax = cmp::min(bitmax, self.size);
@@ -153,7 +189,7 @@ impl Bitmap {
 unsafe impl Sync for Bitmap {}
 unsafe impl Send for Bitmap {}
 
-const BITMAP_UNIT_BITS: usize = usize::BITS as usize * BYTE_BITS;
+const BITMAP_UNIT_BITS: usize = usize::BITS as usize;
 
 /* Translates a bit offset into a starting index in the bitmap array. */
 const fn first_idx(bitoff: usize) -> usize {
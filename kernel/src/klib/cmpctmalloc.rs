@@ -12,6 +12,8 @@ use crate::defines::BYTES_PER_USIZE;
 use crate::{debug::*, BOOT_CONTEXT, ZX_ASSERT_MSG};
 use crate::types::vaddr_t;
 use crate::{errors::ErrNO, ZX_ASSERT, defines::{PAGE_SIZE, PAGE_SHIFT}};
+use crate::pmm::{PMM_NODE_ANY, pmm_alloc_flag_node};
+use crate::locking::mutex::Mutex;
 use super::list::{ListNode, Linked, List};
 
 /*
@@ -62,14 +64,30 @@ const HEAP_LARGE_ALLOC_BYTES: usize = (1 << HEAP_ALLOC_VIRTUAL_BITS) - HEAP_GROW
  * On 64 bit, the 8 byte bucket is useless, since the freelist header is
  * 16 bytes larger than the header, but we have it for simplicity.
  */
-const NUMBER_OF_BUCKETS: usize = 1 + 15 + (HEAP_ALLOC_VIRTUAL_BITS - 7) * 8;
+pub const NUMBER_OF_BUCKETS: usize = 1 + 15 + (HEAP_ALLOC_VIRTUAL_BITS - 7) * 8;
 
-const BUCKET_WORDS: usize = ((NUMBER_OF_BUCKETS) + 31) >> 5;
+pub const BUCKET_WORDS: usize = ((NUMBER_OF_BUCKETS) + 31) >> 5;
 
 /* If a header's |flag| field has this bit set,
  * it is free and lives in a free bucket. */
 const FREE_BIT: u32 = 1 << 0;
 
+/* With heap-redzone, the bits above FREE_BIT of an allocated header's
+ * |flag| hold the size requested by the caller, and REDZONE_SIZE bytes
+ * of REDZONE_MAGIC are written right after it to catch overruns. */
+#[cfg(feature = "heap-redzone")]
+const REDZONE_SHIFT: u32 = 1;
+#[cfg(feature = "heap-redzone")]
+const REDZONE_SIZE: usize = 8;
+#[cfg(feature = "heap-redzone")]
+const REDZONE_MAGIC: u8 = 0xE5;
+
+/* With heap-poison, free memory beyond the free_t at the start of each
+ * free area is filled with POISON_MAGIC, and checked again when it's
+ * allocated, to catch writes after free. */
+#[cfg(feature = "heap-poison")]
+const POISON_MAGIC: u8 = 0xAA;
+
 #[allow(non_camel_case_types)]
 struct header_t {
     /* Pointer to the previous area in memory order. */
@@ -77,7 +95,8 @@ struct header_t {
     /* The size of the memory area in bytes, including this header.
      * The right sentinel will have 0 in this field. */
     size: u32,
-    /* The most bit is used to store extra state: see FREE_BIT. */
+    /* The most bit is used to store extra state: see FREE_BIT.
+     * The remaining bits may hold the requested size: see REDZONE_SHIFT. */
     flag: u32,
 }
 
@@ -124,21 +143,94 @@ pub struct Heap {
     /* Bitmask that tracks whether a given free_lists entry has any elements.
      * See set_free_list_bit(), clear_free_list_bit(). */
     free_list_bits: [u32; BUCKET_WORDS],
+
+    /* Number of live allocations. */
+    alloc_count: usize,
+
+    /* High-water mark of bytes in use, i.e. size - remaining. */
+    peak_used: usize,
+
+    /* Whether the memory comes from the OS, so that the heap grows when it
+     * runs out of free space and gives whole OS allocations back. */
+    from_os: bool,
+}
+
+/* A snapshot of the usage of the heap, e.g. for leak hunting. */
+pub struct HeapStats {
+    /* Total bytes allocated from the OS for the heap. */
+    pub size: usize,
+    /* Bytes of usable free space in the heap. */
+    pub remaining: usize,
+    /* Number of live allocations. */
+    pub alloc_count: usize,
+    /* High-water mark of bytes in use. */
+    pub peak_used: usize,
+}
+
+/* A snapshot of the free space of the heap, e.g. for fragmentation. */
+pub struct HeapInfo {
+    /* Total bytes allocated from the OS for the heap. */
+    pub size: usize,
+    /* Bytes of usable free space in the heap. */
+    pub remaining: usize,
+    /* Number of free blocks in each bucket. See size_to_index_helper(). */
+    pub free_blocks: [usize; NUMBER_OF_BUCKETS],
+    /* Whether an OS allocation is held in cached_os_alloc. */
+    pub cached_os_alloc: bool,
 }
 
 const EMPTY_LIST: List<free_t> = List::new();
 
 impl Heap {
-    const fn new() -> Self {
+    const fn new(from_os: bool) -> Self {
         Self {
             size: 0,
             remaining: 0,
             cached_os_alloc: null_mut(),
             free_lists: [EMPTY_LIST; NUMBER_OF_BUCKETS],
             free_list_bits: [0; BUCKET_WORDS],
+            alloc_count: 0,
+            peak_used: 0,
+            from_os,
         }
     }
 
+    /* A heap which only serves the memory given to add_region, e.g. for
+     * tests: it never grows by itself, nor gives anything to the OS.
+     * It has to be init() where it stays before being used. */
+    pub const fn new_fixed() -> Self {
+        Self::new(false)
+    }
+
+    /* For tests: a heap which grows from the OS like the kernel heap.
+     * It has to be cmpct_heap_init() where it stays before being used. */
+    #[cfg(feature = "unittest")]
+    pub const fn new_from_os() -> Self {
+        Self::new(true)
+    }
+
+    /* Initialize the free lists, which point to themselves when empty,
+     * so the heap mustn't be moved after this. */
+    pub fn init(&mut self) {
+        for i in 0..NUMBER_OF_BUCKETS {
+            self.free_lists[i].init();
+        }
+    }
+
+    /* Add the memory [area, area + size) of a fixed heap as the free area
+     * between two sentinels. |area| and |size| are 8-byte aligned, and
+     * |size| is within HEAP_LARGE_ALLOC_BYTES like a heap_grow. */
+    pub fn add_region(&mut self, area: vaddr_t, size: usize)
+        -> Result<(), ErrNO> {
+        if self.from_os || !IS_ALIGNED!(area, 8) || !IS_ALIGNED!(size, 8) ||
+            size < HEAP_GROW_OVER_HEAD + SIZE_OF_FREE_T ||
+            size > HEAP_LARGE_ALLOC_BYTES + HEAP_GROW_OVER_HEAD {
+            return Err(ErrNO::InvalidArgs);
+        }
+        self.size += size;
+        add_to_heap(self, area, size)
+    }
+
     #[inline]
     fn set_free_list_bit(&mut self, index: usize) {
         self.free_list_bits[index >> 5] |= 1 << (31 - (index & 0x1f));
@@ -153,20 +245,42 @@ impl Heap {
 unsafe impl Send for Heap {}
 unsafe impl Sync for Heap {}
 
+/* Serializes the users of the kernel heap. The cmpct_* functions take it
+ * around the cmpct_heap_* ones, which leave the locking of the given heap
+ * to the caller. Nothing under it may allocate from the kernel heap.
+ *
+ * Lock order: HEAP_LOCK, then ASPACE_LIST. Growing the heap and giving
+ * memory back to the OS map and unmap pages through VirtualAlloc, which
+ * takes ASPACE_LIST with HEAP_LOCK held. So, once the heap is up, nothing
+ * may allocate from or free to the kernel heap with ASPACE_LIST held. */
+static HEAP_LOCK: Mutex<()> = Mutex::new(());
+
+/* Run |f| on the kernel heap with HEAP_LOCK held. */
+fn with_heap<R, F: FnOnce(&mut Heap) -> R>(f: F) -> R {
+    let _guard = HEAP_LOCK.lock();
+    f(BOOT_CONTEXT.heap())
+}
+
 pub fn cmpct_init() -> Result<(), ErrNO> {
     dprintf!(INFO, "cmpct_init ...\n");
     unsafe {
-        (*BOOT_CONTEXT.data.get()).heap = Some(Heap::new());
+        /* A second heap would leak the first one with all its memory. */
+        let heap = &mut (*BOOT_CONTEXT.data.get()).heap;
+        if heap.is_some() {
+            dprintf!(CRITICAL, "cmpct_init: heap has been initialized!\n");
+            return Err(ErrNO::AlreadyExists);
+        }
+        *heap = Some(Heap::new(true));
     }
 
-    let heap = BOOT_CONTEXT.heap();
-
-    /* Initialize the free lists. */
-    for i in 0..NUMBER_OF_BUCKETS {
-        heap.free_lists[i].init();
-    }
+    with_heap(cmpct_heap_init)
+}
 
-    heap_grow(HEAP_USABLE_GROW_SIZE)
+/* Set up a heap from the OS where it stays, and grow it for the first
+ * time. */
+pub fn cmpct_heap_init(heap: &mut Heap) -> Result<(), ErrNO> {
+    heap.init();
+    heap_grow(heap, HEAP_USABLE_GROW_SIZE, PMM_NODE_ANY)
 }
 
 const SIZE_OF_HEADER_T: usize = mem::size_of::<header_t>();
@@ -188,8 +302,14 @@ const HEAP_GROW_OVER_HEAD: usize = SIZE_OF_HEADER_T * 2;
 const HEAP_USABLE_GROW_SIZE: usize = HEAP_GROW_SIZE - HEAP_GROW_OVER_HEAD;
 
 /* Create a new free-list entry of at least size bytes (including the
- * allocation header).  Called with the lock, apart from during init. */
-fn heap_grow(mut size: usize) -> Result<(), ErrNO> {
+ * allocation header).  Called with the lock, apart from during init.
+ * New memory is preferably from the numa node. */
+fn heap_grow(heap: &mut Heap, mut size: usize, node: usize)
+    -> Result<(), ErrNO> {
+    if !heap.from_os {
+        return Err(ErrNO::NoMem);
+    }
+
     /* This function accesses field members of header_t which are poisoned
      * so it has to be NO_ASAN.
      *
@@ -205,7 +325,6 @@ fn heap_grow(mut size: usize) -> Result<(), ErrNO> {
 
     let mut area = 0;
 
-    let heap = BOOT_CONTEXT.heap();
     let os_alloc = heap.cached_os_alloc;
     if os_alloc != null_mut() {
         unsafe {
@@ -223,26 +342,26 @@ fn heap_grow(mut size: usize) -> Result<(), ErrNO> {
                  * future calls to heap_grow(). */
                 dprintf!(INFO, "Returning too-small saved 0x{:x}-byte (<0x{:x} bytes)\n",
                          (*os_alloc).size, size);
-                free_to_os(os_alloc as vaddr_t, (*os_alloc).size())?;
+                free_to_os(heap, os_alloc as vaddr_t, (*os_alloc).size())?;
             }
         }
         heap.cached_os_alloc = null_mut();
     }
 
     if area == 0 {
-        area = heap_page_alloc(size >> PAGE_SHIFT)?;
+        area = heap_page_alloc(size >> PAGE_SHIFT, node)?;
         dprintf!(INFO, "Growing heap by 0x{:x} bytes, new area {:x}\n", size, area);
         heap.size += size;
     }
 
-    add_to_heap(area, size)
+    add_to_heap(heap, area, size)
 }
 
-fn heap_page_alloc(pages: usize) -> Result<vaddr_t, ErrNO> {
+fn heap_page_alloc(pages: usize, node: usize) -> Result<vaddr_t, ErrNO> {
     ZX_ASSERT!(pages > 0);
     dprintf!(INFO, "heap_page_alloc...\n");
     let alloc = BOOT_CONTEXT.virtual_alloc();
-    alloc.alloc_pages(pages)
+    alloc.alloc_pages_etc(pages, pmm_alloc_flag_node(node))
 }
 
 fn heap_page_free(va: vaddr_t, pages: usize) -> Result<(), ErrNO> {
@@ -266,7 +385,8 @@ fn create_allocation_header(va: vaddr_t, offset: usize,
     va + offset + SIZE_OF_HEADER_T
 }
 
-fn add_to_heap(area: vaddr_t, size: usize) -> Result<(), ErrNO> {
+fn add_to_heap(heap: &mut Heap, area: vaddr_t, size: usize)
+    -> Result<(), ErrNO> {
     /* Set up the left sentinel. */
     let left = area as *mut header_t;
     let free_area = create_allocation_header(area, 0, SIZE_OF_HEADER_T, null_mut());
@@ -274,7 +394,9 @@ fn add_to_heap(area: vaddr_t, size: usize) -> Result<(), ErrNO> {
     /* Set up the usable memory area, which will be marked free. */
     let free_header = free_area as *mut header_t;
     let free_size = size - 2 * SIZE_OF_HEADER_T;
-    create_free_area(free_area, left, free_size);
+    #[cfg(feature = "heap-poison")]
+    poison_fill(free_area + SIZE_OF_FREE_T, free_area + free_size);
+    create_free_area(heap, free_area, left, free_size);
 
     /* Set up the right sentinel. */
     let right = area + size - SIZE_OF_HEADER_T;
@@ -282,7 +404,8 @@ fn add_to_heap(area: vaddr_t, size: usize) -> Result<(), ErrNO> {
     Ok(())
 }
 
-fn create_free_area(area: vaddr_t, left: *mut header_t, size: usize) {
+fn create_free_area(heap: &mut Heap, area: vaddr_t, left: *mut header_t,
+                    size: usize) {
     let mut ptr = area as *mut free_t;
     unsafe {
         (*ptr).queue_node.init();
@@ -293,7 +416,6 @@ fn create_free_area(area: vaddr_t, left: *mut header_t, size: usize) {
 
     let bucket = size_to_index_freeing(size - SIZE_OF_HEADER_T);
 
-    let heap = BOOT_CONTEXT.heap();
     heap.set_free_list_bit(bucket);
     heap.free_lists[bucket].add_head(ptr);
     heap.remaining += size;
@@ -350,10 +472,28 @@ fn size_to_index_helper(size: usize, adjust: isize, increment: usize) -> (usize,
 }
 
 pub fn cmpct_alloc(size: usize) -> *mut u8 {
+    cmpct_alloc_node(PMM_NODE_ANY, size)
+}
+
+/* Same as cmpct_alloc, but if the heap has to grow, it prefers
+ * the memory of the numa node. Memory already in the heap (or the
+ * cached OS allocation) is reused regardless of its node. */
+pub fn cmpct_alloc_node(node: usize, size: usize) -> *mut u8 {
+    with_heap(|heap| cmpct_heap_alloc(heap, node, size))
+}
+
+/* The cmpct_* functions work on the kernel heap; the cmpct_heap_* ones
+ * do the same on the given |heap|. */
+pub fn cmpct_heap_alloc(heap: &mut Heap, node: usize, size: usize)
+    -> *mut u8 {
     if size == 0 {
         return null_mut();
     }
 
+    /* Reserve room for the redzone in the slack of the usable block. */
+    #[cfg(feature = "heap-redzone")]
+    let (requested, size) = (size, size + REDZONE_SIZE);
+
     /* Large allocations are no longer allowed. */
     if size > HEAP_MAX_ALLOC_SIZE {
         return null_mut();
@@ -363,9 +503,7 @@ pub fn cmpct_alloc(size: usize) -> *mut u8 {
 
     let rounded_up = rounded_up + SIZE_OF_HEADER_T;
 
-    let heap = BOOT_CONTEXT.heap();
-
-    let bucket = match find_nonempty_bucket(start_bucket) {
+    let bucket = match find_nonempty_bucket(heap, start_bucket) {
         Ok(ret) => {
             ret
         },
@@ -380,13 +518,13 @@ pub fn cmpct_alloc(size: usize) -> *mut u8 {
             ZX_ASSERT!(growby >= rounded_up);
             /* Try to add a new OS allocation to the heap, reducing the size
              * until we succeed or get too small. */
-            while let Err(_) = heap_grow(growby) {
+            while let Err(_) = heap_grow(heap, growby, node) {
                 if growby <= rounded_up {
                     return null_mut();
                 }
                 growby = cmp::max(growby >> 1, rounded_up);
             }
-            match find_nonempty_bucket(start_bucket) {
+            match find_nonempty_bucket(heap, start_bucket) {
                 Ok(ret) => {
                     ret
                 },
@@ -412,16 +550,16 @@ pub fn cmpct_alloc(size: usize) -> *mut u8 {
     // coalescing and returning pages to the OS.
     if left_over >= SIZE_OF_FREE_T && left_over > (size >> 6) {
         let right = right_header(head as *mut header_t);
-        unlink_free(head, bucket);
+        unlink_free(heap, head, bucket);
         let free = head as usize + rounded_up;
         let left = head as *mut header_t;
-        create_free_area(free, left, left_over);
+        create_free_area(heap, free, left, left_over);
         unsafe {
             (*right).left = free as *mut header_t;
             (*head).header.size -= left_over as u32;
         }
     } else {
-        unlink_free(head, bucket);
+        unlink_free(heap, head, bucket);
     }
 
     let ret;
@@ -430,22 +568,38 @@ pub fn cmpct_alloc(size: usize) -> *mut u8 {
             (*head).header.size(), (*head).header.left);
     }
     //memset(ret, 0, size);
+    #[cfg(feature = "heap-poison")]
+    unsafe {
+        let end = head as vaddr_t + (*head).header.size();
+        if !poison_check(head as vaddr_t + SIZE_OF_FREE_T, end) {
+            panic!("cmpct_alloc: block 0x{:x} was written after free!", ret);
+        }
+    }
+    #[cfg(feature = "heap-redzone")]
+    redzone_fill(ret, requested);
+    heap.alloc_count += 1;
+    heap.peak_used = cmp::max(heap.peak_used, heap.size - heap.remaining);
     dprintf!(INFO, "cmpct_alloc 0x{:x} 0x{:x}...\n", size, ret);
     ret as *mut u8
 }
 
 pub fn cmpct_memalign(align: usize, size: usize) -> *mut u8 {
+    with_heap(|heap| cmpct_heap_memalign(heap, align, size))
+}
+
+pub fn cmpct_heap_memalign(heap: &mut Heap, align: usize, size: usize)
+    -> *mut u8 {
     if size == 0 {
         return null_mut();
     }
 
     if align < 8 {
-        return cmpct_alloc(size);
+        return cmpct_heap_alloc(heap, PMM_NODE_ANY, size);
     }
 
     let padded_size = size + align + SIZE_OF_FREE_T;
 
-    let unaligned = cmpct_alloc(padded_size);
+    let unaligned = cmpct_heap_alloc(heap, PMM_NODE_ANY, padded_size);
     if unaligned == null_mut() {
         return null_mut();
     }
@@ -466,14 +620,131 @@ pub fn cmpct_memalign(align: usize, size: usize) -> *mut u8 {
             (*unaligned_header).size = left_over as u32;
             (*right).left = header as *mut header_t;
         }
-        cmpct_free(unaligned as *mut u8);
+        /* The piece in front now ends somewhere else: move its redzone. */
+        #[cfg(feature = "heap-redzone")]
+        redzone_fill(unaligned, left_over - SIZE_OF_HEADER_T - REDZONE_SIZE);
+        /* This also takes the extra allocation off the count. */
+        cmpct_heap_free(heap, unaligned as *mut u8);
     }
 
+    /* The caller asked for |size| bytes, not for the padding. */
+    #[cfg(feature = "heap-redzone")]
+    redzone_fill(payload, size);
+
     payload as *mut u8
 }
 
-fn unlink_free(free_area: *mut free_t, bucket: usize) {
-    let heap = BOOT_CONTEXT.heap();
+/*
+ * Resize the allocation at |payload| to |size| bytes, keeping its content
+ * up to the smaller of the two sizes. The block is resized in place if it
+ * can be, absorbing its right neighbor when that one is free and large
+ * enough; otherwise the content is moved to a new allocation and the old
+ * one is freed. Returns null if that fails, leaving |payload| as it was.
+ * A null |payload| is just cmpct_alloc, and a zero |size| is cmpct_free.
+ */
+pub fn cmpct_realloc(payload: *mut u8, size: usize) -> *mut u8 {
+    with_heap(|heap| cmpct_heap_realloc(heap, payload, size))
+}
+
+pub fn cmpct_heap_realloc(heap: &mut Heap, payload: *mut u8, size: usize)
+    -> *mut u8 {
+    if payload == null_mut() {
+        return cmpct_heap_alloc(heap, PMM_NODE_ANY, size);
+    }
+    if size == 0 {
+        cmpct_heap_free(heap, payload);
+        return null_mut();
+    }
+
+    #[cfg(feature = "heap-redzone")]
+    if !cmpct_check_redzone(payload) {
+        panic!("cmpct_realloc: redzone of block 0x{:x} is clobbered!",
+               payload as vaddr_t);
+    }
+
+    let header = (payload as vaddr_t - SIZE_OF_HEADER_T) as *mut header_t;
+    #[cfg(not(feature = "heap-redzone"))]
+    let old_size = unsafe { (*header).size() } - SIZE_OF_HEADER_T;
+    /* With the redzone, only the requested bytes are the content. */
+    #[cfg(feature = "heap-redzone")]
+    let (old_size, requested, size) = unsafe {
+        (((*header).flag >> REDZONE_SHIFT) as usize, size, size + REDZONE_SIZE)
+    };
+
+    if size > HEAP_MAX_ALLOC_SIZE {
+        return null_mut();
+    }
+    let (_, rounded_up) = size_to_index_allocating(size);
+    let rounded_up = rounded_up + SIZE_OF_HEADER_T;
+
+    let right = right_header(header);
+    let cur_size = unsafe { (*header).size() };
+    if cur_size < rounded_up && is_tagged_as_free(right) &&
+        cur_size + unsafe { (*right).size() } >= rounded_up {
+        /* Grow into the free right neighbor. */
+        unlink_free_unknown_bucket(heap, right as *mut free_t);
+        unsafe {
+            (*header).size += (*right).size;
+            (*right_header(right)).left = header;
+        }
+    }
+
+    if unsafe { (*header).size() } >= rounded_up {
+        shrink_block(heap, header, rounded_up);
+        #[cfg(feature = "heap-redzone")]
+        redzone_fill(payload as vaddr_t, requested);
+        heap.peak_used = cmp::max(heap.peak_used, heap.size - heap.remaining);
+        dprintf!(INFO, "cmpct_realloc 0x{:x} 0x{:x} in place\n",
+                 size, payload as vaddr_t);
+        return payload;
+    }
+
+    #[cfg(feature = "heap-redzone")]
+    let size = requested;
+    let new_payload = cmpct_heap_alloc(heap, PMM_NODE_ANY, size);
+    if new_payload == null_mut() {
+        return null_mut();
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(payload, new_payload,
+                                       cmp::min(old_size, size));
+    }
+    cmpct_heap_free(heap, payload);
+    new_payload
+}
+
+/* Cut the allocated block at |header| down to |size| bytes if the rest is
+ * large enough to be a free area, which is merged with the right neighbor
+ * if that one is free. */
+fn shrink_block(heap: &mut Heap, header: *mut header_t, size: usize) {
+    let left_over = unsafe { (*header).size() } - size;
+    if left_over < SIZE_OF_FREE_T {
+        return;
+    }
+
+    let free = header as vaddr_t + size;
+    #[cfg(feature = "heap-poison")]
+    poison_fill(free + SIZE_OF_FREE_T, free + left_over);
+
+    let mut right = right_header(header);
+    let mut free_size = left_over;
+    if is_tagged_as_free(right) {
+        unlink_free_unknown_bucket(heap, right as *mut free_t);
+        free_size += unsafe { (*right).size() };
+        let right_right = right_header(right);
+        #[cfg(feature = "heap-poison")]
+        poison_fill(right as vaddr_t, right as vaddr_t + SIZE_OF_FREE_T);
+        right = right_right;
+    }
+
+    unsafe {
+        (*header).size = size as u32;
+        (*right).left = free as *mut header_t;
+    }
+    create_free_area(heap, free, header, free_size);
+}
+
+fn unlink_free(heap: &mut Heap, free_area: *mut free_t, bucket: usize) {
     unsafe {
         ZX_ASSERT!(heap.remaining >= (*free_area).header.size());
         heap.remaining -= (*free_area).header.size();
@@ -491,39 +762,306 @@ fn right_header(header: *const header_t) -> *mut header_t {
     }
 }
 
-fn find_nonempty_bucket(index: usize) -> Result<usize, ErrNO> {
-    let heap = BOOT_CONTEXT.heap();
-    let mut mask = (1u32 << (31 - (index & 0x1f))) - 1;
-    mask = mask * 2 + 1;
-    mask &= heap.free_list_bits[index >> 5];
+fn find_nonempty_bucket(heap: &Heap, index: usize) -> Result<usize, ErrNO> {
+    find_nonempty_bucket_in(&heap.free_list_bits, index)
+}
+
+/* Find the first bucket at or after |index| whose bit is set in |bits|,
+ * in which bucket i is the bit (31 - i % 32) of the word i / 32.
+ * NOTE: Search up to the last word even if it's partially used, the bits
+ * beyond NUMBER_OF_BUCKETS are never set. */
+pub fn find_nonempty_bucket_in(bits: &[u32], index: usize)
+    -> Result<usize, ErrNO> {
+    let word = index >> 5;
+    if word >= bits.len() {
+        return Err(ErrNO::NotFound);
+    }
+
+    /* Keep the bits of index and the buckets after it in the same word. */
+    let mask = (u32::MAX >> (index & 0x1f)) & bits[word];
     if mask != 0 {
-        return Ok((index & !0x1f) + mask.leading_zeros() as usize);
-    }
-    let start = ROUNDUP!(index +1 , 32) >> 5;
-    let end = NUMBER_OF_BUCKETS >> 5;
-    for i in start..end {
-        mask = heap.free_list_bits[i];
-        if mask != 0 {
-            return Ok((i << 5) + mask.leading_zeros() as usize);
+        return Ok((word << 5) + mask.leading_zeros() as usize);
+    }
+    for i in (word + 1)..bits.len() {
+        if bits[i] != 0 {
+            return Ok((i << 5) + bits[i].leading_zeros() as usize);
         }
     }
     Err(ErrNO::NotFound)
 }
 
 pub fn cmpct_free(payload: *mut u8) {
+    with_heap(|heap| cmpct_heap_free(heap, payload))
+}
+
+pub fn cmpct_heap_free(heap: &mut Heap, payload: *mut u8) {
+    match cmpct_heap_try_free(heap, payload) {
+        Ok(()) => {},
+        Err(ErrNO::BadState) => {
+            panic!("cmpct_free: redzone of block 0x{:x} is clobbered!",
+                   payload as vaddr_t);
+        },
+        Err(_) => {
+            panic!("cmpct_free error!");
+        },
+    }
+}
+
+/* Same as cmpct_heap_free, but it's BadState rather than a panic if
+ * the redzone of the block is clobbered, and the block stays allocated. */
+pub fn cmpct_heap_try_free(heap: &mut Heap, payload: *mut u8)
+    -> Result<(), ErrNO> {
     dprintf!(INFO, "cmpct_free 0x{:x}...\n", payload as usize);
     if payload == null_mut() {
-        return;
+        return Ok(());
+    }
+
+    #[cfg(feature = "heap-redzone")]
+    if !cmpct_check_redzone(payload) {
+        return Err(ErrNO::BadState);
     }
 
     let header = (payload as vaddr_t - SIZE_OF_HEADER_T) as *mut header_t;
-    if let Err(_) = cmpct_free_internal(payload, header) {
-        panic!("cmpct_free error!");
+    cmpct_free_internal(heap, payload, header)?;
+
+    ZX_ASSERT!(heap.alloc_count > 0);
+    heap.alloc_count -= 1;
+    Ok(())
+}
+
+/*
+ * Check whether |payload| is a live allocation of this heap, e.g. before
+ * freeing a pointer of uncertain origin. The heap pages around it are found
+ * by the virtual allocator, whose pages only serve the heap, then the OS
+ * allocations there are walked from their left sentinels, header by header.
+ * It owns |payload| only if the walk lands right on its header, and that
+ * header isn't free. O(size of the OS allocations), so not for hot paths.
+ */
+#[allow(dead_code)]
+pub fn cmpct_owns(payload: *mut u8) -> bool {
+    if payload.is_null() || !IS_ALIGNED!(payload as usize, BYTES_PER_USIZE) {
+        return false;
     }
+    with_heap(|heap| heap_owns(heap, payload))
 }
 
-fn cmpct_free_internal(_payload: *mut u8, header: *mut header_t)
-    -> Result<(), ErrNO> {
+fn heap_owns(heap: &Heap, payload: *mut u8) -> bool {
+    let target = (payload as vaddr_t - SIZE_OF_HEADER_T) as *mut header_t;
+
+    let (start, end) = match BOOT_CONTEXT.virtual_alloc()
+                               .allocated_run(target as vaddr_t) {
+        Some(run) => run,
+        None => return false,
+    };
+
+    let mut os_alloc = start;
+    while os_alloc < end && os_alloc <= target as vaddr_t {
+        let left = os_alloc as *mut header_t;
+        if left == heap.cached_os_alloc {
+            /* Nothing lives in the cached one, it's a whole block. */
+            os_alloc += unsafe { (*left).size() };
+            continue;
+        }
+        if !is_start_of_os_allocation(left) ||
+            unsafe { (*left).size() } != SIZE_OF_HEADER_T {
+            return false;
+        }
+
+        let mut prev = left;
+        let mut header = right_header(left);
+        loop {
+            if header as vaddr_t + SIZE_OF_HEADER_T > end ||
+                unsafe { (*header).left } != prev {
+                return false;
+            }
+            if is_end_of_os_allocation(header) {
+                break;
+            }
+            if header == target {
+                return !is_tagged_as_free(header);
+            }
+            if header > target ||
+                unsafe { (*header).size() } <= SIZE_OF_HEADER_T {
+                return false;
+            }
+            prev = header;
+            header = right_header(header);
+        }
+        /* The next OS allocation follows the right sentinel. */
+        os_alloc = header as vaddr_t + SIZE_OF_HEADER_T;
+    }
+    false
+}
+
+/*
+ * For tests: give all the OS allocations of the heap back and start it
+ * over from a fresh heap_grow, so that a test case doesn't depend on what
+ * the cases before it left in the free lists. That's only possible when
+ * nothing is allocated from the heap, so it's BadState otherwise, which
+ * it always is for the kernel heap.
+ */
+#[cfg(feature = "unittest")]
+pub fn cmpct_reset() -> Result<(), ErrNO> {
+    with_heap(cmpct_heap_reset)
+}
+
+#[cfg(feature = "unittest")]
+pub fn cmpct_heap_reset(heap: &mut Heap) -> Result<(), ErrNO> {
+    cmpct_heap_release(heap)?;
+    cmpct_heap_init(heap)
+}
+
+/* For tests: give all the OS allocations of a heap from the OS back and
+ * zero it, e.g. before it's dropped. It has to be init again to be used. */
+#[cfg(feature = "unittest")]
+pub fn cmpct_heap_release(heap: &mut Heap) -> Result<(), ErrNO> {
+    if !heap.from_os || heap.alloc_count != 0 {
+        return Err(ErrNO::BadState);
+    }
+
+    /* With nothing allocated, each OS allocation is either the cached one
+     * or a single free area between its two sentinels. */
+    for i in 0..NUMBER_OF_BUCKETS {
+        loop {
+            let free_area = heap.free_lists[i].pop_head();
+            if free_area == null_mut() {
+                break;
+            }
+            let header = free_area as *mut header_t;
+            let left = unsafe { (*header).left };
+            ZX_ASSERT!(IS_PAGE_ALIGNED!(left as usize) &&
+                       is_start_of_os_allocation(left));
+            ZX_ASSERT!(is_end_of_os_allocation(right_header(header)));
+            let size = unsafe { (*header).size() } + 2 * SIZE_OF_HEADER_T;
+            free_to_os(heap, left as vaddr_t, size)?;
+        }
+    }
+    let os_alloc = heap.cached_os_alloc;
+    if os_alloc != null_mut() {
+        free_to_os(heap, os_alloc as vaddr_t, unsafe { (*os_alloc).size() })?;
+    }
+    ZX_ASSERT!(heap.size == 0);
+
+    *heap = Heap::new(true);
+    Ok(())
+}
+
+pub fn cmpct_get_stats() -> HeapStats {
+    with_heap(|heap| cmpct_heap_get_stats(heap))
+}
+
+pub fn cmpct_heap_get_stats(heap: &Heap) -> HeapStats {
+    HeapStats {
+        size: heap.size,
+        remaining: heap.remaining,
+        alloc_count: heap.alloc_count,
+        peak_used: heap.peak_used,
+    }
+}
+
+/*
+ * Return the cached OS allocation to the OS, e.g. when the PMM runs low on
+ * pages. It's kept though if the heap is short of free space without it:
+ * at least an eighth of the rest of the heap has to be free, the same 12%
+ * that cmpct_alloc grows the heap by, or it would soon be needed again.
+ */
+pub fn cmpct_trim() -> Result<(), ErrNO> {
+    with_heap(heap_trim)
+}
+
+fn heap_trim(heap: &mut Heap) -> Result<(), ErrNO> {
+    let os_alloc = heap.cached_os_alloc;
+    if os_alloc == null_mut() {
+        return Ok(());
+    }
+
+    let cached_size = unsafe { (*os_alloc).size() };
+    if heap.remaining < (heap.size - cached_size) >> 3 {
+        dprintf!(INFO, "Keeping 0x{:x}-byte OS alloc, 0x{:x} bytes free\n",
+                 cached_size, heap.remaining);
+        return Ok(());
+    }
+
+    dprintf!(INFO, "Trimming 0x{:x}-byte OS alloc {:x}\n",
+             cached_size, os_alloc as vaddr_t);
+    free_to_os(heap, os_alloc as vaddr_t, cached_size)?;
+    heap.cached_os_alloc = null_mut();
+    Ok(())
+}
+
+/* O(number of free blocks), as the free lists are walked to count them. */
+pub fn cmpct_get_info() -> HeapInfo {
+    with_heap(|heap| cmpct_heap_get_info(heap))
+}
+
+pub fn cmpct_heap_get_info(heap: &Heap) -> HeapInfo {
+    let mut free_blocks = [0; NUMBER_OF_BUCKETS];
+    for i in 0..NUMBER_OF_BUCKETS {
+        free_blocks[i] = heap.free_lists[i].iter().count();
+    }
+    HeapInfo {
+        size: heap.size,
+        remaining: heap.remaining,
+        free_blocks,
+        cached_os_alloc: heap.cached_os_alloc != null_mut(),
+    }
+}
+
+/* Record the requested size in the header and
+ * poison the bytes just past it with REDZONE_MAGIC. */
+#[cfg(feature = "heap-redzone")]
+fn redzone_fill(payload: vaddr_t, requested: usize) {
+    let header = (payload - SIZE_OF_HEADER_T) as *mut header_t;
+    unsafe {
+        ZX_ASSERT!(SIZE_OF_HEADER_T + requested + REDZONE_SIZE <= (*header).size());
+        (*header).flag = (requested as u32) << REDZONE_SHIFT;
+        core::ptr::write_bytes((payload + requested) as *mut u8,
+                               REDZONE_MAGIC, REDZONE_SIZE);
+    }
+}
+
+/* Returns false if anything has written past the requested size. */
+#[cfg(feature = "heap-redzone")]
+pub fn cmpct_check_redzone(payload: *const u8) -> bool {
+    let header = (payload as vaddr_t - SIZE_OF_HEADER_T) as *const header_t;
+    let requested = unsafe { ((*header).flag >> REDZONE_SHIFT) as usize };
+    let redzone = unsafe {
+        core::slice::from_raw_parts(payload.add(requested), REDZONE_SIZE)
+    };
+    redzone.iter().all(|b| *b == REDZONE_MAGIC)
+}
+
+#[cfg(feature = "heap-poison")]
+fn poison_fill(start: vaddr_t, end: vaddr_t) {
+    if start < end {
+        unsafe {
+            core::ptr::write_bytes(start as *mut u8, POISON_MAGIC, end - start);
+        }
+    }
+}
+
+/* Returns false if anything in [start, end) isn't POISON_MAGIC. */
+#[cfg(feature = "heap-poison")]
+fn poison_check(start: vaddr_t, end: vaddr_t) -> bool {
+    if start >= end {
+        return true;
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts(start as *const u8, end - start)
+    };
+    bytes.iter().all(|b| *b == POISON_MAGIC)
+}
+
+/* Whether the freed block at |payload| is still poisoned, past the free_t
+ * written over its start. */
+#[cfg(feature = "heap-poison")]
+pub fn cmpct_check_poison(payload: *const u8, size: usize) -> bool {
+    let start = payload as vaddr_t - SIZE_OF_HEADER_T + SIZE_OF_FREE_T;
+    poison_check(start, payload as vaddr_t + size)
+}
+
+fn cmpct_free_internal(heap: &mut Heap, _payload: *mut u8,
+                       header: *mut header_t) -> Result<(), ErrNO> {
     ZX_ASSERT!(!is_tagged_as_free(header));     /* Double free! */
     let size;
     let left;
@@ -533,6 +1071,12 @@ fn cmpct_free_internal(_payload: *mut u8, header: *mut header_t)
         left = (*header).left;
     }
 
+    /* The headers which end up inside the coalesced area are poisoned
+     * as well, below. The free_t of the area itself is written over the
+     * start of it. */
+    #[cfg(feature = "heap-poison")]
+    poison_fill(header as vaddr_t + SIZE_OF_HEADER_T, header as vaddr_t + size);
+
     unsafe {
         dprintf!(INFO, "cmpct_free_internal: left {:x} size 0x{:x} flag {:x} self.size 0x{:x}\n",
             left as vaddr_t, (*left).size(), (*left).flag, size);
@@ -540,23 +1084,29 @@ fn cmpct_free_internal(_payload: *mut u8, header: *mut header_t)
 
     if left != null_mut() && is_tagged_as_free(left) {
         /* Coalesce with left free object. */
-        unlink_free_unknown_bucket(left as *mut free_t);
+        unlink_free_unknown_bucket(heap, left as *mut free_t);
         let left_left = unsafe { (*left).left };
         let right = right_header(header);
+        #[cfg(feature = "heap-poison")]
+        poison_fill(header as vaddr_t, header as vaddr_t + SIZE_OF_HEADER_T);
         if is_tagged_as_free(right) {
             /* Coalesce both sides. */
-            unlink_free_unknown_bucket(right as *mut free_t);
+            unlink_free_unknown_bucket(heap, right as *mut free_t);
             let right_right = right_header(right);
+            let right_size = unsafe { (*right).size() };
+            #[cfg(feature = "heap-poison")]
+            poison_fill(right as vaddr_t, right as vaddr_t + SIZE_OF_FREE_T);
             unsafe {
                 (*right_right).left = left;
-                free_memory(left as vaddr_t, left_left,
-                    (*left).size() + size + (*right).size())?;
+                free_memory(heap, left as vaddr_t, left_left,
+                    (*left).size() + size + right_size)?;
             }
         } else {
             /* Coalesce only left. */
             unsafe {
                 (*right).left = left;
-                free_memory(left as vaddr_t, left_left, (*left).size() + size)?;
+                free_memory(heap, left as vaddr_t, left_left,
+                            (*left).size() + size)?;
             }
         }
     } else {
@@ -564,13 +1114,16 @@ fn cmpct_free_internal(_payload: *mut u8, header: *mut header_t)
         if is_tagged_as_free(right) {
             /* Coalesce only right. */
             let right_right = right_header(right);
-            unlink_free_unknown_bucket(right as *mut free_t);
+            unlink_free_unknown_bucket(heap, right as *mut free_t);
+            let right_size = unsafe { (*right).size() };
+            #[cfg(feature = "heap-poison")]
+            poison_fill(right as vaddr_t, right as vaddr_t + SIZE_OF_FREE_T);
             unsafe {
                 (*right_right).left = header;
-                free_memory(header as vaddr_t, left, size + (*right).size())?;
+                free_memory(heap, header as vaddr_t, left, size + right_size)?;
             }
         } else {
-            free_memory(header as vaddr_t, left, size)?;
+            free_memory(heap, header as vaddr_t, left, size)?;
         }
     }
 
@@ -595,17 +1148,18 @@ fn is_end_of_os_allocation(header: *const header_t) -> bool {
 // |left| and |size| should be set to the values that the header_t would have
 // contained. This is broken out because the header_t will not contain the
 // proper size when coalescing neighboring areas.
-fn free_memory(va: vaddr_t, left: *mut header_t, size: usize)
+fn free_memory(heap: &mut Heap, va: vaddr_t, left: *mut header_t, size: usize)
     -> Result<(), ErrNO> {
-    if IS_PAGE_ALIGNED!(left as usize) && is_start_of_os_allocation(left) &&
+    if heap.from_os &&
+        IS_PAGE_ALIGNED!(left as usize) && is_start_of_os_allocation(left) &&
         is_end_of_os_allocation((va + size) as *mut header_t) {
         /* Assert that it's safe to do a simple 2*sizeof(header_t)) below. */
         unsafe {
             ZX_ASSERT!((*left).size() == SIZE_OF_HEADER_T);
         }
-        possibly_free_to_os(left as vaddr_t, size + 2 * SIZE_OF_HEADER_T)
+        possibly_free_to_os(heap, left as vaddr_t, size + 2 * SIZE_OF_HEADER_T)
     } else {
-        create_free_area(va, left, size);
+        create_free_area(heap, va, left, size);
         Ok(())
     }
 }
@@ -614,9 +1168,8 @@ fn free_memory(va: vaddr_t, left: *mut header_t, size: usize)
 // cached_os_alloc. |left_sentinel| is the start of the OS allocation, and
 // |total_size| is the (page-aligned) number of bytes that were originally
 // allocated from the OS.
-fn possibly_free_to_os(left_sentinel: vaddr_t, total_size: usize)
-    -> Result<(), ErrNO> {
-    let heap = BOOT_CONTEXT.heap();
+fn possibly_free_to_os(heap: &mut Heap, left_sentinel: vaddr_t,
+                       total_size: usize) -> Result<(), ErrNO> {
     if heap.cached_os_alloc == null_mut() {
         dprintf!(INFO, "Keeping 0x{:x}-byte OS alloc {:x}\n", total_size, left_sentinel);
         heap.cached_os_alloc = left_sentinel as *mut header_t;
@@ -629,23 +1182,22 @@ fn possibly_free_to_os(left_sentinel: vaddr_t, total_size: usize)
     }
 
     dprintf!(INFO, "Returning 0x{:x} bytes to OS\n", total_size);
-    free_to_os(left_sentinel, total_size)
+    free_to_os(heap, left_sentinel, total_size)
 }
 
-fn free_to_os(va: vaddr_t, size: usize) -> Result<(), ErrNO> {
+fn free_to_os(heap: &mut Heap, va: vaddr_t, size: usize) -> Result<(), ErrNO> {
     ZX_ASSERT!(IS_PAGE_ALIGNED!(va));
     ZX_ASSERT!(IS_PAGE_ALIGNED!(size));
     heap_page_free(va, size >> PAGE_SHIFT)?;
 
-    let heap = BOOT_CONTEXT.heap();
     heap.size -= size;
     Ok(())
 }
 
-fn unlink_free_unknown_bucket(free_area: *mut free_t) {
+fn unlink_free_unknown_bucket(heap: &mut Heap, free_area: *mut free_t) {
     unsafe {
         let bucket = size_to_index_freeing((*free_area).header.size() - SIZE_OF_HEADER_T);
-        unlink_free(free_area, bucket);
+        unlink_free(heap, free_area, bucket);
     }
 }
 
@@ -9,6 +9,7 @@
 #![allow(dead_code)]
 
 use core::{marker::PhantomData, ptr::null_mut};
+use core::ops::ControlFlow;
 use crate::{ZX_ASSERT_MSG, ZX_ASSERT};
 
 #[macro_export(local_inner_macros)]
@@ -108,10 +109,11 @@ impl<'a, T: Linked<T>> Iterator for Iter<'a, T> {
         if self.cursor == self.head {
             None
         } else {
+            let node = self.cursor;
             unsafe {
-                self.cursor = (*self.cursor).next;
-                Some(T::from_node(self.cursor))
+                self.cursor = (*node).next;
             }
+            Some(T::from_node(node))
         }
     }
 }
@@ -130,10 +132,11 @@ impl<'a, T: Linked<T>> Iterator for IterMut<'a, T> {
         if self.cursor == self.head {
             None
         } else {
+            let node = self.cursor;
             unsafe {
-                self.cursor = (*self.cursor).next;
-                Some(T::from_node(self.cursor))
+                self.cursor = (*node).next;
             }
+            Some(T::from_node(node))
         }
     }
 }
@@ -186,6 +189,28 @@ impl<T: Linked<T>> List<T> {
         self.node.next == self.ref_node
     }
 
+    /* Returns the first element which matches pred, or null if none. */
+    pub fn find<F>(&self, pred: F) -> *mut T
+    where F: Fn(&T) -> bool
+    {
+        for elt in self.iter() {
+            if unsafe { pred(&*elt) } {
+                return elt;
+            }
+        }
+        null_mut()
+    }
+
+    /* Calls f on each element in order until f returns Break. */
+    pub fn for_each<F>(&mut self, mut f: F) -> ControlFlow<()>
+    where F: FnMut(&mut T) -> ControlFlow<()>
+    {
+        for elt in self.iter_mut() {
+            unsafe { f(&mut *elt)?; }
+        }
+        ControlFlow::Continue(())
+    }
+
     pub fn add_head(&mut self, elt: *mut T) {
         ZX_ASSERT_MSG!(self.is_initialized(), "List hasn't been initialized!");
         unsafe { self.add_head_node((*elt).into_node()); }
@@ -255,6 +280,32 @@ impl<T: Linked<T>> List<T> {
         other.init();
     }
 
+    /* Moves the elements after elt (which must be in this list) to the
+     * tail of other in O(1), so splice is the inverse of it.
+     * NOTE: The tail is not returned as a new list, because a list refers
+     * to its own node and would be broken by the move. */
+    pub fn split_after(&mut self, elt: *mut T, other: &mut Self) {
+        ZX_ASSERT_MSG!(self.is_initialized(), "List hasn't been initialized!");
+        ZX_ASSERT_MSG!(other.is_initialized(), "List hasn't been initialized!");
+        let node = unsafe { (*elt).into_node() };
+        unsafe {
+            ZX_ASSERT!((*node).is_in_list());
+            if (*node).next == self.ref_node {
+                return;
+            }
+
+            let first = (*node).next;
+            let last = self.node.prev;
+            (*node).next = self.ref_node;
+            self.node.prev = node;
+
+            (*first).prev = other.node.prev;
+            (*other.node.prev).next = first;
+            (*last).next = other.ref_node;
+            other.node.prev = last;
+        }
+    }
+
     pub fn _len(&self) -> usize {
         let mut ret = 0;
         let mut next = self.node.next;
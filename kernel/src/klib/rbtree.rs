@@ -10,7 +10,7 @@
 
 use alloc::boxed::Box;
 use core::cmp::Ord;
-use core::fmt::Debug;
+use core::fmt::{self, Debug};
 use core::cmp::Ordering;
 use core::{ptr, marker};
 
@@ -37,6 +37,45 @@ impl<K: Ord, V> RBTree<K, V> {
         }
     }
 
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.root.is_null()
+    }
+
+    /* Remove and drop all the entries, leaving the tree empty. */
+    pub fn clear(&mut self) {
+        let mut node = self.root;
+        self.root = NodePtr::null();
+        self.len = 0;
+
+        /* Free in post order: descend to a leaf, free it, unlink it from
+         * its parent and go on from there. So every node is freed once
+         * after both of its subtrees, without recursion. */
+        while !node.is_null() {
+            if !node.left().is_null() {
+                node = node.left();
+            } else if !node.right().is_null() {
+                node = node.right();
+            } else {
+                let mut parent = node.parent();
+                if parent.left() == node {
+                    parent.set_left(NodePtr::null());
+                } else {
+                    parent.set_right(NodePtr::null());
+                }
+                unsafe {
+                    drop(Box::from_raw(node.0));
+                }
+                node = parent;
+            }
+        }
+    }
+
     // upper_bound(key) : Finds the element (E) in the tree such that E.key > key
     pub fn upper_bound(&self, k: &K) -> IterMut<K, V> {
         self.bound(k, true)
@@ -47,6 +86,16 @@ impl<K: Ord, V> RBTree<K, V> {
         self.bound(k, false)
     }
 
+    // Like lower_bound and upper_bound, but return a cursor at the element,
+    // which can move to either side of it
+    pub fn lower_bound_cursor(&self, k: &K) -> Cursor<K, V> {
+        Cursor::new(self.bound(k, false).cursor)
+    }
+
+    pub fn upper_bound_cursor(&self, k: &K) -> Cursor<K, V> {
+        Cursor::new(self.bound(k, true).cursor)
+    }
+
     fn bound(&self, k: &K, strict: bool) -> IterMut<K, V> {
         if self.root.is_null() {
             return IterMut::null();
@@ -229,6 +278,157 @@ impl<K: Ord, V> RBTree<K, V> {
         self.root.set_black_color();
     }
 
+    /* Remove the entry of key, returning its value if there was one. */
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let node = self.find_node(k);
+        if node.is_null() {
+            return None;
+        }
+
+        unsafe { Some(self.delete(node)) }
+    }
+
+    /* Unlink node from the tree and free it, returning its value. */
+    unsafe fn delete(&mut self, node: NodePtr<K, V>) -> V {
+        let mut child;
+        let mut parent;
+        let color;
+
+        self.len -= 1;
+
+        if !node.left().is_null() && !node.right().is_null() {
+            /* With two children, the successor (the minimum of the right
+             * subtree, which has no left child) takes the place of node,
+             * and it's the successor's old position that gets removed. */
+            let mut replace = node.right().min_node();
+            self.transplant(node, replace);
+
+            child = replace.right();
+            parent = replace.parent();
+            color = replace.get_color();
+            if parent == node {
+                parent = replace;
+            } else {
+                child.set_parent(parent);
+                parent.set_left(child);
+                replace.set_right(node.right());
+                node.right().set_parent(replace);
+            }
+
+            replace.set_parent(node.parent());
+            replace.set_color(node.get_color());
+            replace.set_left(node.left());
+            node.left().set_parent(replace);
+        } else {
+            child = if !node.left().is_null() {
+                node.left()
+            } else {
+                node.right()
+            };
+            parent = node.parent();
+            color = node.get_color();
+
+            child.set_parent(parent);
+            self.transplant(node, child);
+        }
+
+        /* Removing a black node shortens the black height of the paths
+         * through child, which may be null, so its parent is passed along */
+        if color == Color::Black {
+            self.delete_fixup(child, parent);
+        }
+
+        Box::from_raw(node.0).value
+    }
+
+    /* Make the parent of node point to new instead, or the root. */
+    #[inline]
+    unsafe fn transplant(&mut self, node: NodePtr<K, V>, new: NodePtr<K, V>) {
+        let mut parent = node.parent();
+        if parent.is_null() {
+            self.root = new;
+        } else if parent.left() == node {
+            parent.set_left(new);
+        } else {
+            parent.set_right(new);
+        }
+    }
+
+    #[inline]
+    unsafe fn delete_fixup(&mut self, mut node: NodePtr<K, V>,
+                           mut parent: NodePtr<K, V>) {
+        let mut other;
+
+        /* node carries an extra black, which is pushed up the tree
+         * till it reaches a red node or the root. */
+        while node != self.root && node.is_black_color() {
+            if parent.left() == node {
+                other = parent.right();
+                if other.is_red_color() {
+                    other.set_black_color();
+                    parent.set_red_color();
+                    self.left_rotate(parent);
+                    other = parent.right();
+                }
+
+                if other.left().is_black_color() &&
+                   other.right().is_black_color() {
+                    other.set_red_color();
+                    node = parent;
+                    parent = node.parent();
+                } else {
+                    if other.right().is_black_color() {
+                        other.left().set_black_color();
+                        other.set_red_color();
+                        self.right_rotate(other);
+                        other = parent.right();
+                    }
+                    other.set_color(parent.get_color());
+                    parent.set_black_color();
+                    other.right().set_black_color();
+                    self.left_rotate(parent);
+                    node = self.root;
+                    break;
+                }
+            } else {
+                other = parent.left();
+                if other.is_red_color() {
+                    other.set_black_color();
+                    parent.set_red_color();
+                    self.right_rotate(parent);
+                    other = parent.left();
+                }
+
+                if other.left().is_black_color() &&
+                   other.right().is_black_color() {
+                    other.set_red_color();
+                    node = parent;
+                    parent = node.parent();
+                } else {
+                    if other.left().is_black_color() {
+                        other.right().set_black_color();
+                        other.set_red_color();
+                        self.left_rotate(other);
+                        other = parent.left();
+                    }
+                    other.set_color(parent.get_color());
+                    parent.set_black_color();
+                    other.left().set_black_color();
+                    self.right_rotate(parent);
+                    node = self.root;
+                    break;
+                }
+            }
+        }
+        node.set_black_color();
+    }
+
+    /* Number of nodes on the longest path from the root down. */
+    #[cfg(feature = "unittest")]
+    pub fn height(&self) -> usize {
+        self.root.height()
+    }
+
     #[inline]
     unsafe fn left_rotate(&mut self, mut node: NodePtr<K, V>) {
         let mut temp = node.right();
@@ -299,6 +499,28 @@ impl<K: Ord, V> RBTree<K, V> {
         }
     }
 
+    /// Return the entry with the minimum key
+    #[inline]
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        let node = self.first_child();
+        if node.is_null() {
+            return None;
+        }
+
+        unsafe { Some((&(*node.0).key, &(*node.0).value)) }
+    }
+
+    /// Return the entry with the maximum key
+    #[inline]
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        let node = self.last_child();
+        if node.is_null() {
+            return None;
+        }
+
+        unsafe { Some((&(*node.0).key, &(*node.0).value)) }
+    }
+
     /// Return the key and value iter
     #[inline]
     pub fn iter(&self) -> Iter<K, V> {
@@ -313,6 +535,100 @@ impl<K: Ord, V> RBTree<K, V> {
     pub fn iter_mut(&mut self) -> IterMut<K, V> {
         IterMut::new(self.first_child())
     }
+
+    /// Return the key and value iter in descending order of keys
+    #[inline]
+    pub fn iter_rev(&self) -> RevIter<K, V> {
+        RevIter::new(self.last_child())
+    }
+
+    // range(start, end) : Iterates in ascending order over the elements (E)
+    // in the tree such that start <= E.key < end
+    pub fn range(&self, start: &K, end: &K) -> Range<K, V> {
+        if start >= end {
+            return Range::new(NodePtr::null(), NodePtr::null());
+        }
+        Range::new(self.lower_bound(start).cursor,
+                   self.lower_bound(end).cursor)
+    }
+
+    // rev_from(key) : Iterates in descending order from the element (E)
+    // in the tree such that E.key <= key
+    pub fn rev_from(&self, k: &K) -> RevIter<K, V> {
+        let mut found = NodePtr::null();
+        let mut temp = self.root;
+        while !temp.is_null() {
+            unsafe {
+                if k.cmp(&(*temp.0).key) == Ordering::Less {
+                    temp = temp.left();
+                } else {
+                    /* This node is now our candidate for our found node. */
+                    found = temp;
+                    temp = temp.right();
+                }
+            }
+        }
+        RevIter::new(found)
+    }
+}
+
+impl<K: Ord, V> Drop for RBTree<K, V> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<K: Ord, V> FromIterator<(K, V)> for RBTree<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut tree = RBTree::new();
+        for (k, v) in iter {
+            tree.insert(k, v);
+        }
+        tree
+    }
+}
+
+impl<K: Ord, V> IntoIterator for RBTree<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    /* The nodes are handed over to the iterator, which frees them. */
+    fn into_iter(mut self) -> IntoIter<K, V> {
+        let iter = IntoIter {
+            head: self.first_child(),
+            len: self.len,
+        };
+        self.root = NodePtr::null();
+        self.len = 0;
+        iter
+    }
+}
+
+#[cfg(feature = "unittest")]
+impl<K: Ord + Debug, V> RBTree<K, V> {
+    /* Check the red black properties, panicking on the first one broken:
+     * the root is black, no red node has a red child and every path from
+     * the root down to a leaf has the same number of black nodes. */
+    pub fn assert_invariants(&self) {
+        assert!(self.root.is_black_color(), "rbtree: root is red");
+        self.root.black_height();
+    }
+}
+
+/* Dump the entries in order of keys as (key, color), which shows both
+ * the ordering and the coloring the balance comes from. */
+impl<K: Ord + Debug, V> Debug for RBTree<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        let mut node = self.first_child();
+        while !node.is_null() {
+            unsafe {
+                list.entry(&(&(*node.0).key, node.get_color()));
+            }
+            node = node.next();
+        }
+        list.finish()
+    }
 }
 
 /*****************RBTreeNode***************************/
@@ -483,6 +799,46 @@ impl<K: Ord, V> NodePtr<K, V> {
         return temp;
     }
 
+    #[inline]
+    fn max_node(self) -> NodePtr<K, V> {
+        let mut temp = self.clone();
+        while !temp.right().is_null() {
+            temp = temp.right();
+        }
+        return temp;
+    }
+
+    /* Black height of the subtree, counting the null leaves. */
+    #[cfg(feature = "unittest")]
+    fn black_height(&self) -> usize where K: Debug {
+        if self.is_null() {
+            return 1;
+        }
+
+        let key = unsafe { &(*self.0).key };
+        if self.is_red_color() {
+            assert!(self.left().is_black_color() &&
+                    self.right().is_black_color(),
+                    "rbtree: red node {:?} has a red child", key);
+        }
+
+        let left = self.left().black_height();
+        let right = self.right().black_height();
+        assert!(left == right,
+                "rbtree: black heights {} and {} differ below {:?}",
+                left, right, key);
+
+        left + self.is_black_color() as usize
+    }
+
+    #[cfg(feature = "unittest")]
+    fn height(&self) -> usize {
+        if self.is_null() {
+            return 0;
+        }
+        1 + core::cmp::max(self.left().height(), self.right().height())
+    }
+
     #[inline]
     fn is_left_child(&self) -> bool {
         self.parent().left() == *self
@@ -505,6 +861,24 @@ impl<K: Ord, V> NodePtr<K, V> {
             }
         }
     }
+
+    #[inline]
+    fn prev(self) -> NodePtr<K, V> {
+        if !self.left().is_null() {
+            self.left().max_node()
+        } else {
+            let mut temp = self;
+            loop {
+                if temp.parent().is_null() {
+                    return NodePtr::null();
+                }
+                if !temp.is_left_child() {
+                    return temp.parent();
+                }
+                temp = temp.parent();
+            }
+        }
+    }
 }
 
 /// provide iter ref for RBTree
@@ -584,4 +958,164 @@ impl<'a, K: Ord + 'a, V: 'a> Iterator for IterMut<'a, K, V> {
         self.cursor = self.cursor.next();
         Some((k, v))
     }
-}
\ No newline at end of file
+}
+
+/// provide a cursor at an element of RBTree, which moves in either
+/// direction. Moving past either end leaves it at no element, where it
+/// stays; so does a bound which is found by no element.
+pub struct Cursor<'a, K: Ord, V> {
+    cursor: NodePtr<K, V>,
+    _marker: marker::PhantomData<&'a ()>,
+}
+
+impl<'a, K: Ord + 'a, V: 'a> Cursor<'a, K, V> {
+    fn new(ptr: NodePtr<K, V>) -> Self {
+        Cursor {
+            cursor: ptr,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.cursor.is_null()
+    }
+
+    pub fn key(&self) -> Option<&'a K> {
+        if self.cursor.is_null() {
+            return None;
+        }
+        unsafe { Some(&(*self.cursor.0).key) }
+    }
+
+    pub fn value(&self) -> Option<&'a V> {
+        if self.cursor.is_null() {
+            return None;
+        }
+        unsafe { Some(&(*self.cursor.0).value) }
+    }
+
+    pub fn move_next(&mut self) {
+        if !self.cursor.is_null() {
+            self.cursor = self.cursor.next();
+        }
+    }
+
+    pub fn move_prev(&mut self) {
+        if !self.cursor.is_null() {
+            self.cursor = self.cursor.prev();
+        }
+    }
+}
+
+/// provide range iter ref for RBTree
+pub struct Range<'a, K: Ord, V> {
+    cursor: NodePtr<K, V>,
+    /* The first node past the range, or null if there's none */
+    end: NodePtr<K, V>,
+    _marker: marker::PhantomData<&'a ()>,
+}
+
+impl<'a, K: Ord, V> Range<'a, K, V> {
+    fn new(cursor: NodePtr<K, V>, end: NodePtr<K, V>) -> Self {
+        Range {
+            cursor,
+            end,
+            _marker: marker::PhantomData,
+        }
+    }
+}
+
+impl<'a, K: Ord + 'a, V: 'a> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.is_null() || self.cursor == self.end {
+            return None;
+        }
+
+        let (k, v) = unsafe {
+            (&(*self.cursor.0).key, &(*self.cursor.0).value)
+        };
+
+        self.cursor = self.cursor.next();
+        Some((k, v))
+    }
+}
+
+/// provide reverse iter ref for RBTree
+pub struct RevIter<'a, K: Ord, V> {
+    cursor: NodePtr<K, V>,
+    _marker: marker::PhantomData<&'a ()>,
+}
+
+impl<'a, K: Ord, V> RevIter<'a, K, V> {
+    fn new(ptr: NodePtr<K, V>) -> Self {
+        RevIter {
+            cursor: ptr,
+            _marker: marker::PhantomData,
+        }
+    }
+}
+
+impl<'a, K: Ord + 'a, V: 'a> Iterator for RevIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.is_null() {
+            return None;
+        }
+
+        let (k, v) = unsafe {
+            (&(*self.cursor.0).key, &(*self.cursor.0).value)
+        };
+
+        self.cursor = self.cursor.prev();
+        Some((k, v))
+    }
+}
+
+/// provide owning iter for RBTree, in ascending order of keys
+pub struct IntoIter<K: Ord, V> {
+    /* The node with the minimum key of the ones left */
+    head: NodePtr<K, V>,
+    len: usize,
+}
+
+impl<K: Ord, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        /* The minimum has no left child, and it's either the root or
+         * the left child of its parent. Its right subtree takes its
+         * place, so that holds for the next minimum as well. The tree
+         * is never searched again, so there's no need to rebalance. */
+        let node = self.head;
+        let mut right = node.right();
+        let mut parent = node.parent();
+        right.set_parent(parent);
+        parent.set_left(right);
+        self.head = if right.is_null() {
+            parent
+        } else {
+            right.min_node()
+        };
+        self.len -= 1;
+
+        let node = unsafe { Box::from_raw(node.0) };
+        Some((node.key, node.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<K: Ord, V> Drop for IntoIter<K, V> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
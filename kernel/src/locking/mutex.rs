@@ -9,6 +9,7 @@
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::cell::UnsafeCell;
+use core::mem;
 use core::ops::{Deref, DerefMut};
 use crate::thread::{ThreadPtr, thread_get_current};
 
@@ -44,6 +45,11 @@ impl<T> Mutex<T> {
         MutexGuard::new(self)
     }
 
+    #[allow(dead_code)]
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != 0
+    }
+
     /* Optimistic trylock that only works in the uncontended case.
      * Make sure to follow with a trylock before failing */
     fn try_lock_fast(&self) -> bool {
@@ -74,27 +80,46 @@ impl<'mutex, T: ?Sized> MutexGuard<'mutex, T> {
         }
     }
 
-    fn unlock(&self) {
-        if self.unlock_fast() {
-            return;
+    /* Make a new guard for a part of the locked data, e.g. one field
+     * or one element. The lock is held until the new guard is dropped. */
+    #[allow(dead_code)]
+    pub fn map<U: ?Sized, F>(guard: Self, f: F) -> MappedMutexGuard<'mutex, U>
+    where F: FnOnce(&mut T) -> &mut U {
+        let owner = &guard.lock.owner;
+        let data = f(unsafe { &mut *guard.lock.data.get() }) as *mut U;
+        /* The mapped guard takes over the unlocking */
+        mem::forget(guard);
+        MappedMutexGuard {
+            owner,
+            data,
         }
-        todo!("__mutex_unlock_slowpath(lock, _RET_IP_)");
     }
 
-    fn unlock_fast(&self) -> bool {
-        let ret =
-            self.lock.owner.compare_exchange(thread_get_current(), 0,
-                                     Ordering::Release,
-                                     Ordering::Relaxed);
-        match ret {
-            Ok(_) => true,
-            Err(val) => {
-                if val == 0 {
-                    panic!("Mutex already unlocked! current 0x{:x}",
-                           thread_get_current());
-                }
-                false
+    fn unlock(&self) {
+        mutex_unlock(&self.lock.owner);
+    }
+}
+
+fn mutex_unlock(owner: &AtomicUsize) {
+    if mutex_unlock_fast(owner) {
+        return;
+    }
+    todo!("__mutex_unlock_slowpath(lock, _RET_IP_)");
+}
+
+fn mutex_unlock_fast(owner: &AtomicUsize) -> bool {
+    let ret =
+        owner.compare_exchange(thread_get_current(), 0,
+                               Ordering::Release,
+                               Ordering::Relaxed);
+    match ret {
+        Ok(_) => true,
+        Err(val) => {
+            if val == 0 {
+                panic!("Mutex already unlocked! current 0x{:x}",
+                       thread_get_current());
             }
+            false
         }
     }
 }
@@ -121,4 +146,35 @@ impl<T: ?Sized> Drop for MutexGuard<'_, T> {
 }
 
 impl<T: ?Sized> !Send for MutexGuard<'_, T> {}
-unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
\ No newline at end of file
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+/* A guard made by MutexGuard::map. It only knows about the projected
+ * part of the data, but still releases the whole mutex on drop. */
+pub struct MappedMutexGuard<'a, U: ?Sized + 'a> {
+    owner: &'a AtomicUsize,
+    data: *mut U,
+}
+
+impl<U: ?Sized> Deref for MappedMutexGuard<'_, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedMutexGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<U: ?Sized> Drop for MappedMutexGuard<'_, U> {
+    #[inline]
+    fn drop(&mut self) {
+        mutex_unlock(self.owner);
+    }
+}
+
+impl<U: ?Sized> !Send for MappedMutexGuard<'_, U> {}
+unsafe impl<U: ?Sized + Sync> Sync for MappedMutexGuard<'_, U> {}
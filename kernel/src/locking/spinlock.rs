@@ -6,18 +6,48 @@
  * at https://opensource.org/licenses/MIT
  */
 
-use core::sync::atomic::AtomicU32;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::arch::irq::{arch_local_irq_restore, arch_local_irq_save};
 
 pub const ARCH_SPIN_LOCK_UNLOCKED: u32 = 0;
+pub const ARCH_SPIN_LOCK_LOCKED: u32 = 1;
 
 pub struct RawSpinLock {
-    _lock: AtomicU32,
+    lock: AtomicU32,
 }
 
 impl RawSpinLock {
     pub const fn new() -> Self {
         Self {
-            _lock: AtomicU32::new(ARCH_SPIN_LOCK_UNLOCKED),
+            lock: AtomicU32::new(ARCH_SPIN_LOCK_UNLOCKED),
         }
     }
-}
\ No newline at end of file
+
+    pub fn lock(&self) {
+        while self.lock.compare_exchange_weak(ARCH_SPIN_LOCK_UNLOCKED,
+                                              ARCH_SPIN_LOCK_LOCKED,
+                                              Ordering::Acquire,
+                                              Ordering::Relaxed).is_err() {
+            spin_loop();
+        }
+    }
+
+    pub fn unlock(&self) {
+        self.lock.store(ARCH_SPIN_LOCK_UNLOCKED, Ordering::Release);
+    }
+
+    /* Disable the local interrupts before taking the lock, so that
+     * an interrupt handler on this CPU can't spin on it forever.
+     * The returned flags are for unlock_irqrestore. */
+    pub fn lock_irqsave(&self) -> usize {
+        let flags = arch_local_irq_save();
+        self.lock();
+        flags
+    }
+
+    pub fn unlock_irqrestore(&self, flags: usize) {
+        self.unlock();
+        arch_local_irq_restore(flags);
+    }
+}
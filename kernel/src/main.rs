@@ -31,10 +31,11 @@ use crate::errors::ErrNO;
 use crate::defines::*;
 use crate::mp::mp_init;
 use crate::platform::platform_early_init;
-use crate::aspace::vm_init_preheap;
+use crate::aspace::{vm_init_preheap, VmAspace, ASPACE_LIST, KERNEL_ASPACE_ID};
 use crate::klib::list::List;
+use crate::locking::mutex::{MappedMutexGuard, MutexGuard};
 use crate::allocator::heap_init;
-use crate::thread::{thread_init_early, Thread};
+use crate::thread::{thread_init, thread_init_early, Thread};
 use crate::vm::vm::vm_init;
 
 global_asm!(include_str!("arch/riscv64/start.S"));
@@ -77,6 +78,8 @@ mod init;
 mod locking;
 mod percpu;
 mod sched;
+mod wait_queue;
+mod dpc;
 mod cpu;
 
 pub struct BootContext {
@@ -140,6 +143,26 @@ impl BootContext {
         panic!("NOT init stdout yet!");
     }
 
+    /* The aspace is handed out along with ASPACE_LIST, which is held
+     * until the guard is dropped. NOTE: The caller mustn't hold
+     * ASPACE_LIST already, nor allocate from the heap or VirtualAlloc
+     * while holding the guard, as they take ASPACE_LIST too. */
+    fn get_aspace_by_id(&self, id: usize)
+        -> Option<MappedMutexGuard<'static, VmAspace>> {
+        let aspace_list = ASPACE_LIST.lock();
+        let aspace = aspace_list.find(|a| a.id() == id);
+        if aspace.is_null() {
+            return None;
+        }
+        Some(MutexGuard::map(aspace_list, |_| unsafe { &mut (*aspace) }))
+    }
+
+    fn kernel_aspace(&self) -> MappedMutexGuard<'static, VmAspace> {
+        if let Some(ret) = self.get_aspace_by_id(KERNEL_ASPACE_ID) {
+            return ret;
+        }
+        panic!("NOT init kernel aspace yet!");
+    }
 }
 
 pub struct WrapBootContext {
@@ -191,6 +214,21 @@ impl WrapBootContext {
             (*self.data.get()).stdout()
         }
     }
+
+    #[allow(dead_code)]
+    fn get_aspace_by_id(&self, id: usize)
+        -> Option<MappedMutexGuard<'static, VmAspace>> {
+        unsafe {
+            (*self.data.get()).get_aspace_by_id(id)
+        }
+    }
+
+    #[allow(dead_code)]
+    fn kernel_aspace(&self) -> MappedMutexGuard<'static, VmAspace> {
+        unsafe {
+            (*self.data.get()).kernel_aspace()
+        }
+    }
 }
 
 pub static BOOT_CONTEXT: WrapBootContext = WrapBootContext::new();
@@ -293,12 +331,19 @@ fn _lk_main() -> Result<(), ErrNO> {
     kernel_init()?;
     // lk_primary_cpu_init_level(LK_INIT_LEVEL_KERNEL, LK_INIT_LEVEL_THREADING - 1);
 
+    // start the threads of the boot cpu, e.g. the dpc thread
+    dprintf!(SPEW, "initializing threading\n");
+    thread_init()?;
+    // lk_primary_cpu_init_level(LK_INIT_LEVEL_THREADING, ...);
+
     // create a thread to complete system initialization
     dprintf!(SPEW, "creating bootstrap completion thread\n");
     let thread = Thread::create("bootstrap2", bootstrap2, None,
                                 Thread::DEFAULT_PRIORITY)?;
-    thread.detach();
-    thread.resume();
+    unsafe {
+        (*thread).detach();
+        (*thread).resume();
+    }
 
     println!("lk_main ok!");
 
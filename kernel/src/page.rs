@@ -128,6 +128,12 @@ impl vm_page_object {
         self.object_or_stack_owner.store(obj, Ordering::Relaxed);
     }
 
+    /* Detach the page from its vm object, e.g. when it's freed. */
+    pub fn clear_object(&mut self) {
+        ZX_ASSERT!(!self.is_stack_owned());
+        self.object_or_stack_owner.store(0, Ordering::Relaxed);
+    }
+
     #[allow(dead_code)]
     fn clear_stack_owner(&self) {
         self.clear_stack_owner_internal(0);
@@ -185,7 +191,6 @@ impl vm_page_object {
         self.pin_count = pin_count;
     }
 
-    #[allow(dead_code)]
     pub fn cow_left_split(&self) -> bool {
         (self.pin_count & Self::VM_PAGE_OBJECT_COW_LEFT_SPLIT) != 0
     }
@@ -198,7 +203,6 @@ impl vm_page_object {
         }
     }
 
-    #[allow(dead_code)]
     pub fn cow_right_split(&self) -> bool {
         (self.pin_count & Self::VM_PAGE_OBJECT_COW_RIGHT_SPLIT) != 0
     }
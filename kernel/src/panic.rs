@@ -7,13 +7,42 @@
  */
 
 use core::panic::PanicInfo;
+#[cfg(feature = "unittest")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::arch::sbi::machine_power_off;
+#[cfg(feature = "unittest")]
+use crate::arch::thread::{ArchThreadState, arch_context_switch};
 use crate::println;
+#[cfg(feature = "unittest")]
+use crate::thread::Thread;
+
+/* The context to switch back into on the next panic, armed by a test
+ * which expects the panic. 0 if none is armed. */
+#[cfg(feature = "unittest")]
+static PANIC_CATCHER: AtomicUsize = AtomicUsize::new(0);
+
+/* Arm the next panic to switch into |catcher| rather than powering off.
+ * The panicking thread is abandoned there, so it must be one the test
+ * started on its own stack. */
+#[cfg(feature = "unittest")]
+pub fn expect_panic(catcher: *const ArchThreadState) {
+    PANIC_CATCHER.store(catcher as usize, Ordering::Relaxed);
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
 
+    #[cfg(feature = "unittest")]
+    {
+        let catcher = PANIC_CATCHER.swap(0, Ordering::Relaxed);
+        if catcher != 0 {
+            let current = Thread::current();
+            arch_context_switch(&mut current.arch,
+                                catcher as *const ArchThreadState);
+        }
+    }
+
     /* Power off on panic */
     machine_power_off();
     loop {}
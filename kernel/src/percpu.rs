@@ -10,6 +10,7 @@ use core::ptr::null_mut;
 
 use crate::ZX_ASSERT;
 use crate::config_generated::_CONFIG_NR_CPUS;
+use crate::dpc::DpcQueue;
 use crate::locking::mutex::Mutex;
 use crate::thread::{Thread, thread_construct_first};
 use crate::sched::Scheduler;
@@ -19,12 +20,15 @@ pub const BOOT_CPU_ID: usize = 0;
 pub struct PerCPU {
     idle_thread: Thread,
     scheduler: Scheduler,
+    dpc_queue: DpcQueue,
 }
 
 impl PerCPU {
     pub fn init(&mut self) {
         self.scheduler = Scheduler::new();
         self.idle_thread = Thread::new();
+        self.dpc_queue = DpcQueue::new();
+        self.dpc_queue.init();
     }
 
     pub fn idle_thread_ptr(&mut self) -> *mut Thread {
@@ -44,6 +48,10 @@ impl PerCPU {
     pub fn scheduler(&mut self) -> &mut Scheduler {
         &mut self.scheduler
     }
+
+    pub fn dpc_queue(&mut self) -> &mut DpcQueue {
+        &mut self.dpc_queue
+    }
 }
 
 type PerCPUPtr = *mut PerCPU;
@@ -68,6 +76,18 @@ impl PerCPUArray {
     pub fn set(&mut self, index: usize, percpu_ptr: PerCPUPtr) {
         self.data[index] = percpu_ptr;
     }
+
+    /* Set the slot and hand back what it held before */
+    #[allow(dead_code)]
+    pub fn replace(&mut self, index: usize, percpu_ptr: PerCPUPtr)
+        -> PerCPUPtr {
+        core::mem::replace(&mut self.data[index], percpu_ptr)
+    }
+
+    /* Iterate over the cpus which have been set up */
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut PerCPU> {
+        self.data.iter().filter(|p| !p.is_null()).map(|p| unsafe { &mut (**p) })
+    }
 }
 
 pub static mut PERCPU_ARRAY: Mutex<PerCPUArray> =
@@ -23,6 +23,8 @@ use crate::{ROUNDUP_PAGE_SIZE, ROUNDUP};
 use crate::List;
 use crate::pmm::pmm_alloc_range;
 use crate::vm_page_state;
+use crate::arch::timer::riscv_timer_set_frequency;
+use crate::arch::topology::topology_set_cpu_capacity;
 
 pub mod boot_reserve;
 pub mod periphmap;
@@ -226,7 +228,7 @@ fn early_init_dt_load(dtb_va: usize) -> Result<DeviceTree, ErrNO> {
     unsafe {
         let buf = slice::from_raw_parts_mut(dtb_va as *mut u8,
                                             totalsize as usize);
-        DeviceTree::load(buf).or_else(|e| {
+        DeviceTree::load_unaligned(buf).or_else(|e| {
             dprintf!(CRITICAL, "Can't load dtb: {:?}\n", e);
             Err(ErrNO::BadDTB)
         })
@@ -263,6 +265,9 @@ fn early_init_dt_scan(dt: &DeviceTree) -> Result<ZBIMemRangeVec, ErrNO> {
     let cmdline = early_init_dt_scan_chosen(dt);
     dprintf!(INFO, "command line = {}\n", cmdline);
 
+    /* Retrieve the frequency of timebase for timer */
+    early_init_dt_scan_cpus(dt)?;
+
     /* Setup memory, calling early_init_dt_add_memory_arch */
     early_init_dt_scan_memory(dt, addr_cells, size_cells)
 }
@@ -333,31 +338,47 @@ fn early_init_dt_scan_chosen(dt: &DeviceTree) -> &str {
     ""
 }
 
+/*
+ * early_init_dt_scan_cpus - fetch the timebase frequency of cpus
+ * and their capacities
+ */
+fn early_init_dt_scan_cpus(dt: &DeviceTree) -> Result<(), ErrNO> {
+    let freq = dt.timebase_frequency().or_else(|e| {
+        dprintf!(CRITICAL, "No valid timebase-frequency in dtb: {:?}\n", e);
+        Err(ErrNO::BadDTB)
+    })?;
+    dprintf!(INFO, "timebase-frequency = {}\n", freq);
+
+    riscv_timer_set_frequency(freq);
+
+    /* Relative capacities of heterogeneous cores, if any */
+    if let Some(cpus) = dt.find("/cpus") {
+        for cpu in cpus.children.iter() {
+            if let (Ok(hartid), Ok(capacity)) =
+                (cpu.prop_u32("reg"), cpu.prop_u32("capacity-dmips-mhz")) {
+                dprintf!(INFO, "cpu {} capacity {}\n", hartid, capacity);
+                topology_set_cpu_capacity(hartid as usize, capacity);
+            }
+        }
+    }
+    Ok(())
+}
+
 /*
  * early_init_dt_scan_memory - Look for and parse memory nodes
  */
 fn early_init_dt_scan_memory(dt: &DeviceTree, addr_cells: u32, size_cells: u32)
     -> Result<ZBIMemRangeVec, ErrNO> {
 
-    let root = dt.find("/").ok_or_else(|| ErrNO::BadDTB)?;
-
     let mut mem_config = Vec::<ZBIMemRange>::with_capacity(MAX_ZBI_MEM_RANGES);
 
-    let mut cb = |base, size| {
-        add_memory_arch(&mut mem_config, base, size);
-    };
-
-    for child in &root.children {
-        /* We are scanning "memory" nodes only */
-        if let Ok(t) = child.prop_str("device_type") {
-            if t != "memory" {
-                continue;
-            }
-        } else {
-            continue;
-        }
-
-        parse_reg(child, addr_cells, size_cells, &mut cb);
+    let regions = dt.memory_regions().map_err(|e| {
+        dprintf!(CRITICAL, "Bad memory nodes in dtb: {:?}\n", e);
+        ErrNO::BadDTB
+    })?;
+    for (base, size) in regions {
+        dprintf!(INFO, " - 0x{:x}, 0x{:x}\n", base, size);
+        add_memory_arch(&mut mem_config, base as usize, size as usize);
     }
 
     early_scan_reserved_mem(dt, &mut mem_config, addr_cells, size_cells)?;
@@ -368,22 +389,25 @@ fn parse_reg<F>(node: &Node, addr_cells: u32, size_cells: u32, mut cb: F)
 where
     F: FnMut(usize, usize)
 {
-    let mut pos = 0;
-    let reg_len = node.prop_len("reg");
-    while pos < reg_len {
-        let base = if addr_cells == 2 {
-            node.prop_u64_at("reg", pos).unwrap() as usize
-        } else {
-            node.prop_u32_at("reg", pos).unwrap() as usize
-        };
-        pos += (addr_cells << 2) as usize;
-
-        let size = if size_cells == 2 {
-            node.prop_u64_at("reg", pos).unwrap() as usize
-        } else {
-            node.prop_u32_at("reg", pos).unwrap() as usize
+    /* Entries without any cells would never consume the property */
+    if addr_cells + size_cells == 0 {
+        return;
+    }
+    let mut cells = match node.cells("reg", 1) {
+        Ok(cells) => cells,
+        Err(_) => return,
+    };
+    while !cells.is_empty() {
+        let base = cells.read(addr_cells as usize);
+        let size = cells.read(size_cells as usize);
+        let (base, size) = match (base, size) {
+            (Some(base), Some(size)) => (base as usize, size as usize),
+            _ => {
+                dprintf!(WARN, "{}: bad reg with cells ({}, {})\n",
+                         node.name, addr_cells, size_cells);
+                break;
+            },
         };
-        pos += (size_cells << 2) as usize;
 
         if size == 0 {
             continue;
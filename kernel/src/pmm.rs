@@ -20,6 +20,7 @@ use crate::{print, dprintf, ZX_ASSERT};
 use crate::{PAGE_SIZE, PAGE_SHIFT, paddr_to_physmap};
 use alloc::vec::Vec;
 use crate::types::*;
+use crate::arch::mmu::arch_zero_page;
 use crate::klib::list::List;
 use crate::page::vm_page_t;
 use crate::vm_page_state::{self, vm_page_state_t};
@@ -33,7 +34,6 @@ use crate::platform::boot_reserve::{
 /* no restrictions on which arena to allocate from */
 pub const PMM_ALLOC_FLAG_ANY: u32 = 0 << 0;
 /* allocate only from arenas marked LO_MEM */
-#[allow(dead_code)]
 pub const PMM_ALLOC_FLAG_LO_MEM: u32 = 1 << 0;
 // The caller is able to wait and retry this allocation and so pmm allocation functions are allowed
 // to return ZX_ERR_SHOULD_WAIT, as opposed to ZX_ERR_NO_MEMORY, to indicate that the caller should
@@ -48,10 +48,81 @@ pub const PMM_ALLOC_FLAG_CAN_BORROW: u32 = 1 << 2;
 // Require a loaned page, and fail to allocate if a loaned page isn't available.
 #[allow(dead_code)]
 pub const PMM_ALLOC_FLAG_MUST_BORROW: u32 = 1 << 3;
+// The content of the allocated pages is zeroed before they are returned, so that callers don't
+// need to zero them again. This leaves room for the PMM to pre-zero free pages in the background.
+pub const PMM_ALLOC_FLAG_ZERO: u32 = 1 << 4;
+
+// The preferred numa node can be encoded into the flags by pmm_alloc_flag_node(). The pmm falls
+// back to any node if the preferred one has no memory. Zero means no preference.
+const PMM_ALLOC_NODE_SHIFT: u32 = 8;
+const PMM_ALLOC_NODE_MASK: u32 = 0xff << PMM_ALLOC_NODE_SHIFT;
+/* no preference of numa node */
+pub const PMM_NODE_ANY: usize = usize::MAX;
+
+pub fn pmm_alloc_flag_node(node: usize) -> u32 {
+    if node == PMM_NODE_ANY {
+        return 0;
+    }
+    ZX_ASSERT!(node < (PMM_ALLOC_NODE_MASK >> PMM_ALLOC_NODE_SHIFT) as usize);
+    ((node + 1) as u32) << PMM_ALLOC_NODE_SHIFT
+}
+
+pub fn pmm_alloc_flags_to_node(flags: u32) -> usize {
+    match (flags & PMM_ALLOC_NODE_MASK) >> PMM_ALLOC_NODE_SHIFT {
+        0 => PMM_NODE_ANY,
+        n => (n - 1) as usize,
+    }
+}
+
+// The preferred arena can be encoded into the flags by pmm_alloc_flag_arena(), as an index in
+// the arenas sorted by base address. It's a hint for locality: the arena policy is followed
+// instead once the preferred arena has no free page. Zero means no preference.
+const PMM_ALLOC_ARENA_SHIFT: u32 = 16;
+const PMM_ALLOC_ARENA_MASK: u32 = 0xff << PMM_ALLOC_ARENA_SHIFT;
+/* no preference of arena */
+pub const PMM_ARENA_ANY: usize = usize::MAX;
+
+#[allow(dead_code)]
+pub fn pmm_alloc_flag_arena(arena: usize) -> u32 {
+    if arena == PMM_ARENA_ANY {
+        return 0;
+    }
+    ZX_ASSERT!(arena < MAX_ARENAS);
+    ((arena + 1) as u32) << PMM_ALLOC_ARENA_SHIFT
+}
+
+pub fn pmm_alloc_flags_to_arena(flags: u32) -> usize {
+    match (flags & PMM_ALLOC_ARENA_MASK) >> PMM_ALLOC_ARENA_SHIFT {
+        0 => PMM_ARENA_ANY,
+        n => (n - 1) as usize,
+    }
+}
 
 /* all of the configured memory arenas */
 pub const MAX_ARENAS: usize = 16;
 
+/* flags of an arena in ArenaInfo */
+
+/* the arena is low memory, which PMM_ALLOC_FLAG_LO_MEM allocates from */
+pub const PMM_ARENA_FLAG_LO_MEM: u32 = 1 << 0;
+
+/* Which arena the free pages are handed out from */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArenaPolicy {
+    /* The head of the free list, which drains the arenas one after
+     * another in the order they were added */
+    FreeListOrder,
+    /* The arenas take turns to hand out a page each, which spreads
+     * the allocations across them */
+    RoundRobin,
+}
+
+/* Called with the free count once an allocation runs out of pages,
+ * so that a monitor can react to low memory. Unlike CAN_WAIT, it never
+ * makes the allocation wait or retry. */
+pub type LowMemCallback = fn(usize);
+
+#[derive(Clone)]
 pub struct ArenaInfo {
     pub name: String,
     pub flags: u32,
@@ -117,9 +188,207 @@ impl PageArray {
     }
 }
 
+/* Runs of free pages in an arena, as (first page index, page count).
+ * Runs are sorted by index, never empty and never adjacent to each other,
+ * so a search for contiguous pages only visits the runs rather than
+ * every page of the arena. (They are kept in a sorted array and
+ * searched by binary search.)
+ * The array is carved out of the arena together with the page array,
+ * so that updating the runs under the pmm locks never allocates.
+ * As free runs aren't adjacent, an arena of n pages has at most
+ * (n + 1) / 2 of them. */
+pub struct FreeRuns {
+    runs: *mut (usize, usize),
+    len: usize,
+    capacity: usize,
+}
+
+unsafe impl Send for FreeRuns {}
+
+impl FreeRuns {
+    pub const fn new() -> Self {
+        Self {
+            runs: null_mut(),
+            len: 0,
+            capacity: 0,
+        }
+    }
+
+    /* Room for the runs of an arena of |page_count| pages. */
+    pub const fn size_for(page_count: usize) -> usize {
+        (page_count + 1) / 2 * mem::size_of::<(usize, usize)>()
+    }
+
+    /* Back the runs with |capacity| entries at |buf|. */
+    pub fn init(&mut self, buf: *mut (usize, usize), capacity: usize) {
+        ZX_ASSERT!(self.len == 0);
+        self.runs = buf;
+        self.capacity = capacity;
+    }
+
+    fn runs(&self) -> &[(usize, usize)] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.runs, self.len) }
+    }
+
+    fn runs_mut(&mut self) -> &mut [(usize, usize)] {
+        if self.len == 0 {
+            return &mut [];
+        }
+        unsafe { core::slice::from_raw_parts_mut(self.runs, self.len) }
+    }
+
+    fn insert_at(&mut self, pos: usize, run: (usize, usize)) {
+        ZX_ASSERT!(pos <= self.len && self.len < self.capacity);
+        unsafe {
+            let p = self.runs.add(pos);
+            core::ptr::copy(p, p.add(1), self.len - pos);
+            p.write(run);
+        }
+        self.len += 1;
+    }
+
+    fn remove_at(&mut self, pos: usize) -> (usize, usize) {
+        ZX_ASSERT!(pos < self.len);
+        self.len -= 1;
+        unsafe {
+            let p = self.runs.add(pos);
+            let run = p.read();
+            core::ptr::copy(p.add(1), p, self.len - pos);
+            run
+        }
+    }
+
+    /* Position of the run which would contain index, if any. */
+    fn run_before(&self, index: usize) -> Option<usize> {
+        let pos = self.runs().partition_point(|&(start, _)| start <= index);
+        if pos == 0 {
+            None
+        } else {
+            Some(pos - 1)
+        }
+    }
+
+    /* Mark [index, index + count) as free. */
+    pub fn insert(&mut self, index: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+
+        let pos = self.runs().partition_point(|&(start, _)| start < index);
+        if pos > 0 {
+            let (start, len) = self.runs()[pos - 1];
+            ZX_ASSERT!(start + len <= index);
+        }
+        if pos < self.len {
+            ZX_ASSERT!(index + count <= self.runs()[pos].0);
+        }
+
+        /* Coalesce with the neighbours */
+        let merge_left = pos > 0 && {
+            let (start, len) = self.runs()[pos - 1];
+            start + len == index
+        };
+        let merge_right = pos < self.len &&
+            self.runs()[pos].0 == index + count;
+
+        match (merge_left, merge_right) {
+            (true, true) => {
+                let (_, len) = self.remove_at(pos);
+                self.runs_mut()[pos - 1].1 += count + len;
+            },
+            (true, false) => {
+                self.runs_mut()[pos - 1].1 += count;
+            },
+            (false, true) => {
+                let runs = self.runs_mut();
+                runs[pos].0 = index;
+                runs[pos].1 += count;
+            },
+            (false, false) => {
+                self.insert_at(pos, (index, count));
+            },
+        }
+    }
+
+    /* Mark [index, index + count) as allocated.
+     * The range must be inside a single free run. */
+    pub fn remove(&mut self, index: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+
+        let pos = self.run_before(index);
+        ZX_ASSERT!(pos.is_some());
+        let pos = pos.unwrap();
+        let (start, len) = self.runs()[pos];
+        ZX_ASSERT!(index + count <= start + len);
+
+        let left = index - start;
+        let right = start + len - (index + count);
+        match (left, right) {
+            (0, 0) => {
+                self.remove_at(pos);
+            },
+            (0, _) => {
+                self.runs_mut()[pos] = (index + count, right);
+            },
+            (_, 0) => {
+                self.runs_mut()[pos].1 = left;
+            },
+            (_, _) => {
+                self.runs_mut()[pos].1 = left;
+                self.insert_at(pos + 1, (index + count, right));
+            },
+        }
+    }
+
+    /* Find the first index of |count| free pages whose physical address
+     * (relative to |base|) is aligned to 2^alignment_log2. */
+    pub fn find(&self, base: paddr_t, count: usize, alignment_log2: usize)
+        -> Option<usize> {
+        let align = 1usize << alignment_log2;
+        for &(start, len) in self.runs().iter() {
+            if len < count {
+                continue;
+            }
+            let pa = ROUNDUP!(base + start * PAGE_SIZE, align);
+            let index = (pa - base) / PAGE_SIZE;
+            if index + count <= start + len {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    /* Number of pages of the largest run. */
+    pub fn largest(&self) -> usize {
+        self.runs().iter().map(|&(_, len)| len).max().unwrap_or(0)
+    }
+
+    /* Index of the lowest free page. */
+    pub fn first(&self) -> Option<usize> {
+        self.runs().first().map(|&(start, _)| start)
+    }
+
+    /* Total number of free pages. */
+    pub fn count(&self) -> usize {
+        self.runs().iter().map(|&(_, len)| len).sum()
+    }
+
+    /* Whether the runs are sorted, non-empty and coalesced. */
+    pub fn is_consistent(&self) -> bool {
+        self.runs().iter().all(|&(_, len)| len > 0) &&
+        self.runs().windows(2).all(|w| w[0].0 + w[0].1 < w[1].0)
+    }
+}
+
 pub struct PmmArena {
     info: ArenaInfo,
     page_array: PageArray,
+    free_runs: FreeRuns,
 }
 
 impl PmmArena {
@@ -127,6 +396,7 @@ impl PmmArena {
         PmmArena {
             info,
             page_array: PageArray::new(),
+            free_runs: FreeRuns::new(),
         }
     }
 
@@ -134,7 +404,10 @@ impl PmmArena {
         /* allocate an array of pages to back this one */
         let page_count = self.info.size / PAGE_SIZE;
         let vm_page_sz = mem::size_of::<vm_page_t>();
-        let page_array_size = ROUNDUP_PAGE_SIZE!(page_count*vm_page_sz);
+        /* the free runs follow the pages in the same chunk */
+        let pages_size = page_count * vm_page_sz;
+        let runs_size = FreeRuns::size_for(page_count);
+        let page_array_size = ROUNDUP_PAGE_SIZE!(pages_size + runs_size);
 
         /* if the arena is too small to be useful, bail */
         if page_array_size >= self.info.size {
@@ -158,7 +431,9 @@ impl PmmArena {
         dprintf!(INFO, "page array chunk {:x} ~ {:x}\n", range.pa, range.len);
 
         let page_array_va = paddr_to_physmap(range.pa);
-        self.page_array.init(page_array_va, page_array_size);
+        self.page_array.init(page_array_va, pages_size);
+        self.free_runs.init((page_array_va + pages_size) as *mut (usize, usize),
+                            runs_size / mem::size_of::<(usize, usize)>());
 
         /* |page_count| pages in the state FREE */
         //vm_page::add_to_initial_count(vm_page_state::FREE, page_count);
@@ -200,6 +475,9 @@ impl PmmArena {
             i += 1;
         }
 
+        self.free_runs.insert(0, array_start_index);
+        self.free_runs.insert(array_end_index, page_count - array_end_index);
+
         pmm_node.add_free_pages(&mut list, page_count);
         dprintf!(INFO, "init page_array ok!\n");
         Ok(())
@@ -217,10 +495,34 @@ impl PmmArena {
         self.info.size
     }
 
+    pub fn flags(&self) -> u32 {
+        self.info.flags
+    }
+
+    fn is_lo_mem(&self) -> bool {
+        (self.flags() & PMM_ARENA_FLAG_LO_MEM) != 0
+    }
+
     fn address_in_arena(&self, pa: paddr_t) -> bool {
         pa >= self.base() && pa <= self.base() + self.size() - 1
     }
 
+    /* Number of pages of the largest physically contiguous free run. */
+    #[allow(dead_code)]
+    pub fn largest_free_run(&self) -> usize {
+        self.free_runs.largest()
+    }
+
+    #[allow(dead_code)]
+    pub fn free_runs(&self) -> &FreeRuns {
+        &self.free_runs
+    }
+
+    fn page_index(&self, pa: paddr_t) -> usize {
+        ZX_ASSERT!(self.address_in_arena(pa));
+        (pa - self.base()) / PAGE_SIZE
+    }
+
     fn find_specific(&self, pa: paddr_t) -> *mut vm_page_t {
         if !self.address_in_arena(pa) {
             return null_mut();
@@ -235,6 +537,12 @@ impl PmmArena {
 struct FreePageList {
     count: usize,
     list: List<vm_page_t>,
+    policy: ArenaPolicy,
+    /* The arena to hand out the next page under RoundRobin */
+    next_arena: usize,
+    low_mem_callback: Option<LowMemCallback>,
+    /* CAN_WAIT allocations are told to wait below this free count */
+    should_wait_watermark: usize,
 }
 
 impl FreePageList {
@@ -242,6 +550,10 @@ impl FreePageList {
         Self {
             count: 0,
             list : List::<vm_page_t>::new(),
+            policy: ArenaPolicy::FreeListOrder,
+            next_arena: 0,
+            low_mem_callback: None,
+            should_wait_watermark: 0,
         }
     }
 
@@ -279,6 +591,68 @@ impl PmmNode {
         &self.page_queues
     }
 
+    #[allow(dead_code)]
+    pub fn set_arena_policy(&self, policy: ArenaPolicy) {
+        let mut free_list = self.free_list.lock();
+        free_list.policy = policy;
+        free_list.next_arena = 0;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_low_mem_callback(&self, callback: Option<LowMemCallback>) {
+        self.free_list.lock().low_mem_callback = callback;
+    }
+
+    /* Zero, the default, never tells CAN_WAIT allocations to wait
+     * until the pages have run out. */
+    #[allow(dead_code)]
+    pub fn set_should_wait_watermark(&self, pages: usize) {
+        self.free_list.lock().should_wait_watermark = pages;
+    }
+
+    /* Whether a CAN_WAIT allocation should wait rather than go on. */
+    fn should_wait(&self, alloc_flags: u32) -> bool {
+        if (alloc_flags & PMM_ALLOC_FLAG_CAN_WAIT) == 0 {
+            return false;
+        }
+        let free_list = self.free_list.lock();
+        free_list.count < free_list.should_wait_watermark
+    }
+
+    /* The error for running out of pages, which a caller able to wait
+     * is told to wait for. */
+    fn out_of_pages_error(alloc_flags: u32) -> ErrNO {
+        if (alloc_flags & PMM_ALLOC_FLAG_CAN_WAIT) != 0 {
+            ErrNO::ShouldWait
+        } else {
+            ErrNO::NoMem
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn free_count(&self) -> usize {
+        self.free_list.lock().count
+    }
+
+    /* Size of all the arenas, including the pages backing the page arrays */
+    #[allow(dead_code)]
+    pub fn total_bytes(&self) -> usize {
+        self.arena_cumulative_size.load(Ordering::Relaxed)
+    }
+
+    /* Report that the free list ran out. The callback is called after
+     * the lock is dropped, so it may look into the pmm itself. */
+    fn report_out_of_pages(free_list: MutexGuard<FreePageList>) {
+        let count = free_list.count;
+        let callback = free_list.low_mem_callback;
+        drop(free_list);
+
+        dprintf!(CRITICAL, "PMM: out of pages, free count {}\n", count);
+        if let Some(callback) = callback {
+            callback(count);
+        }
+    }
+
     /* during early boot before threading exists. */
     pub fn add_arena(&self, info: ArenaInfo) -> Result<(), ErrNO> {
         dprintf!(INFO, "PMM: adding arena '{}' base {:x} size {:x}\n",
@@ -325,6 +699,23 @@ impl PmmNode {
         dprintf!(INFO, "free count now {}\n", free_list.count);
     }
 
+    /*
+     * Visit every page on the free list, e.g. for a scrubber to zero them
+     * or for a verifier to check they're still FREE. The free list is
+     * locked for the whole walk so that nothing can be allocated from
+     * under |f|, which therefore must not call into pmm itself.
+     */
+    #[allow(dead_code)]
+    pub fn for_each_free_page<F: FnMut(*mut vm_page_t)>(&self, mut f: F) {
+        let free_list = self.free_list.lock();
+        let mut visited = 0;
+        for page in free_list.list.iter() {
+            f(page);
+            visited += 1;
+        }
+        ZX_ASSERT!(visited == free_list.count);
+    }
+
     fn alloc_range(&self, address: paddr_t, count: usize,
                    list: &mut List<vm_page_t>) -> Result<(), ErrNO> {
         dprintf!(INFO, "address {:x}, count {:x}\n", address, count);
@@ -343,8 +734,8 @@ impl PmmNode {
         /* walk through the arenas, looking to see
          * if the physical page belongs to it */
         let mut free_list = self.free_list.lock();
-        let arenas = self.arenas.lock();
-        for area in arenas.iter() {
+        let mut arenas = self.arenas.lock();
+        for area in arenas.iter_mut() {
             while allocated < count && area.address_in_arena(address) {
                 let page = area.find_specific(address);
 
@@ -367,6 +758,7 @@ impl PmmNode {
                     list.add_tail(page);
                     allocated += 1;
                 }
+                area.free_runs.remove(area.page_index(address), 1);
 
                 address += PAGE_SIZE;
             }
@@ -380,18 +772,77 @@ impl PmmNode {
 
         if allocated != count {
             /* we were not able to allocate the entire run, free these pages */
-            self.free_list_locked(list);
+            Self::free_list_locked(&mut free_list, &mut arenas, list);
             return Err(ErrNO::NotFound);
         }
 
         Ok(())
     }
 
-    fn alloc_page(&self, _flags: u32) -> *mut vm_page_t {
+    fn alloc_page(&self, flags: u32) -> *mut vm_page_t {
         let mut free_list = self.free_list.lock();
-        let page = free_list.list.pop_head();
+        let page = self.take_free_page_locked(&mut free_list, flags);
+        if page.is_null() {
+            Self::report_out_of_pages(free_list);
+            return null_mut();
+        }
+        drop(free_list);
+        dprintf!(INFO, "alloc page: pa {:x}\n", unsafe { (*page).paddr() });
+
+        Self::zero_page_if_needed(flags, unsafe { (*page).paddr() });
+        page
+    }
+
+    /* Take a free page off the free list and out of the free runs of its
+     * arena, and make it ALLOC. The arena preferred by |alloc_flags| goes
+     * first, then it's up to the arena policy. Only LO_MEM arenas are
+     * looked into if |alloc_flags| asks for it. Null if there's none. */
+    fn take_free_page_locked(&self, free_list: &mut FreePageList,
+                             alloc_flags: u32) -> *mut vm_page_t {
+        let mut arenas = self.arenas.lock();
+        let lo_mem = (alloc_flags & PMM_ALLOC_FLAG_LO_MEM) != 0;
+        let has_free = |arena: &PmmArena| {
+            arena.free_runs.first().is_some() && (!lo_mem || arena.is_lo_mem())
+        };
+
+        let prefer = pmm_alloc_flags_to_arena(alloc_flags);
+        let mut chosen = None;
+        if prefer < arenas.len() && has_free(&arenas[prefer]) {
+            chosen = Some(prefer);
+        } else if free_list.policy == ArenaPolicy::RoundRobin {
+            let num = arenas.len();
+            chosen = (0..num).map(|i| (free_list.next_arena + i) % num)
+                .find(|&i| has_free(&arenas[i]));
+            if let Some(i) = chosen {
+                free_list.next_arena = (i + 1) % num;
+            }
+        } else if lo_mem {
+            /* The free list mixes the arenas up, so go by arena instead */
+            chosen = (0..arenas.len()).find(|&i| has_free(&arenas[i]));
+        }
+
+        let page = match chosen {
+            Some(i) => {
+                let arena = &mut arenas[i];
+                let index = arena.free_runs.first().unwrap();
+                arena.free_runs.remove(index, 1);
+                let page = arena.page_array.get_page(index);
+                unsafe { (*page).delete_from_list(); }
+                page
+            },
+            None if lo_mem => return null_mut(),
+            None => {
+                drop(arenas);
+                let page = free_list.list.pop_head();
+                if page.is_null() {
+                    return null_mut();
+                }
+                self.remove_free_run_locked(page);
+                page
+            },
+        };
+
         unsafe {
-            dprintf!(INFO, "alloc page: pa {:x}\n", (*page).paddr());
             ZX_ASSERT!(!(*page).is_loaned());
             self.alloc_page_helper_locked(page);
         }
@@ -399,7 +850,85 @@ impl PmmNode {
         page
     }
 
-    fn alloc_pages(&self, mut count: usize, alloc_flags: u32,
+    /* The page is owned by the caller now, so it can be zeroed
+     * without holding any lock. */
+    fn zero_page_if_needed(alloc_flags: u32, pa: paddr_t) {
+        if (alloc_flags & PMM_ALLOC_FLAG_ZERO) == 0 {
+            return;
+        }
+        unsafe {
+            arch_zero_page(paddr_to_physmap(pa));
+        }
+    }
+
+    /* Take a page which has been removed from the free list
+     * out of the free runs of its arena as well. */
+    fn remove_free_run_locked(&self, page: *mut vm_page_t) {
+        let pa = unsafe { (*page).paddr() };
+        let mut arenas = self.arenas.lock();
+        for arena in arenas.iter_mut() {
+            if arena.address_in_arena(pa) {
+                let index = arena.page_index(pa);
+                arena.free_runs.remove(index, 1);
+                return;
+            }
+        }
+        panic!("page {:x} isn't in any arena!", pa);
+    }
+
+    pub fn alloc_contiguous(&self, count: usize, alloc_flags: u32,
+                            alignment_log2: usize, pa: &mut paddr_t,
+                            list: &mut List<vm_page_t>) -> Result<(), ErrNO> {
+        dprintf!(INFO, "count {}, align {}\n", count, alignment_log2);
+
+        ZX_ASSERT!(list.is_initialized());
+        if count == 0 {
+            return Ok(());
+        }
+
+        let alignment_log2 = core::cmp::max(alignment_log2, PAGE_SHIFT);
+
+        let lo_mem = (alloc_flags & PMM_ALLOC_FLAG_LO_MEM) != 0;
+        let mut free_list = self.free_list.lock();
+        let mut arenas = self.arenas.lock();
+        for arena in arenas.iter_mut() {
+            if lo_mem && !arena.is_lo_mem() {
+                continue;
+            }
+            let index = match arena.free_runs.find(arena.base(), count,
+                                                   alignment_log2) {
+                Some(index) => index,
+                None => continue,
+            };
+
+            /* The free runs tell us all of these pages are free. */
+            for i in index..(index + count) {
+                let page = arena.page_array.get_page(i);
+                unsafe {
+                    ZX_ASSERT!((*page).is_free());
+                    (*page).delete_from_list();
+                    self.alloc_page_helper_locked(page);
+                }
+                list.add_tail(page);
+            }
+            arena.free_runs.remove(index, count);
+            free_list.count -= count;
+            let base = arena.base();
+            drop(arenas);
+            drop(free_list);
+
+            *pa = base + index * PAGE_SIZE;
+            for i in 0..count {
+                Self::zero_page_if_needed(alloc_flags, *pa + i * PAGE_SIZE);
+            }
+            return Ok(());
+        }
+
+        dprintf!(INFO, "couldn't find run of {} pages\n", count);
+        Err(ErrNO::NoMem)
+    }
+
+    pub fn alloc_pages(&self, mut count: usize, alloc_flags: u32,
                    list: &mut List<vm_page_t>)
         -> Result<(), ErrNO> {
 
@@ -410,10 +939,12 @@ impl PmmNode {
 
         if count == 0 {
             return Ok(());
+        } else if self.should_wait(alloc_flags) {
+            return Err(ErrNO::ShouldWait);
         } else if count == 1 {
             let page = self.alloc_page(alloc_flags);
             if page == null_mut() {
-                return Err(ErrNO::NoMem);
+                return Err(Self::out_of_pages_error(alloc_flags));
             }
             list.add_tail(page);
             return Ok(());
@@ -421,23 +952,63 @@ impl PmmNode {
 
         while count > 0 {
             let mut free_list = self.free_list.lock();
-            let page = free_list.list.pop_head();
+            let page = self.take_free_page_locked(&mut free_list, alloc_flags);
             if page == null_mut() {
-                return Err(ErrNO::NoMem);
-            }
-            unsafe {
-                self.alloc_page_helper_locked(page);
+                Self::report_out_of_pages(free_list);
+                return Err(Self::out_of_pages_error(alloc_flags));
             }
             list.add_tail(page);
-            free_list.count -= 1;
+            drop(free_list);
+
+            Self::zero_page_if_needed(alloc_flags, unsafe { (*page).paddr() });
             count -= 1;
         }
 
         Ok(())
     }
 
-    fn free_list_locked(&self, _list: &mut List<vm_page_t>) {
-        todo!("Implement [free_list_locked]");
+    /* Give the pages of |list| back, which leaves it empty. The caller
+     * must own them, i.e. they have been allocated from this node. */
+    pub fn free_list(&self, list: &mut List<vm_page_t>) {
+        let mut free_list = self.free_list.lock();
+        let mut arenas = self.arenas.lock();
+        Self::free_list_locked(&mut free_list, &mut arenas, list);
+    }
+
+    /* The same as free_list, with both of the locks held already. */
+    fn free_list_locked(free_list: &mut FreePageList, arenas: &mut Vec<PmmArena>,
+                        list: &mut List<vm_page_t>) {
+        let mut count = 0;
+        for page in list.iter() {
+            let pa = unsafe { (*page).paddr() };
+            let arena = arenas.iter_mut().find(|a| a.address_in_arena(pa));
+            ZX_ASSERT!(arena.is_some());
+            let arena = arena.unwrap();
+
+            unsafe { Self::free_page_helper_locked(page); }
+            arena.free_runs.insert(arena.page_index(pa), 1);
+            count += 1;
+        }
+
+        free_list.list.splice(list);
+        free_list.count += count;
+        dprintf!(SPEW, "free {} pages, free count now {}\n",
+                 count, free_list.count);
+    }
+
+    unsafe fn free_page_helper_locked(page: *mut vm_page_t) {
+        dprintf!(SPEW, "freeing page pa {:x}, prev state {:x}\n",
+                 (*page).paddr(), (*page).state());
+
+        ZX_ASSERT!(!(*page).is_free());
+        ZX_ASSERT!(!(*page).is_loaned());
+        ZX_ASSERT!((*page).object.pin_count() == 0);
+
+        /* The transition back to FREE happens under lock_, together with
+         * putting the page on the free list, which hands the ownership
+         * back to the PmmNode. */
+        (*page).object.clear_object();
+        (*page).set_state(vm_page_state::FREE);
     }
 
     unsafe fn alloc_page_helper_locked(&self, page: *mut vm_page_t) {
@@ -487,6 +1058,19 @@ impl PmmNode {
     pub fn get_arenas(&self) -> MutexGuard<Vec<PmmArena>> {
         self.arenas.lock()
     }
+
+    /* A copy of the info of the arenas in the order of base address,
+     * which can be gone through without holding the lock. */
+    pub fn get_arena_info(&self) -> Vec<ArenaInfo> {
+        self.arenas.lock().iter().map(|arena| arena.info.clone()).collect()
+    }
+}
+
+/* Select the pmm node for the preferred node in the flags.
+ * There is only one node for now, which every hint falls back to. */
+fn pmm_node_for(alloc_flags: u32) -> &'static PmmNode {
+    let _node = pmm_alloc_flags_to_node(alloc_flags);
+    &PMM_NODE
 }
 
 pub fn pmm_alloc_range(pa: paddr_t, count: usize, list: &mut List<vm_page_t>)
@@ -495,13 +1079,42 @@ pub fn pmm_alloc_range(pa: paddr_t, count: usize, list: &mut List<vm_page_t>)
 }
 
 pub fn pmm_alloc_page(flags: u32) -> *mut vm_page_t {
-    PMM_NODE.alloc_page(flags)
+    pmm_node_for(flags).alloc_page(flags)
 }
 
 pub fn pmm_alloc_pages(count: usize, alloc_flags: u32,
                        list: &mut List<vm_page_t>)
     -> Result<(), ErrNO> {
-    PMM_NODE.alloc_pages(count, alloc_flags, list)
+    pmm_node_for(alloc_flags).alloc_pages(count, alloc_flags, list)
+}
+
+#[allow(dead_code)]
+pub fn pmm_set_arena_policy(policy: ArenaPolicy) {
+    PMM_NODE.set_arena_policy(policy)
+}
+
+#[allow(dead_code)]
+pub fn pmm_set_low_mem_callback(callback: Option<LowMemCallback>) {
+    PMM_NODE.set_low_mem_callback(callback)
+}
+
+#[allow(dead_code)]
+pub fn pmm_set_should_wait_watermark(pages: usize) {
+    PMM_NODE.set_should_wait_watermark(pages)
+}
+
+#[allow(dead_code)]
+pub fn pmm_count_free_pages() -> usize {
+    PMM_NODE.free_count()
+}
+
+#[allow(dead_code)]
+pub fn pmm_total_bytes() -> usize {
+    PMM_NODE.total_bytes()
+}
+
+pub fn pmm_get_arena_info() -> Vec<ArenaInfo> {
+    PMM_NODE.get_arena_info()
 }
 
 pub fn pmm_add_arena(info: ArenaInfo) -> Result<(), ErrNO> {
@@ -511,31 +1124,31 @@ pub fn pmm_add_arena(info: ArenaInfo) -> Result<(), ErrNO> {
 }
 
 pub fn pmm_alloc_contiguous(count: usize, alloc_flags: u32,
-                            alignment_log2: usize, _pa: &mut paddr_t,
+                            alignment_log2: usize, pa: &mut paddr_t,
                             list: &mut List<vm_page_t>)
     -> Result<(), ErrNO> {
     /* if we're called with a single page, just fall through to
      * the regular allocation routine */
     if count == 1 && alignment_log2 <= PAGE_SHIFT {
-        let page = PMM_NODE.alloc_page(alloc_flags);
+        let page = pmm_node_for(alloc_flags).alloc_page(alloc_flags);
         if page == null_mut() {
             return Err(ErrNO::NoMem);
         }
+        unsafe { *pa = (*page).paddr(); }
         list.add_tail(page);
         return Ok(());
     }
 
-    todo!("pmm_alloc_contiguous");
-    //pmm_node.alloc_contiguous(count, alloc_flags, alignment_log2, pa, list)
+    pmm_node_for(alloc_flags).alloc_contiguous(count, alloc_flags,
+                                               alignment_log2, pa, list)
 }
 
 pub fn paddr_to_vm_page(pa: paddr_t) -> *mut vm_page_t {
     PMM_NODE.paddr_to_page(pa)
 }
 
-pub fn pmm_free(_list: &List::<vm_page_t>) {
-    todo!("pmm_free!");
-    //pmm_node.FreeList(list)
+pub fn pmm_free(list: &mut List<vm_page_t>) {
+    PMM_NODE.free_list(list)
 }
 
 pub fn pmm_page_queues() -> &'static PageQueues {
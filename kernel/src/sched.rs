@@ -7,15 +7,25 @@
  */
 
 use core::ptr::null_mut;
+use alloc::vec::Vec;
 use crate::debug::*;
+use crate::ZX_ASSERT;
 
-use crate::thread::Thread;
+use crate::thread::{Thread, thread_reap_deferred};
+use crate::percpu::PERCPU_ARRAY;
+use crate::wait_queue::WaitQueue;
 use crate::arch::smp::arch_curr_cpu_num;
+use crate::arch::thread::{arch_context_switch, arch_idle};
+use crate::arch::timer::current_time;
 use crate::cpu::{cpu_num_t, cpu_mask_t, INVALID_CPU, CPU_MASK_ALL, cpu_num_to_mask};
 
 type SchedWeight = usize;
-type SchedDuration = usize;
-type SchedPerformanceScale = usize;
+pub type SchedDuration = usize;
+pub type SchedPerformanceScale = usize;
+
+/* Performance scales are fixed-point in (0, 1], in which this stands for 1,
+ * i.e. the scale of the highest performance CPU. */
+pub const K_PERFORMANCE_SCALE_ONE: SchedPerformanceScale = 1 << 10;
 
 macro_rules! ZX_MSEC {
     ($n: expr) => { (1000000usize * $n) }
@@ -28,6 +38,10 @@ const fn sched_ms(milliseconds: usize) -> SchedDuration {
 /* Default minimum granularity of time slices. */
 const K_DEFAULT_MINIMUM_GRANULARITY: SchedDuration = sched_ms(1);
 
+/* A running thread keeps the CPU at least this long before it's
+ * preempted by a ready thread of equal priority. */
+const K_DEFAULT_TIME_SLICE: SchedDuration = K_DEFAULT_MINIMUM_GRANULARITY;
+
 // Table of fixed-point constants converting from kernel priority to fair
 // scheduler weight.
 const K_PRIORITY_TO_WEIGHT_TABLE: [SchedWeight; 32] = [
@@ -66,15 +80,36 @@ enum SchedDiscipline {
     _Deadline(SchedDeadlineParams),
 }
 
-enum ThreadState {
+#[derive(Clone, Copy, PartialEq)]
+pub enum ThreadState {
     ThreadInitial,
-    _ThreadReady,
+    ThreadReady,
     ThreadRunning,
-    _ThreadBlocked,
-    _ThreadBlockedReadLock,
-    _ThreadSleeping,
+    ThreadBlocked,
+    ThreadBlockedReadLock,
+    ThreadSleeping,
     _ThreadSuspended,
-    _ThreadDeath,
+    #[allow(dead_code)]
+    ThreadDeath,
+}
+
+// Why a thread is blocking, which selects its blocked state.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlockReason {
+    Normal,
+    ReadLock,
+    Sleep,
+}
+
+impl BlockReason {
+    fn state(&self) -> ThreadState {
+        match self {
+            BlockReason::Normal => ThreadState::ThreadBlocked,
+            BlockReason::ReadLock => ThreadState::ThreadBlockedReadLock,
+            BlockReason::Sleep => ThreadState::ThreadSleeping,
+        }
+    }
 }
 
 pub struct SchedulerState {
@@ -111,9 +146,37 @@ impl SchedulerState {
         }
     }
 
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    #[allow(dead_code)]
+    pub fn set_state(&mut self, state: ThreadState) {
+        self.state = state;
+    }
+
+    /* Sets the CPUs the thread is permitted to run on. */
+    #[allow(dead_code)]
+    pub fn set_hard_affinity(&mut self, affinity: cpu_mask_t) {
+        self.hard_affinity = affinity;
+    }
+
     fn set_discipline(&mut self, discipline: SchedDiscipline) {
         self.discipline = discipline;
     }
+
+    fn weight(&self) -> SchedWeight {
+        if let SchedDiscipline::Fair(params) = &self.discipline {
+            params.weight
+        } else {
+            panic!("Bad discipline! Only support fair!");
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn effective_priority(&self) -> usize {
+        self.effective_priority
+    }
 }
 
 pub struct Scheduler {
@@ -138,8 +201,30 @@ pub struct Scheduler {
      * This value is initially determined from the system topology,
      * when available, and by userspace performance/thermal management
      * at runtime. */
-    _performance_scale: SchedPerformanceScale,
+    performance_scale: SchedPerformanceScale,
     performance_scale_reciprocal: SchedPerformanceScale,
+
+    /* Threads in READY state on this CPU, ordered by effective priority
+     * from the highest. Threads of equal priority are kept in FIFO order. */
+    run_queue: Vec<*mut Thread>,
+
+    /* When the active thread was switched to, which starts its time slice */
+    start_of_current_time_slice_ns: SchedDuration,
+
+    /* Called when this CPU switches to its idle thread and away from it */
+    enter_idle_hook: IdleHook,
+    exit_idle_hook: IdleHook,
+}
+
+/* A hook of power management, called with the number of the CPU. */
+pub type IdleHook = fn(cpu_num_t);
+
+/* Wait for an interrupt before switching to the idle thread */
+fn default_enter_idle(_cpu: cpu_num_t) {
+    arch_idle();
+}
+
+fn default_exit_idle(_cpu: cpu_num_t) {
 }
 
 impl Scheduler {
@@ -151,8 +236,12 @@ impl Scheduler {
             runnable_fair_task_count: 0,
             total_expected_runtime_ns: 0,
             exported_total_expected_runtime_ns: 0,
-            _performance_scale: 1,
-            performance_scale_reciprocal: 1,
+            performance_scale: K_PERFORMANCE_SCALE_ONE,
+            performance_scale_reciprocal: K_PERFORMANCE_SCALE_ONE,
+            run_queue: Vec::new(),
+            start_of_current_time_slice_ns: 0,
+            enter_idle_hook: default_enter_idle,
+            exit_idle_hook: default_exit_idle,
         }
     }
 
@@ -198,6 +287,222 @@ impl Scheduler {
         sched_state.expected_runtime_ns = K_DEFAULT_MINIMUM_GRANULARITY;
     }
 
+    /* Changes the base priority of the thread and recomputes its weight.
+     * If the thread is associated with a run queue, the weight_total of
+     * that scheduler is updated, and a READY thread is re-positioned. */
+    #[allow(dead_code)]
+    pub fn set_priority(thread: *mut Thread, priority: usize) {
+        ZX_ASSERT!(priority <= Thread::HIGHEST_PRIORITY);
+
+        let ss = unsafe { (*thread).sched_state() };
+        let old_weight = ss.weight();
+        let new_weight = priority_to_weight(priority);
+        ss.set_discipline(SchedDiscipline::Fair(SchedFairParams::new(new_weight)));
+        ss.base_priority = priority;
+        ss.effective_priority = priority;
+
+        if !ss.active {
+            return;
+        }
+
+        let sched = unsafe { (*thread).percpu().scheduler() };
+        sched.weight_total = sched.weight_total - old_weight + new_weight;
+        if ss.state == ThreadState::ThreadReady {
+            sched.erase_queue(thread);
+            sched.insert_queue(thread);
+        }
+    }
+
+    /* Makes the thread READY and puts it in the run queue of its CPU. */
+    #[allow(dead_code)]
+    pub fn unblock(thread: *mut Thread) {
+        let ss = unsafe { (*thread).sched_state() };
+        ZX_ASSERT!(!ss.active);
+        ZX_ASSERT!(ss.state != ThreadState::ThreadDeath &&
+                   ss.state != ThreadState::ThreadRunning);
+
+        let sched = unsafe { (*thread).percpu().scheduler() };
+        ss.state = ThreadState::ThreadReady;
+        ss.curr_cpu = sched.this_cpu;
+        ss.active = true;
+        sched.weight_total += ss.weight();
+        sched.runnable_fair_task_count += 1;
+        sched.update_total_expected_runtime(ss.expected_runtime_ns);
+        sched.insert_queue(thread);
+    }
+
+    /* Takes a READY thread out of its run queue, e.g. when it exits. */
+    #[allow(dead_code)]
+    pub fn remove(thread: *mut Thread) {
+        let ss = unsafe { (*thread).sched_state() };
+        ZX_ASSERT!(ss.active && ss.state == ThreadState::ThreadReady);
+        Self::deactivate(thread);
+    }
+
+    /* Marks the current thread dead and switches away from it for good.
+     * It's reaped once it's been switched away from. */
+    pub fn exit_current() {
+        let current = Thread::current() as *mut Thread;
+        Self::deactivate(current);
+        unsafe {
+            (*current).sched_state().set_state(ThreadState::ThreadDeath);
+        }
+        Self::reschedule();
+    }
+
+    /* Blocks the current thread on the wait queue and switches to
+     * the next thread. It's a bug to block with preemption disabled. */
+    #[allow(dead_code)]
+    pub fn block_current(reason: BlockReason, wait_queue: &mut WaitQueue) {
+        let current = Thread::current();
+        if current.preemption_state.preempt_disabled() {
+            panic!("Blocking '{}' with preemption disabled!", current.name());
+        }
+
+        Self::block(current, reason, wait_queue);
+        Self::reschedule();
+    }
+
+    /* Moves the thread into the blocked state of the reason and onto
+     * the wait queue, dropping it from the bookkeeping of its CPU. */
+    #[allow(dead_code)]
+    pub fn block(thread: *mut Thread, reason: BlockReason,
+                 wait_queue: &mut WaitQueue) {
+        let ss = unsafe { (*thread).sched_state() };
+        ZX_ASSERT!(ss.active);
+        ZX_ASSERT!(ss.state == ThreadState::ThreadRunning ||
+                   ss.state == ThreadState::ThreadReady);
+        Self::deactivate(thread);
+        ss.state = reason.state();
+        wait_queue.insert(thread);
+    }
+
+    /* Picks the thread to run next on this CPU: the current thread keeps
+     * running unless it can't, a thread of higher priority is ready, or
+     * its time slice is over and a thread of equal priority is ready.
+     * The idle thread gives way to any ready thread. */
+    #[allow(dead_code)]
+    pub fn reschedule() {
+        let current = Thread::current() as *mut Thread;
+        let percpu = unsafe { (*current).percpu() };
+        let ss = unsafe { (*current).sched_state() };
+        let now = current_time() as SchedDuration;
+
+        if ss.state == ThreadState::ThreadRunning {
+            let next = percpu.scheduler().peek_next();
+            if next.is_null() {
+                return;
+            }
+            if current != percpu.idle_thread_ptr() {
+                let priority = unsafe { (*next).sched_state.effective_priority };
+                if priority < ss.effective_priority {
+                    return;
+                }
+                if priority == ss.effective_priority &&
+                    !percpu.scheduler().time_slice_expired(now) {
+                    return;
+                }
+            }
+            ss.state = ThreadState::ThreadReady;
+            /* The idle thread runs only when nothing else is ready,
+             * so it never waits in the run queue. */
+            if current != percpu.idle_thread_ptr() {
+                percpu.scheduler().insert_queue(current);
+            }
+        } else if ss.state == ThreadState::ThreadDeath {
+            /* It's only reaped once switched away from below */
+            thread_reap_deferred();
+        }
+
+        let idle = percpu.idle_thread_ptr();
+        let sched = percpu.scheduler();
+        let next = if sched.run_queue.is_empty() {
+            idle
+        } else {
+            sched.run_queue.remove(0)
+        };
+        unsafe {
+            (*next).sched_state.state = ThreadState::ThreadRunning;
+        }
+        sched.active_thread = next;
+        if next != current {
+            sched.start_of_current_time_slice_ns = now;
+            if next == idle {
+                sched.on_enter_idle();
+            } else if current == idle {
+                sched.on_exit_idle();
+            }
+            Self::context_switch(current, next);
+        }
+    }
+
+    /* Replaces the hooks around the idle transition of this CPU,
+     * which e.g. enter and leave a low-power state of the hart. */
+    #[allow(dead_code)]
+    pub fn set_idle_hooks(&mut self, enter: IdleHook, exit: IdleHook) {
+        self.enter_idle_hook = enter;
+        self.exit_idle_hook = exit;
+    }
+
+    fn time_slice_expired(&self, now: SchedDuration) -> bool {
+        now - self.start_of_current_time_slice_ns >= K_DEFAULT_TIME_SLICE
+    }
+
+    fn on_enter_idle(&self) {
+        (self.enter_idle_hook)(self.this_cpu);
+    }
+
+    fn on_exit_idle(&self) {
+        (self.exit_idle_hook)(self.this_cpu);
+    }
+
+    fn context_switch(old: *mut Thread, new: *mut Thread) {
+        unsafe {
+            arch_context_switch(&mut (*old).arch, &(*new).arch);
+        }
+    }
+
+    /* Drops the thread from the run queue and the bookkeeping of its CPU. */
+    fn deactivate(thread: *mut Thread) {
+        let ss = unsafe { (*thread).sched_state() };
+        let sched = unsafe { (*thread).percpu().scheduler() };
+        if ss.state == ThreadState::ThreadReady {
+            sched.erase_queue(thread);
+        }
+        ss.active = false;
+        ss.curr_cpu = INVALID_CPU;
+        sched.weight_total -= ss.weight();
+        sched.runnable_fair_task_count -= 1;
+        sched.total_expected_runtime_ns -= ss.expected_runtime_ns;
+        sched.update_total_expected_runtime(0);
+    }
+
+    /* The estimate of the queuing time on this CPU, scaled by
+     * its relative performance, as exported to monitors. */
+    pub fn load_estimate(&self) -> SchedDuration {
+        self.exported_total_expected_runtime_ns
+    }
+
+    /* The thread which would be selected to run next, or null. */
+    #[allow(dead_code)]
+    pub fn peek_next(&self) -> *mut Thread {
+        self.run_queue.first().copied().unwrap_or(null_mut())
+    }
+
+    fn insert_queue(&mut self, thread: *mut Thread) {
+        let priority = unsafe { (*thread).sched_state.effective_priority };
+        let pos = self.run_queue.iter()
+            .position(|t| unsafe { (**t).sched_state.effective_priority } < priority)
+            .unwrap_or(self.run_queue.len());
+        self.run_queue.insert(pos, thread);
+    }
+
+    fn erase_queue(&mut self, thread: *mut Thread) {
+        let pos = self.run_queue.iter().position(|t| *t == thread);
+        ZX_ASSERT!(pos.is_some());
+        self.run_queue.remove(pos.unwrap());
+    }
+
     /* Updates the total expected runtime estimator with the given delta.
      * The exported value is scaled by the relative performance factor of
      * the CPU to account for performance differences in the estimate. */
@@ -212,7 +517,53 @@ impl Scheduler {
     /* Scales the given value up by the reciprocal of
      * the CPU performance scale. */
     fn scale_up(&self, value: SchedDuration) -> SchedDuration {
-        value * self.performance_scale_reciprocal()
+        value * self.performance_scale_reciprocal() / K_PERFORMANCE_SCALE_ONE
+    }
+
+    pub fn performance_scale(&self) -> SchedPerformanceScale {
+        self.performance_scale
+    }
+
+    /* Sets the performance scale of this CPU relative to the highest
+     * performance one, e.g. from the topology. The reciprocal is kept
+     * in step, and the exported load is rescaled with it. */
+    pub fn set_performance_scale(&mut self, scale: SchedPerformanceScale) {
+        ZX_ASSERT!(scale > 0 && scale <= K_PERFORMANCE_SCALE_ONE);
+        self.performance_scale = scale;
+        self.performance_scale_reciprocal =
+            K_PERFORMANCE_SCALE_ONE * K_PERFORMANCE_SCALE_ONE / scale;
+        self.update_total_expected_runtime(0);
+    }
+
+    /* The load on this CPU if it took the thread as well, scaled by its
+     * performance. A slower CPU loses to a faster one of the same load. */
+    fn projected_load(&self, thread: *mut Thread) -> SchedDuration {
+        let ss = unsafe { &(*thread).sched_state };
+        self.scale_up(self.total_expected_runtime_ns + ss.expected_runtime_ns)
+    }
+
+    /* Finds the CPU to run the thread on: the one with the lowest projected
+     * load among the CPUs that the thread is permitted to run on, so that
+     * work prefers faster cores. Ties go to the lower CPU number. */
+    #[allow(dead_code)]
+    pub fn find_target_cpu(thread: *mut Thread) -> cpu_num_t {
+        let hard_affinity = unsafe { (*thread).sched_state.hard_affinity };
+        let mut percpu_array = unsafe { PERCPU_ARRAY.lock() };
+        let mut target = INVALID_CPU;
+        let mut target_load = SchedDuration::MAX;
+        for percpu in percpu_array.iter_mut() {
+            let sched = percpu.scheduler();
+            if cpu_num_to_mask(sched.this_cpu) & hard_affinity == 0 {
+                continue;
+            }
+            let load = sched.projected_load(thread);
+            if load < target_load {
+                target = sched.this_cpu;
+                target_load = load;
+            }
+        }
+        ZX_ASSERT!(target != INVALID_CPU);
+        target
     }
 
     /* the reciprocal performance scale of the CPU this scheduler instance
@@ -220,4 +571,12 @@ impl Scheduler {
     fn performance_scale_reciprocal(&self) -> SchedPerformanceScale {
         self.performance_scale_reciprocal
     }
-}
\ No newline at end of file
+}
+
+/* The system-wide scheduling pressure: the sum of
+ * the exported load estimates of all CPUs. */
+#[allow(dead_code)]
+pub fn sched_total_load() -> SchedDuration {
+    let mut percpu_array = unsafe { PERCPU_ARRAY.lock() };
+    percpu_array.iter_mut().map(|p| p.scheduler().load_estimate()).sum()
+}
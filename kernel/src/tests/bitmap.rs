@@ -0,0 +1,68 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use crate::klib::bitmap::Bitmap;
+
+pub fn test_bitmap() {
+    test_with_capacity();
+    test_storage_units();
+}
+
+fn test_with_capacity() {
+    println!(" Test: bitmap with capacity ...");
+    let mut bitmap = Bitmap::with_capacity(200);
+    assert!(bitmap.size() == 200);
+    assert!(bitmap.storage_num() == 4);
+
+    let mut out = 0;
+    assert!(bitmap.scan(0, 200, false, &mut out));
+
+    /* A run across the boundary of storage units */
+    bitmap.set(60, 130).unwrap();
+    assert!(bitmap.scan(60, 130, true, &mut out));
+    assert!(!bitmap.scan(0, 200, false, &mut out) && out == 60);
+    assert!(bitmap.find(true, 0, 200, 70).unwrap() == 60);
+    assert!(bitmap.find(false, 60, 200, 10).unwrap() == 130);
+    assert!(bitmap.find(true, 0, 200, 71).is_err());
+
+    /* The last storage unit */
+    bitmap.set(190, 200).unwrap();
+    assert!(!bitmap.scan(130, 200, false, &mut out) && out == 190);
+    assert!(!bitmap.reverse_scan(0, 190, true, &mut out) && out == 189);
+    assert!(bitmap.set(190, 201).is_err());
+
+    assert!(bitmap.count_set(0, 200) == 80);
+    assert!(bitmap.count_set(61, 195) == 74);
+    assert!(bitmap.count_set(130, 190) == 0);
+
+    bitmap.clear(64, 128).unwrap();
+    assert!(bitmap.count_set(0, 200) == 16);
+    assert!(bitmap.find(false, 0, 200, 64).unwrap() == 64);
+    assert!(bitmap.find(true, 64, 200, 1).unwrap() == 128);
+    println!(" Test: bitmap with capacity ok!\n");
+}
+
+fn test_storage_units() {
+    println!(" Test: bitmap storage units ...");
+    /* Caller provided storage, which is laid out in usize words */
+    let mut words = [0usize; 2];
+    let mut bitmap = Bitmap::new();
+    bitmap.storage_init(words.as_mut_ptr() as usize,
+                        core::mem::size_of_val(&words));
+    bitmap.init(128);
+
+    /* A bit lands in the word which holds it, 64 bits per word */
+    bitmap.set(65, 66).unwrap();
+    assert!(words == [0, 1 << 1]);
+
+    /* The last word of a range ending within it is scanned too */
+    let mut out = 0;
+    assert!(!bitmap.scan(0, 70, false, &mut out) && out == 65);
+    assert!(bitmap.scan(0, 65, false, &mut out));
+    println!(" Test: bitmap storage units ok!\n");
+}
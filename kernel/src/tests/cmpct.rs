@@ -7,7 +7,24 @@
  */
 
 use core::ptr::null_mut;
-use crate::klib::cmpctmalloc::{cmpct_alloc, cmpct_free};
+use alloc::boxed::Box;
+use alloc::vec;
+use crate::errors::ErrNO;
+use crate::types::vaddr_t;
+use crate::klib::cmpctmalloc::{
+    cmpct_alloc, cmpct_alloc_node, cmpct_free, cmpct_get_info,
+    cmpct_get_stats, cmpct_init,
+    cmpct_memalign, cmpct_owns, cmpct_realloc, cmpct_reset, cmpct_trim,
+    cmpct_heap_alloc, cmpct_heap_free, cmpct_heap_get_info,
+    cmpct_heap_get_stats, cmpct_heap_init, cmpct_heap_memalign,
+    cmpct_heap_realloc, cmpct_heap_release, cmpct_heap_reset,
+    find_nonempty_bucket_in, Heap, BUCKET_WORDS, NUMBER_OF_BUCKETS,
+};
+use crate::pmm::{PMM_NODE_ANY, pmm_alloc_flag_node, pmm_alloc_flags_to_node};
+#[cfg(feature = "heap-redzone")]
+use crate::klib::cmpctmalloc::{cmpct_check_redzone, cmpct_heap_try_free};
+#[cfg(feature = "heap-poison")]
+use crate::klib::cmpctmalloc::cmpct_check_poison;
 
 const PADDING_SEED: usize = 0xCDEF_0123_4567_89AB;
 
@@ -20,6 +37,22 @@ pub fn test_cmpct() {
     }
 
     test_bundle_alloc();
+    test_alloc_node();
+    test_stats();
+    test_find_nonempty_bucket();
+    test_owns();
+    test_reset();
+    test_realloc();
+    test_info();
+    test_fixed_heap();
+    test_trim();
+
+    #[cfg(feature = "heap-redzone")]
+    test_redzone();
+    #[cfg(feature = "heap-redzone")]
+    test_redzone_free();
+    #[cfg(feature = "heap-poison")]
+    test_poison();
 }
 
 fn test_alloc_and_free(size: usize) {
@@ -45,6 +78,204 @@ fn test_bundle_alloc() {
     println!(" Test: bundle alloc ok!\n");
 }
 
+fn test_alloc_node() {
+    println!(" Test: alloc with node hint ...");
+    assert!(pmm_alloc_flag_node(PMM_NODE_ANY) == 0);
+    assert!(pmm_alloc_flags_to_node(0) == PMM_NODE_ANY);
+    assert!(pmm_alloc_flags_to_node(pmm_alloc_flag_node(0)) == 0);
+    assert!(pmm_alloc_flags_to_node(pmm_alloc_flag_node(3)) == 3);
+
+    /* Only one node on this board; the hint mustn't change anything. */
+    for &node in [0, PMM_NODE_ANY].iter() {
+        for &size in [24, 600, 256 * 1024].iter() {
+            let ptr = cmpct_alloc_node(node, size);
+            assert!(ptr != null_mut());
+            fill_in(ptr, size);
+            check_on(ptr, size);
+            cmpct_free(ptr);
+        }
+    }
+    println!(" Test: alloc with node hint ok!\n");
+}
+
+fn test_stats() {
+    println!(" Test: heap stats ...");
+    let before = cmpct_get_stats();
+
+    let mut ptr: [*mut u8; 8] = [null_mut(); 8];
+    for i in 0..8 {
+        ptr[i] = cmpct_alloc(64 * (i + 1));
+        assert!(ptr[i] != null_mut());
+    }
+    let stats = cmpct_get_stats();
+    assert!(stats.alloc_count == before.alloc_count + 8);
+    let used = stats.size - stats.remaining;
+    assert!(used >= (before.size - before.remaining) + 64 * 36);
+    assert!(stats.peak_used >= used);
+
+    for i in 0..3 {
+        cmpct_free(ptr[i]);
+    }
+    let stats = cmpct_get_stats();
+    assert!(stats.alloc_count == before.alloc_count + 5);
+    assert!(stats.size - stats.remaining < used);
+    /* The peak stays where it was */
+    assert!(stats.peak_used >= used);
+
+    /* An aligned allocation counts once */
+    let aligned = cmpct_memalign(256, 100);
+    assert!(aligned as usize % 256 == 0);
+    assert!(cmpct_get_stats().alloc_count == before.alloc_count + 6);
+    cmpct_free(aligned);
+
+    for i in 3..8 {
+        cmpct_free(ptr[i]);
+    }
+    assert!(cmpct_get_stats().alloc_count == before.alloc_count);
+    println!(" Test: heap stats ok!\n");
+}
+
+fn set_bucket(bits: &mut [u32], index: usize) {
+    bits[index >> 5] |= 1 << (31 - (index & 0x1f));
+}
+
+fn test_find_nonempty_bucket() {
+    println!(" Test: find nonempty bucket ...");
+    let bits = [0u32; BUCKET_WORDS];
+    for index in [0, 31, 32, NUMBER_OF_BUCKETS - 1] {
+        assert!(find_nonempty_bucket_in(&bits, index) == Err(ErrNO::NotFound));
+    }
+
+    /* The index at the last bit of a word */
+    let mut bits = [0u32; BUCKET_WORDS];
+    set_bucket(&mut bits, 31);
+    assert!(find_nonempty_bucket_in(&bits, 0) == Ok(31));
+    assert!(find_nonempty_bucket_in(&bits, 31) == Ok(31));
+    assert!(find_nonempty_bucket_in(&bits, 32) == Err(ErrNO::NotFound));
+
+    /* Search into a later word */
+    let mut bits = [0u32; BUCKET_WORDS];
+    set_bucket(&mut bits, 5);
+    set_bucket(&mut bits, 70);
+    assert!(find_nonempty_bucket_in(&bits, 5) == Ok(5));
+    assert!(find_nonempty_bucket_in(&bits, 6) == Ok(70));
+    assert!(find_nonempty_bucket_in(&bits, 31) == Ok(70));
+    assert!(find_nonempty_bucket_in(&bits, 71) == Err(ErrNO::NotFound));
+
+    /* The final word near NUMBER_OF_BUCKETS */
+    let last = NUMBER_OF_BUCKETS - 1;
+    let mut bits = [0u32; BUCKET_WORDS];
+    set_bucket(&mut bits, last);
+    assert!(find_nonempty_bucket_in(&bits, 0) == Ok(last));
+    assert!(find_nonempty_bucket_in(&bits, last - 1) == Ok(last));
+    assert!(find_nonempty_bucket_in(&bits, last) == Ok(last));
+    assert!(find_nonempty_bucket_in(&bits, BUCKET_WORDS << 5) ==
+            Err(ErrNO::NotFound));
+
+    /* A word which isn't a full one is searched as well */
+    let mut bits = [0u32; 3];
+    set_bucket(&mut bits, 80);
+    assert!(find_nonempty_bucket_in(&bits, 33) == Ok(80));
+    println!(" Test: find nonempty bucket ok!\n");
+}
+
+fn test_owns() {
+    println!(" Test: cmpct owns ...");
+    let ptr = cmpct_alloc(64);
+    assert!(!ptr.is_null());
+    assert!(cmpct_owns(ptr));
+    /* Not the start of an allocation */
+    assert!(!cmpct_owns(unsafe { ptr.add(16) }));
+
+    let mut local = 0usize;
+    assert!(!cmpct_owns(&mut local as *mut usize as *mut u8));
+    assert!(!cmpct_owns(null_mut()));
+
+    cmpct_free(ptr);
+    assert!(!cmpct_owns(ptr));
+    println!(" Test: cmpct owns ok!\n");
+}
+
+#[cfg(feature = "heap-redzone")]
+fn test_redzone() {
+    println!(" Test: redzone ...");
+    let size = 21;
+    let ptr = cmpct_alloc(size);
+    fill_in(ptr, size);
+    assert!(cmpct_check_redzone(ptr));
+
+    /* Overrun the buffer by one byte. */
+    let saved = unsafe { *ptr.add(size) };
+    unsafe { *ptr.add(size) = !saved; }
+    assert!(!cmpct_check_redzone(ptr));
+
+    /* Repair it so that cmpct_free doesn't panic. */
+    unsafe { *ptr.add(size) = saved; }
+    assert!(cmpct_check_redzone(ptr));
+    cmpct_free(ptr);
+    println!(" Test: redzone ok!\n");
+}
+
+#[cfg(feature = "heap-redzone")]
+fn test_redzone_free() {
+    println!(" Test: redzone free ...");
+    const REGION_SIZE: usize = 16 * 1024;
+    let mut region = vec![0u64; REGION_SIZE / 8];
+    let mut heap = Box::new(Heap::new_fixed());
+    heap.init();
+    assert!(heap.add_region(region.as_mut_ptr() as vaddr_t, REGION_SIZE).is_ok());
+
+    /* The redzone of an aligned block is right after the requested
+     * size, wherever the padding has left its end. */
+    for align in [0, 64, 256] {
+        let size = 40;
+        let ptr = if align == 0 {
+            cmpct_heap_alloc(&mut heap, PMM_NODE_ANY, size)
+        } else {
+            cmpct_heap_memalign(&mut heap, align, size)
+        };
+        assert!(!ptr.is_null());
+        fill_in(ptr, size);
+        assert!(cmpct_check_redzone(ptr));
+
+        /* Overrun by one byte: free reports it and keeps the block */
+        let saved = unsafe { *ptr.add(size) };
+        unsafe { *ptr.add(size) = !saved; }
+        assert!(cmpct_heap_try_free(&mut heap, ptr) == Err(ErrNO::BadState));
+        assert!(cmpct_heap_get_stats(&heap).alloc_count == 1);
+
+        unsafe { *ptr.add(size) = saved; }
+        check_on(ptr, size);
+        assert!(cmpct_heap_try_free(&mut heap, ptr).is_ok());
+        assert!(cmpct_heap_get_stats(&heap).alloc_count == 0);
+    }
+
+    drop(heap);
+    drop(region);
+    println!(" Test: redzone free ok!\n");
+}
+
+#[cfg(feature = "heap-poison")]
+fn test_poison() {
+    println!(" Test: poison ...");
+    /* The neighbor, likely from the same OS allocation, keeps that in
+     * use, so the freed block stays mapped and can still be read. */
+    let size = 256;
+    let ptr = cmpct_alloc(size);
+    let neighbor = cmpct_alloc(size);
+    fill_in(ptr, size);
+    cmpct_free(ptr);
+    assert!(cmpct_check_poison(ptr, size));
+
+    /* The poisoned memory is checked and handed out again */
+    let ptr = cmpct_alloc(size);
+    fill_in(ptr, size);
+    check_on(ptr, size);
+    cmpct_free(ptr);
+    cmpct_free(neighbor);
+    println!(" Test: poison ok!\n");
+}
+
 fn fill_in(mut ptr: *mut u8, mut size: usize) {
     let padding = (PADDING_SEED ^ size) as u64;
     while size >= 8 {
@@ -115,4 +346,231 @@ fn check_on(mut ptr: *mut u8, mut size: usize) {
         }
         size -= 1;
     }
-}
\ No newline at end of file
+}
+/* Allocate blocks of |sizes| from |heap|, then free them in |order|.
+ * Returns where the blocks were, from the lowest one. */
+fn heap_scenario(heap: &mut Heap, sizes: &[usize; 4], order: &[usize; 4])
+    -> [usize; 4] {
+    let mut ptr: [*mut u8; 4] = [null_mut(); 4];
+    for i in 0..4 {
+        ptr[i] = cmpct_heap_alloc(heap, PMM_NODE_ANY, sizes[i]);
+        assert!(ptr[i] != null_mut());
+    }
+    for &i in order.iter() {
+        cmpct_heap_free(heap, ptr[i]);
+    }
+    let lowest = ptr.iter().min().copied().unwrap() as usize;
+    ptr.map(|p| p as usize - lowest)
+}
+
+fn test_reset() {
+    println!(" Test: heap reset ...");
+    /* The kernel heap has been initialized at boot, and it can't be reset
+     * as there's always something allocated from it */
+    assert!(cmpct_init() == Err(ErrNO::AlreadyExists));
+    assert!(cmpct_reset() == Err(ErrNO::BadState));
+
+    /* A heap from the OS of its own, off the stack as it's big */
+    let mut heap = Box::new(Heap::new_from_os());
+    assert!(cmpct_heap_init(&mut heap).is_ok());
+    let fresh = cmpct_heap_get_info(&heap);
+    let fresh_stats = cmpct_heap_get_stats(&heap);
+    let is_fresh = |heap: &Heap| {
+        let info = cmpct_heap_get_info(heap);
+        let stats = cmpct_heap_get_stats(heap);
+        info.size == fresh.size && info.remaining == fresh.remaining &&
+            info.free_blocks == fresh.free_blocks &&
+            info.cached_os_alloc == fresh.cached_os_alloc &&
+            stats.alloc_count == 0 && stats.peak_used == fresh_stats.peak_used
+    };
+    let first = heap_scenario(&mut heap, &[24, 100, 24, 300], &[2, 0, 3, 1]);
+
+    /* Another scenario, which takes an OS allocation of its own and
+     * leaves it cached */
+    let big = cmpct_heap_alloc(&mut heap, PMM_NODE_ANY, 1000 * 1024);
+    assert!(big != null_mut());
+    heap_scenario(&mut heap, &[600, 40, 200, 40], &[1, 3, 0, 2]);
+    cmpct_heap_free(&mut heap, big);
+    assert!(cmpct_heap_get_info(&heap).cached_os_alloc);
+    assert!(!is_fresh(&heap));
+
+    /* Not while something is allocated */
+    let live = cmpct_heap_alloc(&mut heap, PMM_NODE_ANY, 64);
+    assert!(live != null_mut());
+    assert!(cmpct_heap_reset(&mut heap) == Err(ErrNO::BadState));
+    cmpct_heap_free(&mut heap, live);
+
+    /* After a reset, the counters are those of a fresh heap, and the
+     * first scenario plays out the same again */
+    assert!(cmpct_heap_reset(&mut heap).is_ok());
+    assert!(is_fresh(&heap));
+    assert!(heap_scenario(&mut heap, &[24, 100, 24, 300], &[2, 0, 3, 1]) == first);
+    assert!(cmpct_heap_reset(&mut heap).is_ok());
+    assert!(is_fresh(&heap));
+
+    /* All of it goes back to the OS */
+    assert!(cmpct_heap_release(&mut heap).is_ok());
+    assert!(cmpct_heap_get_stats(&heap).size == 0);
+    drop(heap);
+    println!(" Test: heap reset ok!\n");
+}
+
+fn test_realloc() {
+    println!(" Test: cmpct realloc ...");
+    let before = cmpct_get_stats();
+
+    /* A null pointer is just an allocation, and a zero size a free */
+    let ptr = cmpct_realloc(null_mut(), 40);
+    assert!(ptr != null_mut() && cmpct_owns(ptr));
+    assert!(cmpct_get_stats().alloc_count == before.alloc_count + 1);
+    assert!(cmpct_realloc(ptr, 0) == null_mut());
+    assert!(!cmpct_owns(ptr));
+    assert!(cmpct_get_stats().alloc_count == before.alloc_count);
+
+    /* Shrinking frees the tail, which the block grows back into */
+    let ptr = cmpct_alloc(8192);
+    fill_in(ptr, 64);
+    assert!(cmpct_realloc(ptr, 64) == ptr);
+    check_on(ptr, 64);
+    assert!(cmpct_realloc(ptr, 4096) == ptr);
+    check_on(ptr, 64);
+    assert!(cmpct_get_stats().alloc_count == before.alloc_count + 1);
+    cmpct_free(ptr);
+
+    /* A block followed closely by another one has no room to grow,
+     * so it's moved. */
+    let mut ptr: [*mut u8; 16] = [null_mut(); 16];
+    for i in 0..16 {
+        ptr[i] = cmpct_alloc(64);
+        fill_in(ptr[i], 64);
+    }
+    let i = (0..16).find(|&i| ptr.iter().any(|&p| {
+        p > ptr[i] && p as usize - ptr[i] as usize <= 128
+    })).unwrap();
+    let old = ptr[i];
+    ptr[i] = cmpct_realloc(old, 512);
+    assert!(ptr[i] != null_mut() && ptr[i] != old);
+    assert!(cmpct_owns(ptr[i]) && !cmpct_owns(old));
+    check_on(ptr[i], 64);
+    assert!(cmpct_get_stats().alloc_count == before.alloc_count + 16);
+    for i in 0..16 {
+        cmpct_free(ptr[i]);
+    }
+    assert!(cmpct_get_stats().alloc_count == before.alloc_count);
+    println!(" Test: cmpct realloc ok!\n");
+}
+
+fn test_info() {
+    println!(" Test: heap info ...");
+    let before = cmpct_get_info();
+    assert!(before.size == cmpct_get_stats().size);
+    assert!(before.remaining == cmpct_get_stats().remaining);
+    assert!(before.remaining <= before.size);
+    /* Some free space is left in a heap which serves the kernel */
+    assert!(before.free_blocks.iter().sum::<usize>() > 0);
+
+    let mut ptr: [*mut u8; 8] = [null_mut(); 8];
+    for i in 0..8 {
+        ptr[i] = cmpct_alloc(100 * (i + 1));
+        assert!(ptr[i] != null_mut());
+    }
+    let info = cmpct_get_info();
+    assert!(info.size == before.size);
+    assert!(info.remaining < before.remaining);
+
+    /* Freeing gives all the memory back to the free lists */
+    for i in (0..8).rev() {
+        cmpct_free(ptr[i]);
+    }
+    let info = cmpct_get_info();
+    assert!(info.size == before.size);
+    assert!(info.remaining == before.remaining);
+    assert!(info.cached_os_alloc == before.cached_os_alloc);
+    println!(" Test: heap info ok!\n");
+}
+
+fn test_fixed_heap() {
+    println!(" Test: fixed heap ...");
+    const REGION_SIZE: usize = 64 * 1024;
+    let mut region = vec![0u64; REGION_SIZE / 8];
+    let area = region.as_mut_ptr() as vaddr_t;
+    let in_region = |ptr: *mut u8| {
+        ptr as vaddr_t >= area && (ptr as vaddr_t) < area + REGION_SIZE
+    };
+
+    /* The heap is big, so keep it off the stack; it mustn't move either */
+    let mut heap = Box::new(Heap::new_fixed());
+    heap.init();
+    assert!(heap.add_region(area + 4, REGION_SIZE - 8) == Err(ErrNO::InvalidArgs));
+    assert!(heap.add_region(area, 16) == Err(ErrNO::InvalidArgs));
+    assert!(heap.add_region(area, REGION_SIZE).is_ok());
+    let before = cmpct_heap_get_info(&heap);
+    assert!(before.size == REGION_SIZE && before.remaining > 0);
+    let kernel_before = cmpct_get_stats();
+
+    let mut ptr: [*mut u8; 16] = [null_mut(); 16];
+    for i in 0..16 {
+        ptr[i] = cmpct_heap_alloc(&mut heap, PMM_NODE_ANY, 64 * (i + 1));
+        assert!(in_region(ptr[i]));
+        fill_in(ptr[i], 64 * (i + 1));
+    }
+    assert!(cmpct_heap_get_stats(&heap).alloc_count == 16);
+
+    let aligned = cmpct_heap_memalign(&mut heap, 256, 100);
+    assert!(in_region(aligned) && aligned as usize % 256 == 0);
+    cmpct_heap_free(&mut heap, aligned);
+    ptr[3] = cmpct_heap_realloc(&mut heap, ptr[3], 2048);
+    assert!(in_region(ptr[3]));
+    check_on(ptr[3], 64 * 4);
+    ptr[3] = cmpct_heap_realloc(&mut heap, ptr[3], 64 * 4);
+
+    /* It never grows beyond the region */
+    assert!(cmpct_heap_alloc(&mut heap, PMM_NODE_ANY, REGION_SIZE).is_null());
+
+    for i in 0..16 {
+        check_on(ptr[i], 64 * (i + 1));
+        cmpct_heap_free(&mut heap, ptr[i]);
+    }
+    /* All free again, and the region stays in the heap */
+    let info = cmpct_heap_get_info(&heap);
+    assert!(info.size == before.size && info.remaining == before.remaining);
+    assert!(!info.cached_os_alloc);
+    assert!(cmpct_heap_get_stats(&heap).alloc_count == 0);
+    assert!(in_region(cmpct_heap_alloc(&mut heap, PMM_NODE_ANY, 128)));
+
+    /* The kernel heap had nothing to do with it */
+    assert!(cmpct_get_stats().alloc_count == kernel_before.alloc_count);
+    drop(heap);
+    drop(region);
+    println!(" Test: fixed heap ok!\n");
+}
+
+fn test_trim() {
+    println!(" Test: heap trim ...");
+    /* Plenty of free space in the heap, so the cache isn't needed */
+    let spare = cmpct_alloc(768 * 1024);
+    assert!(spare != null_mut());
+    let spare = cmpct_realloc(spare, 64);
+    assert!(cmpct_trim().is_ok());
+    assert!(!cmpct_get_info().cached_os_alloc);
+
+    /* Too big for any free block, so it takes an OS allocation of its own,
+     * which is cached once it's freed */
+    let big = cmpct_alloc(1000 * 1024);
+    assert!(big != null_mut());
+    cmpct_free(big);
+    let info = cmpct_get_info();
+    assert!(info.cached_os_alloc);
+
+    assert!(cmpct_trim().is_ok());
+    let trimmed = cmpct_get_info();
+    assert!(!trimmed.cached_os_alloc);
+    assert!(trimmed.size < info.size);
+    assert!(trimmed.remaining == info.remaining);
+
+    /* Nothing is left to trim */
+    assert!(cmpct_trim().is_ok());
+    assert!(cmpct_get_info().size == trimmed.size);
+    cmpct_free(spare);
+    println!(" Test: heap trim ok!\n");
+}
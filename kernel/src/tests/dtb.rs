@@ -0,0 +1,1580 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use core::ops::ControlFlow;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::format;
+use alloc::string::String;
+use device_tree::bootargs::BootArgs;
+use device_tree::noderef::NodeRef;
+use device_tree::util::SliceRead;
+use device_tree::{DEFAULT_MAX_DEPTH, DeviceTree, DeviceTreeBuilder,
+                  DeviceTreeError, OverlayError, PathError, PropError, PropValue, TreeChange};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+const FDT_HEADER_SIZE: usize = 40;
+
+/* A tiny builder of flattened device tree blobs for tests */
+pub struct FdtBlob {
+    reserved: Vec<(u64, u64)>,
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtBlob {
+    pub fn new() -> Self {
+        Self {
+            reserved: Vec::new(),
+            structs: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, val: u32) {
+        buf.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn pad(buf: &mut Vec<u8>) {
+        while buf.len() % 4 != 0 {
+            buf.push(0);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn reserve(&mut self, base: u64, size: u64) -> &mut Self {
+        self.reserved.push((base, size));
+        self
+    }
+
+    pub fn begin_node(&mut self, name: &str) -> &mut Self {
+        Self::push_u32(&mut self.structs, FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        Self::pad(&mut self.structs);
+        self
+    }
+
+    pub fn end_node(&mut self) -> &mut Self {
+        Self::push_u32(&mut self.structs, FDT_END_NODE);
+        self
+    }
+
+    pub fn prop(&mut self, name: &str, val: &[u8]) -> &mut Self {
+        let name_offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+
+        Self::push_u32(&mut self.structs, FDT_PROP);
+        Self::push_u32(&mut self.structs, val.len() as u32);
+        Self::push_u32(&mut self.structs, name_offset);
+        self.structs.extend_from_slice(val);
+        Self::pad(&mut self.structs);
+        self
+    }
+
+    pub fn prop_u32(&mut self, name: &str, val: u32) -> &mut Self {
+        self.prop(name, &val.to_be_bytes())
+    }
+
+    pub fn prop_str(&mut self, name: &str, val: &str) -> &mut Self {
+        let mut buf = Vec::from(val.as_bytes());
+        buf.push(0);
+        self.prop(name, &buf)
+    }
+
+    pub fn finish(&self) -> Vec<u8> {
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + (self.reserved.len() + 1) * 16;
+        let size_dt_struct = self.structs.len() + 4;
+        let off_dt_strings = off_dt_struct + size_dt_struct;
+        let totalsize = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(totalsize);
+        Self::push_u32(&mut blob, FDT_MAGIC);
+        Self::push_u32(&mut blob, totalsize as u32);
+        Self::push_u32(&mut blob, off_dt_struct as u32);
+        Self::push_u32(&mut blob, off_dt_strings as u32);
+        Self::push_u32(&mut blob, off_mem_rsvmap as u32);
+        Self::push_u32(&mut blob, 17);  /* version */
+        Self::push_u32(&mut blob, 16);  /* last_comp_version */
+        Self::push_u32(&mut blob, 0);   /* boot_cpuid_phys */
+        Self::push_u32(&mut blob, self.strings.len() as u32);
+        Self::push_u32(&mut blob, size_dt_struct as u32);
+
+        for &(base, size) in self.reserved.iter() {
+            blob.extend_from_slice(&base.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0u8; 16]);
+
+        blob.extend_from_slice(&self.structs);
+        Self::push_u32(&mut blob, FDT_END);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+pub fn patch_u32(blob: &mut Vec<u8>, pos: usize, val: u32) {
+    blob[pos..pos+4].copy_from_slice(&val.to_be_bytes());
+}
+
+pub fn test_dtb() {
+    test_timebase_frequency();
+    test_get_property();
+    test_verify();
+    test_uart_probe();
+    test_node_count();
+    test_props_iter();
+    test_try_find();
+    test_diff();
+    test_load_unaligned();
+    test_reg_named();
+    test_interrupts_of();
+    test_struct_bounds();
+    test_walk();
+    test_builder();
+    test_phandle_index();
+    test_bstring0_max();
+    test_apply_overlay();
+    test_max_depth();
+    test_prop_le();
+    test_gpio_spec();
+    test_prop_capacity();
+    test_bootargs();
+    test_cells();
+    test_version_16();
+    test_load_ref();
+    test_descendants();
+    test_prop_str_list();
+    test_compatible();
+    test_find_phandle();
+    test_prop_cells();
+    test_prop_out_of_bounds();
+    test_display();
+    test_store();
+    test_reserved_bounds();
+    test_memory_regions();
+    test_find_by_name();
+    test_resolve_alias();
+    test_read_be_sized();
+}
+
+fn test_timebase_frequency() {
+    println!(" Test: dtb timebase-frequency ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("cpus")
+                .prop_u32("timebase-frequency", 10000000)
+                .begin_node("cpu@0")
+                    .prop_str("device_type", "cpu")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    assert!(dt.timebase_frequency().unwrap() == 10000000);
+
+    /* Fall back to the per-cpu node */
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("cpus")
+                .begin_node("cpu@0")
+                    .prop_str("device_type", "cpu")
+                    .prop_u32("timebase-frequency", 10000000)
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    assert!(dt.timebase_frequency().unwrap() == 10000000);
+
+    /* No default frequency is assumed */
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("cpus")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    assert!(matches!(dt.timebase_frequency(), Err(PropError::NotFound)));
+    println!(" Test: dtb timebase-frequency ok!\n");
+}
+
+fn test_get_property() {
+    println!(" Test: dtb get_property ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop("ranges", &[])
+            .prop_u32("#address-cells", 2)
+            .prop("reg", &[0, 0, 0, 1, 0, 0, 0, 2])
+            .prop_str("model", "riscv-virtio,qemu")
+            .prop("compatible", b"sifive,uart0\0ns16550a\0")
+            .prop("mac", &[1, 2, 3, 4, 5, 6])
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let root = dt.find("/").unwrap();
+
+    assert!(root.get_property("ranges") == Some(PropValue::Empty));
+    assert!(root.get_property("#address-cells") == Some(PropValue::U32(2)));
+    assert!(root.get_property("reg") ==
+            Some(PropValue::U32Array(vec![1, 2])));
+    assert!(root.get_property("model") ==
+            Some(PropValue::Str(String::from("riscv-virtio,qemu"))));
+    assert!(root.get_property("compatible") ==
+            Some(PropValue::StrList(vec![String::from("sifive,uart0"),
+                                         String::from("ns16550a")])));
+    assert!(root.get_property("mac") ==
+            Some(PropValue::Bytes(vec![1, 2, 3, 4, 5, 6])));
+    assert!(root.get_property("none").is_none());
+    println!(" Test: dtb get_property ok!\n");
+}
+
+fn test_verify() {
+    println!(" Test: dtb verify ...");
+    let blob = FdtBlob::new()
+        .reserve(0x80000000, 0x200000)
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("chosen")
+            .end_node()
+        .end_node()
+        .finish();
+    assert!(DeviceTree::verify(&blob).is_ok());
+
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 0, 0xdeadbeef);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::InvalidMagicNumber)));
+
+    let mut bad = blob.clone();
+    bad.push(0);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::SizeMismatch)));
+
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 20, 15);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::VersionNotSupported)));
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 24, 18);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::VersionNotSupported)));
+
+    /* off_dt_strings is before off_dt_struct */
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 12, 48);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::ParseError(12))));
+
+    /* off_dt_struct is out of totalsize */
+    let mut bad = blob.clone();
+    let len = bad.len() as u32;
+    patch_u32(&mut bad, 8, len + 4);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::SizeMismatch)));
+
+    /* The terminator of reserved memory block is missing */
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, FDT_HEADER_SIZE + 16 + 12, 0x1000);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::ParseError(72))));
+
+    /* END_NODE is missing for root */
+    let bad = FdtBlob::new()
+        .begin_node("")
+            .begin_node("chosen")
+            .end_node()
+        .finish();
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::ParseError(_))));
+
+    /* Too many END_NODE */
+    let bad = FdtBlob::new()
+        .begin_node("")
+        .end_node()
+        .end_node()
+        .finish();
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::ParseError(_))));
+
+    /* Unknown token */
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, FDT_HEADER_SIZE + 32, 0x7);
+    assert!(matches!(DeviceTree::verify(&bad),
+                     Err(DeviceTreeError::ParseError(_))));
+    println!(" Test: dtb verify ok!\n");
+}
+
+fn test_uart_probe() {
+    println!(" Test: dtb uart probe ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .prop_u32("#size-cells", 2)
+            .begin_node("chosen")
+                .prop_str("stdout-path", "/soc/serial@10000000")
+            .end_node()
+            .begin_node("soc")
+                .begin_node("serial@10000000")
+                    .prop("reg", &[0, 0, 0, 0, 0x10, 0, 0, 0,
+                                   0, 0, 0, 0, 0, 0, 1, 0])
+                    .prop_u32("clock-frequency", 3686400)
+                    .prop_u32("interrupts", 10)
+                    .prop_str("compatible", "ns16550a")
+                .end_node()
+                .begin_node("serial@10001000")
+                    .prop("reg", &[0, 0, 0, 0, 0x10, 0, 0x10, 0,
+                                   0, 0, 0, 0, 0, 0, 1, 0])
+                    .prop_u32("interrupts", 11)
+                    .prop_str("compatible", "ns16550a")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    let path = dt.find("/chosen").unwrap().prop_str("stdout-path").unwrap();
+    let uart = dt.find(path).unwrap();
+    assert!(uart.prop_u64_at("reg", 0).unwrap() == 0x10000000);
+    assert!(uart.prop_u64_at("reg", 8).unwrap() == 0x100);
+    assert!(uart.prop_u32("interrupts").unwrap() == 10);
+    assert!(uart.prop_u32_or("clock-frequency", 1843200) == 3686400);
+
+    /* Missing clock-frequency falls back to the default */
+    let uart = dt.find("/soc/serial@10001000").unwrap();
+    assert!(uart.prop_u64_at("reg", 0).unwrap() == 0x10001000);
+    assert!(uart.prop_u32("interrupts").unwrap() == 11);
+    assert!(uart.prop_u32_or("clock-frequency", 1843200) == 1843200);
+    assert!(matches!(uart.prop_u32("clock-frequency"),
+                     Err(PropError::NotFound)));
+    println!(" Test: dtb uart probe ok!\n");
+}
+
+fn test_node_count() {
+    println!(" Test: dtb node count ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("cpus")
+                .begin_node("cpu@0")
+                .end_node()
+                .begin_node("cpu@1")
+                .end_node()
+            .end_node()
+            .begin_node("chosen")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    assert!(dt.node_count() == 5);
+
+    let root = dt.find("/").unwrap();
+    assert!(root.child_count() == 2);
+    assert!(!root.is_leaf());
+    let cpus = dt.find("/cpus").unwrap();
+    assert!(cpus.child_count() == 2);
+    assert!(cpus.node_count() == 3);
+    let cpu = dt.find("/cpus/cpu@1").unwrap();
+    assert!(cpu.is_leaf());
+    assert!(cpu.child_count() == 0);
+    println!(" Test: dtb node count ok!\n");
+}
+
+fn test_props_iter() {
+    println!(" Test: dtb props_iter ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("memory@80000000")
+                .prop_str("device_type", "memory")
+                .prop("reg", &[0, 0, 0, 0, 0x80, 0, 0, 0])
+                .prop("dma-coherent", &[])
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let mem = dt.find("/memory@80000000").unwrap();
+
+    let props: Vec<(&str, &[u8])> = mem.props_iter().collect();
+    assert!(props.len() == 3);
+    assert!(props[0] == ("device_type", &b"memory\0"[..]));
+    assert!(props[1] == ("reg", &[0, 0, 0, 0, 0x80, 0, 0, 0][..]));
+    assert!(props[2] == ("dma-coherent", &[][..]));
+
+    let root = dt.find("/").unwrap();
+    assert!(root.props_iter().next().is_none());
+    println!(" Test: dtb props_iter ok!\n");
+}
+
+fn test_try_find() {
+    println!(" Test: dtb try_find ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("foo")
+                .begin_node("bar")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    assert!(dt.try_find("/").unwrap().unwrap().name == "");
+    assert!(dt.try_find("/foo").unwrap().unwrap().name == "foo");
+    assert!(dt.try_find("/foo/bar").unwrap().unwrap().name == "bar");
+    assert!(dt.try_find("/baz").unwrap().is_none());
+    assert!(dt.try_find("/foo/baz").unwrap().is_none());
+
+    assert!(dt.try_find("//foo").unwrap_err() == PathError::EmptyComponent);
+    assert!(dt.try_find("/foo//bar").unwrap_err() == PathError::EmptyComponent);
+    assert!(dt.try_find("/foo/").unwrap_err() == PathError::TrailingSlash);
+    assert!(dt.try_find("foo").unwrap_err() == PathError::NotAbsolute);
+    assert!(dt.try_find("").unwrap_err() == PathError::NotAbsolute);
+
+    /* find remains the convenience of them */
+    assert!(dt.find("/foo/bar").is_some());
+    assert!(dt.find("foo").is_none());
+    println!(" Test: dtb try_find ok!\n");
+}
+
+fn test_diff() {
+    println!(" Test: dtb diff ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("soc")
+                .begin_node("uart@10000000")
+                    .prop_str("compatible", "ns16550a")
+                    .prop_u32("clock-frequency", 3686400)
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let mut patched = DeviceTree::load(&blob).unwrap();
+    assert!(dt == patched);
+    assert!(dt.diff(&patched).is_empty());
+
+    let uart = &mut patched.root.children[0].children[0];
+    for (key, val) in uart.props.iter_mut() {
+        if key == "clock-frequency" {
+            *val = Vec::from(&1843200u32.to_be_bytes()[..]);
+        }
+    }
+    assert!(dt != patched);
+    assert!(dt.diff(&patched) ==
+            vec![TreeChange::PropChanged(String::from("/soc/uart@10000000"),
+                                         String::from("clock-frequency"))]);
+
+    /* Siblings of the same name are compared by position */
+    let twins = |second: u32| {
+        DeviceTreeBuilder::new()
+            .begin_node("")
+                .begin_node("cpu")
+                    .prop_u32("reg", 0)
+                .end_node()
+                .begin_node("cpu")
+                    .prop_u32("reg", second)
+                .end_node()
+            .end_node()
+            .build()
+    };
+    assert!(twins(0) != twins(1));
+    assert!(twins(1) == twins(1));
+    println!(" Test: dtb diff ok!\n");
+}
+
+fn test_load_unaligned() {
+    println!(" Test: dtb load unaligned ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("chosen")
+                .prop_str("bootargs", "console=ttyS0")
+            .end_node()
+        .end_node()
+        .finish();
+
+    /* Place the blob at an address which isn't 4-aligned */
+    let mut buf = vec![0u8; blob.len() + 4];
+    let off = if (buf.as_ptr() as usize + 1) % 4 == 0 { 2 } else { 1 };
+    buf[off..off+blob.len()].copy_from_slice(&blob);
+    let unaligned = &buf[off..off+blob.len()];
+    assert!(unaligned.as_ptr() as usize % 4 != 0);
+
+    let dt = DeviceTree::load_unaligned(unaligned).unwrap();
+    assert!(dt == DeviceTree::load(&blob).unwrap());
+    let chosen = dt.find("/chosen").unwrap();
+    assert!(chosen.prop_str("bootargs").unwrap() == "console=ttyS0");
+    println!(" Test: dtb load unaligned ok!\n");
+}
+
+fn test_reg_named() {
+    println!(" Test: dtb reg_named ...");
+    let mut reg = Vec::new();
+    for cell in [0u32, 0x1000_0000, 0, 0x100, 0, 0x1000_2000, 0, 0x40] {
+        reg.extend_from_slice(&cell.to_be_bytes());
+    }
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("dma@10000000")
+                .prop("reg", &reg)
+                .prop("reg-names", b"ctrl\0data\0")
+            .end_node()
+            .begin_node("bad@20000000")
+                /* Only one bank for two names */
+                .prop("reg", &reg[..16])
+                .prop("reg-names", b"ctrl\0data\0")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    let dma = dt.find("/dma@10000000").unwrap();
+    assert!(dma.reg_named("data", 2, 2) == Some((0x1000_2000, 0x40)));
+    assert!(dma.reg_named("ctrl", 2, 2) == Some((0x1000_0000, 0x100)));
+    assert!(dma.reg_named("irq", 2, 2).is_none());
+
+    let bad = dt.find("/bad@20000000").unwrap();
+    assert!(bad.reg_named("ctrl", 2, 2).is_some());
+    assert!(bad.reg_named("data", 2, 2).is_none());
+    println!(" Test: dtb reg_named ok!\n");
+}
+
+fn test_interrupts_of() {
+    println!(" Test: dtb interrupts_of ...");
+    let mut irqs = Vec::new();
+    for cell in [5u32, 4, 6, 1] {
+        irqs.extend_from_slice(&cell.to_be_bytes());
+    }
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("soc")
+                .prop_u32("interrupt-parent", 1)
+                .begin_node("intc@c000000")
+                    .prop_u32("phandle", 1)
+                    .prop_u32("#interrupt-cells", 2)
+                    .prop("interrupt-controller", &[])
+                .end_node()
+                .begin_node("eth@10010000")
+                    .prop("interrupts", &irqs)
+                .end_node()
+                .begin_node("bad@10020000")
+                    .prop("interrupts", &irqs[..12])
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    /* interrupt-parent is inherited from soc */
+    let eth = dt.find("/soc/eth@10010000").unwrap();
+    let specs = dt.interrupts_of(eth).unwrap();
+    assert!(specs == vec![vec![5, 4], vec![6, 1]]);
+
+    let bad = dt.find("/soc/bad@10020000").unwrap();
+    assert!(dt.interrupts_of(bad).is_err());
+    let intc = dt.find("/soc/intc@c000000").unwrap();
+    assert!(matches!(dt.interrupts_of(intc), Err(PropError::NotFound)));
+    println!(" Test: dtb interrupts_of ok!\n");
+}
+
+fn test_struct_bounds() {
+    println!(" Test: dtb struct bounds ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("chosen")
+            .end_node()
+        .end_node()
+        .finish();
+    assert!(DeviceTree::load(&blob).is_ok());
+    let size_dt_struct = u32::from_be_bytes(blob[36..40].try_into().unwrap());
+
+    /* The END_NODE of root lies beyond the declared end of structure */
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 36, size_dt_struct - 8);
+    assert!(matches!(DeviceTree::load(&bad),
+                     Err(DeviceTreeError::ParseError(_))));
+
+    /* The structure block runs out of the blob */
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 36, blob.len() as u32);
+    assert!(matches!(DeviceTree::load(&bad),
+                     Err(DeviceTreeError::SizeMismatch)));
+
+    /* The name of property lies beyond the declared end of strings */
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 32, 0);
+    assert!(DeviceTree::load(&bad).is_err());
+    println!(" Test: dtb struct bounds ok!\n");
+}
+
+fn test_walk() {
+    println!(" Test: dtb walk ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("cpus")
+                .begin_node("cpu@0")
+                .end_node()
+                .begin_node("cpu@1")
+                .end_node()
+            .end_node()
+            .begin_node("chosen")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    /* Pre-order with the depth of each node */
+    let mut visited = Vec::new();
+    let ret = dt.root.walk(&mut |node, depth| {
+        visited.push((String::from(node.name.as_str()), depth));
+        ControlFlow::Continue(())
+    });
+    assert!(ret == ControlFlow::Continue(()));
+    assert!(visited == vec![(String::from(""), 0), (String::from("cpus"), 1),
+                            (String::from("cpu@0"), 2),
+                            (String::from("cpu@1"), 2),
+                            (String::from("chosen"), 1)]);
+
+    /* Stop after visiting 3 nodes */
+    let mut names = Vec::new();
+    let ret = dt.root.walk(&mut |node, _| {
+        names.push(String::from(node.name.as_str()));
+        if names.len() == 3 {
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    });
+    assert!(ret == ControlFlow::Break(()));
+    assert!(names == vec!["", "cpus", "cpu@0"]);
+    println!(" Test: dtb walk ok!\n");
+}
+
+fn test_builder() {
+    println!(" Test: dtb builder ...");
+    let dt = DeviceTreeBuilder::new()
+        .reserve(0x80000000, 0x200000)
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("chosen")
+                .prop("bootargs", b"console=ttyS0\0")
+            .end_node()
+            .begin_node("cpus")
+                .begin_node("cpu@0")
+                .end_node()
+            .end_node()
+        .end_node()
+        .build();
+
+    /* The same tree as the one loaded from the equivalent blob */
+    let blob = FdtBlob::new()
+        .reserve(0x80000000, 0x200000)
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("chosen")
+                .prop_str("bootargs", "console=ttyS0")
+            .end_node()
+            .begin_node("cpus")
+                .begin_node("cpu@0")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    assert!(dt == DeviceTree::load(&blob).unwrap());
+
+    assert!(dt.node_count() == 4);
+    assert!(dt.root.prop_u32("#address-cells").unwrap() == 2);
+    let chosen = dt.find("/chosen").unwrap();
+    assert!(chosen.prop_str("bootargs").unwrap() == "console=ttyS0");
+    assert!(dt.find("/cpus/cpu@0").unwrap().is_leaf());
+    println!(" Test: dtb builder ok!\n");
+}
+
+fn test_phandle_index() {
+    println!(" Test: dtb phandle index ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("intc")
+                .prop_u32("phandle", 1)
+            .end_node()
+            .begin_node("clocks")
+                .begin_node("osc")
+                    .prop_u32("linux,phandle", 7)
+                .end_node()
+                .begin_node("pll")
+                    .prop_u32("phandle", 3)
+                .end_node()
+            .end_node()
+            .begin_node("dup")
+                .prop_u32("phandle", 3)
+            .end_node()
+            .begin_node("chosen")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    assert!(dt.find("/intc").unwrap().phandle() == Some(1));
+    assert!(dt.find("/clocks/osc").unwrap().phandle() == Some(7));
+    assert!(dt.find("/chosen").unwrap().phandle().is_none());
+
+    let index = dt.phandle_index();
+    assert!(index.len() == 3);
+    assert!(index[&1].name == "intc");
+    assert!(index[&7].name == "osc");
+    /* The first one in depth-first order wins */
+    assert!(index[&3].name == "pll");
+    assert!(index.get(&2).is_none());
+    println!(" Test: dtb phandle index ok!\n");
+}
+
+fn test_bstring0_max() {
+    println!(" Test: dtb read_bstring0_max ...");
+    let buf: &[u8] = b"cpu\0chosen";
+    assert!(buf.read_bstring0_max(0, 4).unwrap() == b"cpu");
+    assert!(buf.read_bstring0_max(0, 3).is_err());
+    /* No NUL till the end of buffer, whatever the limit */
+    assert!(buf.read_bstring0_max(4, 100).is_err());
+    assert!(buf.read_bstring0(4).is_err());
+
+    /* The structure block ends in the middle of a node name */
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("chosen")
+            .end_node()
+        .end_node()
+        .finish();
+    let off_dt_struct = u32::from_be_bytes(blob[8..12].try_into().unwrap());
+    let name = blob.windows(6).position(|w| w == b"chosen").unwrap() as u32;
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, 36, name + 3 - off_dt_struct);
+    assert!(matches!(DeviceTree::load(&bad),
+                     Err(DeviceTreeError::ParseError(_))));
+    println!(" Test: dtb read_bstring0_max ok!\n");
+}
+
+fn test_apply_overlay() {
+    println!(" Test: dtb apply overlay ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("soc")
+                .begin_node("uart@10000000")
+                    .prop_str("compatible", "ns16550a")
+                .end_node()
+                .begin_node("eth@10010000")
+                    .prop_u32("phandle", 5)
+                    .prop_str("status", "disabled")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let mut dt = DeviceTree::load(&blob).unwrap();
+
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("fragment@0")
+                .prop_str("target-path", "/soc/uart@10000000")
+                .begin_node("__overlay__")
+                    .prop_u32("current-speed", 115200)
+                .end_node()
+            .end_node()
+            .begin_node("fragment@1")
+                .prop_u32("target", 5)
+                .begin_node("__overlay__")
+                    .prop_str("status", "okay")
+                    .begin_node("phy@0")
+                    .end_node()
+                .end_node()
+            .end_node()
+            .begin_node("__fixups__")
+            .end_node()
+        .end_node()
+        .finish();
+    let overlay = DeviceTree::load(&blob).unwrap();
+    assert!(dt.apply_overlay(&overlay).is_ok());
+
+    /* A property is added, and the other one is overridden */
+    let uart = dt.find("/soc/uart@10000000").unwrap();
+    assert!(uart.prop_u32("current-speed").unwrap() == 115200);
+    assert!(uart.prop_str("compatible").unwrap() == "ns16550a");
+    let eth = dt.find("/soc/eth@10010000").unwrap();
+    assert!(eth.prop_str("status").unwrap() == "okay");
+    assert!(eth.props.len() == 2);
+    assert!(dt.find("/soc/eth@10010000/phy@0").is_some());
+    assert!(dt.find("/__fixups__").is_none());
+
+    /* A bad fragment leaves the tree unchanged */
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("fragment@0")
+                .prop_str("target-path", "/soc/uart@10000000")
+                .begin_node("__overlay__")
+                    .prop_u32("current-speed", 9600)
+                .end_node()
+            .end_node()
+            .begin_node("fragment@1")
+                .prop_str("target-path", "/soc/i2c@10020000")
+                .begin_node("__overlay__")
+                .end_node()
+            .end_node()
+            .begin_node("fragment@2")
+                .begin_node("__overlay__")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let overlay = DeviceTree::load(&blob).unwrap();
+    assert!(dt.apply_overlay(&overlay) ==
+            Err(OverlayError::TargetNotFound(String::from("fragment@1"))));
+    let uart = dt.find("/soc/uart@10000000").unwrap();
+    assert!(uart.prop_u32("current-speed").unwrap() == 115200);
+
+    let mut overlay = overlay;
+    overlay.root.children.remove(1);
+    assert!(dt.apply_overlay(&overlay) ==
+            Err(OverlayError::NoTarget(String::from("fragment@2"))));
+    println!(" Test: dtb apply overlay ok!\n");
+}
+
+/* A chain of nodes nested |depth| levels below the root */
+fn nested_blob(depth: usize) -> Vec<u8> {
+    let mut blob = FdtBlob::new();
+    blob.begin_node("");
+    for _ in 0..depth {
+        blob.begin_node("n");
+    }
+    for _ in 0..=depth {
+        blob.end_node();
+    }
+    blob.finish()
+}
+
+fn test_max_depth() {
+    println!(" Test: dtb max depth ...");
+    assert!(DeviceTree::load(&nested_blob(DEFAULT_MAX_DEPTH)).is_ok());
+    assert!(matches!(DeviceTree::load(&nested_blob(DEFAULT_MAX_DEPTH + 1)),
+                     Err(DeviceTreeError::ParseError(_))));
+
+    /* Far deeper than the kernel stack could take */
+    assert!(matches!(DeviceTree::load(&nested_blob(100000)),
+                     Err(DeviceTreeError::ParseError(_))));
+
+    let blob = nested_blob(3);
+    assert!(DeviceTree::load_with_max_depth(&blob, 3).is_ok());
+    assert!(DeviceTree::load_with_max_depth(&blob, 2).is_err());
+    let dt = DeviceTree::load_with_max_depth(&blob, 3).unwrap();
+    assert!(dt.find("/n/n/n").is_some());
+    println!(" Test: dtb max depth ok!\n");
+}
+
+fn test_prop_le() {
+    println!(" Test: dtb little-endian props ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop("vendor,blob", &[0xaa, 0x78, 0x56, 0x34, 0x12,
+                                   0x01, 0x02, 0x03, 0x04,
+                                   0x05, 0x06, 0x07, 0x08])
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let root = &dt.root;
+
+    assert!(root.prop_le_u32_at("vendor,blob", 1).unwrap() == 0x1234_5678);
+    assert!(root.prop_u32_at("vendor,blob", 1).unwrap() == 0x7856_3412);
+    assert!(root.prop_le_u64_at("vendor,blob", 5).unwrap() ==
+            0x0807_0605_0403_0201);
+    assert!(root.prop_raw_at("vendor,blob", 1, 4).unwrap() ==
+            &[0x78, 0x56, 0x34, 0x12]);
+
+    /* Out of the value, or no such property */
+    assert!(root.prop_le_u32_at("vendor,blob", 10).is_err());
+    assert!(root.prop_le_u64_at("vendor,blob", 6).is_err());
+    assert!(root.prop_raw_at("vendor,blob", 10, 4).is_err());
+    assert!(matches!(root.prop_le_u32_at("vendor,none", 0),
+                     Err(PropError::NotFound)));
+    println!(" Test: dtb little-endian props ok!\n");
+}
+
+fn test_gpio_spec() {
+    println!(" Test: dtb gpio_spec ...");
+    let mut gpios = Vec::new();
+    for cell in [1u32, 5, 0, 0, 2, 7, 1] {
+        gpios.extend_from_slice(&cell.to_be_bytes());
+    }
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("gpio@10060000")
+                .prop_u32("phandle", 1)
+                .prop_u32("#gpio-cells", 2)
+                .prop("gpio-controller", &[])
+            .end_node()
+            .begin_node("gpio@10070000")
+                .prop_u32("phandle", 2)
+                .prop_u32("#gpio-cells", 2)
+                .prop("gpio-controller", &[])
+            .end_node()
+            .begin_node("leds")
+                .prop("led-gpios", &gpios)
+                .prop("short-gpios", &gpios[..8])
+                .prop("stray-gpios", &[0, 0, 0, 9])
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let leds = dt.find("/leds").unwrap();
+
+    /* The empty entry (phandle 0) in the middle is skipped */
+    let specs = dt.gpio_spec(leds, "led-gpios").unwrap();
+    assert!(specs.len() == 2);
+    assert!(specs[0].node.name == "gpio@10060000");
+    assert!(specs[0].args == vec![5, 0]);
+    assert!(specs[1].node.name == "gpio@10070000");
+    assert!(specs[1].args == vec![7, 1]);
+
+    /* Truncated specifier, unknown phandle and missing property */
+    assert!(dt.gpio_spec(leds, "short-gpios").is_err());
+    assert!(matches!(dt.gpio_spec(leds, "stray-gpios"),
+                     Err(PropError::NotFound)));
+    assert!(matches!(dt.gpio_spec(leds, "none-gpios"),
+                     Err(PropError::NotFound)));
+    println!(" Test: dtb gpio_spec ok!\n");
+}
+
+fn test_prop_capacity() {
+    println!(" Test: dtb prop capacity ...");
+    let names: Vec<String> = (0..100).map(|i| format!("vendor,prop{}", i)).collect();
+    let mut blob = FdtBlob::new();
+    blob.begin_node("");
+    for (i, name) in names.iter().enumerate() {
+        blob.prop_u32(name, i as u32);
+    }
+    let blob = blob.end_node().finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    /* All props are read into a vec allocated once, without regrowth */
+    assert!(dt.root.prop_count() == 100);
+    assert!(dt.root.props.capacity() == 100);
+    assert!(dt.root.prop_u32("vendor,prop99").unwrap() == 99);
+    println!(" Test: dtb prop capacity ok!\n");
+}
+
+fn test_bootargs() {
+    println!(" Test: dtb bootargs ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("chosen")
+                .prop_str("bootargs", "console=ttyS0 root=/dev/vda")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let mut args = BootArgs::from_tree(&dt);
+    assert!(args.len() == 2);
+    assert!(args.get("console") == Some("ttyS0"));
+    assert!(args.get("root") == Some("/dev/vda"));
+    assert!(args.get("quiet").is_none());
+
+    /* Override in place, and append what's new */
+    args.set("console", Some("hvc0"));
+    args.append("quiet root=/dev/vdb");
+    assert!(args.get("quiet") == Some("") && args.contains("quiet"));
+    assert!(format!("{}", args) == "console=hvc0 root=/dev/vdb quiet");
+
+    /* A later duplicate wins, and set folds them into one */
+    let mut args = BootArgs::parse("  a=1 b a=2\t");
+    assert!(args.get("a") == Some("2"));
+    args.set("a", Some("3"));
+    assert!(format!("{}", args) == "a=3 b");
+    assert!(args.remove("b") && !args.remove("b"));
+    assert!(BootArgs::parse("").is_empty());
+    println!(" Test: dtb bootargs ok!\n");
+}
+
+fn test_cells() {
+    println!(" Test: dtb cells ...");
+    let mut reg = Vec::new();
+    for (addr, size) in [(0x1_8000_0000u64, 0x1000u32), (0x2000, 0x20)] {
+        reg.extend_from_slice(&addr.to_be_bytes());
+        reg.extend_from_slice(&size.to_be_bytes());
+    }
+    reg.extend_from_slice(&[0, 0]);
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("memory@80000000")
+                .prop("reg", &reg)
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let node = dt.find("/memory@80000000").unwrap();
+
+    /* 2-cell addresses with 1-cell sizes, read in place */
+    let mut cells = node.cells("reg", 1).unwrap();
+    assert!(cells.remaining() == 6);
+    assert!(cells.read(2) == Some(0x1_8000_0000) && cells.read(1) == Some(0x1000));
+    assert!(cells.read(2) == Some(0x2000) && cells.read(1) == Some(0x20));
+    /* Only half a cell is left */
+    assert!(cells.is_empty() && cells.read(1).is_none());
+
+    /* As plain iterators of either width */
+    assert!(node.cells("reg", 1).unwrap().sum::<u64>() ==
+            1 + 0x8000_0000 + 0x1000 + 0x2000 + 0x20);
+    let wide = node.cells("reg", 2).unwrap();
+    assert!(wide.len() == 3);
+    assert!(wide.last() == Some(0x2000_0000_0020));
+    assert!(matches!(node.cells("none", 1), Err(PropError::NotFound)));
+    println!(" Test: dtb cells ok!\n");
+}
+
+/* Turn a blob from FdtBlob into a version 16 one, whose header ends
+ * before size_dt_struct. */
+fn to_version_16(blob: &[u8]) -> Vec<u8> {
+    let off = |pos: usize| u32::from_be_bytes(blob[pos..pos+4].try_into().unwrap());
+    let mut v16 = Vec::new();
+    v16.extend_from_slice(&blob[..36]);
+    v16.extend_from_slice(&blob[FDT_HEADER_SIZE..]);
+    let totalsize = v16.len() as u32;
+    patch_u32(&mut v16, 4, totalsize);
+    for pos in [8, 12, 16] {
+        patch_u32(&mut v16, pos, off(pos) - 4);
+    }
+    patch_u32(&mut v16, 20, 16);
+    v16
+}
+
+fn test_version_16() {
+    println!(" Test: dtb version 16 ...");
+    let blob = FdtBlob::new()
+        .reserve(0x80000000, 0x200000)
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("chosen")
+                .prop_str("bootargs", "console=ttyS0")
+            .end_node()
+        .end_node()
+        .finish();
+    let v16 = to_version_16(&blob);
+    assert!(DeviceTree::verify(&v16).is_ok());
+
+    let dt = DeviceTree::load(&v16).unwrap();
+    assert!(dt.version == 16);
+    assert!(dt.reserved == vec![(0x80000000, 0x200000), (0, 0)]);
+    assert!(dt.root.prop_u32("#address-cells").unwrap() == 2);
+    assert!(dt.find("/chosen").unwrap().prop_str("bootargs").unwrap() ==
+            "console=ttyS0");
+    assert!(dt.root == DeviceTree::load(&blob).unwrap().root);
+
+    /* Not compatible with v17 any more */
+    let mut bad = v16.clone();
+    patch_u32(&mut bad, 24, 18);
+    assert!(matches!(DeviceTree::load(&bad),
+                     Err(DeviceTreeError::VersionNotSupported)));
+    println!(" Test: dtb version 16 ok!\n");
+}
+
+fn test_load_ref() {
+    println!(" Test: dtb load_ref ...");
+    let blob = FdtBlob::new()
+        .reserve(0x80000000, 0x200000)
+        .reserve(0x90000000, 0x1000)
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("cpus")
+                .prop_u32("timebase-frequency", 10000000)
+                .begin_node("cpu@0")
+                    .begin_node("interrupt-controller")
+                    .end_node()
+                .end_node()
+                .begin_node("cpu@1")
+                .end_node()
+            .end_node()
+            .begin_node("chosen")
+                .prop_str("bootargs", "console=ttyS0")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load_ref(&blob).unwrap();
+    assert!(dt.version == 17);
+    assert!(dt.reserved().eq([(0x80000000, 0x200000), (0x90000000, 0x1000)]));
+
+    /* Names and values point right into the blob */
+    let chosen = dt.find("/chosen").unwrap();
+    let bootargs = chosen.prop_str("bootargs").unwrap();
+    assert!(bootargs == "console=ttyS0");
+    let range = blob.as_ptr_range();
+    assert!(range.contains(&bootargs.as_ptr()) && range.contains(&chosen.name.as_ptr()));
+
+    /* Siblings are found past the subtrees before them */
+    let names: Vec<&str> = dt.root.children().map(|n| n.name).collect();
+    assert!(names == ["cpus", "chosen"]);
+    let cpus = dt.find("/cpus").unwrap();
+    assert!(cpus.children().map(|n: NodeRef| n.name).eq(["cpu@0", "cpu@1"]));
+    assert!(cpus.prop_u32("timebase-frequency").unwrap() == 10000000);
+    assert!(dt.find("/cpus/cpu@0/interrupt-controller").is_some());
+    assert!(dt.find("/cpus/cpu@2").is_none() && dt.find("cpus").is_none());
+    assert!(dt.root.props().eq([("#address-cells", &[0u8, 0, 0, 2][..])]));
+    assert!(dt.root.prop_raw("none").is_none());
+
+    /* The same checks as load */
+    let owned = DeviceTree::load(&blob).unwrap();
+    assert!(owned.node_count() == 6);
+    let mut bad = blob.clone();
+    let size_dt_struct = u32::from_be_bytes(blob[36..40].try_into().unwrap());
+    patch_u32(&mut bad, 36, size_dt_struct - 8);
+    assert!(matches!(DeviceTree::load_ref(&bad),
+                     Err(DeviceTreeError::ParseError(_))));
+    assert!(DeviceTree::load(&bad).is_err());
+    println!(" Test: dtb load_ref ok!\n");
+}
+
+fn test_descendants() {
+    println!(" Test: dtb descendants ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("soc")
+                .begin_node("bus@0")
+                    .begin_node("uart@0")
+                    .end_node()
+                .end_node()
+                .begin_node("bus@1")
+                .end_node()
+            .end_node()
+            .begin_node("cpus")
+                .begin_node("cpu@0")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    /* Depth-first pre-order, the same as walk */
+    let names: Vec<&str> = dt.nodes().map(|n| n.name.as_str()).collect();
+    assert!(names == ["", "soc", "bus@0", "uart@0", "bus@1", "cpus", "cpu@0"]);
+    let mut walked = Vec::new();
+    let _ = dt.root.walk(&mut |node, _| {
+        walked.push(String::from(node.name.as_str()));
+        ControlFlow::Continue(())
+    });
+    assert!(walked == names);
+
+    /* A subtree doesn't include the node itself */
+    let soc = dt.find("/soc").unwrap();
+    assert!(soc.descendants().map(|n| n.name.as_str())
+            .eq(["bus@0", "uart@0", "bus@1"]));
+    assert!(dt.find("/cpus/cpu@0").unwrap().descendants().next().is_none());
+    assert!(dt.nodes().count() == dt.node_count());
+    println!(" Test: dtb descendants ok!\n");
+}
+
+fn test_prop_str_list() {
+    println!(" Test: dtb prop_str_list ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("serial@10010000")
+                .prop("compatible", b"sifive,uart0\0sifive,uart\0")
+                .prop_str("status", "okay")
+                .prop("no-nul", b"sifive,uart0")
+                .prop("bad-utf8", &[b'a', 0, 0xff, 0])
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let uart = dt.find("/serial@10010000").unwrap();
+
+    let compat = uart.prop_str_list("compatible").unwrap();
+    assert!(compat.eq(["sifive,uart0", "sifive,uart"]));
+    assert!(uart.prop_str_list("compatible").unwrap().any(|s| s == "sifive,uart"));
+    assert!(uart.prop_str_list("status").unwrap().eq(["okay"]));
+
+    assert!(matches!(uart.prop_str_list("no-nul"), Err(PropError::Missing0)));
+    assert!(matches!(uart.prop_str_list("bad-utf8"), Err(PropError::Utf8Error)));
+    assert!(matches!(uart.prop_str_list("none"), Err(PropError::NotFound)));
+    println!(" Test: dtb prop_str_list ok!\n");
+}
+
+fn test_compatible() {
+    println!(" Test: dtb compatible ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop_str("compatible", "riscv-virtio")
+            .begin_node("soc")
+                .begin_node("serial@10000000")
+                    .prop_str("compatible", "ns16550a")
+                .end_node()
+                .begin_node("serial@10010000")
+                    .prop("compatible", b"sifive,uart0\0ns16550a\0")
+                .end_node()
+                .begin_node("plic@c000000")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    let uart = dt.find("/soc/serial@10010000").unwrap();
+    assert!(uart.is_compatible("sifive,uart0") && uart.is_compatible("ns16550a"));
+    assert!(!uart.is_compatible("sifive") && !uart.is_compatible(""));
+    let plic = dt.find("/soc/plic@c000000").unwrap();
+    assert!(!plic.is_compatible("ns16550a") && !plic.is_compatible(""));
+
+    let names: Vec<&str> = dt.find_compatible("ns16550a")
+        .map(|n| n.name.as_str()).collect();
+    assert!(names == ["serial@10000000", "serial@10010000"]);
+    assert!(dt.find_compatible("riscv-virtio").next().unwrap().name == "");
+    assert!(dt.find_compatible("none").next().is_none());
+    println!(" Test: dtb compatible ok!\n");
+}
+
+fn test_find_phandle() {
+    println!(" Test: dtb find_phandle ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("soc")
+                .begin_node("plic@c000000")
+                    .prop_u32("phandle", 9)
+                .end_node()
+            .end_node()
+            .begin_node("osc")
+                .prop_u32("linux,phandle", 4)
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    assert!(dt.find_phandle(9).unwrap().name == "plic@c000000");
+    /* Older trees only have linux,phandle */
+    assert!(dt.find_phandle(4).unwrap().name == "osc");
+    assert!(dt.find_phandle(5).is_none());
+    assert!(dt.find_phandle(9) == dt.phandle_index().get(&9).copied());
+    println!(" Test: dtb find_phandle ok!\n");
+}
+
+fn test_prop_cells() {
+    println!(" Test: dtb prop_cells ...");
+    let mut ranges = Vec::new();
+    for cell in [0u32, 0x1000_0000, 0, 0x1000_0000, 0x100_0000] {
+        ranges.extend_from_slice(&cell.to_be_bytes());
+    }
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("soc")
+                .prop("ranges", &ranges)
+                .prop("reg", &ranges[..16])
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let soc = dt.find("/soc").unwrap();
+
+    assert!(soc.prop_cells("ranges", 1).unwrap()
+            .eq([0, 0x1000_0000, 0, 0x1000_0000, 0x100_0000]));
+    assert!(soc.prop_cells("reg", 2).unwrap().eq([0x1000_0000, 0x1000_0000]));
+
+    /* 5 cells don't make whole 2-cell values */
+    assert!(soc.prop_cells("ranges", 2).is_err());
+    assert!(soc.cells("ranges", 2).unwrap().count() == 2);
+    assert!(matches!(soc.prop_cells("none", 1), Err(PropError::NotFound)));
+    println!(" Test: dtb prop_cells ok!\n");
+}
+
+fn test_prop_out_of_bounds() {
+    println!(" Test: dtb prop out of bounds ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop("short", &[0, 0, 0, 1, 0, 0])
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let root = &dt.root;
+
+    assert!(root.prop_u32_at("short", 0).unwrap() == 1);
+    assert!(matches!(root.prop_u32_at("short", 4),
+                     Err(PropError::OutOfBounds { len: 6, pos: 4, needed: 4 })));
+    assert!(matches!(root.prop_u64_at("short", 0),
+                     Err(PropError::OutOfBounds { len: 6, pos: 0, needed: 8 })));
+    assert!(matches!(root.prop_le_u32_at("short", 3),
+                     Err(PropError::OutOfBounds { len: 6, pos: 3, needed: 4 })));
+    /* An offset far out doesn't overflow */
+    assert!(matches!(root.prop_u32_at("short", usize::MAX),
+                     Err(PropError::OutOfBounds { pos: usize::MAX, .. })));
+    assert!(matches!(root.prop_u32_at("none", 0), Err(PropError::NotFound)));
+
+    let dt = DeviceTree::load_ref(&blob).unwrap();
+    assert!(matches!(dt.root.prop_u32_at("short", 4),
+                     Err(PropError::OutOfBounds { len: 6, pos: 4, needed: 4 })));
+    println!(" Test: dtb prop out of bounds ok!\n");
+}
+
+fn test_display() {
+    println!(" Test: dtb display ...");
+    let blob = FdtBlob::new()
+        .reserve(0x80000000, 0x200000)
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("chosen")
+                .prop_str("bootargs", "console=\"ttyS0\"")
+                .prop("compatible", b"a,b\0c\0")
+            .end_node()
+            .begin_node("soc")
+                .prop("ranges", &[])
+                .prop("mac", &[0x52, 0x54, 0, 0x12, 0x34, 0x56])
+                .begin_node("uart@0")
+                    .prop("reg", &[0, 0, 0, 0, 0, 0, 1, 0])
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    let lines = [
+        "/dts-v1/;",
+        "/memreserve/ 0x80000000 0x200000;",
+        "",
+        "/ {",
+        "  #address-cells = <0x2>;",
+        "",
+        "  chosen {",
+        "    bootargs = \"console=\\\"ttyS0\\\"\";",
+        "    compatible = \"a,b\", \"c\";",
+        "  };",
+        "",
+        "  soc {",
+        "    ranges;",
+        "    mac = [52 54 00 12 34 56];",
+        "",
+        "    uart@0 {",
+        "      reg = <0x0 0x100>;",
+        "    };",
+        "  };",
+        "};",
+        "",
+    ];
+    let text = format!("{}", dt);
+    assert!(text.split('\n').eq(lines.iter().copied()));
+
+    /* A subtree is printed from its own level */
+    let uart = format!("{}", dt.find("/soc/uart@0").unwrap());
+    assert!(uart == "uart@0 {\n  reg = <0x0 0x100>;\n};\n");
+    println!(" Test: dtb display ok!\n");
+}
+
+fn test_store() {
+    println!(" Test: dtb store ...");
+    let blob = FdtBlob::new()
+        .reserve(0x80000000, 0x200000)
+        .reserve(0x90000000, 0x1000)
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .begin_node("soc")
+                .prop_u32("#address-cells", 1)
+                .begin_node("uart@10000000")
+                    .prop("compatible", b"ns16550a\0")
+                    .prop("mac", &[1, 2, 3, 4, 5])
+                    .prop("ranges", &[])
+                .end_node()
+            .end_node()
+            .begin_node("chosen")
+                .prop_str("bootargs", "console=ttyS0")
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    /* Load, store and reload: the same tree */
+    let stored = dt.store().unwrap();
+    assert!(DeviceTree::verify(&stored).is_ok());
+    let reloaded = DeviceTree::load(&stored).unwrap();
+    assert!(reloaded == dt);
+    assert!(reloaded.store().unwrap() == stored);
+
+    /* #address-cells is stored once in the strings block */
+    let off_dt_strings = u32::from_be_bytes(stored[12..16].try_into().unwrap());
+    let size_dt_strings = u32::from_be_bytes(stored[32..36].try_into().unwrap());
+    assert!(size_dt_strings as usize == b"#address-cells\0compatible\0mac\0ranges\0bootargs\0".len());
+    assert!(off_dt_strings as usize + size_dt_strings as usize == stored.len());
+
+    /* A built tree round-trips as well; empty reservations are dropped */
+    let built = DeviceTreeBuilder::new()
+        .reserve(0x80000000, 0x200000)
+        .reserve(0x88000000, 0)
+        .begin_node("")
+            .prop_u32("#size-cells", 1)
+            .begin_node("memory@80000000")
+            .end_node()
+        .end_node()
+        .build();
+    let reloaded = DeviceTree::load(&built.store().unwrap()).unwrap();
+    assert!(reloaded.reserved == vec![(0x80000000, 0x200000), (0, 0)]);
+    assert!(reloaded.root == built.root);
+    println!(" Test: dtb store ok!\n");
+}
+
+fn test_reserved_bounds() {
+    println!(" Test: dtb reserved bounds ...");
+    let blob = FdtBlob::new()
+        .reserve(0x80000000, 0x200000)
+        .begin_node("")
+        .end_node()
+        .finish();
+    assert!(DeviceTree::load(&blob).is_ok());
+
+    /* The terminator has a size, so the map runs into the structure */
+    let mut bad = blob.clone();
+    patch_u32(&mut bad, FDT_HEADER_SIZE + 16 + 12, 0x1000);
+    assert!(matches!(DeviceTree::load(&bad),
+                     Err(DeviceTreeError::ParseError(72))));
+    assert!(matches!(DeviceTree::load_ref(&bad),
+                     Err(DeviceTreeError::ParseError(72))));
+
+    /* The map is after the structure, and runs out of the blob */
+    let mut bad = blob.clone();
+    let len = bad.len();
+    patch_u32(&mut bad, 16, (len - 16) as u32);
+    patch_u32(&mut bad, len - 16 + 8, 0);
+    patch_u32(&mut bad, len - 16 + 12, 0x1000);
+    assert!(matches!(DeviceTree::load(&bad),
+                     Err(DeviceTreeError::ParseError(p)) if p == len));
+    println!(" Test: dtb reserved bounds ok!\n");
+}
+
+fn reg_bytes(cells: &[u32]) -> Vec<u8> {
+    cells.iter().flat_map(|c| c.to_be_bytes()).collect()
+}
+
+fn test_memory_regions() {
+    println!(" Test: dtb memory_regions ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .prop_u32("#address-cells", 2)
+            .prop_u32("#size-cells", 2)
+            .begin_node("memory@80000000")
+                .prop_str("device_type", "memory")
+                .prop("reg", &reg_bytes(&[0, 0x80000000, 0, 0x4000000,
+                                          0x1, 0, 0, 0,
+                                          0x1, 0x0, 0x1, 0x0]))
+            .end_node()
+            .begin_node("flash@20000000")
+                .prop("reg", &reg_bytes(&[0, 0x20000000, 0, 0x1000000]))
+            .end_node()
+            .begin_node("memory@200000000")
+                .prop_str("device_type", "memory")
+                .prop("reg", &reg_bytes(&[0x2, 0, 0, 0x1000]))
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    /* Only memory nodes, without the empty region */
+    let regions = dt.memory_regions().unwrap();
+    assert!(regions == vec![(0x80000000, 0x4000000),
+                            (0x1_0000_0000, 0x1_0000_0000),
+                            (0x2_0000_0000, 0x1000)]);
+
+    /* Cells default to 1, which leaves the last cell half an entry */
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("memory@0")
+                .prop_str("device_type", "memory")
+                .prop("reg", &reg_bytes(&[0x1000, 0x2000, 0x8000]))
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    assert!(matches!(dt.memory_regions(),
+                     Err(PropError::OutOfBounds { len: 12, pos: 8, needed: 8 })));
+    println!(" Test: dtb memory_regions ok!\n");
+}
+
+fn test_find_by_name() {
+    println!(" Test: dtb find_by_name ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("soc")
+                .begin_node("uart@10000000")
+                    .begin_node("port@0")
+                    .end_node()
+                .end_node()
+                .begin_node("uart@10010000")
+                .end_node()
+                .begin_node("uartx")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    /* Without unit addresses, the first one with the name matches */
+    assert!(dt.find("/soc/uart").is_none());
+    assert!(dt.find_by_name("/soc/uart").unwrap().name == "uart@10000000");
+    assert!(dt.find_by_name("/soc/uart/port").unwrap().name == "port@0");
+    assert!(dt.find_by_name("/soc/uartx").unwrap().name == "uartx");
+
+    /* Exact matches still work */
+    assert!(dt.find_by_name("/soc/uart@10010000").unwrap().name == "uart@10010000");
+    assert!(dt.find("/soc/uart@10010000").unwrap().name == "uart@10010000");
+    assert!(dt.find_by_name("/soc/uart@1").is_none());
+    assert!(dt.find_by_name("/").unwrap().name == "");
+    assert!(dt.find_by_name("soc").is_none());
+    println!(" Test: dtb find_by_name ok!\n");
+}
+
+fn test_resolve_alias() {
+    println!(" Test: dtb resolve_alias ...");
+    let blob = FdtBlob::new()
+        .begin_node("")
+            .begin_node("aliases")
+                .prop_str("serial0", "/soc/uart@10000000")
+                .prop_str("serial1", "/soc/uart@10010000")
+                .prop_str("missing", "/soc/uart@0")
+            .end_node()
+            .begin_node("soc")
+                .begin_node("uart@10000000")
+                .end_node()
+                .begin_node("uart@10010000")
+                .end_node()
+            .end_node()
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+
+    assert!(dt.resolve_alias("serial0").unwrap().name == "uart@10000000");
+    assert!(dt.resolve_alias("serial1").unwrap().name == "uart@10010000");
+    /* Neither the alias nor the path it gives has to exist */
+    assert!(dt.resolve_alias("serial2").is_none());
+    assert!(dt.resolve_alias("missing").is_none());
+
+    let blob = FdtBlob::new()
+        .begin_node("")
+        .end_node()
+        .finish();
+    let dt = DeviceTree::load(&blob).unwrap();
+    assert!(dt.resolve_alias("serial0").is_none());
+    println!(" Test: dtb resolve_alias ok!\n");
+}
+
+fn test_read_be_sized() {
+    println!(" Test: dtb read_be_u16/i32/i64 ...");
+    let buf: &[u8] = &[0x12, 0x34];
+    assert!(buf.read_be_u16(0).unwrap() == 0x1234);
+    assert!(buf.read_be_u16(1).is_err());
+    assert!((&buf[..1]).read_be_u16(0).is_err());
+
+    let buf: &[u8] = &[0xff, 0xff, 0xff, 0xfe];
+    assert!(buf.read_be_i32(0).unwrap() == -2);
+    assert!(buf.read_be_i32(1).is_err());
+    assert!((&buf[..3]).read_be_i32(0).is_err());
+
+    let buf: &[u8] = &[0x80, 0, 0, 0, 0, 0, 0, 0x01];
+    assert!(buf.read_be_i64(0).unwrap() == i64::MIN + 1);
+    assert!(buf.read_be_i64(1).is_err());
+    assert!((&buf[..7]).read_be_i64(0).is_err());
+    println!(" Test: dtb read_be_u16/i32/i64 ok!\n");
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use core::ops::ControlFlow;
+use core::ptr::null_mut;
+use crate::klib::list::{List, ListNode, Linked};
+
+struct Item {
+    val: usize,
+    node: ListNode,
+}
+
+impl Item {
+    const fn new(val: usize) -> Self {
+        Self { val, node: ListNode::new() }
+    }
+}
+
+impl Linked<Item> for Item {
+    fn from_node(ptr: *mut ListNode) -> *mut Item {
+        unsafe {
+            crate::container_of!(ptr, Item, node)
+        }
+    }
+
+    fn into_node(&mut self) -> *mut ListNode {
+        &mut (self.node)
+    }
+}
+
+pub fn test_list() {
+    test_find();
+    test_for_each();
+    test_split_after();
+}
+
+fn test_find() {
+    println!(" Test: list find ...");
+    let mut items = [Item::new(1), Item::new(2), Item::new(3)];
+    let mut list = List::<Item>::new();
+    list.init();
+    for item in items.iter_mut() {
+        list.add_tail(item);
+    }
+
+    let found = list.find(|item| item.val == 2);
+    assert!(found == &mut items[1] as *mut Item);
+    assert!(list.find(|item| item.val == 4) == null_mut());
+    println!(" Test: list find ok!\n");
+}
+
+fn test_for_each() {
+    println!(" Test: list for_each ...");
+    let mut items = [Item::new(1), Item::new(2), Item::new(3)];
+    let mut list = List::<Item>::new();
+    list.init();
+    for item in items.iter_mut() {
+        list.add_tail(item);
+    }
+
+    /* Double each value */
+    let ret = list.for_each(|item| {
+        item.val *= 2;
+        ControlFlow::Continue(())
+    });
+    assert!(ret.is_continue());
+    assert!(items.iter().map(|item| item.val).eq([2, 4, 6]));
+
+    /* Stop at the first value above 2 */
+    let mut visited = 0;
+    let ret = list.for_each(|item| {
+        visited += 1;
+        if item.val > 2 {
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    });
+    assert!(ret.is_break());
+    assert!(visited == 2);
+    println!(" Test: list for_each ok!\n");
+}
+
+fn values(list: &List<Item>) -> impl Iterator<Item = usize> + '_ {
+    list.iter().map(|item| unsafe { (*item).val })
+}
+
+fn test_split_after() {
+    println!(" Test: list split_after ...");
+    let mut items = [
+        Item::new(0), Item::new(1), Item::new(2), Item::new(3), Item::new(4),
+        Item::new(5), Item::new(6), Item::new(7), Item::new(8), Item::new(9),
+    ];
+    let mut list = List::<Item>::new();
+    list.init();
+    for item in items.iter_mut() {
+        list.add_tail(item);
+    }
+
+    let mut tail = List::<Item>::new();
+    tail.init();
+    list.split_after(&mut items[3], &mut tail);
+    assert!(list._len() == 4);
+    assert!(tail._len() == 6);
+    assert!(values(&list).eq(0..4));
+    assert!(values(&tail).eq(4..10));
+    assert!(list.tail() == &mut items[3] as *mut Item);
+    assert!(tail.head() == &mut items[4] as *mut Item);
+    assert!(tail.tail() == &mut items[9] as *mut Item);
+
+    /* Nothing after the tail */
+    let mut empty = List::<Item>::new();
+    empty.init();
+    tail.split_after(&mut items[9], &mut empty);
+    assert!(empty.empty());
+    assert!(tail._len() == 6);
+
+    /* Splice is the inverse */
+    list.splice(&mut tail);
+    assert!(tail.empty());
+    assert!(values(&list).eq(0..10));
+    println!(" Test: list split_after ok!\n");
+}
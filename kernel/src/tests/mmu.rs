@@ -0,0 +1,238 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use alloc::vec::Vec;
+use crate::PFN_TO_PA;
+use crate::PTE_TO_PFN;
+use crate::PTE_TO_PROT;
+use crate::arch::mmu::{
+    PAGE_KERNEL, PAGE_KERNEL_EXEC, PAGE_READ, PAGE_WRITE, PageTable,
+    arch_zero_page, map_page_table, protect_page_table, pte_is_leaf,
+    unmap_page_table, walk_page_table,
+};
+use crate::defines::{PAGE_SIZE, paddr_to_physmap};
+use crate::errors::ErrNO;
+use crate::klib::list::List;
+use crate::pmm::{
+    PMM_ALLOC_FLAG_ANY, pmm_alloc_page, pmm_count_free_pages, pmm_free,
+};
+use crate::vm::vm::vm_verify_wx_in;
+
+pub fn test_mmu() {
+    test_walk_page_table();
+    test_verify_wx();
+    test_unmap_page_table();
+    test_protect_page_table();
+}
+
+fn test_walk_page_table() {
+    println!(" Test: walk page table ...");
+    /* A private root table, it is never loaded into satp */
+    let page = pmm_alloc_page(PMM_ALLOC_FLAG_ANY);
+    assert!(!page.is_null());
+    let root = unsafe {
+        let va = paddr_to_physmap((*page).paddr());
+        arch_zero_page(va);
+        &mut *(va as *mut PageTable)
+    };
+
+    /* Two pages in neighbouring 2M blocks */
+    let va1 = 0x1000_0000;
+    let va2 = va1 + 0x20_0000;
+    let pa1 = 0x8020_0000;
+    let pa2 = 0x8030_1000;
+    assert!(map_page_table(va1, pa1, PAGE_SIZE, PAGE_KERNEL, 0, root).is_ok());
+    assert!(map_page_table(va2, pa2, PAGE_SIZE, PAGE_KERNEL, 0, root).is_ok());
+
+    let mut leaves = Vec::new();
+    let mut tables = Vec::new();
+    walk_page_table(root, 0, |va, level, pte| {
+        if pte_is_leaf(pte) {
+            leaves.push((va, level, PFN_TO_PA!(PTE_TO_PFN!(pte))));
+        } else {
+            tables.push((va, level));
+        }
+    });
+
+    assert!(leaves.len() == 2);
+    let leaf_level = leaves[0].1;
+    assert!(leaves[0] == (va1, leaf_level, pa1));
+    assert!(leaves[1] == (va2, leaf_level, pa2));
+
+    /* One table per level on the shared path, then one for each 2M block */
+    assert!(tables.len() == leaf_level + 1);
+    assert!(tables.iter().all(|&(_, level)| level < leaf_level));
+    assert!(tables.iter().filter(|&&(_, level)| level == leaf_level - 1)
+            .count() == 2);
+    println!(" Test: walk page table ok!\n");
+}
+
+fn test_verify_wx() {
+    println!(" Test: verify W^X ...");
+    let free_before = pmm_count_free_pages();
+    let page = pmm_alloc_page(PMM_ALLOC_FLAG_ANY);
+    assert!(!page.is_null());
+    let root = unsafe {
+        let va = paddr_to_physmap((*page).paddr());
+        arch_zero_page(va);
+        &mut *(va as *mut PageTable)
+    };
+    assert!(vm_verify_wx_in(root) == 0);
+
+    /* A data page beside a deliberate writable and executable one */
+    let va = 0x1000_0000;
+    let pa = 0x8020_0000;
+    assert!(map_page_table(va, pa, PAGE_SIZE, PAGE_KERNEL, 0, root).is_ok());
+    assert!(vm_verify_wx_in(root) == 0);
+    assert!(map_page_table(va + PAGE_SIZE, pa + PAGE_SIZE, PAGE_SIZE,
+                           PAGE_KERNEL_EXEC, 0, root).is_ok());
+    assert!(vm_verify_wx_in(root) == 1);
+
+    /* Both pages go, and their tables with them, then the root */
+    assert!(unmap_page_table(va, 2 * PAGE_SIZE, 0, root) ==
+            Ok(2 * PAGE_SIZE));
+    assert!(vm_verify_wx_in(root) == 0);
+    let mut list = List::new();
+    list.init();
+    list.add_tail(page);
+    pmm_free(&mut list);
+    assert!(pmm_count_free_pages() == free_before);
+    println!(" Test: verify W^X ok!\n");
+}
+
+fn test_unmap_page_table() {
+    println!(" Test: unmap page table ...");
+    let page = pmm_alloc_page(PMM_ALLOC_FLAG_ANY);
+    assert!(!page.is_null());
+    let root = unsafe {
+        let va = paddr_to_physmap((*page).paddr());
+        arch_zero_page(va);
+        &mut *(va as *mut PageTable)
+    };
+    let free_before = pmm_count_free_pages();
+
+    /* Three pages in a row, a hole, and one more in the next 2M block */
+    let va = 0x1000_0000;
+    let pa = 0x8020_0000;
+    for i in 0..3 {
+        assert!(map_page_table(va + i * PAGE_SIZE, pa + i * PAGE_SIZE,
+                               PAGE_SIZE, PAGE_KERNEL, 0, root).is_ok());
+    }
+    let far = va + 0x20_0000;
+    assert!(map_page_table(far, pa, PAGE_SIZE, PAGE_KERNEL, 0, root).is_ok());
+    let leaves = |root: &PageTable| {
+        let mut leaves = Vec::new();
+        walk_page_table(root, 0, |va, _, pte| {
+            if pte_is_leaf(pte) {
+                leaves.push(va);
+            }
+        });
+        leaves
+    };
+
+    /* The hole is skipped over, and the tables stay for the rest */
+    assert!(unmap_page_table(va + PAGE_SIZE, 4 * PAGE_SIZE, 0, root) ==
+            Ok(2 * PAGE_SIZE));
+    assert!(leaves(root) == [va, far]);
+    assert!(unmap_page_table(va + 8 * PAGE_SIZE, PAGE_SIZE, 0, root) == Ok(0));
+    assert!(unmap_page_table(va + 1, PAGE_SIZE, 0, root) ==
+            Err(ErrNO::InvalidArgs));
+
+    /* Everything across both blocks, so the tables are all freed */
+    assert!(unmap_page_table(va, 0x40_0000, 0, root) == Ok(2 * PAGE_SIZE));
+    assert!(leaves(root).is_empty());
+    let mut tables = 0;
+    walk_page_table(root, 0, |_, _, _| tables += 1);
+    assert!(tables == 0);
+
+    /* A page right below a 2M block. The block can't be unmapped
+     * in part, and then the page below it stays mapped as well. */
+    let block = va + 0x20_0000;
+    let block_size = 0x20_0000;
+    assert!(map_page_table(block - PAGE_SIZE, pa, PAGE_SIZE,
+                           PAGE_KERNEL, 0, root).is_ok());
+    assert!(map_page_table(block, pa + 0x20_0000, block_size,
+                           PAGE_KERNEL, 0, root).is_ok());
+    assert!(unmap_page_table(block - PAGE_SIZE, 2 * PAGE_SIZE, 0, root) ==
+            Err(ErrNO::NotSupported));
+    assert!(leaves(root) == [block - PAGE_SIZE, block]);
+    assert!(unmap_page_table(block - PAGE_SIZE, PAGE_SIZE + block_size,
+                             0, root) == Ok(PAGE_SIZE + block_size));
+    assert!(leaves(root).is_empty());
+    assert!(pmm_count_free_pages() == free_before);
+    println!(" Test: unmap page table ok!\n");
+}
+
+fn test_protect_page_table() {
+    println!(" Test: protect page table ...");
+    let page = pmm_alloc_page(PMM_ALLOC_FLAG_ANY);
+    assert!(!page.is_null());
+    let root = unsafe {
+        let va = paddr_to_physmap((*page).paddr());
+        arch_zero_page(va);
+        &mut *(va as *mut PageTable)
+    };
+
+    let va = 0x1000_0000;
+    let pa = 0x8020_0000;
+    for i in 0..3 {
+        assert!(map_page_table(va + i * PAGE_SIZE, pa + i * PAGE_SIZE,
+                               PAGE_SIZE, PAGE_KERNEL, 0, root).is_ok());
+    }
+    let leaves = |root: &PageTable| {
+        let mut leaves = Vec::new();
+        walk_page_table(root, 0, |va, _, pte| {
+            if pte_is_leaf(pte) {
+                leaves.push((va, pte));
+            }
+        });
+        leaves
+    };
+
+    /* Drop the write permission of the last two pages only */
+    assert!(protect_page_table(va + PAGE_SIZE, 2 * PAGE_SIZE, PAGE_READ,
+                               0, root).is_ok());
+    let ptes = leaves(root);
+    assert!(ptes.len() == 3);
+    /* Only the write bit is gone, the rest stays as it was mapped */
+    assert!(PTE_TO_PROT!(ptes[0].1) == PAGE_KERNEL);
+    assert!(PTE_TO_PROT!(ptes[1].1) == PAGE_KERNEL & !PAGE_WRITE);
+    for (i, &(leaf_va, pte)) in ptes.iter().enumerate() {
+        assert!(leaf_va == va + i * PAGE_SIZE);
+        assert!(PFN_TO_PA!(PTE_TO_PFN!(pte)) == pa + i * PAGE_SIZE);
+        assert!((pte & PAGE_READ) != 0);
+        assert!(((pte & PAGE_WRITE) != 0) == (i == 0));
+    }
+
+    /* The holes are skipped over, and the range has to be aligned */
+    assert!(protect_page_table(va, 8 * PAGE_SIZE, PAGE_KERNEL, 0, root).is_ok());
+    assert!(leaves(root).iter().all(|&(_, pte)| (pte & PAGE_WRITE) != 0));
+    assert!(protect_page_table(va + 1, PAGE_SIZE, PAGE_READ, 0, root) ==
+            Err(ErrNO::InvalidArgs));
+
+    assert!(unmap_page_table(va, 3 * PAGE_SIZE, 0, root) == Ok(3 * PAGE_SIZE));
+
+    /* A 2M block is split up to protect a single page of it,
+     * and the rest of it keeps the write permission */
+    let block_size = 0x20_0000;
+    let free_before = pmm_count_free_pages();
+    assert!(map_page_table(va, pa, block_size, PAGE_KERNEL, 0, root).is_ok());
+    assert!(leaves(root).len() == 1);
+    assert!(protect_page_table(va + PAGE_SIZE, PAGE_SIZE, PAGE_READ,
+                               0, root).is_ok());
+    let ptes = leaves(root);
+    assert!(ptes.len() == block_size / PAGE_SIZE);
+    for (i, &(leaf_va, pte)) in ptes.iter().enumerate() {
+        assert!(leaf_va == va + i * PAGE_SIZE);
+        assert!(PFN_TO_PA!(PTE_TO_PFN!(pte)) == pa + i * PAGE_SIZE);
+        assert!(((pte & PAGE_WRITE) != 0) == (i != 1));
+    }
+    assert!(unmap_page_table(va, block_size, 0, root) == Ok(block_size));
+    assert!(pmm_count_free_pages() == free_before);
+    println!(" Test: protect page table ok!\n");
+}
@@ -6,19 +6,49 @@
  * at https://opensource.org/licenses/MIT
  */
 
+use bitmap::test_bitmap;
 use cmpct::test_cmpct;
+use dtb::test_dtb;
 use heap::test_heap;
+use list::test_list;
+use mmu::test_mmu;
 use mutex::test_mutex;
+use pmm::test_pmm;
+use rbtree::test_rbtree;
+use thread::test_thread;
+use virtual_alloc::test_virtual_alloc;
+use vmar::test_vmar;
+use vmo::test_vmo;
 
+mod bitmap;
 mod cmpct;
+mod dtb;
 mod heap;
+mod list;
+mod mmu;
 mod mutex;
+mod pmm;
+mod rbtree;
+mod thread;
+mod virtual_alloc;
+mod vmar;
+mod vmo;
 
 #[cfg(feature = "unittest")]
 pub fn do_tests() {
     println!("\n[TESTS: start ...]\n");
+    test_bitmap();
     test_cmpct();
+    test_dtb();
     test_heap();
+    test_list();
+    test_mmu();
     test_mutex();
+    test_pmm();
+    test_rbtree();
+    test_thread();
+    test_virtual_alloc();
+    test_vmar();
+    test_vmo();
     println!("\n[TESTS: finished!]\n");
 }
@@ -6,7 +6,8 @@
  * at https://opensource.org/licenses/MIT
  */
 
-use crate::locking::mutex::Mutex;
+use alloc::vec;
+use crate::locking::mutex::{Mutex, MutexGuard};
 
 struct Test {
     a: usize,
@@ -31,4 +32,21 @@ pub fn test_mutex() {
         println!("Test: Now a = {}", test.a);
     }
     println!(" Test: mutex ok!");
+    test_mutex_map();
+}
+
+fn test_mutex_map() {
+    println!(" Test: mutex map ...");
+    let m = Mutex::new(vec![1, 2, 3]);
+    {
+        let mut elem = MutexGuard::map(m.lock(), |v| &mut v[1]);
+        assert!(*elem == 2);
+        *elem = 20;
+        /* The whole vec is still locked through the projection */
+        assert!(m.is_locked());
+    }
+    assert!(!m.is_locked());
+    assert!(*m.lock() == vec![1, 20, 3]);
+    assert!(!m.is_locked());
+    println!(" Test: mutex map ok!\n");
 }
@@ -0,0 +1,632 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::errors::ErrNO;
+use crate::klib::list::List;
+use crate::page::vm_page_t;
+use crate::pmm::{
+    ArenaInfo, ArenaPolicy, FreeRuns, PMM_ALLOC_FLAG_CAN_WAIT,
+    PMM_ALLOC_FLAG_LO_MEM, PMM_ALLOC_FLAG_ZERO, PMM_ARENA_FLAG_LO_MEM,
+    PMM_NODE, PmmNode,
+    pmm_alloc_contiguous, pmm_alloc_flag_arena, pmm_alloc_page,
+    pmm_alloc_pages, pmm_alloc_range, pmm_count_free_pages, pmm_free,
+    pmm_get_arena_info, pmm_total_bytes,
+};
+use crate::types::paddr_t;
+use crate::vm_page_state;
+use crate::{PAGE_SHIFT, PAGE_SIZE, paddr_to_physmap};
+
+pub fn test_pmm() {
+    test_free_runs();
+    test_alloc_contiguous();
+    test_alloc_zero();
+    test_for_each_free_page();
+    test_arena_round_robin();
+    test_low_mem_callback();
+    test_free();
+    test_alloc_free_rounds();
+    test_alloc_range();
+    test_alloc_contiguous_aligned();
+    test_count_free_pages();
+    test_alloc_lo_mem();
+    test_should_wait();
+    test_get_arena_info();
+}
+
+fn test_free_runs() {
+    println!(" Test: pmm free runs ...");
+    const BASE: paddr_t = 0x8000_0000;
+    let mut buf = [(0usize, 0usize); 32];
+    assert!(FreeRuns::size_for(64) == core::mem::size_of_val(&buf));
+    let mut runs = FreeRuns::new();
+    runs.init(buf.as_mut_ptr(), buf.len());
+    runs.insert(0, 64);
+    assert!(runs.largest() == 64);
+
+    /* Allocate 4-page blocks aligned to 16K until it runs out */
+    let mut blocks = [0usize; 16];
+    for block in blocks.iter_mut() {
+        let index = runs.find(BASE, 4, 14).unwrap();
+        assert!((BASE + index * PAGE_SIZE) % 0x4000 == 0);
+        runs.remove(index, 4);
+        assert!(runs.is_consistent());
+        *block = index;
+    }
+    assert!(runs.count() == 0);
+    assert!(runs.find(BASE, 1, 12).is_none());
+
+    /* Free every other block, then the rest, out of order */
+    for i in (0..16).step_by(2) {
+        runs.insert(blocks[i], 4);
+        assert!(runs.is_consistent());
+    }
+    assert!(runs.largest() == 4);
+    assert!(runs.find(BASE, 8, 12).is_none());
+    for i in (1..16).step_by(2).rev() {
+        runs.insert(blocks[i], 4);
+        assert!(runs.is_consistent());
+    }
+    assert!(runs.count() == 64);
+    assert!(runs.largest() == 64);
+
+    /* Carve out of the middle of a run */
+    runs.remove(10, 3);
+    assert!(runs.is_consistent());
+    assert!(runs.largest() == 51);
+    assert!(runs.find(BASE, 52, 12).is_none());
+    assert!(runs.find(BASE, 10, 12) == Some(0));
+    assert!(runs.find(BASE, 51, 12) == Some(13));
+    runs.insert(10, 3);
+    assert!(runs.largest() == 64);
+
+    /* Every other page allocated fills the array up */
+    for i in (1..64).step_by(2) {
+        runs.remove(i, 1);
+    }
+    assert!(runs.is_consistent());
+    assert!(runs.count() == 32 && runs.largest() == 1);
+    for i in (1..64).step_by(2) {
+        runs.insert(i, 1);
+    }
+    assert!(runs.count() == 64 && runs.largest() == 64);
+    println!(" Test: pmm free runs ok!\n");
+}
+
+fn test_alloc_contiguous() {
+    println!(" Test: pmm alloc contiguous ...");
+    let largest = || {
+        PMM_NODE.get_arenas().iter()
+            .map(|a| a.largest_free_run()).max().unwrap_or(0)
+    };
+    let before = largest();
+
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(4, 0, 14, &mut pa, &mut list).is_ok());
+    assert!(pa % 0x4000 == 0);
+
+    let mut expected = pa;
+    for page in list.iter() {
+        unsafe {
+            assert!((*page).paddr() == expected);
+            assert!(!(*page).is_free());
+        }
+        expected += PAGE_SIZE;
+    }
+    assert!(expected == pa + 4 * PAGE_SIZE);
+    assert!(largest() <= before);
+    for arena in PMM_NODE.get_arenas().iter() {
+        assert!(arena.free_runs().is_consistent());
+    }
+
+    /* Far more than what we have */
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(pmm_alloc_contiguous(before + 1, 0, 12, &mut pa, &mut list).is_err());
+    assert!(list.empty());
+    println!(" Test: pmm alloc contiguous ok!\n");
+}
+
+fn page_is_zero(pa: paddr_t) -> bool {
+    let va = paddr_to_physmap(pa);
+    let words = unsafe {
+        core::slice::from_raw_parts(va as *const usize,
+                                    PAGE_SIZE / core::mem::size_of::<usize>())
+    };
+    words.iter().all(|&w| w == 0)
+}
+
+fn test_alloc_zero() {
+    println!(" Test: pmm alloc zero ...");
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(pmm_alloc_pages(3, PMM_ALLOC_FLAG_ZERO, &mut list).is_ok());
+    for page in list.iter() {
+        unsafe {
+            assert!(page_is_zero((*page).paddr()));
+        }
+    }
+
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(2, PMM_ALLOC_FLAG_ZERO, 13,
+                                 &mut pa, &mut list).is_ok());
+    assert!(page_is_zero(pa));
+    assert!(page_is_zero(pa + PAGE_SIZE));
+    println!(" Test: pmm alloc zero ok!\n");
+}
+
+fn test_for_each_free_page() {
+    println!(" Test: pmm for each free page ...");
+    let count_free = || {
+        let mut count = 0;
+        PMM_NODE.for_each_free_page(|page| {
+            unsafe { assert!((*page).is_free()); }
+            count += 1;
+        });
+        count
+    };
+    let before = count_free();
+    assert!(before > 0);
+
+    /* The allocated page has gone from the free list */
+    let page = pmm_alloc_page(0);
+    assert!(!page.is_null());
+    assert!(count_free() == before - 1);
+    let pa = unsafe { (*page).paddr() };
+    PMM_NODE.for_each_free_page(|p| {
+        unsafe { assert!((*p).paddr() != pa); }
+    });
+    println!(" Test: pmm for each free page ok!\n");
+}
+
+fn test_arena_round_robin() {
+    println!(" Test: pmm arena round robin ...");
+    /* A private node with two arenas, carved out of a run of pages
+     * allocated from the real one. */
+    const ARENA_PAGES: usize = 16;
+    let size = ARENA_PAGES * PAGE_SIZE;
+    let mut backing = List::<vm_page_t>::new();
+    backing.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(2 * ARENA_PAGES, 0, PAGE_SHIFT,
+                                 &mut pa, &mut backing).is_ok());
+
+    let node = PmmNode::new();
+    node.init();
+    assert!(node.add_arena(ArenaInfo::new("test0", 0, pa, size)).is_ok());
+    assert!(node.add_arena(ArenaInfo::new("test1", 0, pa + size, size)).is_ok());
+    assert!(node.get_arenas().len() == 2);
+    let arena_of = |page: *mut vm_page_t| unsafe {
+        ((*page).paddr() - pa) / size
+    };
+
+    /* By default, the arena added first is drained first */
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(node.alloc_pages(4, 0, &mut list).is_ok());
+    assert!(list.iter().all(|page| arena_of(page) == 0));
+
+    /* Allocations alternate between the arenas */
+    node.set_arena_policy(ArenaPolicy::RoundRobin);
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(node.alloc_pages(6, 0, &mut list).is_ok());
+    for (i, page) in list.iter().enumerate() {
+        assert!(arena_of(page) == i % 2);
+    }
+
+    /* The preferred arena goes first */
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(node.alloc_pages(3, pmm_alloc_flag_arena(1), &mut list).is_ok());
+    assert!(list.iter().all(|page| arena_of(page) == 1));
+
+    for arena in node.get_arenas().iter() {
+        assert!(arena.free_runs().is_consistent());
+    }
+    drop(node);
+    pmm_free(&mut backing);
+    println!(" Test: pmm arena round robin ok!\n");
+}
+
+static LOW_MEM_CALLS: AtomicUsize = AtomicUsize::new(0);
+static LOW_MEM_FREE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+fn on_low_mem(free_count: usize) {
+    LOW_MEM_CALLS.fetch_add(1, Ordering::Relaxed);
+    LOW_MEM_FREE.store(free_count, Ordering::Relaxed);
+}
+
+fn test_low_mem_callback() {
+    println!(" Test: pmm low memory callback ...");
+    const ARENA_PAGES: usize = 8;
+    let mut backing = List::<vm_page_t>::new();
+    backing.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(ARENA_PAGES, 0, PAGE_SHIFT,
+                                 &mut pa, &mut backing).is_ok());
+
+    let node = PmmNode::new();
+    node.init();
+    let info = ArenaInfo::new("test", 0, pa, ARENA_PAGES * PAGE_SIZE);
+    assert!(node.add_arena(info).is_ok());
+    node.set_low_mem_callback(Some(on_low_mem));
+
+    /* Drain the arena page by page, the callback stays quiet */
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    let mut count = 0;
+    while node.alloc_pages(1, 0, &mut list).is_ok() {
+        count += 1;
+        assert!(count <= ARENA_PAGES);
+    }
+    assert!(count > 0 && list.iter().count() == count);
+    assert!(LOW_MEM_CALLS.load(Ordering::Relaxed) == 1);
+    assert!(LOW_MEM_FREE.load(Ordering::Relaxed) == 0);
+
+    /* Either path of the allocation reports exhaustion */
+    assert!(matches!(node.alloc_pages(2, 0, &mut list), Err(ErrNO::NoMem)));
+    assert!(LOW_MEM_CALLS.load(Ordering::Relaxed) == 2);
+
+    node.set_low_mem_callback(None);
+    assert!(node.alloc_pages(1, 0, &mut list).is_err());
+    assert!(LOW_MEM_CALLS.load(Ordering::Relaxed) == 2);
+    drop(node);
+    pmm_free(&mut backing);
+    println!(" Test: pmm low memory callback ok!\n");
+}
+
+fn test_free() {
+    println!(" Test: pmm free ...");
+    let count_free = || {
+        let mut count = 0;
+        PMM_NODE.for_each_free_page(|_| count += 1);
+        count
+    };
+    let largest = || {
+        PMM_NODE.get_arenas().iter()
+            .map(|a| a.largest_free_run()).max().unwrap_or(0)
+    };
+    let before = count_free();
+    let largest_before = largest();
+
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(pmm_alloc_pages(8, 0, &mut list).is_ok());
+    assert!(count_free() == before - 8);
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(4, 0, 14, &mut pa, &mut list).is_ok());
+    assert!(count_free() == before - 12);
+
+    /* All of them go back as FREE, and the list is left empty */
+    let pages: [*mut vm_page_t; 12] = {
+        let mut pages = [core::ptr::null_mut(); 12];
+        for (i, page) in list.iter().enumerate() {
+            pages[i] = page;
+        }
+        pages
+    };
+    pmm_free(&mut list);
+    assert!(list.empty());
+    assert!(count_free() == before);
+    for page in pages {
+        unsafe {
+            assert!((*page).is_free());
+            assert!((*page).object.get_object() == 0);
+        }
+    }
+
+    /* The runs coalesce again, so the pages can be allocated again */
+    for arena in PMM_NODE.get_arenas().iter() {
+        assert!(arena.free_runs().is_consistent());
+    }
+    assert!(largest() == largest_before);
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(pmm_alloc_contiguous(4, 0, 14, &mut pa, &mut list).is_ok());
+    pmm_free(&mut list);
+    assert!(count_free() == before);
+    println!(" Test: pmm free ok!\n");
+}
+
+fn test_alloc_free_rounds() {
+    println!(" Test: pmm alloc and free rounds ...");
+    let before = pmm_count_free_pages();
+    for round in 0..64 {
+        /* Runs of varied sizes and alignments split up the free runs,
+         * which have to merge back once the pages are freed */
+        let count = (round % 16) + 1;
+        let alignment_log2 = PAGE_SHIFT + (round % 4);
+        let mut list = List::<vm_page_t>::new();
+        list.init();
+        let mut pa: paddr_t = 0;
+        assert!(pmm_alloc_contiguous(count, 0, alignment_log2,
+                                     &mut pa, &mut list).is_ok());
+        assert!(pmm_count_free_pages() == before - count);
+
+        pmm_free(&mut list);
+        for arena in PMM_NODE.get_arenas().iter() {
+            assert!(arena.free_runs().is_consistent());
+        }
+        assert!(pmm_count_free_pages() == before);
+    }
+    println!(" Test: pmm alloc and free rounds ok!\n");
+}
+
+fn test_alloc_range() {
+    println!(" Test: pmm alloc range ...");
+    let count_free = || {
+        let mut count = 0;
+        PMM_NODE.for_each_free_page(|_| count += 1);
+        count
+    };
+
+    /* Find 4 free pages in a row, and take them by address */
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(4, 0, 14, &mut pa, &mut list).is_ok());
+    pmm_free(&mut list);
+    let before = count_free();
+    assert!(pmm_alloc_range(pa, 4, &mut list).is_ok());
+    assert!(count_free() == before - 4);
+    let mut expected = pa;
+    for page in list.iter() {
+        unsafe { assert!((*page).paddr() == expected); }
+        expected += PAGE_SIZE;
+    }
+    pmm_free(&mut list);
+
+    /* With the third page taken, the first two are given back */
+    let mut hole = List::<vm_page_t>::new();
+    hole.init();
+    assert!(pmm_alloc_range(pa + 2 * PAGE_SIZE, 1, &mut hole).is_ok());
+    assert!(matches!(pmm_alloc_range(pa, 4, &mut list), Err(ErrNO::NotFound)));
+    assert!(list.empty());
+    assert!(count_free() == before - 1);
+    for arena in PMM_NODE.get_arenas().iter() {
+        assert!(arena.free_runs().is_consistent());
+    }
+
+    pmm_free(&mut hole);
+    assert!(count_free() == before);
+    println!(" Test: pmm alloc range ok!\n");
+}
+
+fn test_alloc_contiguous_aligned() {
+    println!(" Test: pmm alloc contiguous aligned ...");
+    /* A fresh arena of 48 pages, aligned to 64K, in a private node */
+    const ARENA_PAGES: usize = 48;
+    const ALIGN_LOG2: usize = 16;
+    let size = ARENA_PAGES * PAGE_SIZE;
+    let mut backing = List::<vm_page_t>::new();
+    backing.init();
+    let mut base: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(ARENA_PAGES, 0, ALIGN_LOG2,
+                                 &mut base, &mut backing).is_ok());
+
+    let node = PmmNode::new();
+    node.init();
+    assert!(node.add_arena(ArenaInfo::new("test", 0, base, size)).is_ok());
+    let count_free = || {
+        let mut count = 0;
+        node.for_each_free_page(|_| count += 1);
+        count
+    };
+    let before = count_free();
+
+    /* The page array takes a page, so only two such runs fit in */
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    let mut runs = 0;
+    let mut pa: paddr_t = 0;
+    while node.alloc_contiguous(16, 0, ALIGN_LOG2, &mut pa, &mut list).is_ok() {
+        assert!(pa % (1 << ALIGN_LOG2) == 0);
+        assert!(pa >= base && pa + 16 * PAGE_SIZE <= base + size);
+        runs += 1;
+        assert!(runs <= 2);
+    }
+    assert!(runs == 2);
+    assert!(count_free() == before - 32);
+
+    /* The pages of each run are in the list in order */
+    let mut start: paddr_t = 0;
+    for (i, page) in list.iter().enumerate() {
+        unsafe {
+            assert!((*page).state() == vm_page_state::ALLOC);
+            if i % 16 == 0 {
+                start = (*page).paddr();
+            }
+            assert!((*page).paddr() == start + (i % 16) * PAGE_SIZE);
+        }
+    }
+
+    /* A failure leaves nothing behind */
+    let mut failed = List::<vm_page_t>::new();
+    failed.init();
+    assert!(matches!(node.alloc_contiguous(16, 0, ALIGN_LOG2, &mut pa, &mut failed),
+                     Err(ErrNO::NoMem)));
+    assert!(failed.empty());
+    assert!(count_free() == before - 32);
+
+    node.free_list(&mut list);
+    assert!(count_free() == before);
+    for arena in node.get_arenas().iter() {
+        assert!(arena.free_runs().is_consistent());
+    }
+
+    drop(node);
+    pmm_free(&mut backing);
+    println!(" Test: pmm alloc contiguous aligned ok!\n");
+}
+
+fn test_count_free_pages() {
+    println!(" Test: pmm count free pages ...");
+    let before = pmm_count_free_pages();
+    assert!(before > 0);
+    assert!(before * PAGE_SIZE < pmm_total_bytes());
+    let total: usize = PMM_NODE.get_arenas().iter().map(|a| a.size()).sum();
+    assert!(pmm_total_bytes() == total);
+
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(pmm_alloc_pages(5, 0, &mut list).is_ok());
+    assert!(pmm_count_free_pages() == before - 5);
+    let mut count = 0;
+    PMM_NODE.for_each_free_page(|_| count += 1);
+    assert!(count == pmm_count_free_pages());
+
+    pmm_free(&mut list);
+    assert!(pmm_count_free_pages() == before);
+    println!(" Test: pmm count free pages ok!\n");
+}
+
+fn test_alloc_lo_mem() {
+    println!(" Test: pmm alloc lo mem ...");
+    /* A private node with a LO_MEM arena after an ordinary one */
+    const ARENA_PAGES: usize = 16;
+    let size = ARENA_PAGES * PAGE_SIZE;
+    let mut backing = List::<vm_page_t>::new();
+    backing.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(2 * ARENA_PAGES, 0, PAGE_SHIFT,
+                                 &mut pa, &mut backing).is_ok());
+
+    let node = PmmNode::new();
+    node.init();
+    let lo_base = pa + size;
+    assert!(node.add_arena(ArenaInfo::new("test", 0, pa, size)).is_ok());
+    assert!(node.add_arena(ArenaInfo::new("test_lo", PMM_ARENA_FLAG_LO_MEM,
+                                          lo_base, size)).is_ok());
+    let in_lo_mem = |page: *mut vm_page_t| unsafe {
+        (*page).paddr() >= lo_base && (*page).paddr() < lo_base + size
+    };
+
+    /* Without the flag, the free list order hands out the other one */
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(node.alloc_pages(2, 0, &mut list).is_ok());
+    assert!(list.iter().all(|page| !in_lo_mem(page)));
+
+    /* Drain the LO_MEM arena, which never gives a page from elsewhere */
+    let mut lo_list = List::<vm_page_t>::new();
+    lo_list.init();
+    let mut count = 0;
+    while node.alloc_pages(1, PMM_ALLOC_FLAG_LO_MEM, &mut lo_list).is_ok() {
+        count += 1;
+        assert!(count <= ARENA_PAGES);
+    }
+    assert!(count > 0 && lo_list.iter().all(in_lo_mem));
+    let mut pa: paddr_t = 0;
+    assert!(node.alloc_contiguous(2, PMM_ALLOC_FLAG_LO_MEM, PAGE_SHIFT,
+                                  &mut pa, &mut list).is_err());
+
+    /* The ordinary arena still has pages for everyone else */
+    assert!(node.alloc_pages(1, 0, &mut list).is_ok());
+    assert!(list.iter().all(|page| !in_lo_mem(page)));
+
+    /* The same goes for the round robin and contiguous runs */
+    node.free_list(&mut lo_list);
+    node.set_arena_policy(ArenaPolicy::RoundRobin);
+    let mut lo_list = List::<vm_page_t>::new();
+    lo_list.init();
+    assert!(node.alloc_pages(4, PMM_ALLOC_FLAG_LO_MEM, &mut lo_list).is_ok());
+    assert!(node.alloc_contiguous(2, PMM_ALLOC_FLAG_LO_MEM, PAGE_SHIFT,
+                                  &mut pa, &mut lo_list).is_ok());
+    assert!(lo_list.iter().all(in_lo_mem));
+
+    drop(node);
+    pmm_free(&mut backing);
+    println!(" Test: pmm alloc lo mem ok!\n");
+}
+
+fn test_should_wait() {
+    println!(" Test: pmm should wait ...");
+    const ARENA_PAGES: usize = 16;
+    let mut backing = List::<vm_page_t>::new();
+    backing.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(ARENA_PAGES, 0, PAGE_SHIFT,
+                                 &mut pa, &mut backing).is_ok());
+
+    let node = PmmNode::new();
+    node.init();
+    let info = ArenaInfo::new("test", 0, pa, ARENA_PAGES * PAGE_SIZE);
+    assert!(node.add_arena(info).is_ok());
+
+    /* Far above what's free, so only a caller able to wait is held off */
+    node.set_should_wait_watermark(ARENA_PAGES * 2);
+    let before = node.free_count();
+    let mut list = List::<vm_page_t>::new();
+    list.init();
+    assert!(matches!(node.alloc_pages(1, PMM_ALLOC_FLAG_CAN_WAIT, &mut list),
+                     Err(ErrNO::ShouldWait)));
+    assert!(matches!(node.alloc_pages(3, PMM_ALLOC_FLAG_CAN_WAIT, &mut list),
+                     Err(ErrNO::ShouldWait)));
+    assert!(list.empty() && node.free_count() == before);
+    assert!(node.alloc_pages(3, 0, &mut list).is_ok());
+    assert!(node.free_count() == before - 3);
+
+    /* With no watermark, it only has to wait once the pages run out */
+    node.set_should_wait_watermark(0);
+    assert!(node.alloc_pages(2, PMM_ALLOC_FLAG_CAN_WAIT, &mut list).is_ok());
+    assert!(node.alloc_pages(node.free_count(), 0, &mut list).is_ok());
+    assert!(matches!(node.alloc_pages(1, PMM_ALLOC_FLAG_CAN_WAIT, &mut list),
+                     Err(ErrNO::ShouldWait)));
+    assert!(matches!(node.alloc_pages(1, 0, &mut list), Err(ErrNO::NoMem)));
+
+    drop(node);
+    pmm_free(&mut backing);
+    println!(" Test: pmm should wait ok!\n");
+}
+
+fn test_get_arena_info() {
+    println!(" Test: pmm get arena info ...");
+    /* The snapshot of the real node agrees with its arenas */
+    let infos = pmm_get_arena_info();
+    {
+        let arenas = PMM_NODE.get_arenas();
+        assert!(infos.len() == arenas.len() && !infos.is_empty());
+        for (info, arena) in infos.iter().zip(arenas.iter()) {
+            assert!(info.name == arena.name() && info.flags == arena.flags());
+            assert!(info.base == arena.base() && info.size == arena.size());
+        }
+    }
+
+    /* Arenas added out of order come back sorted by base */
+    const ARENA_PAGES: usize = 16;
+    let size = ARENA_PAGES * PAGE_SIZE;
+    let mut backing = List::<vm_page_t>::new();
+    backing.init();
+    let mut pa: paddr_t = 0;
+    assert!(pmm_alloc_contiguous(2 * ARENA_PAGES, 0, PAGE_SHIFT,
+                                 &mut pa, &mut backing).is_ok());
+
+    let node = PmmNode::new();
+    node.init();
+    assert!(node.add_arena(ArenaInfo::new("high", PMM_ARENA_FLAG_LO_MEM,
+                                          pa + size, size)).is_ok());
+    assert!(node.add_arena(ArenaInfo::new("low", 0, pa, size)).is_ok());
+    let infos = node.get_arena_info();
+    assert!(infos.len() == 2);
+    assert!(infos[0].name == "low" && infos[0].flags == 0);
+    assert!(infos[0].base == pa && infos[0].size == size);
+    assert!(infos[1].name == "high" && infos[1].flags == PMM_ARENA_FLAG_LO_MEM);
+    assert!(infos[1].base == pa + size && infos[1].size == size);
+
+    /* It's a copy, so the arenas can be locked while it's around */
+    assert!(node.get_arenas().len() == infos.len());
+
+    drop(node);
+    pmm_free(&mut backing);
+    println!(" Test: pmm get arena info ok!\n");
+}
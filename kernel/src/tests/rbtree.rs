@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::format;
+use alloc::vec::Vec;
+use crate::klib::rbtree::RBTree;
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/* Count how many values have been dropped, to catch leaks and double
+ * frees of the nodes holding them. */
+struct DropCounter(usize);
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn test_rbtree() {
+    test_drop();
+    test_clear();
+    test_remove();
+    test_debug();
+    test_into_iter();
+    test_range();
+    test_first_last();
+    test_cursor();
+}
+
+fn test_drop() {
+    println!(" Test: rbtree drop ...");
+    DROPPED.store(0, Ordering::Relaxed);
+    {
+        let mut tree = RBTree::new();
+        for i in [5, 1, 9, 3, 7, 11, 0, 13, 2, 8, 4] {
+            tree.insert(i, DropCounter(i));
+            tree.assert_invariants();
+        }
+        assert!(tree.len() == 11);
+        assert!(DROPPED.load(Ordering::Relaxed) == 0);
+    }
+    assert!(DROPPED.load(Ordering::Relaxed) == 11);
+
+    /* Nothing to free in an empty tree */
+    let tree: RBTree<usize, DropCounter> = RBTree::new();
+    drop(tree);
+    assert!(DROPPED.load(Ordering::Relaxed) == 11);
+    println!(" Test: rbtree drop ok!\n");
+}
+
+fn test_clear() {
+    println!(" Test: rbtree clear ...");
+    DROPPED.store(0, Ordering::Relaxed);
+    let mut tree = RBTree::new();
+    for i in 0..64 {
+        tree.insert(i, DropCounter(i));
+    }
+    tree.clear();
+    assert!(DROPPED.load(Ordering::Relaxed) == 64);
+    assert!(tree.len() == 0 && tree.is_empty());
+    assert!(tree.iter_rev().next().is_none());
+    assert!(tree.get(&3).is_none());
+
+    /* Still usable after clear, and each node is freed only once */
+    tree.insert(3, DropCounter(3));
+    assert!(tree.get(&3).unwrap().0 == 3);
+    drop(tree);
+    assert!(DROPPED.load(Ordering::Relaxed) == 65);
+    println!(" Test: rbtree clear ok!\n");
+}
+
+fn test_remove() {
+    println!(" Test: rbtree remove ...");
+    const N: usize = 64;
+    let mut tree = RBTree::new();
+    for i in 0..N {
+        tree.insert(i, i * 2);
+        tree.assert_invariants();
+    }
+
+    /* Shuffle the keys to remove, with a fixed LCG for a stable order */
+    let mut keys = [0usize; N];
+    for (i, k) in keys.iter_mut().enumerate() {
+        *k = i;
+    }
+    let mut seed: u64 = 0x2545_f491;
+    for i in (1..N).rev() {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        keys.swap(i, (seed >> 33) as usize % (i + 1));
+    }
+
+    for (removed, k) in keys.iter().enumerate() {
+        assert!(tree.remove(k) == Some(*k * 2));
+        assert!(tree.remove(k).is_none());
+        assert!(tree.len() == N - removed - 1);
+
+        for (i, other) in keys.iter().enumerate() {
+            assert!(tree.get(other).is_some() == (i > removed));
+        }
+
+        /* No path of a red black tree is more than twice another */
+        let len = tree.len();
+        let bound = 2 * (usize::BITS - (len + 1).leading_zeros()) as usize;
+        assert!(tree.height() <= bound);
+        tree.assert_invariants();
+    }
+    assert!(tree.is_empty());
+    assert!(tree.remove(&0).is_none());
+    println!(" Test: rbtree remove ok!\n");
+}
+
+fn test_debug() {
+    println!(" Test: rbtree debug ...");
+    let mut tree = RBTree::new();
+    assert!(format!("{:?}", tree) == "[]");
+    for i in [2, 1, 3] {
+        tree.insert(i, ());
+    }
+    assert!(format!("{:?}", tree) == "[(1, Red), (2, Black), (3, Red)]");
+
+    /* The dump is in order of keys, whatever the order of inserts */
+    let mut tree = RBTree::new();
+    for i in [13, 5, 1, 9, 3, 7, 11, 0, 2, 8, 4, 12, 6, 10] {
+        tree.insert(i, ());
+        tree.assert_invariants();
+    }
+    let dump = format!("{:?}", tree);
+    let keys: Vec<usize> = dump.split('(').skip(1)
+        .map(|entry| entry.split(',').next().unwrap().parse().unwrap())
+        .collect();
+    assert!(keys == (0..14).collect::<Vec<usize>>());
+    println!(" Test: rbtree debug ok!\n");
+}
+
+fn test_into_iter() {
+    println!(" Test: rbtree into_iter ...");
+    let pairs: Vec<(usize, usize)> = (0..100).rev().map(|i| (i, i * 3)).collect();
+    let tree: RBTree<usize, usize> = pairs.into_iter().collect();
+    assert!(tree.len() == 100);
+    tree.assert_invariants();
+
+    let mut iter = tree.into_iter();
+    assert!(iter.size_hint() == (100, Some(100)));
+    let drained: Vec<(usize, usize)> = iter.by_ref().collect();
+    assert!(drained == (0..100).map(|i| (i, i * 3)).collect::<Vec<_>>());
+    assert!(iter.next().is_none());
+
+    /* Values left in a dropped iterator are freed, each just once */
+    DROPPED.store(0, Ordering::Relaxed);
+    let tree: RBTree<usize, DropCounter> =
+        (0..32).map(|i| (i, DropCounter(i))).collect();
+    let mut iter = tree.into_iter();
+    for i in 0..10 {
+        let (k, v) = iter.next().unwrap();
+        assert!(k == i && v.0 == i);
+    }
+    assert!(DROPPED.load(Ordering::Relaxed) == 10);
+    drop(iter);
+    assert!(DROPPED.load(Ordering::Relaxed) == 32);
+    println!(" Test: rbtree into_iter ok!\n");
+}
+
+fn test_range() {
+    println!(" Test: rbtree range ...");
+    let tree: RBTree<usize, usize> = (0..100).map(|i| (i, i * 2)).collect();
+    let keys: Vec<usize> = tree.range(&20, &30).map(|(k, _)| *k).collect();
+    assert!(keys == (20..30).collect::<Vec<usize>>());
+    assert!(tree.range(&20, &30).all(|(k, v)| *v == *k * 2));
+
+    /* Bounds past either end, and empty ranges */
+    assert!(tree.range(&95, &1000).count() == 5);
+    assert!(tree.range(&0, &3).count() == 3);
+    assert!(tree.range(&30, &30).next().is_none());
+    assert!(tree.range(&30, &20).next().is_none());
+    assert!(tree.range(&100, &200).next().is_none());
+
+    /* Keys in between the ones present */
+    let tree: RBTree<usize, ()> = (0..10).map(|i| (i * 10, ())).collect();
+    let keys: Vec<usize> = tree.range(&15, &45).map(|(k, _)| *k).collect();
+    assert!(keys == [20, 30, 40]);
+    println!(" Test: rbtree range ok!\n");
+}
+
+fn test_first_last() {
+    println!(" Test: rbtree first/last_key_value ...");
+    let mut tree = RBTree::new();
+    assert!(tree.first_key_value().is_none());
+    assert!(tree.last_key_value().is_none());
+
+    for i in [5, 1, 9, 3, 7] {
+        tree.insert(i, i * 2);
+    }
+    assert!(tree.first_key_value() == Some((&1, &2)));
+    assert!(tree.last_key_value() == Some((&9, &18)));
+
+    tree.remove(&1);
+    tree.remove(&9);
+    assert!(tree.first_key_value() == Some((&3, &6)));
+    assert!(tree.last_key_value() == Some((&7, &14)));
+    println!(" Test: rbtree first/last_key_value ok!\n");
+}
+
+fn test_cursor() {
+    println!(" Test: rbtree cursor ...");
+    let tree: RBTree<usize, usize> = (0..10).map(|i| (i * 10, i)).collect();
+
+    /* Walk forward and backward from the middle */
+    let mut cursor = tree.lower_bound_cursor(&50);
+    assert!(cursor.key() == Some(&50) && cursor.value() == Some(&5));
+    for k in [60, 70, 80, 90] {
+        cursor.move_next();
+        assert!(cursor.key() == Some(&k));
+    }
+    for k in [80, 70, 60, 50, 40, 30, 20, 10, 0] {
+        cursor.move_prev();
+        assert!(cursor.key() == Some(&k));
+    }
+    cursor.move_prev();
+    assert!(cursor.is_null() && cursor.key().is_none());
+    cursor.move_next();
+    assert!(cursor.is_null());
+
+    let mut cursor = tree.upper_bound_cursor(&50);
+    assert!(cursor.key() == Some(&60));
+    cursor.move_prev();
+    assert!(cursor.key() == Some(&50));
+    let cursor = tree.lower_bound_cursor(&45);
+    assert!(cursor.key() == Some(&50));
+
+    /* Past the last key */
+    let mut cursor = tree.upper_bound_cursor(&90);
+    assert!(cursor.is_null() && cursor.value().is_none());
+    cursor.move_prev();
+    assert!(cursor.is_null());
+    println!(" Test: rbtree cursor ok!\n");
+}
@@ -0,0 +1,663 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::alloc::{alloc, alloc_zeroed, dealloc};
+use crate::arch::thread::arch_context_switch;
+use crate::aspace::ASPACE_LIST;
+use crate::defines::PAGE_SIZE;
+use crate::dpc::{DPC_POOL_SIZE, dpc_thread};
+use crate::errors::ErrNO;
+use crate::klib::list::Linked;
+use crate::config_generated::_CONFIG_NR_CPUS;
+use crate::cpu::{cpu_num_t, cpu_num_to_mask};
+use crate::panic::expect_panic;
+use crate::percpu::{PerCPU, PERCPU_ARRAY};
+use crate::sched::{
+    BlockReason, Scheduler, ThreadState, K_PERFORMANCE_SCALE_ONE,
+    sched_total_load,
+};
+use crate::thread::{
+    Thread, ThreadArg, THREAD_LIST, thread_cleanup, thread_get_current, thread_set_current,
+};
+use crate::vm::kstack::KernelStack;
+use crate::vm::vm_object_paged::ALL_VMOS;
+use crate::wait_queue::WaitQueue;
+
+pub fn test_thread() {
+    test_reap_detached();
+    test_set_priority();
+    test_preemption_state();
+    test_block();
+    test_block_preempt_disabled();
+    test_kstack_guard();
+    test_load_estimate();
+    test_context_switch();
+    test_performance_scale();
+    test_dpc();
+    test_idle_hooks();
+    test_idle_yields();
+    test_alloc_struct();
+    test_create();
+}
+
+fn in_thread_list(thread: *mut Thread) -> bool {
+    THREAD_LIST.lock().iter().any(|t| t == thread)
+}
+
+fn test_reap_detached() {
+    println!(" Test: reap detached thread ...");
+    let thread = unsafe {
+        let thread = alloc(Layout::new::<Thread>()) as *mut Thread;
+        assert!(!thread.is_null());
+        ptr::write(thread, Thread::new());
+        (*thread).set_free_struct(true);
+        (*thread).set_detached(true);
+        thread
+    };
+    THREAD_LIST.lock().add_tail(thread);
+
+    /* Alive thread must not be reaped */
+    assert!(thread_cleanup() == 0);
+    assert!(in_thread_list(thread));
+
+    /* Thread exits */
+    unsafe {
+        (*thread).sched_state().set_state(ThreadState::ThreadDeath);
+    }
+    assert!(thread_cleanup() == 1);
+    assert!(!in_thread_list(thread));
+    assert!(thread_cleanup() == 0);
+    println!(" Test: reap detached thread ok!\n");
+}
+
+/* A private cpu keeps the boot scheduler untouched */
+fn new_percpu() -> *mut PerCPU {
+    unsafe {
+        let percpu = alloc_zeroed(Layout::new::<PerCPU>()) as *mut PerCPU;
+        assert!(!percpu.is_null());
+        (*percpu).init();
+        percpu
+    }
+}
+
+fn free_percpu(percpu: *mut PerCPU) {
+    unsafe {
+        ptr::drop_in_place(percpu);
+        dealloc(percpu as *mut u8, Layout::new::<PerCPU>());
+    }
+}
+
+fn test_set_priority() {
+    println!(" Test: set priority ...");
+    let percpu = new_percpu();
+
+    let mut threads = [Thread::new(), Thread::new()];
+    for t in threads.iter_mut() {
+        t.set_percpu_ptr(percpu);
+        Scheduler::init_thread(t, Thread::DEFAULT_PRIORITY);
+        Scheduler::unblock(t);
+    }
+    let (low, high) = {
+        let (a, b) = threads.split_at_mut(1);
+        (&mut a[0] as *mut Thread, &mut b[0] as *mut Thread)
+    };
+
+    let sched = unsafe { (*percpu).scheduler() };
+    let weight_total = sched.weight_total;
+    /* FIFO order for the equal priority */
+    assert!(sched.peek_next() == low);
+
+    unsafe {
+        assert!((*high).set_priority(Thread::HIGHEST_PRIORITY + 1) ==
+                Err(ErrNO::InvalidArgs));
+        assert!((*high).set_priority(Thread::HIGHEST_PRIORITY).is_ok());
+        assert!((*high).sched_state().state() == ThreadState::ThreadReady);
+    }
+    assert!(sched.weight_total > weight_total);
+    assert!(sched.peek_next() == high);
+
+    /* Back to the original priority, behind the other one */
+    unsafe {
+        assert!((*high).set_priority(Thread::DEFAULT_PRIORITY).is_ok());
+    }
+    assert!(sched.weight_total == weight_total);
+    assert!(sched.peek_next() == low);
+
+    Scheduler::remove(low);
+    Scheduler::remove(high);
+    assert!(sched.weight_total == 0);
+    assert!(sched.peek_next().is_null());
+    free_percpu(percpu);
+    println!(" Test: set priority ok!\n");
+}
+
+fn test_preemption_state() {
+    println!(" Test: preemption state ...");
+    /* Boot thread starts with preemption disabled, so it must not
+     * block_current (which would panic) until threading is up. */
+    assert!(Thread::current().preemption_state.preempt_disabled());
+
+    let thread = Thread::new();
+    let ps = &thread.preemption_state;
+    assert!(!ps.preempt_disabled());
+    ps.preempt_disable();
+    ps.preempt_disable();
+    assert!(ps.preempt_disabled());
+    ps.preempt_reenable();
+    assert!(ps.preempt_disabled());
+    ps.preempt_reenable();
+    assert!(!ps.preempt_disabled());
+    println!(" Test: preemption state ok!\n");
+}
+
+fn test_block() {
+    println!(" Test: block ...");
+    let percpu = new_percpu();
+    let mut threads = [Thread::new(), Thread::new()];
+    for t in threads.iter_mut() {
+        t.set_percpu_ptr(percpu);
+        Scheduler::init_thread(t, Thread::DEFAULT_PRIORITY);
+        Scheduler::unblock(t);
+    }
+    let (first, second) = {
+        let (a, b) = threads.split_at_mut(1);
+        (&mut a[0] as *mut Thread, &mut b[0] as *mut Thread)
+    };
+
+    let sched = unsafe { (*percpu).scheduler() };
+    let weight_total = sched.weight_total;
+    let mut wq = WaitQueue::new();
+
+    Scheduler::block(first, BlockReason::Normal, &mut wq);
+    unsafe {
+        assert!((*first).sched_state().state() == ThreadState::ThreadBlocked);
+    }
+    assert!(wq.count() == 1);
+    assert!(sched.weight_total == weight_total / 2);
+    assert!(sched.runnable_fair_task_count == 1);
+    assert!(sched.peek_next() == second);
+
+    Scheduler::block(second, BlockReason::ReadLock, &mut wq);
+    unsafe {
+        assert!((*second).sched_state().state() ==
+                ThreadState::ThreadBlockedReadLock);
+    }
+    assert!(sched.weight_total == 0);
+    assert!(sched.peek_next().is_null());
+
+    /* Waiters are woken up in FIFO order */
+    assert!(wq.wake_one());
+    assert!(sched.peek_next() == first);
+    assert!(wq.wake_all() == 1);
+    assert!(wq.is_empty());
+    assert!(!wq.wake_one());
+    assert!(sched.weight_total == weight_total);
+    unsafe {
+        assert!((*second).sched_state().state() == ThreadState::ThreadReady);
+    }
+
+    Scheduler::remove(first);
+    Scheduler::remove(second);
+    free_percpu(percpu);
+    println!(" Test: block ok!\n");
+}
+
+/* The wait queue and the progress of the thread blocking
+ * with preemption disabled */
+static PREEMPT_WQ: AtomicUsize = AtomicUsize::new(0);
+static PREEMPT_STEP: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn block_preempt_disabled_entry() -> ! {
+    let wq = PREEMPT_WQ.load(Ordering::Relaxed) as *mut WaitQueue;
+    Thread::current().preemption_state.preempt_disable();
+    PREEMPT_STEP.store(1, Ordering::Relaxed);
+
+    /* It panics here, and the panic switches back to the test */
+    Scheduler::block_current(BlockReason::Normal, unsafe { &mut *wq });
+    PREEMPT_STEP.store(2, Ordering::Relaxed);
+    unreachable!();
+}
+
+fn test_block_preempt_disabled() {
+    println!(" Test: block with preemption disabled ...");
+    let main = Thread::current() as *mut Thread;
+    let mut wq = WaitQueue::new();
+    PREEMPT_WQ.store(&mut wq as *mut WaitQueue as usize, Ordering::Relaxed);
+    PREEMPT_STEP.store(0, Ordering::Relaxed);
+
+    let mut worker = Thread::new();
+    assert!(worker.stack.init().is_ok());
+    let worker_ptr = &mut worker as *mut Thread;
+    worker.arch.init(block_preempt_disabled_entry, worker.stack.top(),
+                     worker_ptr as usize);
+    unsafe {
+        expect_panic(&(*main).arch);
+        arch_context_switch(&mut (*main).arch, &(*worker_ptr).arch);
+    }
+
+    /* It panicked in block_current, before it was blocked */
+    assert!(PREEMPT_STEP.load(Ordering::Relaxed) == 1);
+    assert!(thread_get_current() == main as usize);
+    assert!(wq.is_empty());
+    assert!(worker.sched_state().state() != ThreadState::ThreadBlocked);
+
+    worker.stack.teardown();
+    println!(" Test: block with preemption disabled ok!\n");
+}
+
+fn test_kstack_guard() {
+    println!(" Test: kstack guard pages ...");
+    let vmo_count = ALL_VMOS.lock().len();
+    let mut stack = KernelStack::new();
+    assert!(stack.init().is_ok());
+    let base = stack.base();
+    let top = base + stack.size();
+    assert!(stack.size() > 0);
+    assert!(IS_PAGE_ALIGNED!(base));
+
+    assert!(stack.is_guard_page(base - 1));
+    assert!(stack.is_guard_page(top));
+    assert!(!stack.is_guard_page(base));
+    assert!(!stack.is_guard_page(top - 1));
+
+    {
+        let aspace_list = ASPACE_LIST.lock();
+        let kernel_aspace = unsafe { &*aspace_list.head() };
+        let mut va = base;
+        while va < top {
+            assert!(kernel_aspace.query(va).is_ok());
+            va += PAGE_SIZE;
+        }
+        assert!(kernel_aspace.query(base - PAGE_SIZE) == Err(ErrNO::NotFound));
+        assert!(kernel_aspace.query(top) == Err(ErrNO::NotFound));
+    }
+
+    stack.teardown();
+    assert!(stack.base() == 0);
+    assert!(!stack.is_guard_page(base - 1));
+
+    /* Both the mapping and the reservation are gone */
+    {
+        let aspace_list = ASPACE_LIST.lock();
+        let kernel_aspace = unsafe { &mut *aspace_list.head() };
+        let mut va = base;
+        while va < top {
+            assert!(kernel_aspace.query(va) == Err(ErrNO::NotFound));
+            va += PAGE_SIZE;
+        }
+        assert!(kernel_aspace.root_vmar().find_mapping(base).is_none());
+    }
+    assert!(ALL_VMOS.lock().len() == vmo_count);
+    println!(" Test: kstack guard pages ok!\n");
+}
+
+fn test_load_estimate() {
+    println!(" Test: sched load estimate ...");
+    let percpu = new_percpu();
+    let sched = unsafe { (*percpu).scheduler() };
+    assert!(sched.load_estimate() == 0);
+
+    let mut threads = [Thread::new(), Thread::new(), Thread::new()];
+    let mut unit = 0;
+    for (i, t) in threads.iter_mut().enumerate() {
+        t.set_percpu_ptr(percpu);
+        Scheduler::init_thread(t, Thread::DEFAULT_PRIORITY);
+        Scheduler::unblock(t);
+        if i == 0 {
+            unit = sched.load_estimate();
+            assert!(unit > 0);
+        }
+        /* Each thread of the same expected runtime adds the same load */
+        assert!(sched.load_estimate() == unit * (i + 1));
+    }
+
+    for t in threads.iter_mut() {
+        Scheduler::remove(t);
+    }
+    assert!(sched.load_estimate() == 0);
+    free_percpu(percpu);
+
+    /* The boot thread keeps the boot cpu loaded */
+    assert!(sched_total_load() > 0);
+    println!(" Test: sched load estimate ok!\n");
+}
+
+/* The threads of the cooperative switch test */
+static SWITCH_MAIN: AtomicUsize = AtomicUsize::new(0);
+static SWITCH_OTHER: AtomicUsize = AtomicUsize::new(0);
+static SWITCH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn switch_entry() -> ! {
+    let main = SWITCH_MAIN.load(Ordering::Relaxed) as *mut Thread;
+    let other = SWITCH_OTHER.load(Ordering::Relaxed) as *mut Thread;
+    loop {
+        /* tp has been switched to this thread */
+        assert!(thread_get_current() == other as usize);
+        SWITCH_COUNT.fetch_add(1, Ordering::Relaxed);
+        unsafe {
+            arch_context_switch(&mut (*other).arch, &(*main).arch);
+        }
+    }
+}
+
+fn test_context_switch() {
+    println!(" Test: context switch ...");
+    let main = Thread::current() as *mut Thread;
+    let mut other = Thread::new();
+    assert!(other.stack.init().is_ok());
+    let other_ptr = &mut other as *mut Thread;
+    other.arch.init(switch_entry, other.stack.top(), other_ptr as usize);
+
+    SWITCH_MAIN.store(main as usize, Ordering::Relaxed);
+    SWITCH_OTHER.store(other_ptr as usize, Ordering::Relaxed);
+    SWITCH_COUNT.store(0, Ordering::Relaxed);
+
+    /* Switch into the other thread and back for a few rounds,
+     * the locals here must survive each round trip. */
+    let magic = 0x5a5a_a5a5_usize;
+    for i in 1..=3 {
+        unsafe {
+            arch_context_switch(&mut (*main).arch, &(*other_ptr).arch);
+        }
+        assert!(SWITCH_COUNT.load(Ordering::Relaxed) == i);
+        assert!(thread_get_current() == main as usize);
+        assert!(magic == 0x5a5a_a5a5);
+    }
+
+    other.stack.teardown();
+    println!(" Test: context switch ok!\n");
+}
+
+fn test_performance_scale() {
+    println!(" Test: sched performance scale ...");
+    /* Two extra cpus, the slow one has a lower cpu number */
+    let (slow_cpu, fast_cpu) = (_CONFIG_NR_CPUS - 2, _CONFIG_NR_CPUS - 1);
+    let slow = new_percpu();
+    let fast = new_percpu();
+    unsafe {
+        (*slow).scheduler().this_cpu = slow_cpu;
+        (*fast).scheduler().this_cpu = fast_cpu;
+        (*slow).scheduler().set_performance_scale(K_PERFORMANCE_SCALE_ONE / 2);
+        assert!((*slow).scheduler().performance_scale() ==
+                K_PERFORMANCE_SCALE_ONE / 2);
+    }
+    /* The slots are borrowed, and given back as they were at the end */
+    let saved = unsafe {
+        let mut percpu_array = PERCPU_ARRAY.lock();
+        (percpu_array.replace(slow_cpu, slow),
+         percpu_array.replace(fast_cpu, fast))
+    };
+
+    let mut thread = Thread::new();
+    Scheduler::init_thread(&mut thread, Thread::DEFAULT_PRIORITY);
+    thread.sched_state()
+        .set_hard_affinity(cpu_num_to_mask(slow_cpu) | cpu_num_to_mask(fast_cpu));
+    assert!(Scheduler::find_target_cpu(&mut thread) == fast_cpu);
+
+    /* The load on the half-speed cpu is scaled up twice */
+    let mut threads = [Thread::new(), Thread::new()];
+    for (t, percpu) in threads.iter_mut().zip([fast, slow]) {
+        t.set_percpu_ptr(percpu);
+        Scheduler::init_thread(t, Thread::DEFAULT_PRIORITY);
+        Scheduler::unblock(t);
+    }
+    let unit = unsafe { (*fast).scheduler().load_estimate() };
+    assert!(unit > 0);
+    unsafe {
+        assert!((*slow).scheduler().load_estimate() == unit * 2);
+        (*slow).scheduler().set_performance_scale(K_PERFORMANCE_SCALE_ONE);
+        assert!((*slow).scheduler().load_estimate() == unit);
+        /* A tie of the projected loads goes to the lower cpu number */
+        assert!(Scheduler::find_target_cpu(&mut thread) == slow_cpu);
+        (*slow).scheduler().set_performance_scale(K_PERFORMANCE_SCALE_ONE / 2);
+    }
+    assert!(Scheduler::find_target_cpu(&mut thread) == fast_cpu);
+
+    for t in threads.iter_mut() {
+        Scheduler::remove(t);
+    }
+    unsafe {
+        let mut percpu_array = PERCPU_ARRAY.lock();
+        percpu_array.set(slow_cpu, saved.0);
+        percpu_array.set(fast_cpu, saved.1);
+    }
+    free_percpu(slow);
+    free_percpu(fast);
+    println!(" Test: sched performance scale ok!\n");
+}
+
+static DPC_SUM: AtomicUsize = AtomicUsize::new(0);
+
+fn add_dpc(arg: usize) {
+    DPC_SUM.fetch_add(arg, Ordering::Relaxed);
+}
+
+extern "C" fn dpc_entry() -> ! {
+    let _ = dpc_thread(None);
+    unreachable!();
+}
+
+fn no_idle_hook(_cpu: cpu_num_t) {
+}
+
+fn test_dpc() {
+    println!(" Test: dpc ...");
+    let percpu = new_percpu();
+    /* No wfi when the worker switches to idle */
+    unsafe {
+        (*percpu).scheduler().set_idle_hooks(no_idle_hook, no_idle_hook);
+    }
+    let mut worker = Thread::new();
+    assert!(worker.stack.init().is_ok());
+    worker.set_percpu_ptr(percpu);
+    Scheduler::init_thread(&mut worker, Thread::DPC_PRIORITY);
+    let worker_ptr = &mut worker as *mut Thread;
+    worker.arch.init(dpc_entry, worker.stack.top(), worker_ptr as usize);
+    Scheduler::unblock(worker_ptr);
+    DPC_SUM.store(0, Ordering::Relaxed);
+
+    /* The dpc thread switches to the idle thread of its CPU when it
+     * blocks, so the context here is saved there to get back. */
+    let run_worker = || unsafe {
+        let idle = (*percpu).idle_thread_ptr();
+        arch_context_switch(&mut (*idle).arch, &(*worker_ptr).arch);
+    };
+
+    /* Nothing to do yet */
+    run_worker();
+    unsafe {
+        assert!((*worker_ptr).sched_state().state() ==
+                ThreadState::ThreadBlocked);
+    }
+
+    /* Queuing wakes up the dpc thread, which runs them all */
+    let queue = unsafe { (*percpu).dpc_queue() };
+    assert!(queue.queue(add_dpc, 1).is_ok());
+    assert!(queue.queue(add_dpc, 2).is_ok());
+    assert!(queue.count() == 2);
+    unsafe {
+        assert!((*percpu).scheduler().peek_next() == worker_ptr);
+    }
+    assert!(DPC_SUM.load(Ordering::Relaxed) == 0);
+
+    run_worker();
+    assert!(DPC_SUM.load(Ordering::Relaxed) == 3);
+    assert!(queue.count() == 0);
+    unsafe {
+        assert!((*worker_ptr).sched_state().state() ==
+                ThreadState::ThreadBlocked);
+        assert!((*percpu).scheduler().peek_next().is_null());
+    }
+
+    /* The slots are back in the pool, and no more than it holds
+     * can be queued at once */
+    for _ in 0..DPC_POOL_SIZE {
+        assert!(queue.queue(add_dpc, 1).is_ok());
+    }
+    assert!(queue.queue(add_dpc, 1) == Err(ErrNO::NoMem));
+    run_worker();
+    assert!(DPC_SUM.load(Ordering::Relaxed) == 3 + DPC_POOL_SIZE);
+    assert!(queue.count() == 0);
+
+    worker.stack.teardown();
+    free_percpu(percpu);
+    println!(" Test: dpc ok!\n");
+}
+
+static IDLE_ENTERS: AtomicUsize = AtomicUsize::new(0);
+static IDLE_EXITS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_enter_idle(_cpu: cpu_num_t) {
+    IDLE_ENTERS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn count_exit_idle(_cpu: cpu_num_t) {
+    IDLE_EXITS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn test_idle_hooks() {
+    println!(" Test: idle hooks ...");
+    let main = Thread::current() as *mut Thread;
+    let percpu = new_percpu();
+    let idle = unsafe {
+        (*percpu).scheduler().set_idle_hooks(count_enter_idle, count_exit_idle);
+        let idle = (*percpu).idle_thread_ptr();
+        (*idle).set_percpu_ptr(percpu);
+        idle
+    };
+    IDLE_ENTERS.store(0, Ordering::Relaxed);
+    IDLE_EXITS.store(0, Ordering::Relaxed);
+
+    /* A dpc thread, which blocks whenever its queue is empty */
+    let mut worker = Thread::new();
+    assert!(worker.stack.init().is_ok());
+    worker.set_percpu_ptr(percpu);
+    Scheduler::init_thread(&mut worker, Thread::DPC_PRIORITY);
+    let worker_ptr = &mut worker as *mut Thread;
+    worker.arch.init(dpc_entry, worker.stack.top(), worker_ptr as usize);
+    Scheduler::unblock(worker_ptr);
+
+    /* The worker blocks at once and the CPU goes idle, which is
+     * where the context here has been saved. */
+    unsafe {
+        arch_context_switch(&mut (*idle).arch, &(*worker_ptr).arch);
+    }
+    assert!(IDLE_ENTERS.load(Ordering::Relaxed) == 1);
+    assert!(IDLE_EXITS.load(Ordering::Relaxed) == 0);
+
+    /* Work arrives, and the idle thread switches to the worker */
+    unsafe {
+        assert!((*percpu).dpc_queue().queue(add_dpc, 0).is_ok());
+    }
+    thread_set_current(idle as usize);
+    Scheduler::reschedule();
+    thread_set_current(main as usize);
+    assert!(IDLE_EXITS.load(Ordering::Relaxed) == 1);
+    assert!(IDLE_ENTERS.load(Ordering::Relaxed) == 2);
+    unsafe {
+        assert!((*percpu).scheduler().peek_next().is_null());
+    }
+
+    worker.stack.teardown();
+    free_percpu(percpu);
+    println!(" Test: idle hooks ok!\n");
+}
+
+fn test_idle_yields() {
+    println!(" Test: idle yields to any ready thread ...");
+    let main = Thread::current() as *mut Thread;
+    let percpu = new_percpu();
+    /* No wfi when the worker switches to idle */
+    let idle = unsafe {
+        (*percpu).scheduler().set_idle_hooks(no_idle_hook, no_idle_hook);
+        let idle = (*percpu).idle_thread_ptr();
+        (*idle).set_percpu_ptr(percpu);
+        idle
+    };
+
+    /* A dpc thread of the lowest priority, as low as the idle thread */
+    let mut worker = Thread::new();
+    assert!(worker.stack.init().is_ok());
+    worker.set_percpu_ptr(percpu);
+    Scheduler::init_thread(&mut worker, 0);
+    let worker_ptr = &mut worker as *mut Thread;
+    worker.arch.init(dpc_entry, worker.stack.top(), worker_ptr as usize);
+    Scheduler::unblock(worker_ptr);
+
+    /* The worker blocks at once and the CPU goes idle */
+    unsafe {
+        arch_context_switch(&mut (*idle).arch, &(*worker_ptr).arch);
+    }
+    DPC_SUM.store(0, Ordering::Relaxed);
+    unsafe {
+        assert!((*percpu).dpc_queue().queue(add_dpc, 1).is_ok());
+        assert!((*worker_ptr).sched_state().effective_priority() ==
+                (*idle).sched_state().effective_priority());
+    }
+
+    /* The idle thread switches to the worker all the same */
+    thread_set_current(idle as usize);
+    Scheduler::reschedule();
+    thread_set_current(main as usize);
+    assert!(DPC_SUM.load(Ordering::Relaxed) == 1);
+    unsafe {
+        assert!((*worker_ptr).sched_state().state() ==
+                ThreadState::ThreadBlocked);
+    }
+
+    worker.stack.teardown();
+    free_percpu(percpu);
+    println!(" Test: idle yields to any ready thread ok!\n");
+}
+
+fn test_alloc_struct() {
+    println!(" Test: alloc thread struct ...");
+    let thread = Thread::alloc_struct();
+    assert!(!thread.is_null());
+    unsafe {
+        /* Nothing is left uninitialized before construct_thread */
+        let bytes = core::slice::from_raw_parts(thread as *const u8,
+                                                mem::size_of::<Thread>());
+        assert!(bytes.iter().all(|b| *b == 0));
+        assert!(!(*thread).is_in_list());
+        assert!((*thread).thread_info.cpu == 0);
+        dealloc(thread as *mut u8, Layout::new::<Thread>());
+    }
+    println!(" Test: alloc thread struct ok!\n");
+}
+
+fn create_entry(_arg: Option<ThreadArg>) -> Result<(), ErrNO> {
+    Ok(())
+}
+
+fn test_create() {
+    println!(" Test: create thread ...");
+    let current = Thread::current() as *mut Thread;
+    let thread = Thread::create("test", create_entry, None,
+                                Thread::DEFAULT_PRIORITY);
+    assert!(thread.is_ok());
+    let thread = thread.unwrap();
+    assert!(in_thread_list(thread));
+    unsafe {
+        /* It waits for resume on the CPU of its creator */
+        assert!((*thread).sched_state().state() == ThreadState::ThreadInitial);
+        assert!((*thread).thread_info.cpu == (*current).thread_info.cpu);
+        assert!((*thread).percpu_ptr() == (*current).percpu_ptr());
+
+        /* Never run, it's reaped as soon as it's dead */
+        (*thread).detach();
+        (*thread).sched_state().set_state(ThreadState::ThreadDeath);
+    }
+    assert!(thread_cleanup() == 1);
+    assert!(!in_thread_list(thread));
+    println!(" Test: create thread ok!\n");
+}
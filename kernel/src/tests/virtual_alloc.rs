@@ -0,0 +1,156 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use crate::BOOT_CONTEXT;
+use crate::arch::mmu::{PAGE_EXEC, PAGE_READ, PAGE_WRITE};
+use crate::errors::ErrNO;
+use crate::aspace::{
+    ExistingEntryAction, vm_get_kernel_heap_base, vm_get_kernel_heap_size,
+};
+use crate::{ARCH_HEAP_ALIGN_BITS, PAGE_SHIFT, PAGE_SIZE};
+use crate::pmm::paddr_to_vm_page;
+use crate::vm::vm::{
+    ARCH_MMU_FLAG_PERM_EXECUTE, ARCH_MMU_FLAG_PERM_READ,
+    ARCH_MMU_FLAG_PERM_WRITE,
+};
+use crate::vm_page_state;
+
+pub fn test_virtual_alloc() {
+    test_alloc_aligned();
+    test_stats();
+    test_unmap();
+    test_protect();
+}
+
+fn test_alloc_aligned() {
+    println!(" Test: virtual alloc aligned ...");
+    /* A run of the heap alignment and a few pages more, so the
+     * contiguous path and the page by page one are both taken */
+    let align = 1usize << ARCH_HEAP_ALIGN_BITS;
+    let align_pages = align >> PAGE_SHIFT;
+    let pages = align_pages + 3;
+    let alloc = BOOT_CONTEXT.virtual_alloc();
+    let va = alloc.alloc_pages(pages).unwrap();
+    assert!(va % align == 0);
+    assert!(alloc.allocated_run(va) == Some((va, va + pages * PAGE_SIZE)));
+
+    let (base, _) = BOOT_CONTEXT.kernel_aspace().query(va).unwrap();
+    assert!(base % align == 0);
+    for i in 0..pages {
+        let (pa, _) =
+            BOOT_CONTEXT.kernel_aspace().query(va + i * PAGE_SIZE).unwrap();
+        if i < align_pages {
+            assert!(pa == base + i * PAGE_SIZE);
+        }
+        let page = paddr_to_vm_page(pa);
+        unsafe { assert!((*page).state() == vm_page_state::HEAP); }
+    }
+
+    /* The pages are unmapped and back in the pmm */
+    assert!(alloc.free_pages(va, pages).is_ok());
+    assert!(BOOT_CONTEXT.kernel_aspace().query(va) == Err(ErrNO::NotFound));
+    assert!(alloc.allocated_run(va).is_none());
+    unsafe { assert!((*paddr_to_vm_page(base)).is_free()); }
+    println!(" Test: virtual alloc aligned ok!\n");
+}
+
+fn test_stats() {
+    println!(" Test: virtual alloc stats ...");
+    let alloc = BOOT_CONTEXT.virtual_alloc();
+    assert!(alloc.region_bounds() ==
+            (vm_get_kernel_heap_base(), vm_get_kernel_heap_size()));
+    let total = vm_get_kernel_heap_size() / PAGE_SIZE;
+    let allocated = alloc.allocated_pages();
+    let free = alloc.free_pages_available();
+    assert!(allocated > 0);
+    assert!(allocated + free + alloc.bitmap_pages() == total);
+
+    /* Each allocation takes its pages only, not the padding around it */
+    let mut sum = 0;
+    let mut vas = [0; 3];
+    for (i, pages) in [1, 3, 8].into_iter().enumerate() {
+        vas[i] = alloc.alloc_pages(pages).unwrap();
+        sum += pages;
+        assert!(alloc.allocated_pages() == allocated + sum);
+        assert!(alloc.free_pages_available() == free - sum);
+    }
+
+    /* Freeing them out of order balances it out */
+    for (i, pages) in [(1, 3), (0, 1), (2, 8)] {
+        assert!(alloc.free_pages(vas[i], pages).is_ok());
+        sum -= pages;
+        assert!(alloc.allocated_pages() == allocated + sum);
+        assert!(alloc.free_pages_available() == free - sum);
+    }
+    println!(" Test: virtual alloc stats ok!\n");
+}
+
+fn test_unmap() {
+    println!(" Test: kernel aspace unmap ...");
+    let alloc = BOOT_CONTEXT.virtual_alloc();
+    let va = alloc.alloc_pages(4).unwrap();
+    /* Held only between the VirtualAlloc calls, which lock ASPACE_LIST */
+    let mut aspace = BOOT_CONTEXT.kernel_aspace();
+    let mut pas = [0; 4];
+    for i in 0..4 {
+        pas[i] = aspace.query(va + i * PAGE_SIZE).unwrap().0;
+    }
+
+    /* Not everything is mapped, so nothing is unmapped */
+    assert!(aspace.unmap(va, 5, false) == Err(ErrNO::NotFound));
+    assert!(aspace.query(va).is_ok());
+    assert!(aspace.unmap(va + 1, 1, false) == Err(ErrNO::InvalidArgs));
+
+    /* The second page, then the rest with the hole tolerated */
+    assert!(aspace.unmap(va + PAGE_SIZE, 1, false) == Ok(1));
+    assert!(aspace.query(va + PAGE_SIZE) == Err(ErrNO::NotFound));
+    assert!(aspace.query(va).is_ok());
+    assert!(aspace.unmap(va, 4, true) == Ok(3));
+    for i in 0..4 {
+        assert!(aspace.query(va + i * PAGE_SIZE) == Err(ErrNO::NotFound));
+    }
+    assert!(aspace.unmap(va, 4, true) == Ok(0));
+
+    /* Map the pages again, so that they can be freed as usual */
+    let mmu_flags = ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE;
+    assert!(aspace.map(va, &pas, 4, mmu_flags,
+                       ExistingEntryAction::Error) == Ok(4));
+    assert!(aspace.query(va + 3 * PAGE_SIZE).unwrap().0 == pas[3]);
+    drop(aspace);
+    assert!(alloc.free_pages(va, 4).is_ok());
+    println!(" Test: kernel aspace unmap ok!\n");
+}
+
+fn test_protect() {
+    println!(" Test: kernel aspace protect ...");
+    let alloc = BOOT_CONTEXT.virtual_alloc();
+    let va = alloc.alloc_pages(1).unwrap();
+    let aspace = BOOT_CONTEXT.kernel_aspace();
+    let (pa, prot) = aspace.query(va).unwrap();
+    assert!((prot & PAGE_EXEC) == 0);
+
+    /* Read only first, then read and execute */
+    assert!(aspace.protect(va, 1, ARCH_MMU_FLAG_PERM_READ).is_ok());
+    let (_, prot) = aspace.query(va).unwrap();
+    assert!((prot & (PAGE_READ | PAGE_WRITE | PAGE_EXEC)) == PAGE_READ);
+    let flags = ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_EXECUTE;
+    assert!(aspace.protect(va, 1, flags).is_ok());
+    let (rx_pa, prot) = aspace.query(va).unwrap();
+    assert!(rx_pa == pa);
+    assert!((prot & (PAGE_READ | PAGE_WRITE | PAGE_EXEC)) ==
+            (PAGE_READ | PAGE_EXEC));
+    assert!(aspace.protect(va, 1, ARCH_MMU_FLAG_PERM_WRITE) ==
+            Err(ErrNO::InvalidArgs));
+
+    /* Back to read and write before the heap takes it again */
+    let flags = ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE;
+    assert!(aspace.protect(va, 1, flags).is_ok());
+    drop(aspace);
+    assert!(alloc.free_pages(va, 1).is_ok());
+    println!(" Test: kernel aspace protect ok!\n");
+}
@@ -0,0 +1,289 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use alloc::vec::Vec;
+use crate::BOOT_CONTEXT;
+use crate::aspace::{KERNEL_ASPACE_ID, vm_get_kernel_heap_base};
+use crate::defines::PAGE_SIZE;
+use crate::errors::ErrNO;
+use crate::vm::vmar::{AllocMode, VmAddressRegion};
+
+const TEST_BASE: usize = 0x1000_0000;
+const TEST_SIZE: usize = 0x100_0000;
+
+pub fn test_vmar() {
+    test_alloc_top_down();
+    test_alloc_compact();
+    test_alloc_leftmost();
+    test_alloc_random();
+    test_kernel_aspace();
+    test_reserve_region();
+    test_find_mapping();
+    test_destroy_child();
+    test_children_storage();
+}
+
+fn new_region(base: usize, size: usize) -> VmAddressRegion {
+    let mut vmar = VmAddressRegion::new();
+    vmar.init(base, size, 0);
+    vmar
+}
+
+fn test_alloc_top_down() {
+    println!(" Test: vmar alloc top down ...");
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    vmar.insert_child(new_region(TEST_BASE + 0x10_0000, 0x10_0000));
+    vmar.insert_child(new_region(TEST_BASE + 0x80_0000, 0x10_0000));
+
+    /* Bottom up by default */
+    let spot = vmar.alloc_spot_locked(4 * PAGE_SIZE, 0, 0, usize::MAX, false,
+                                      AllocMode::Leftmost);
+    assert!(spot == TEST_BASE);
+
+    /* Top down without limit: at the end of the region */
+    let spot = vmar.alloc_spot_locked(4 * PAGE_SIZE, 0, 0, usize::MAX, true,
+                                      AllocMode::Leftmost);
+    assert!(spot == TEST_BASE + TEST_SIZE - 4 * PAGE_SIZE);
+
+    /* Top down below the limit: just below the limit */
+    let limit = TEST_BASE + 0x40_0000 + 0x800;
+    let spot = vmar.alloc_spot_locked(4 * PAGE_SIZE, 0, 0, limit, true,
+                                      AllocMode::Leftmost);
+    assert!(spot + 4 * PAGE_SIZE <= limit);
+    assert!(spot == TEST_BASE + 0x40_0000 - 4 * PAGE_SIZE);
+
+    /* The limit is inside a child: below the child */
+    let limit = TEST_BASE + 0x88_0000;
+    let spot = vmar.alloc_spot_locked(4 * PAGE_SIZE, 0, 0, limit, true,
+                                      AllocMode::Leftmost);
+    assert!(spot == TEST_BASE + 0x80_0000 - 4 * PAGE_SIZE);
+
+    /* Alignment is kept */
+    let spot = vmar.alloc_spot_locked(4 * PAGE_SIZE, 20, 0, limit, true,
+                                      AllocMode::Leftmost);
+    assert!(spot == TEST_BASE + 0x70_0000);
+    println!(" Test: vmar alloc top down ok!\n");
+}
+
+fn test_alloc_compact() {
+    println!(" Test: vmar alloc compact ...");
+    const SIZE: usize = 4 * PAGE_SIZE;
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    vmar.insert_child(new_region(TEST_BASE + 0x10_0000, 0x10_0000));
+    vmar.insert_child(new_region(TEST_BASE + 0x80_0000, 0x10_0000));
+
+    /* Compact: the tightest gap [0x00_0000, 0x10_0000), at either end */
+    let spot = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, false,
+                                      AllocMode::Compact);
+    assert!(spot == TEST_BASE);
+    let spot = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, true,
+                                      AllocMode::Compact);
+    assert!(spot == TEST_BASE + 0x10_0000 - SIZE);
+
+    /* Too large for the first gap: the tighter of the other two */
+    let spot = vmar.alloc_spot_locked(0x20_0000, 0, 0, usize::MAX, false,
+                                      AllocMode::Compact);
+    assert!(spot == TEST_BASE + 0x20_0000);
+
+    /* The last gap is the largest one, although little of it is left
+     * below the limit, so the tightest gap is still the first one */
+    let limit = TEST_BASE + 0x90_0000 + SIZE;
+    let spot = vmar.alloc_spot_locked(SIZE, 0, 0, limit, false,
+                                      AllocMode::Compact);
+    assert!(spot == TEST_BASE);
+    println!(" Test: vmar alloc compact ok!\n");
+}
+
+fn test_alloc_leftmost() {
+    println!(" Test: vmar alloc leftmost ...");
+    const SIZE: usize = 4 * PAGE_SIZE;
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    vmar.insert_child(new_region(TEST_BASE + 0x10_0000, 0x10_0000));
+    vmar.insert_child(new_region(TEST_BASE + 0x80_0000, 0x10_0000));
+
+    /* Successive allocations are adjacent */
+    let mut expected = TEST_BASE;
+    for _ in 0..4 {
+        let spot = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, false,
+                                          AllocMode::Leftmost);
+        assert!(spot == expected);
+        vmar.insert_child(new_region(spot, SIZE));
+        expected += SIZE;
+    }
+
+    let mut expected = TEST_BASE + TEST_SIZE - SIZE;
+    for _ in 0..4 {
+        let spot = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, true,
+                                          AllocMode::Leftmost);
+        assert!(spot == expected);
+        vmar.insert_child(new_region(spot, SIZE));
+        expected -= SIZE;
+    }
+    println!(" Test: vmar alloc leftmost ok!\n");
+}
+
+fn test_alloc_random() {
+    println!(" Test: vmar alloc random ...");
+    const SIZE: usize = 4 * PAGE_SIZE;
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    vmar.insert_child(new_region(TEST_BASE + 0x10_0000, 0x10_0000));
+    vmar.set_prng_seed(1);
+
+    /* The 253 positions of the first gap and 3 more of the second one */
+    let mut spots = Vec::new();
+    for _ in 0..16 {
+        let spot = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, false,
+                                          AllocMode::Random);
+        assert!(spot + SIZE <= TEST_BASE + 0x10_0000 ||
+                (spot >= TEST_BASE + 0x20_0000 &&
+                 spot < TEST_BASE + 0x20_0000 + 3 * PAGE_SIZE));
+        spots.push(spot);
+    }
+    spots.sort();
+    spots.dedup();
+    assert!(spots.len() > 1);
+
+    /* Aligned, from the top and below the limit */
+    let limit = TEST_BASE + 0x80_0000;
+    for _ in 0..16 {
+        let spot = vmar.alloc_spot_locked(SIZE, 16, 0, limit, true,
+                                          AllocMode::Random);
+        assert!(spot % 0x1_0000 == 0);
+        assert!(spot + SIZE <= limit && spot >= TEST_BASE + 0x20_0000);
+    }
+    println!(" Test: vmar alloc random ok!\n");
+}
+
+fn test_kernel_aspace() {
+    println!(" Test: kernel aspace by id ...");
+    assert!(KERNEL_ASPACE_ID == 0);
+    let aspace = &*BOOT_CONTEXT.get_aspace_by_id(0).unwrap() as *const _;
+    assert!(aspace == &*BOOT_CONTEXT.kernel_aspace() as *const _);
+    assert!(BOOT_CONTEXT.kernel_aspace().id() == 0);
+    assert!(BOOT_CONTEXT.get_aspace_by_id(1).is_none());
+
+    /* The heap is mapped in the kernel aspace */
+    let heap_base = vm_get_kernel_heap_base();
+    assert!(BOOT_CONTEXT.kernel_aspace().query(heap_base).is_ok());
+    println!(" Test: kernel aspace by id ok!\n");
+}
+
+fn test_reserve_region() {
+    println!(" Test: vmar reserve region ...");
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    assert!(vmar.reserve_region(TEST_BASE + 0x10_0000, 0x10_0000, 0).is_ok());
+
+    /* A boot region overlapping the first one at either side */
+    assert!(matches!(vmar.reserve_region(TEST_BASE + 0x18_0000, 0x10_0000, 0),
+                     Err(ErrNO::AlreadyExists)));
+    assert!(matches!(vmar.reserve_region(TEST_BASE, 0x10_1000, 0),
+                     Err(ErrNO::AlreadyExists)));
+    assert!(matches!(vmar.reserve_region(TEST_BASE, TEST_SIZE, 0),
+                     Err(ErrNO::AlreadyExists)));
+
+    /* Adjacent ones are fine, out of the region or empty ones are not */
+    assert!(vmar.reserve_region(TEST_BASE, 0x10_0000, 0).is_ok());
+    assert!(vmar.reserve_region(TEST_BASE + 0x20_0000, PAGE_SIZE, 0).is_ok());
+    assert!(matches!(vmar.reserve_region(TEST_BASE + TEST_SIZE - PAGE_SIZE,
+                                         2 * PAGE_SIZE, 0),
+                     Err(ErrNO::InvalidArgs)));
+    assert!(matches!(vmar.reserve_region(TEST_BASE - PAGE_SIZE, PAGE_SIZE, 0),
+                     Err(ErrNO::InvalidArgs)));
+    assert!(matches!(vmar.reserve_region(TEST_BASE + 0x30_0000, 0, 0),
+                     Err(ErrNO::InvalidArgs)));
+
+    /* The reserved regions are left alone by allocation */
+    let spot = vmar.alloc_spot_locked(PAGE_SIZE, 0, 0, usize::MAX, false,
+                                      AllocMode::Leftmost);
+    assert!(spot == TEST_BASE + 0x20_0000 + PAGE_SIZE);
+    println!(" Test: vmar reserve region ok!\n");
+}
+
+fn test_find_mapping() {
+    println!(" Test: vmar find mapping ...");
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    assert!(vmar.find_mapping(TEST_BASE).is_none());
+
+    /* Inserted out of order, with a nested one in the middle child */
+    vmar.insert_child(new_region(TEST_BASE + 0x80_0000, 0x10_0000));
+    vmar.insert_child(new_region(TEST_BASE, 0x10_0000));
+    let mut middle = new_region(TEST_BASE + 0x40_0000, 0x10_0000);
+    middle.insert_child(new_region(TEST_BASE + 0x44_0000, PAGE_SIZE));
+    vmar.insert_child(middle);
+
+    let base_of = |va| vmar.find_mapping(va).map(|r| r.base);
+    assert!(base_of(TEST_BASE) == Some(TEST_BASE));
+    assert!(base_of(TEST_BASE + 0x10_0000 - 1) == Some(TEST_BASE));
+    assert!(base_of(TEST_BASE + 0x40_0000) == Some(TEST_BASE + 0x40_0000));
+    assert!(base_of(TEST_BASE + 0x44_0000) == Some(TEST_BASE + 0x44_0000));
+    assert!(base_of(TEST_BASE + 0x44_0000 + PAGE_SIZE) ==
+            Some(TEST_BASE + 0x40_0000));
+    assert!(base_of(TEST_BASE + 0x88_0000) == Some(TEST_BASE + 0x80_0000));
+
+    /* The gaps, the end of a child, and out of the region */
+    assert!(base_of(TEST_BASE + 0x10_0000).is_none());
+    assert!(base_of(TEST_BASE + 0x90_0000).is_none());
+    assert!(base_of(TEST_BASE - 1).is_none());
+    assert!(base_of(TEST_BASE + TEST_SIZE).is_none());
+    assert!(base_of(usize::MAX).is_none());
+    println!(" Test: vmar find mapping ok!\n");
+}
+
+fn test_destroy_child() {
+    println!(" Test: vmar destroy child ...");
+    const SIZE: usize = 4 * PAGE_SIZE;
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    assert!(vmar.reserve_region(TEST_BASE + 0x10_0000, SIZE, 0).is_ok());
+    let spot = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, false,
+                                      AllocMode::Leftmost);
+    vmar.insert_child(new_region(spot, SIZE));
+    let next = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, false,
+                                      AllocMode::Leftmost);
+    assert!(next == spot + SIZE);
+
+    /* Only a base a child starts at */
+    assert!(vmar.destroy_child(spot + PAGE_SIZE).is_none());
+    let child = vmar.destroy_child(spot).unwrap();
+    assert!(child.base == spot && child.size == SIZE);
+    assert!(vmar.destroy_child(spot).is_none());
+    assert!(vmar.find_mapping(spot).is_none());
+
+    /* The gap is open again, and the other child is still there */
+    let again = vmar.alloc_spot_locked(SIZE, 0, 0, usize::MAX, false,
+                                       AllocMode::Leftmost);
+    assert!(again == spot);
+    assert!(vmar.find_mapping(TEST_BASE + 0x10_0000).is_some());
+    println!(" Test: vmar destroy child ok!\n");
+}
+
+fn test_children_storage() {
+    println!(" Test: vmar children storage ...");
+    const SIZE: usize = 4 * PAGE_SIZE;
+    let mut vmar = new_region(TEST_BASE, TEST_SIZE);
+    assert!(!vmar.has_child_slot());
+
+    /* Storage which holds no more than the current one is handed back */
+    let smaller = vmar.swap_children_storage(Vec::new());
+    assert!(smaller.capacity() == 0);
+    assert!(!vmar.has_child_slot());
+
+    /* The children move into the bigger storage, in the same order */
+    let old = vmar.swap_children_storage(Vec::with_capacity(2));
+    assert!(old.capacity() == 0);
+    assert!(vmar.has_child_slot());
+    vmar.insert_child(new_region(TEST_BASE + SIZE, SIZE));
+    vmar.insert_child(new_region(TEST_BASE, SIZE));
+    assert!(!vmar.has_child_slot());
+    let old = vmar.swap_children_storage(Vec::with_capacity(4));
+    assert!(old.capacity() >= 2 && old.is_empty());
+    assert!(vmar.child_count() == 2 && vmar.has_child_slot());
+    assert!(vmar.find_mapping(TEST_BASE).unwrap().base == TEST_BASE);
+    assert!(vmar.find_mapping(TEST_BASE + SIZE).unwrap().base ==
+            TEST_BASE + SIZE);
+    println!(" Test: vmar children storage ok!\n");
+}
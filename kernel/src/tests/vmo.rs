@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+use crate::defines::PAGE_SIZE;
+use crate::pmm::PMM_ALLOC_FLAG_ANY;
+use crate::vm::vm_object_paged::VmObjectPaged;
+use crate::vm::vm_page_list::{VmPageList, VmPageOrMarker};
+use alloc::vec::Vec;
+
+pub fn test_vmo() {
+    test_create_clone();
+    test_clone_twice();
+    test_page_list_reverse();
+}
+
+fn test_create_clone() {
+    println!(" Test: vmo create clone ...");
+    let vmo_ref = VmObjectPaged::create(PMM_ALLOC_FLAG_ANY, 0, 2 * PAGE_SIZE)
+        .expect("create vmo");
+    let parent = vmo_ref.lock();
+    parent.write(&[0x11u8; 16], 0).unwrap();
+    parent.write(&[0x22u8; 16], PAGE_SIZE).unwrap();
+
+    let clone_ref = parent.create_clone(0, 2 * PAGE_SIZE).expect("clone");
+    let child = clone_ref.lock();
+
+    /* Clone sees the content of its parent */
+    let mut buf = [0u8; 16];
+    child.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x11u8; 16]);
+
+    /* Write in the child must not change the parent */
+    child.write(&[0x33u8; 16], 0).unwrap();
+    child.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x33u8; 16]);
+    parent.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x11u8; 16]);
+
+    /* Write in the parent must not change the child */
+    parent.write(&[0x44u8; 16], PAGE_SIZE).unwrap();
+    child.read(&mut buf, PAGE_SIZE).unwrap();
+    assert!(buf == [0x22u8; 16]);
+    parent.read(&mut buf, PAGE_SIZE).unwrap();
+    assert!(buf == [0x44u8; 16]);
+    println!(" Test: vmo create clone ok!\n");
+}
+
+fn test_clone_twice() {
+    println!(" Test: vmo clone twice ...");
+    let vmo_ref = VmObjectPaged::create(PMM_ALLOC_FLAG_ANY, 0, PAGE_SIZE)
+        .expect("create vmo");
+    let parent = vmo_ref.lock();
+    parent.write(&[0x11u8; 16], 0).unwrap();
+
+    /* The first clone diverges, which splits the page in the hidden
+     * parent for its side only */
+    let first_ref = parent.create_clone(0, PAGE_SIZE).expect("clone");
+    let first = first_ref.lock();
+    first.write(&[0x22u8; 16], 0).unwrap();
+
+    /* The second clone sits under a new hidden parent, and still sees
+     * the page two levels up */
+    let second_ref = parent.create_clone(0, PAGE_SIZE).expect("clone");
+    let second = second_ref.lock();
+    let mut buf = [0u8; 16];
+    second.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x11u8; 16]);
+    parent.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x11u8; 16]);
+    first.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x22u8; 16]);
+
+    /* Writing in the second clone leaves the others alone */
+    second.write(&[0x33u8; 16], 0).unwrap();
+    parent.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x11u8; 16]);
+    first.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x22u8; 16]);
+    second.read(&mut buf, 0).unwrap();
+    assert!(buf == [0x33u8; 16]);
+    println!(" Test: vmo clone twice ok!\n");
+}
+
+fn test_page_list_reverse() {
+    println!(" Test: page list reverse walk ...");
+    /* Page 16 falls into the second node */
+    let pages = [0, 3, 16, 20];
+    let mut pl = VmPageList::new();
+    for i in pages.iter() {
+        *pl.lookup_or_allocate(i * PAGE_SIZE).unwrap() = VmPageOrMarker::marker();
+    }
+
+    let walk = |start: usize, end: usize| {
+        let mut offsets = Vec::new();
+        let mut per_page_func = |p: &VmPageOrMarker, offset: usize| {
+            assert!(p.is_marker());
+            offsets.push(offset / PAGE_SIZE);
+            Ok(())
+        };
+        pl.for_every_page_in_range_reverse(&mut per_page_func,
+                                           start * PAGE_SIZE,
+                                           end * PAGE_SIZE).unwrap();
+        offsets
+    };
+
+    assert!(walk(0, 32) == [20, 16, 3, 0]);
+    assert!(walk(2, 17) == [16, 3]);
+    assert!(walk(4, 16).is_empty());
+    assert!(walk(21, 64).is_empty());
+    assert!(walk(3, 3).is_empty());
+    println!(" Test: page list reverse walk ok!\n");
+}
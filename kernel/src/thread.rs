@@ -9,19 +9,22 @@
 use core::alloc::Layout;
 use core::arch::asm;
 use core::mem;
-use core::ptr::null_mut;
+use core::ptr::{self, null_mut};
 use core::sync::atomic::{AtomicU32, Ordering};
-use alloc::alloc::{alloc, alloc_zeroed};
+use alloc::alloc::{alloc_zeroed, dealloc};
 use alloc::string::String;
+use alloc::vec::Vec;
 
 use crate::arch::smp::arch_curr_cpu_num;
+use crate::arch::thread::ArchThreadState;
 use crate::errors::ErrNO;
 use crate::klib::list::{Linked, List, ListNode};
 use crate::locking::mutex::Mutex;
 use crate::ZX_ASSERT;
 use crate::percpu::{PerCPU, BOOT_CPU_ID, PERCPU_ARRAY};
 use crate::arch::irq::arch_irqs_disabled;
-use crate::sched::{SchedulerState, Scheduler};
+use crate::dpc::{dpc_init_for_cpu, dpc_queue};
+use crate::sched::{SchedulerState, Scheduler, ThreadState};
 use crate::vm::kstack::KernelStack;
 
 pub const THREAD_FLAG_DETACHED:     u32 = 1 << 0;
@@ -109,6 +112,11 @@ impl PreemptionState {
         }
     }
 
+    // Whether the preempt disable counter of the thread is non-zero.
+    pub fn preempt_disabled(&self) -> bool {
+        Self::preempt_disable_count(self.state.load(Ordering::Relaxed)) > 0
+    }
+
     // PreemptDisable() increments the preempt disable counter for the current
     // thread. While preempt disable is non-zero, preemption of the thread is
     // disabled, including preemption from interrupt handlers. During this time,
@@ -121,11 +129,19 @@ impl PreemptionState {
     //
     // A call to PreemptDisable() must be matched by a later call to
     // PreemptReenable() to decrement the preempt disable counter.
-    fn preempt_disable(&self) {
+    pub fn preempt_disable(&self) {
         let old_state = self.state.fetch_add(1, Ordering::Relaxed);
         ZX_ASSERT!(Self::preempt_disable_count(old_state) < Self::K_MAX_COUNT_VALUE);
     }
 
+    // PreemptReenable() decrements the preempt disable counter. It must be
+    // matched with an earlier call to PreemptDisable().
+    #[allow(dead_code)]
+    pub fn preempt_reenable(&self) {
+        let old_state = self.state.fetch_sub(1, Ordering::Relaxed);
+        ZX_ASSERT!(Self::preempt_disable_count(old_state) > 0);
+    }
+
     fn preempt_disable_count(state: u32) -> u32 {
         state & Self::K_PREEMPT_DISABLE_MASK
     }
@@ -162,6 +178,7 @@ pub struct Thread {
     pub task_state: TaskState,
     pub preemption_state: PreemptionState,
     pub stack: KernelStack,
+    pub arch: ArchThreadState,
 }
 
 unsafe impl Send for Thread {}
@@ -185,7 +202,7 @@ impl Thread {
 
     const _LOWEST_PRIORITY:  usize = 0;
     pub const HIGHEST_PRIORITY: usize = Self::NUM_PRIORITIES - 1;
-    const _DPC_PRIORITY:     usize = Self::NUM_PRIORITIES - 2;
+    pub const DPC_PRIORITY:  usize = Self::NUM_PRIORITIES - 2;
     const _IDLE_PRIORITY:    usize = Self::_LOWEST_PRIORITY;
     const _LOW_PRIORITY:     usize = Self::NUM_PRIORITIES / 4;
     pub const DEFAULT_PRIORITY: usize = Self::NUM_PRIORITIES / 2;
@@ -208,6 +225,7 @@ impl Thread {
             task_state: TaskState::new(),
             preemption_state: PreemptionState::new(),
             stack: KernelStack::new(),
+            arch: ArchThreadState::new(),
         }
     }
 
@@ -228,9 +246,8 @@ impl Thread {
         self.percpu = ptr;
     }
 
-    #[allow(dead_code)]
     pub fn create(name: &str, entry: ThreadStartEntry, arg: Option<ThreadArg>,
-                  priority: usize) -> Result<Self, ErrNO> {
+                  priority: usize) -> Result<*mut Thread, ErrNO> {
         Thread::create_etc(null_mut(), name, entry, arg, priority, None)
     }
 
@@ -267,34 +284,60 @@ impl Thread {
                   entry: ThreadStartEntry, arg: Option<ThreadArg>,
                   priority: usize,
                   _alt_trampoline: Option<&_ThreadTrampolineEntry>)
-        -> Result<Self, ErrNO>
+        -> Result<*mut Thread, ErrNO>
     {
-        let mut _flags: u32 = 0;
+        let mut flags: u32 = 0;
 
         if thread == null_mut() {
-            let layout = Layout::new::<Thread>();
-            thread = unsafe { alloc(layout) as *mut Thread };
+            thread = Self::alloc_struct();
             if thread.is_null() {
                 panic!("Out of memory!");
             }
-            _flags |= THREAD_FLAG_FREE_STRUCT;
+            flags |= THREAD_FLAG_FREE_STRUCT;
         }
 
         /* thread is at least as aligned as the thread is supposed to be */
         ZX_ASSERT!(IS_ALIGNED!(thread as usize, mem::align_of::<Thread>()));
 
+        unsafe {
+            ptr::write(thread, Thread::new());
+        }
         construct_thread(thread, name);
+        unsafe {
+            (*thread).thread_info.flags |= flags;
+        }
 
         unsafe {
             (*thread).task_state.init(entry, arg);
         }
         Scheduler::init_thread(thread, priority);
 
+        if let Err(e) = unsafe { (*thread).stack.init() } {
+            if (flags & THREAD_FLAG_FREE_STRUCT) != 0 {
+                unsafe { Thread::free(thread); }
+            }
+            return Err(e);
+        }
+
+        /* It starts on the CPU of its creator, from thread_trampoline */
+        let current = Thread::current();
         unsafe {
-            (*thread).stack.init()?;
+            (*thread).thread_info.cpu = current.thread_info.cpu;
+            (*thread).set_percpu_ptr(current.percpu_ptr());
+            (*thread).arch.init(thread_trampoline, (*thread).stack.top(),
+                                thread as usize);
         }
 
-        todo!("create_etc!");
+        THREAD_LIST.lock().add_tail(thread);
+        Ok(thread)
+    }
+
+    /* Allocate a thread struct from heap. It is zeroed rather than left
+     * uninitialized, so that any field not set up by construct_thread
+     * (pointers, atomics and list nodes read by the scheduler) starts
+     * as null or zero, just like the PerCPU of the boot cpu. */
+    pub fn alloc_struct() -> *mut Thread {
+        unsafe { alloc_zeroed(Layout::new::<Thread>()) as *mut Thread }
     }
 
     #[allow(dead_code)]
@@ -326,38 +369,41 @@ impl Thread {
      * created with thread_create() or which has been suspended with
      * thread_suspend(). It can not fail.
      */
-    #[allow(dead_code)]
-    pub fn resume(&self) {
-        todo!("resume!");
-        /*
-  Guard<MonitoredSpinLock, IrqSave> guard{ThreadLock::Get(), SOURCE_TAG};
-
-  if (state() == THREAD_DEATH) {
-    // The thread is dead, resuming it is a no-op.
-    return;
-  }
+    pub fn resume(&mut self) {
+        /* Todo: take the thread lock, and clear the suspend signal
+         * once there are signals. */
+        let state = self.sched_state.state();
+        if state == ThreadState::ThreadInitial ||
+            state == ThreadState::_ThreadSuspended {
+            /* Wake up the new thread, putting it in a run queue */
+            Scheduler::unblock(self);
+        }
+    }
 
-  // Clear the suspend signal in case there is a pending suspend
-  signals_.fetch_and(~THREAD_SIGNAL_SUSPEND, ktl::memory_order_relaxed);
-  if (state() == THREAD_INITIAL || state() == THREAD_SUSPENDED) {
-    // Wake up the new thread, putting it in a run queue on a cpu.
-    Scheduler::Unblock(this);
-  }
+    /* Change the priority of the thread, which is an integer
+     * from 0 (lowest) to 31 (highest). */
+    #[allow(dead_code)]
+    pub fn set_priority(&mut self, priority: usize) -> Result<(), ErrNO> {
+        if priority > Self::HIGHEST_PRIORITY {
+            return Err(ErrNO::InvalidArgs);
+        }
+        Scheduler::set_priority(self, priority);
+        Ok(())
+    }
 
-  kcounter_add(thread_resume_count, 1);
-  */
+    pub fn name(&self) -> &str {
+        self.name.as_str()
     }
 
-    fn set_name(&mut self, name: &str) {
+    pub fn set_name(&mut self, name: &str) {
         self.name = String::from(name);
     }
 
-    #[allow(dead_code)]
     fn detatched(&self) -> bool {
         (self.thread_info.flags & THREAD_FLAG_DETACHED) != 0
     }
 
-    fn set_detached(&mut self, detatched: bool) {
+    pub fn set_detached(&mut self, detatched: bool) {
         if detatched {
             self.thread_info.flags |= THREAD_FLAG_DETACHED;
         } else {
@@ -365,6 +411,37 @@ impl Thread {
         }
     }
 
+    fn free_struct(&self) -> bool {
+        (self.thread_info.flags & THREAD_FLAG_FREE_STRUCT) != 0
+    }
+
+    #[allow(dead_code)]
+    pub fn set_free_struct(&mut self, free_struct: bool) {
+        if free_struct {
+            self.thread_info.flags |= THREAD_FLAG_FREE_STRUCT;
+        } else {
+            self.thread_info.flags &= !THREAD_FLAG_FREE_STRUCT;
+        }
+    }
+
+    /* Nobody will join a detached thread, so it's up to the reaper
+     * to free it after it's dead. */
+    fn reapable(&self) -> bool {
+        self.sched_state.state() == ThreadState::ThreadDeath &&
+            self.detatched() && self.free_struct()
+    }
+
+    /* Free the kernel stack and the struct of a thread,
+     * which must have been allocated from heap. */
+    unsafe fn free(thread: *mut Thread) {
+        ZX_ASSERT!((*thread).free_struct());
+        ZX_ASSERT!(!(*thread).is_in_list());
+
+        (*thread).stack.teardown();
+        ptr::drop_in_place(thread);
+        dealloc(thread as *mut u8, Layout::new::<Thread>());
+    }
+
     pub fn sched_state(&mut self) -> &mut SchedulerState {
         &mut self.sched_state
     }
@@ -399,6 +476,12 @@ fn construct_boot_percpu() {
     }
 }
 
+/* Start the per-cpu threads of the boot cpu, e.g. the dpc thread,
+ * which need the heap and the kernel aspace for their stacks. */
+pub fn thread_init() -> Result<(), ErrNO> {
+    dpc_init_for_cpu()
+}
+
 /**
  * @brief Construct a thread t around the current running state
  *
@@ -433,6 +516,59 @@ pub fn thread_construct_first(thread: *mut Thread, name: &str) {
     }
 }
 
+/*
+ * Reap threads which are dead and detached, and whose struct was
+ * allocated by create_etc. As nobody will join them, their kernel stacks
+ * and structs are freed here. It is expected to be run from the idle
+ * thread (or a dpc) rather than by the dying thread itself, which is
+ * still running on its stack.
+ *
+ * Returns the number of the reaped threads.
+ */
+pub fn thread_cleanup() -> usize {
+    let mut dead = Vec::new();
+    {
+        let mut thread_list = THREAD_LIST.lock();
+        for t in thread_list.iter_mut() {
+            if unsafe { (*t).reapable() } {
+                dead.push(t);
+            }
+        }
+        for t in dead.iter() {
+            unsafe { (**t).delete_from_list(); }
+        }
+    }
+
+    for t in dead.iter() {
+        unsafe { Thread::free(*t); }
+    }
+    dead.len()
+}
+
+fn thread_cleanup_dpc(_arg: usize) {
+    thread_cleanup();
+}
+
+/* Defer the reaping to the dpc thread of the current CPU, as a dying
+ * thread can't free the stack it's running on. Each reaper takes all
+ * the dead threads at once, so if the dpc pool is full, the thread is
+ * left to the reaper of the next one which dies. */
+pub fn thread_reap_deferred() {
+    let _ = dpc_queue(thread_cleanup_dpc, 0);
+}
+
+/* Where a thread made by create_etc starts. It runs the entry of its
+ * task, and dies once the entry returns. */
+extern "C" fn thread_trampoline() -> ! {
+    let current = Thread::current();
+    let entry = current.task_state.entry;
+    let arg = current.task_state.arg.take();
+    let _ = entry(arg);
+
+    Scheduler::exit_current();
+    unreachable!();
+}
+
 fn arch_thread_construct_first(_t: *mut Thread) {
 }
 
@@ -6,15 +6,26 @@
  * at https://opensource.org/licenses/MIT
  */
 
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::ZX_ASSERT;
+use crate::klib::list::List;
+use crate::locking::mutex::MutexGuard;
 use crate::pmm::PMM_ALLOC_FLAG_ANY;
 use crate::types::*;
-use crate::aspace::ASPACE_LIST;
+use crate::debug::*;
+use crate::aspace::{
+    ASPACE_LIST, ExistingEntryAction, VmAspace, VMAR_FLAG_CAN_MAP_SPECIFIC,
+    VMAR_FLAG_CAN_MAP_READ, VMAR_FLAG_CAN_MAP_WRITE,
+};
 use crate::errors::ErrNO;
-use crate::vm::vm_object_paged::VmObjectPaged;
-use crate::defines::ARCH_DEFAULT_STACK_SIZE;
+use crate::vm::vm::{
+    ARCH_MMU_FLAG_CACHED, ARCH_MMU_FLAG_PERM_READ, ARCH_MMU_FLAG_PERM_WRITE,
+};
+use crate::vm::vm_object_paged::{ALL_VMOS, VmObjectPaged, VmObjectPagedLockRef};
+use crate::defines::{ARCH_DEFAULT_STACK_SIZE, PAGE_SHIFT, PAGE_SIZE};
 
-use super::vmar::VmAddressRegion;
+use super::vmar::{AllocMode, VmAddressRegion};
 
 /* stack size */
 pub const DEFAULT_STACK_SIZE: usize = ARCH_DEFAULT_STACK_SIZE;
@@ -33,8 +44,10 @@ const K_SAFE: StackType = StackType {
 struct KernelStackMapping {
     base: vaddr_t,
     size: usize,
-    #[allow(dead_code)]
-    vmar: VmAddressRegion,
+    /* The base of the reservation inserted into the root vmar,
+     * which covers the stack and its guard pages */
+    vmar_base: vaddr_t,
+    vmo: Option<VmObjectPagedLockRef>,
 }
 
 impl KernelStackMapping {
@@ -42,11 +55,11 @@ impl KernelStackMapping {
         Self {
             base: 0,
             size: 0,
-            vmar: VmAddressRegion::new(),
+            vmar_base: 0,
+            vmo: None,
         }
     }
 
-    #[allow(dead_code)]
     fn top(&self) -> vaddr_t {
         self.base + self.size
     }
@@ -64,31 +77,150 @@ impl KernelStack {
     }
 
     pub fn init(&mut self) -> Result<(), ErrNO> {
-        allocate_map(K_SAFE, &self.main_map)
+        allocate_map(K_SAFE, &mut self.main_map)
+    }
+
+    /* The lowest address of the stack, just above the lower guard page */
+    pub fn base(&self) -> vaddr_t {
+        self.main_map.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.main_map.size
+    }
+
+    #[allow(dead_code)]
+    pub fn top(&self) -> vaddr_t {
+        self.main_map.top()
+    }
+
+    /* For the page fault handler: a fault on a guard page
+     * means that the stack has overflowed (or underflowed). */
+    #[allow(dead_code)]
+    pub fn is_guard_page(&self, va: vaddr_t) -> bool {
+        let map = &self.main_map;
+        if map.base == 0 {
+            return false;
+        }
+        (va < map.base && va >= map.base - PAGE_SIZE) ||
+            (va >= map.top() && va < map.top() + PAGE_SIZE)
+    }
+
+    /* Release the mapping of the stack. It is safe to call it
+     * on a stack which has never been mapped. */
+    pub fn teardown(&mut self) {
+        if self.main_map.base == 0 {
+            return;
+        }
+
+        let map = &mut self.main_map;
+        /* The child is dropped once ASPACE_LIST is released */
+        let kstack_vmar = {
+            let aspace_list = ASPACE_LIST.lock();
+            let kernel_aspace = aspace_list.head();
+            let count = map.size / PAGE_SIZE;
+            unsafe {
+                let ret = (*kernel_aspace).unmap(map.base, count, false);
+                ZX_ASSERT!(ret == Ok(count));
+                (*kernel_aspace).root_vmar().destroy_child(map.vmar_base)
+            }
+        };
+        ZX_ASSERT!(kstack_vmar.is_some());
+        drop(kstack_vmar);
+
+        /* The vmo is only released once ALL_VMOS drops it as well */
+        if let Some(vmo) = map.vmo.take() {
+            ALL_VMOS.lock().retain(|v| !Arc::ptr_eq(v, &vmo));
+        }
+
+        dprintf!(INFO, "kstack: unmapped [0x{:x}, 0x{:x})\n",
+                 map.base, map.top());
+        self.main_map = KernelStackMapping::new();
+    }
+}
+
+/* Lock ASPACE_LIST with room for one more child in the root vmar.
+ * Growing the heap takes ASPACE_LIST too, so insert_child must not
+ * allocate under it: the storage of the children is allocated, and the
+ * old one freed, while the lock isn't held. */
+fn lock_with_child_slot() -> MutexGuard<'static, List<VmAspace>> {
+    loop {
+        let count = {
+            let aspace_list = ASPACE_LIST.lock();
+            let vmar = unsafe { (*aspace_list.head()).root_vmar() };
+            if vmar.has_child_slot() {
+                return aspace_list;
+            }
+            vmar.child_count()
+        };
+
+        let storage = Vec::with_capacity((count + 1) * 2);
+        let old_storage = {
+            let aspace_list = ASPACE_LIST.lock();
+            let vmar = unsafe { (*aspace_list.head()).root_vmar() };
+            vmar.swap_children_storage(storage)
+        };
+        drop(old_storage);
     }
 }
 
 /* Allocates and maps a kernel stack with one page of padding
  * before and after the mapping. */
-fn allocate_map(stype: StackType, map: &KernelStackMapping)
+fn allocate_map(stype: StackType, map: &mut KernelStackMapping)
     -> Result<(), ErrNO>
 {
     /* assert that this mapping hasn't already be created */
     ZX_ASSERT!(map.base == 0);
     ZX_ASSERT!(map.size == 0);
 
+    /* Create a VMO for our stack before taking the aspace lock,
+     * as growing the heap also needs it. */
+    let stack_vmo = VmObjectPaged::create(PMM_ALLOC_FLAG_ANY,
+                                          VmObjectPaged::K_ALWAYS_PINNED,
+                                          stype.size)?;
+    let phys = {
+        let mut vmo = stack_vmo.as_ref().lock();
+        vmo.set_name(stype.name);
+        vmo.lookup(0, stype.size / PAGE_SIZE)?
+    };
+
     /* get a handle to the root vmar */
-    let aspace_list = ASPACE_LIST.lock();
+    let aspace_list = lock_with_child_slot();
     let kernel_aspace = aspace_list.head();
-    unsafe {
-        let _vmar = (*kernel_aspace).root_vmar();
-        /* Create a VMO for our stack */
-        let stack_vmo = VmObjectPaged::create(PMM_ALLOC_FLAG_ANY,
-                                              VmObjectPaged::K_ALWAYS_PINNED,
-                                              stype.size)?;
-        let mut stack_vmo = stack_vmo.as_ref().lock();
-        stack_vmo.set_name(stype.name);
+    let vmar = unsafe { (*kernel_aspace).root_vmar() };
+
+    /* create a vmar with enough padding for a page before and after
+     * the stack */
+    let padding_size = PAGE_SIZE;
+    let mmu_flags = ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE;
+    let size = padding_size * 2 + stype.size;
+    let spot = vmar.alloc_spot_locked(size, PAGE_SHIFT, mmu_flags,
+                                      usize::MAX, false, AllocMode::Leftmost);
+    let mut kstack_vmar = VmAddressRegion::new();
+    kstack_vmar.init(spot, size, VMAR_FLAG_CAN_MAP_SPECIFIC |
+                     VMAR_FLAG_CAN_MAP_READ | VMAR_FLAG_CAN_MAP_WRITE);
+    vmar.insert_child(kstack_vmar);
+
+    /* map it, leaving the padding pages unmapped as the guards */
+    let base = spot + padding_size;
+    let ret = unsafe {
+        (*kernel_aspace).map(base, &phys, phys.len(),
+                             ARCH_MMU_FLAG_CACHED | mmu_flags,
+                             ExistingEntryAction::Error)
+    };
+    if let Err(e) = ret {
+        let kstack_vmar = vmar.destroy_child(spot);
+        drop(aspace_list);
+        drop(kstack_vmar);
+        return Err(e);
     }
 
-    todo!("allocate_map!");
-}
\ No newline at end of file
+    dprintf!(INFO, "kstack: '{}' mapped at [0x{:x}, 0x{:x})\n",
+             stype.name, base, base + stype.size);
+
+    map.base = base;
+    map.size = stype.size;
+    map.vmar_base = spot;
+    map.vmo = Some(stack_vmo);
+    Ok(())
+}
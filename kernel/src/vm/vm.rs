@@ -6,15 +6,15 @@
  * at https://opensource.org/licenses/MIT
  */
 
-use alloc::vec::Vec;
 use spin::lazy::Lazy;
 use crate::ZX_ASSERT;
 use crate::arch::mmu::PAGE_READ;
 use crate::arch::mmu::PAGE_WRITE;
+use crate::arch::mmu::PAGE_EXEC;
+use crate::arch::mmu::{PageTable, _swapper_pgd, find_wx_mappings};
 use crate::aspace::ASPACE_LIST;
 use crate::errors::ErrNO;
-use crate::pmm::PMM_NODE;
-use crate::pmm::PmmArena;
+use crate::pmm::{ArenaInfo, pmm_get_arena_info};
 use crate::types::*;
 use crate::defines::*;
 use crate::debug::*;
@@ -41,7 +41,7 @@ pub const GAP_MMU_FLAGS: usize = ARCH_MMU_FLAG_PERM_READ |
 
 pub fn mmu_prot_from_flags(mmu_flags: usize) -> prot_t {
     let mask = ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE |
-        ARCH_MMU_FLAG_UNCACHED_DEVICE;
+        ARCH_MMU_FLAG_PERM_EXECUTE | ARCH_MMU_FLAG_UNCACHED_DEVICE;
     if (mmu_flags & !mask) != 0 {
         panic!("bad flags: 0x{:x}", mmu_flags);
     }
@@ -53,12 +53,14 @@ pub fn mmu_prot_from_flags(mmu_flags: usize) -> prot_t {
     if (mmu_flags & ARCH_MMU_FLAG_PERM_WRITE) != 0 {
         prot |= PAGE_WRITE;
     }
+    if (mmu_flags & ARCH_MMU_FLAG_PERM_EXECUTE) != 0 {
+        prot |= PAGE_EXEC;
+    }
 
     prot
 }
 
 /* List of the kernel program's various segments. */
-#[allow(dead_code)]
 struct KernelRegion {
     name: &'static str,
     base: vaddr_t,
@@ -134,12 +136,31 @@ pub fn vm_init() -> Result<(), ErrNO> {
     // Mark the physmap no-execute.
     physmap_protect_arena_regions_noexecute();
 
+    /* boot_map has mapped the whole kernel image RWX,
+     * now give each of its regions its own permissions. */
+    for region in KERNEL_REGIONS.iter() {
+        dprintf!(INFO, "VM: protecting kernel region [0x{:x}, 0x{:x}) \
+                 flags 0x{:x} name '{}'\n",
+                 region.base, region.base + region.size,
+                 region.arch_mmu_flags, region.name);
+        kernel_protect_region(region.base, region.size,
+                              region.arch_mmu_flags);
+    }
+
+    #[cfg(feature = "unittest")]
+    ZX_ASSERT!(vm_verify_wx() == 0);
+
     /* Todo: vm_init! */
     Ok(())
 }
 
 // Protect the region [ |base|, |base| + |size| ) from the physmap.
 fn physmap_protect_region(base: vaddr_t, size: usize, mmu_flags: usize) {
+    kernel_protect_region(base, size, mmu_flags);
+}
+
+/* Change the flags of [ |base|, |base| + |size| ) in the kernel aspace. */
+fn kernel_protect_region(base: vaddr_t, size: usize, mmu_flags: usize) {
     ZX_ASSERT!(base % PAGE_SIZE == 0);
     ZX_ASSERT!(size % PAGE_SIZE == 0);
     let page_count = size / PAGE_SIZE;
@@ -165,23 +186,21 @@ fn physmap_protect_non_arena_regions() {
         physmap_protect_region(base, size, GAP_MMU_FLAGS);
     };
 
-    {
-        let arenas = PMM_NODE.get_arenas();
-        physmap_for_each_gap(&physmap_protect_gap, &arenas);
-    }
+    let arenas = pmm_get_arena_info();
+    physmap_for_each_gap(&physmap_protect_gap, &arenas);
 }
 
-fn physmap_for_each_gap<F>(func: &F, arenas: &Vec<PmmArena>)
+fn physmap_for_each_gap<F>(func: &F, arenas: &[ArenaInfo])
     where F: Fn(vaddr_t, usize) {
     // Iterate over the arenas and invoke |func| for the gaps between them.
     //
     // |gap_base| is the base address of the last identified gap.
     let mut gap_base = PHYSMAP_BASE;
     for arena in arenas {
-        let arena_base = paddr_to_physmap(arena.base());
+        let arena_base = paddr_to_physmap(arena.base);
         ZX_ASSERT!(arena_base >= gap_base && arena_base % PAGE_SIZE == 0);
 
-        let arena_size = arena.size();
+        let arena_size = arena.size;
         ZX_ASSERT!(arena_size > 0 && arena_size % PAGE_SIZE == 0);
 
         dprintf!(SPEW, "gap_base=0x{:x}; arena_base=0x{:x}; arena_size=0x{:x}\n",
@@ -202,21 +221,31 @@ fn physmap_for_each_gap<F>(func: &F, arenas: &Vec<PmmArena>)
     }
 }
 
+// The arenas of the physmap are for data only, never to be executed.
+const K_PHYSMAP_MMU_FLAGS: usize =
+    ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE;
+
 fn physmap_protect_arena_regions_noexecute() {
+    for arena in pmm_get_arena_info() {
+        physmap_protect_region(paddr_to_physmap(arena.base), arena.size,
+                               K_PHYSMAP_MMU_FLAGS);
+    }
 }
-/*
-  const size_t num_arenas = pmm_num_arenas();
-  fbl::AllocChecker ac;
-  auto arenas = ktl::unique_ptr<pmm_arena_info_t[]>(new (&ac) pmm_arena_info_t[num_arenas]);
-  ASSERT(ac.check());
-  const size_t size = num_arenas * sizeof(pmm_arena_info_t);
-
-  zx_status_t status = pmm_get_arena_info(num_arenas, 0, arenas.get(), size);
-  ASSERT(status == ZX_OK);
-
-  for (uint i = 0; i < num_arenas; i++) {
-    physmap_protect_region(reinterpret_cast<vaddr_t>(paddr_to_physmap(arenas[i].base)),
-                           /*size=*/arenas[i].size, /*mmu_flags=*/kPhysmapMmuFlags);
-  }
+
+/* Check that W^X holds in the kernel aspace: log every page which is
+ * writable and executable at the same time. Returns their count. */
+#[allow(dead_code)]
+pub fn vm_verify_wx() -> usize {
+    vm_verify_wx_in(unsafe { &_swapper_pgd })
+}
+
+/* Same as vm_verify_wx, but for the page table tree rooted at |root|. */
+pub fn vm_verify_wx_in(root: &PageTable) -> usize {
+    let mut offenders = 0;
+    find_wx_mappings(root, |va, level, pte| {
+        dprintf!(CRITICAL, "W+X mapping at 0x{:x} (level {}, pte 0x{:x})\n",
+                 va, level, pte);
+        offenders += 1;
+    });
+    offenders
 }
-*/
\ No newline at end of file
@@ -6,6 +6,7 @@
  * at https://opensource.org/licenses/MIT
  */
 
+use core::cmp::min;
 use core::ptr::null_mut;
 use alloc::sync::Arc;
 use crate::ZX_ASSERT;
@@ -21,12 +22,14 @@ use crate::page::{vm_page_t, vm_page, vm_page_object};
 use super::page_source::PageSource;
 use super::vm_object_paged::VmObjectPaged;
 use super::vm_page_list::{VmPageList, VmPageOrMarker};
-use crate::pmm::pmm_page_queues;
+use crate::pmm::{pmm_alloc_page, pmm_page_queues};
 use crate::debug::*;
 
 #[allow(dead_code)]
 type VmCowPagesPtr = *mut VmCowPages;
 
+pub type VmCowPagesLockRef = Arc<Mutex<VmCowPages>>;
+
 /* Controls the type of content that can be overwritten by
  * the Add[New]Page[s]Locked functions. */
 pub enum CanOverwriteContent {
@@ -59,7 +62,20 @@ pub struct VmCowPages {
     // optional reference back to a VmObjectPaged so that
     // we can perform mapping updates. This is a raw pointer to avoid
     // circular references, the VmObjectPaged destructor needs to update it.
-    paged_ref: Arc<Mutex<VmObjectPaged>>,
+    // Hidden nodes have no VmObjectPaged of their own.
+    paged_ref: Option<Arc<Mutex<VmObjectPaged>>>,
+
+    /* Reference to our parent in a clone tree. For a hidden parent the
+     * original VmCowPages is the left child and the clone is the right. */
+    parent: Option<VmCowPagesLockRef>,
+    /* Offset in the parent where our content starts. */
+    parent_offset: usize,
+    /* Offset into our own range past which content can no longer be
+     * looked up in the parent. */
+    parent_limit: usize,
+    /* Which side of our (hidden) parent we are on. It is used to select
+     * the split bit of a page in the parent when we diverge from it. */
+    is_left_child: bool,
 }
 
 impl VmCowPages {
@@ -95,7 +111,11 @@ impl VmCowPages {
             page_list: Mutex::new(VmPageList::new()),
             page_source: Arc::new(Mutex::new(PageSource::new())),
             pinned_page_count: 0,
-            paged_ref: Arc::new(Mutex::new(VmObjectPaged::new(options))),
+            paged_ref: None,
+            parent: None,
+            parent_offset: 0,
+            parent_limit: 0,
+            is_left_child: false,
         }
     }
 
@@ -173,8 +193,11 @@ impl VmCowPages {
         if self.page_source.is_null() {
             return false;
         }
+        return page_source_->properties().is_preserving_page_content;
         */
-        todo!("is_source_preserving_page_content");
+        /* PageSource is only a placeholder now and never preserves
+         * page content, so all pages are anonymous. */
+        false
     }
 
     fn add_new_page(&mut self, offset: usize, page: *mut vm_page_t,
@@ -378,12 +401,256 @@ impl VmCowPages {
     // This object has no tracking of mappings, but understands that
     // they exist. When it manipulates pages in a way that could effect
     // mappings it uses the backlink to notify the VmObjectPaged.
-    // Currently it is assumed that all nodes always have backlinks with
-    // the 1:1 hierarchy mapping.
+    // Currently it is assumed that all visible nodes always have backlinks
+    // with the 1:1 hierarchy mapping, hidden nodes never have one.
     pub fn set_paged_backlink_locked(&mut self,
         paged_ref: Arc<Mutex<VmObjectPaged>>)
     {
-        self.paged_ref = paged_ref;
+        self.paged_ref = Some(paged_ref);
+    }
+
+    fn is_hidden(&self) -> bool {
+        (self.options & Self::K_HIDDEN) != 0
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /* Creates a copy-on-write clone of [offset, offset + size) of |self_ref|.
+     *
+     * A hidden VmCowPages is inserted above this node and takes over all
+     * of its pages. This node becomes the left child of the hidden parent
+     * and the returned clone becomes the right child. Both children then
+     * see the content of the hidden parent until they write to a page,
+     * at which point the page is copied down (see get_page_for_write). */
+    pub fn create_clone(self_ref: &VmCowPagesLockRef,
+                        offset: usize, size: usize)
+        -> Result<VmCowPagesLockRef, ErrNO>
+    {
+        ZX_ASSERT!(IS_PAGE_ALIGNED!(offset));
+        ZX_ASSERT!(IS_PAGE_ALIGNED!(size));
+
+        let mut cow = self_ref.lock();
+        ZX_ASSERT!(!cow.is_hidden());
+
+        /* Pinned pages must stay in place, so they cannot be
+         * handed over to a hidden parent. */
+        if cow.pinned_page_count > 0 {
+            return Err(ErrNO::BadState);
+        }
+
+        let hidden_ref = Arc::new(Mutex::new(
+            Self::new(Self::K_HIDDEN, cow.pmm_alloc_flags, cow.size)
+        ));
+
+        {
+            let mut hidden = hidden_ref.lock();
+
+            /* The hidden parent takes our place in the clone tree. */
+            hidden.parent = cow.parent.take();
+            hidden.parent_offset = cow.parent_offset;
+            hidden.parent_limit = cow.parent_limit;
+            hidden.is_left_child = cow.is_left_child;
+
+            /* Move all of our pages into the hidden parent,
+             * and update their backlinks. */
+            let hidden_obj = &(*hidden) as *const VmCowPages as usize;
+            let mut pl = hidden.page_list.lock();
+            core::mem::swap(&mut *pl, &mut *cow.page_list.lock());
+
+            let mut per_page_func = |p: &VmPageOrMarker, _page_offset| {
+                if p.is_page() {
+                    unsafe { (*p.page()).object.set_object(hidden_obj); }
+                }
+                Ok(())
+            };
+            pl.for_every_page_in_range(&mut per_page_func, 0, cow.size)?;
+        }
+
+        cow.parent = Some(hidden_ref.clone());
+        cow.parent_offset = 0;
+        cow.parent_limit = cow.size;
+        cow.is_left_child = true;
+
+        let mut clone = Self::new(cow.options, cow.pmm_alloc_flags, size);
+        clone.parent = Some(hidden_ref);
+        clone.parent_offset = offset;
+        clone.parent_limit = if offset < cow.size {
+            min(size, cow.size - offset)
+        } else {
+            0
+        };
+        clone.is_left_child = false;
+
+        Ok(Arc::new(Mutex::new(clone)))
     }
 
+    /* Looks up the page which holds the content of |offset|,
+     * searching up the clone tree if we don't own the page. */
+    pub fn get_page_for_read(&self, offset: usize) -> Option<*mut vm_page_t> {
+        ZX_ASSERT!(offset < self.size);
+        if let Some(p) = self.page_list.lock().lookup(offset) {
+            if p.is_page() {
+                return Some(p.page());
+            }
+        }
+
+        if offset >= self.parent_limit {
+            return None;
+        }
+        let parent = self.parent.as_ref()?.lock();
+        let parent_offset = offset + self.parent_offset;
+
+        /* Split bits are only kept in the direct hidden parent, so they
+         * tell nothing about a page further up the tree. That one belongs
+         * to the side of our parent, which the parent itself checks. */
+        let owned = match parent.page_list.lock().lookup(parent_offset) {
+            Some(p) if p.is_page() => Some(p.page()),
+            _ => None,
+        };
+        let page = match owned {
+            Some(page) => page,
+            None => return parent.get_page_for_read(parent_offset),
+        };
+
+        let split = unsafe {
+            if self.is_left_child {
+                (*page).object.cow_left_split()
+            } else {
+                (*page).object.cow_right_split()
+            }
+        };
+        /* A split page is no longer visible to this side. */
+        if split {
+            return None;
+        }
+        Some(page)
+    }
+
+    /* Returns a page owned by this node for |offset| that can be written.
+     * Content shared with the hidden parent is copied down first, and
+     * ranges with no content at all are committed as zero pages. */
+    pub fn get_page_for_write(&mut self, offset: usize)
+        -> Result<*mut vm_page_t, ErrNO>
+    {
+        ZX_ASSERT!(IS_PAGE_ALIGNED!(offset));
+        if offset >= self.size {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        if let Some(p) = self.page_list.lock().lookup(offset) {
+            if p.is_page() {
+                return Ok(p.page());
+            }
+        }
+
+        let src = if offset < self.parent_limit {
+            match &self.parent {
+                Some(parent) => self.fork_page_from_parent(parent.clone(),
+                                                           offset)?,
+                None => None,
+            }
+        } else {
+            None
+        };
+
+        let src = match src {
+            /* The page has been migrated to us, nothing to copy. */
+            Some((page, true)) => return Ok(page),
+            Some((page, false)) => Some(page),
+            None => None,
+        };
+
+        let page = pmm_alloc_page(self.pmm_alloc_flags);
+        if page.is_null() {
+            return Err(ErrNO::NoMem);
+        }
+
+        match src {
+            Some(src) => {
+                unsafe {
+                    let src_va = paddr_to_physmap((*src).paddr());
+                    let dst_va = paddr_to_physmap((*page).paddr());
+                    core::ptr::copy_nonoverlapping(src_va as *const u8,
+                                                   dst_va as *mut u8,
+                                                   PAGE_SIZE);
+                }
+                self.add_new_page(offset, page, &CanOverwriteContent::Zero,
+                                  None, false, false)?;
+            },
+            None => {
+                self.add_new_page(offset, page, &CanOverwriteContent::Zero,
+                                  None, true, false)?;
+            }
+        }
+
+        Ok(page)
+    }
+
+    /* Handles the parent side of a copy-on-write fault at |offset|.
+     *
+     * If the page in the hidden parent is still visible to the other child,
+     * our split bit is set and the page is returned as the source for
+     * the copy. If the other child has already diverged, the page is
+     * no longer needed by the parent, so it is moved down into us.
+     * The returned flag tells whether the page has been moved. */
+    fn fork_page_from_parent(&mut self, parent_ref: VmCowPagesLockRef,
+                             offset: usize)
+        -> Result<Option<(*mut vm_page_t, bool)>, ErrNO>
+    {
+        let parent = parent_ref.lock();
+        let parent_offset = offset + self.parent_offset;
+        let page = match parent.get_page_for_read(parent_offset) {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+
+        let mut pl = parent.page_list.lock();
+        let owned_by_parent = match pl.lookup(parent_offset) {
+            Some(p) => p.is_page() && p.page() == page,
+            None => false,
+        };
+
+        /* Split bits are only kept in the direct hidden parent.
+         * Content from further up the tree is always copied. */
+        if !owned_by_parent || !parent.is_hidden() {
+            return Ok(Some((page, false)));
+        }
+
+        let page_ref = unsafe { &mut (*page) };
+        let other_split = if self.is_left_child {
+            page_ref.object.cow_right_split()
+        } else {
+            page_ref.object.cow_left_split()
+        };
+
+        if !other_split {
+            if self.is_left_child {
+                page_ref.object.set_cow_left_split(true);
+            } else {
+                page_ref.object.set_cow_right_split(true);
+            }
+            return Ok(Some((page, false)));
+        }
+
+        /* It should never be the case that both split bits are set,
+         * so move the page into this child instead. */
+        if let Some(slot) = pl.lookup_mut(parent_offset) {
+            slot.set_empty();
+        }
+        drop(pl);
+        drop(parent);
+
+        page_ref.object.set_cow_left_split(false);
+        page_ref.object.set_cow_right_split(false);
+        page_ref.object.set_object(self as *const VmCowPages as usize);
+        page_ref.object.set_page_offset(offset);
+
+        let p = VmPageOrMarker::as_page(page);
+        let mut pl = self.page_list.lock();
+        let slot = pl.lookup_or_allocate(offset)?;
+        slot.set(&p);
+        Ok(Some((page, true)))
+    }
 }
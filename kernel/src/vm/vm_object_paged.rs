@@ -10,20 +10,24 @@ use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::vec::Vec;
 use crate::ZX_ASSERT;
-use crate::defines::PAGE_SIZE;
+use core::cmp::min;
+use crate::defines::{PAGE_SIZE, paddr_to_physmap};
 use crate::errors::ErrNO;
 use crate::klib::list::{List, ListNode, Linked};
 use crate::page::vm_page_t;
+use crate::types::paddr_t;
 use crate::locking::mutex::Mutex;
 use crate::pmm::{PMM_ALLOC_FLAG_CAN_WAIT, pmm_alloc_pages};
-use crate::vm::vm_cow_pages::{VmCowPages, CanOverwriteContent};
+use crate::vm::vm_cow_pages::{
+    VmCowPages, VmCowPagesLockRef, CanOverwriteContent
+};
 
-type VmObjectPagedLockRef = Arc<Mutex<VmObjectPaged>>;
+pub type VmObjectPagedLockRef = Arc<Mutex<VmObjectPaged>>;
 
 pub struct VmObjectPaged {
     name: String,
     options: u32,
-    cow_pages: Option<VmCowPages>,
+    cow_pages: Option<VmCowPagesLockRef>,
 }
 
 impl VmObjectPaged {
@@ -47,7 +51,7 @@ impl VmObjectPaged {
     }
 
     pub fn set_name(&mut self, name: &str) {
-        self.set_name(name);
+        self.name = String::from(name);
     }
 
     fn check_bits(options: u32, refval: u32) -> bool {
@@ -85,8 +89,10 @@ impl VmObjectPaged {
         /* make sure size is page aligned */
         let size = ROUNDUP_PAGE_SIZE!(size);
 
-        let mut cow_pages =
-            VmCowPages::create(VmCowPages::K_NONE, pmm_alloc_flags, size)?;
+        let cow_ref = Arc::new(Mutex::new(
+            VmCowPages::create(VmCowPages::K_NONE, pmm_alloc_flags, size)?
+        ));
+        let mut cow_pages = cow_ref.lock();
 
         /* If this VMO will always be pinned, allocate and pin the pages
          * in the VmCowPages prior to creating the VmObjectPaged.
@@ -114,15 +120,123 @@ impl VmObjectPaged {
 
         // This creation has succeeded. Must wire up the cow pages and *then* place in the globals list.
         cow_pages.set_paged_backlink_locked(vmo_ref.clone());
+        drop(cow_pages);
         {
             let mut vmo = vmo_ref.as_ref().lock();
-            vmo.cow_pages = Some(cow_pages);
+            vmo.cow_pages = Some(cow_ref);
         }
         ALL_VMOS.lock().push(vmo_ref.clone());
 
         Ok(vmo_ref)
     }
 
+    fn cow_pages(&self) -> Result<&VmCowPagesLockRef, ErrNO> {
+        self.cow_pages.as_ref().ok_or(ErrNO::BadState)
+    }
+
+    /* Looks up the physical addresses of |count| committed pages
+     * starting from |offset|, e.g. for mapping a pinned vmo. */
+    pub fn lookup(&self, offset: usize, count: usize)
+        -> Result<Vec<paddr_t>, ErrNO>
+    {
+        if !IS_PAGE_ALIGNED!(offset) {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let cow = self.cow_pages()?.lock();
+        if offset + count * PAGE_SIZE > cow.size() {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        let mut phys = Vec::with_capacity(count);
+        for i in 0..count {
+            match cow.get_page_for_read(offset + i * PAGE_SIZE) {
+                Some(page) => phys.push(unsafe { (*page).paddr() }),
+                None => return Err(ErrNO::NotFound),
+            }
+        }
+        Ok(phys)
+    }
+
+    /* Creates a copy-on-write clone of [offset, offset + size).
+     * The clone and this vmo share their pages until either side
+     * writes to a page, then the page is copied down to the writer. */
+    pub fn create_clone(&self, offset: usize, size: usize)
+        -> Result<VmObjectPagedLockRef, ErrNO>
+    {
+        if !IS_PAGE_ALIGNED!(offset) {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let size = ROUNDUP_PAGE_SIZE!(size);
+
+        let cow_ref = VmCowPages::create_clone(self.cow_pages()?,
+                                               offset, size)?;
+
+        let options = self.options & Self::K_CAN_BLOCK_ON_PAGE_REQUESTS;
+        let vmo_ref = Arc::new(Mutex::new(VmObjectPaged::new(options)));
+
+        cow_ref.lock().set_paged_backlink_locked(vmo_ref.clone());
+        {
+            let mut vmo = vmo_ref.as_ref().lock();
+            vmo.cow_pages = Some(cow_ref);
+        }
+        ALL_VMOS.lock().push(vmo_ref.clone());
+
+        Ok(vmo_ref)
+    }
+
+    /* Reads content from |offset| into |data|.
+     * Ranges that have never been committed read as zeros. */
+    pub fn read(&self, data: &mut [u8], offset: usize) -> Result<(), ErrNO> {
+        let cow = self.cow_pages()?.lock();
+        if offset + data.len() > cow.size() {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let page_offset = (offset + pos) % PAGE_SIZE;
+            let len = min(PAGE_SIZE - page_offset, data.len() - pos);
+            let dst = &mut data[pos..pos+len];
+            match cow.get_page_for_read(ROUNDDOWN!(offset + pos, PAGE_SIZE)) {
+                Some(page) => {
+                    let va = paddr_to_physmap(unsafe { (*page).paddr() });
+                    let src = unsafe {
+                        core::slice::from_raw_parts(
+                            (va + page_offset) as *const u8, len)
+                    };
+                    dst.copy_from_slice(src);
+                },
+                None => dst.fill(0),
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /* Writes |data| at |offset|, committing pages as needed. */
+    pub fn write(&self, data: &[u8], offset: usize) -> Result<(), ErrNO> {
+        let mut cow = self.cow_pages()?.lock();
+        if offset + data.len() > cow.size() {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let page_offset = (offset + pos) % PAGE_SIZE;
+            let len = min(PAGE_SIZE - page_offset, data.len() - pos);
+            let page =
+                cow.get_page_for_write(ROUNDDOWN!(offset + pos, PAGE_SIZE))?;
+            let va = paddr_to_physmap(unsafe { (*page).paddr() });
+            let dst = unsafe {
+                core::slice::from_raw_parts_mut(
+                    (va + page_offset) as *mut u8, len)
+            };
+            dst.copy_from_slice(&data[pos..pos+len]);
+            pos += len;
+        }
+        Ok(())
+    }
+
 }
 
 pub static ALL_VMOS: Mutex<Vec::<VmObjectPagedLockRef>> = Mutex::new(Vec::new());
\ No newline at end of file
@@ -7,7 +7,7 @@
  */
 
 
-use core::cmp::min;
+use core::cmp::{min, max};
 use crate::errors::ErrNO;
 use crate::klib::rbtree::RBTree;
 use crate::page::vm_page_t;
@@ -159,7 +159,6 @@ impl VmPageListNode {
         self.obj_offset
     }
 
-    #[allow(dead_code)]
     pub fn lookup(&self, index: usize) -> &VmPageOrMarker {
         ZX_ASSERT!(index < Self::K_PAGE_FAN_OUT);
         &self.pages[index]
@@ -192,6 +191,26 @@ impl VmPageListNode {
         Ok(())
     }
 
+    fn for_every_page_in_range_reverse<F>(&self, per_page_func: &mut F,
+                                          start_offset: usize,
+                                          end_offset: usize,
+                                          skew: usize)
+        -> Result<(), ErrNO>
+    where F: FnMut(&VmPageOrMarker, usize) -> Result<(), ErrNO>
+    {
+        ZX_ASSERT!(end_offset >= start_offset);
+        ZX_ASSERT!(start_offset >= self.obj_offset);
+        ZX_ASSERT!(end_offset <= self.end_offset());
+        let start = (start_offset - self.obj_offset) / PAGE_SIZE;
+        let end = (end_offset - self.obj_offset) / PAGE_SIZE;
+        for i in (start..end).rev() {
+            if !self.pages[i].is_empty() {
+                per_page_func(&self.pages[i], self.obj_offset + i * PAGE_SIZE - skew)?;
+            }
+        }
+        Ok(())
+    }
+
     // for every page or marker in the node call the passed in function.
     fn for_every_page<F>(&self, per_page_func: &mut F, skew: usize)
         -> Result<(), ErrNO>
@@ -264,6 +283,24 @@ impl VmPageList {
         panic!("Bad VmPageListNode!");
     }
 
+    pub fn lookup(&self, offset: usize) -> Option<&VmPageOrMarker> {
+        let node_offset = Self::offset_to_node_offset(offset, self.list_skew);
+        let index = Self::offset_to_node_index(offset, self.list_skew);
+
+        /* lookup the tree node that holds this page */
+        self.list.get(&node_offset).map(|pln| pln.lookup(index))
+    }
+
+    pub fn lookup_mut(&mut self, offset: usize)
+        -> Option<&mut VmPageOrMarker>
+    {
+        let node_offset = Self::offset_to_node_offset(offset, self.list_skew);
+        let index = Self::offset_to_node_index(offset, self.list_skew);
+
+        /* lookup the tree node that holds this page */
+        self.list.get_mut(&node_offset).map(|pln| pln.lookup_mut(index))
+    }
+
     pub fn for_every_page_in_range<F>(&self, per_page_func: &mut F,
                                       start_offset: usize, end_offset: usize)
         -> Result<(), ErrNO>
@@ -309,4 +346,34 @@ impl VmPageList {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /* Same as for_every_page_in_range, but from the highest offset
+     * down to the lowest, e.g. for eviction scans. */
+    #[allow(dead_code)]
+    pub fn for_every_page_in_range_reverse<F>(&self, per_page_func: &mut F,
+                                              start_offset: usize,
+                                              end_offset: usize)
+        -> Result<(), ErrNO>
+    where F: FnMut(&VmPageOrMarker, usize) -> Result<(), ErrNO>
+    {
+        if start_offset >= end_offset {
+            return Ok(());
+        }
+        let start_offset = start_offset + self.list_skew;
+        let end_offset = end_offset + self.list_skew;
+
+        // Walk down from the node (if any) that contains the last offset.
+        let offset = ROUNDDOWN!(end_offset - 1, VmPageListNode::K_PAGE_FAN_OUT * PAGE_SIZE);
+        for (_, cur) in self.list.rev_from(&offset) {
+            if cur.end_offset() <= start_offset {
+                break;
+            }
+            cur.for_every_page_in_range_reverse(per_page_func,
+                                                max(start_offset, cur.offset()),
+                                                min(end_offset, cur.end_offset()),
+                                                self.list_skew)?;
+        }
+
+        Ok(())
+    }
+}
@@ -6,18 +6,39 @@
  * at https://opensource.org/licenses/MIT
  */
 
-use core::cmp::max;
+use core::cmp::{max, min};
 use alloc::vec::Vec;
 use crate::ZX_ASSERT;
 use crate::debug::*;
 use crate::defines::PAGE_SHIFT;
+use crate::errors::ErrNO;
 use crate::types::vaddr_t;
 
+/* How alloc_spot_locked picks the spot among the fitting gaps. */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllocMode {
+    /* The smallest fitting gap by its whole size, to reduce
+     * fragmentation. */
+    Compact,
+    /* Uniformly at random among the fitting aligned positions,
+     * capped by the entropy limit. */
+    Random,
+    /* The first fitting gap (the last one for top_down). */
+    Leftmost,
+}
+
+/* The number of positions a Random allocation chooses among at most. */
+const ALLOC_ENTROPY_BITS: usize = 8;
+
+/* The default seed of the PRNG for Random allocations. */
+const DEFAULT_PRNG_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
 pub struct VmAddressRegion {
     pub base: vaddr_t,
     pub size: usize,
     pub flags: usize,
     children: Vec<VmAddressRegion>,
+    prng_state: u64,
 }
 
 impl VmAddressRegion {
@@ -27,9 +48,22 @@ impl VmAddressRegion {
             size: 0,
             flags: 0,
             children: Vec::new(),
+            prng_state: DEFAULT_PRNG_SEED,
         }
     }
 
+    /* Reseed the PRNG which Random allocations draw from. */
+    #[allow(dead_code)]
+    pub fn set_prng_seed(&mut self, seed: u64) {
+        self.prng_state = seed;
+    }
+
+    fn next_random(&mut self) -> usize {
+        self.prng_state = self.prng_state
+            .wrapping_mul(6364136223846793005).wrapping_add(1);
+        (self.prng_state >> 33) as usize
+    }
+
     pub fn init(&mut self, base: vaddr_t, size: usize, flags: usize) {
         self.base = base;
         self.size = size;
@@ -42,8 +76,11 @@ impl VmAddressRegion {
          * (base + size) <= (self.base + self.size)
          * Typically, the value end may overbound and become ZERO!
          */
+        if base < self.base {
+            return false;
+        }
         let offset = base - self.base;
-        base >= self.base && offset < self.size && self.size - offset >= size
+        offset < self.size && self.size - offset >= size
     }
 
     pub fn insert_child(&mut self, child: Self) {
@@ -58,16 +95,109 @@ impl VmAddressRegion {
         }
     }
 
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    /* Whether insert_child has room for one more child without
+     * growing the storage of the children. */
+    pub fn has_child_slot(&self) -> bool {
+        self.children.len() < self.children.capacity()
+    }
+
+    /* Move the children into |storage| if it holds more of them, and
+     * hand back the storage which is no longer used. It lets a caller
+     * which must not allocate under its lock, allocate and free the
+     * storage outside of it. */
+    pub fn swap_children_storage(&mut self, mut storage: Vec<Self>)
+        -> Vec<Self> {
+        ZX_ASSERT!(storage.is_empty());
+        if storage.capacity() > self.children.capacity() {
+            storage.append(&mut self.children);
+            core::mem::swap(&mut self.children, &mut storage);
+        }
+        storage
+    }
+
+    /* Remove the child starting at |base| and hand it back, so that the
+     * caller can reclaim its mappings. None if there's no such child. */
+    pub fn destroy_child(&mut self, base: vaddr_t) -> Option<VmAddressRegion> {
+        let index = self.children.binary_search_by_key(&base, |r| r.base).ok()?;
+        Some(self.children.remove(index))
+    }
+
+    /* Find the region covering |va| among the children, which are sorted
+     * by base, and go down into its own children as far as they cover it.
+     * None if no child covers |va|. */
+    #[allow(dead_code)]
+    pub fn find_mapping(&self, va: vaddr_t) -> Option<&VmAddressRegion> {
+        let index = self.children.partition_point(|r| r.base <= va);
+        if index == 0 {
+            return None;
+        }
+
+        /* The last child starting at or below va, compare the offset
+         * rather than the end, which may overflow. */
+        let child = &self.children[index - 1];
+        if va - child.base >= child.size {
+            return None;
+        }
+        child.find_mapping(va).or(Some(child))
+    }
+
+    /* Whether [base, base + size) overlaps any child.
+     * Compare the last bytes rather than the ends, which may overflow. */
+    fn overlaps_child(&self, base: vaddr_t, size: usize) -> bool {
+        ZX_ASSERT!(size > 0);
+        let last = base + (size - 1);
+        self.children.iter().any(|child| {
+            child.size > 0 && base <= child.base + (child.size - 1) &&
+                child.base <= last
+        })
+    }
+
+    /*
+     * Reserve [base, base + size) as a new child, for the fixed regions
+     * set up during boot. Unlike insert_child, a range out of this region
+     * is InvalidArgs, and one overlapping an existing child is
+     * AlreadyExists, so a misconfigured base is caught rather than
+     * silently shadowing another region.
+     */
+    pub fn reserve_region(&mut self, base: vaddr_t, size: usize, flags: usize)
+        -> Result<(), ErrNO> {
+        if size == 0 || !self.cover_range(base, size) {
+            return Err(ErrNO::InvalidArgs);
+        }
+        if self.overlaps_child(base, size) {
+            return Err(ErrNO::AlreadyExists);
+        }
+
+        let mut child = VmAddressRegion::new();
+        child.init(base, size, flags);
+        self.insert_child(child);
+        Ok(())
+    }
+
     /*
-     * Perform allocations for VMARs. This allocator works by choosing uniformly
-     * at random from a set of positions that could satisfy the allocation.
-     * The set of positions are the 'left' most positions of the address space
-     * and are capped by the address entropy limit. The entropy limit is retrieved
-     * from the address space, and can vary based on whether the user has
-     * requested compact allocations or not.
+     * Perform allocations for VMARs, picking the spot by |mode|.
+     * AllocMode::Leftmost takes the first fitting gap, which is what
+     * the kernel stacks and the heap use. AllocMode::Compact takes the
+     * smallest fitting gap, ranked by the size of the whole gap rather
+     * than the part of it below |upper_limit|. AllocMode::Random chooses
+     * uniformly at random from a set of positions that could satisfy
+     * the allocation. The set of positions are the 'left' most positions
+     * of the address space and are capped by the address entropy limit.
+     *
+     * With |top_down|, the spot is placed as high as possible with its end
+     * no more than |upper_limit| (OFFSET_IS_UPPER_LIMIT semantics), e.g.
+     * for stacks growing down towards their guards. Otherwise the lowest
+     * spot is chosen. The gaps and the positions are then preferred from
+     * the top, so Leftmost takes the last gap, and Random the highest
+     * positions.
      */
     pub fn alloc_spot_locked(&mut self, size: usize, align_pow2: usize,
-                             _arch_mmu_flags: usize, upper_limit: vaddr_t)
+                             _arch_mmu_flags: usize, upper_limit: vaddr_t,
+                             top_down: bool, mode: AllocMode)
         -> vaddr_t
     {
         ZX_ASSERT!(size > 0 && IS_PAGE_ALIGNED!(size));
@@ -76,7 +206,7 @@ impl VmAddressRegion {
 
         let align_pow2 = max(align_pow2, PAGE_SHIFT);
         let alloc_spot = self.get_alloc_spot(align_pow2, size,
-            self.base, self.size, upper_limit);
+            self.base, self.size, upper_limit, top_down, mode);
         /* Sanity check that the allocation fits. */
         let (_, overflowed) = alloc_spot.overflowing_add(size - 1);
         ZX_ASSERT!(!overflowed);
@@ -85,9 +215,11 @@ impl VmAddressRegion {
 
     /* Get the allocation spot that is free and large enough for the aligned size. */
     fn get_alloc_spot(&mut self, align_pow2: usize, size: usize,
-        parent_base: vaddr_t, parent_size: usize, upper_limit: vaddr_t) -> vaddr_t {
+        parent_base: vaddr_t, parent_size: usize, upper_limit: vaddr_t,
+        top_down: bool, mode: AllocMode) -> vaddr_t {
         let (alloc_spot, found) =
-            self.find_alloc_spot_in_gaps(size, align_pow2, parent_base, parent_size, upper_limit);
+            self.find_alloc_spot_in_gaps(size, align_pow2, parent_base, parent_size,
+                                         upper_limit, top_down, mode);
         ZX_ASSERT!(found);
 
         let align: vaddr_t = 1 << align_pow2;
@@ -97,26 +229,77 @@ impl VmAddressRegion {
 
     /* Try to find the spot among all the gaps. */
     fn find_alloc_spot_in_gaps(&mut self, size: usize, align_pow2: usize,
-        parent_base: vaddr_t, parent_size: vaddr_t, upper_limit: vaddr_t) -> (vaddr_t, bool) {
+        parent_base: vaddr_t, parent_size: vaddr_t, upper_limit: vaddr_t,
+        top_down: bool, mode: AllocMode) -> (vaddr_t, bool) {
         let align = 1 << align_pow2;
-        /* Found indicates whether we have found the spot with index |selected_indexes|. */
-        let mut found = false;
-        /* alloc_spot is the virtual start address of the spot to allocate if we find one. */
-        let mut alloc_spot: vaddr_t = 0;
+        /* The fitting gaps as their lowest and highest aligned spots,
+         * and the sizes of the whole gaps. */
+        let mut spans: Vec<(vaddr_t, vaddr_t, usize)> = Vec::new();
         let func = |gap_base: vaddr_t, gap_len: usize| {
             ZX_ASSERT!(IS_ALIGNED!(gap_base, align));
             if gap_len < size || gap_base + size > upper_limit {
                 /* Ignore gap that is too small or out of range. */
                 return true;
             }
-            found = true;
-            alloc_spot = gap_base;
-            return false;
+
+            /* The highest aligned spot in this gap below upper_limit.
+             * NOTE: Don't calculate the end of gap, it may overflow. */
+            let top = min(gap_base + (gap_len - size), upper_limit - size);
+            spans.push((gap_base, ROUNDDOWN!(top, align), gap_len));
+            return true;
         };
 
         self.for_each_gap(func, align_pow2, parent_base, parent_size);
 
-        (alloc_spot, found)
+        /* Gaps are in ascending order, prefer them from the top if top_down. */
+        if top_down {
+            spans.reverse();
+        }
+        let spot_of = |&(low, high, _): &(vaddr_t, vaddr_t, usize)| {
+            if top_down { high } else { low }
+        };
+
+        let spot = match mode {
+            AllocMode::Leftmost => spans.first().map(spot_of),
+            /* Ties go to the first gap, as for Leftmost. */
+            AllocMode::Compact => spans.iter()
+                .min_by_key(|&&(_, _, gap_len)| gap_len).map(spot_of),
+            AllocMode::Random => self.random_spot(&spans, align, top_down),
+        };
+
+        match spot {
+            Some(spot) => (spot, true),
+            None => (0, false),
+        }
+    }
+
+    /* Choose uniformly among the aligned positions of |spans|, taken in
+     * their order (from the top of each one if |top_down|) up to the
+     * entropy limit. */
+    fn random_spot(&mut self, spans: &[(vaddr_t, vaddr_t, usize)],
+                   align: usize, top_down: bool) -> Option<vaddr_t> {
+        let limit = 1 << ALLOC_ENTROPY_BITS;
+        let mut count = 0;
+        for &(low, high, _) in spans {
+            count = min(count + (high - low) / align + 1, limit);
+        }
+        if count == 0 {
+            return None;
+        }
+
+        let mut index = self.next_random() % count;
+        for &(low, high, _) in spans {
+            let positions = (high - low) / align + 1;
+            if index < positions {
+                return Some(if top_down {
+                    high - index * align
+                } else {
+                    low + index * align
+                });
+            }
+            index -= positions;
+        }
+        None
     }
 
     /* Utility for allocators for iterating over gaps between allocations.
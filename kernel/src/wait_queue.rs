@@ -0,0 +1,57 @@
+/*
+ * Copyright (c) 2022 Shi Lei
+ *
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+use crate::sched::Scheduler;
+use crate::thread::Thread;
+
+/* Threads blocked on something, woken up in FIFO order. */
+pub struct WaitQueue {
+    queue: Vec<*mut Thread>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /* Only Scheduler::block puts threads here. */
+    pub(crate) fn insert(&mut self, thread: *mut Thread) {
+        self.queue.push(thread);
+    }
+
+    /* Wakes up the thread which has waited longest.
+     * Returns false if there is no waiter. */
+    pub fn wake_one(&mut self) -> bool {
+        if self.queue.is_empty() {
+            return false;
+        }
+        let thread = self.queue.remove(0);
+        Scheduler::unblock(thread);
+        true
+    }
+
+    /* Wakes up all waiters and returns their number. */
+    pub fn wake_all(&mut self) -> usize {
+        let count = self.queue.len();
+        while self.wake_one() {}
+        count
+    }
+}